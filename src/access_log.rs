@@ -0,0 +1,331 @@
+// Sampled access log with key redaction.
+//
+// One request in every `access_log_sample` is logged as a JSON line with its method, target, status, latency,
+// response bytes and db name, server errors are always logged. Lines go to their own sink - access.log next to
+// rocky.log, stdout in dev mode - so they can be shipped and parsed apart from the application log. Workers only queue
+// lines, a writer thread writes them and flushes once the queue is drained, lines that don't fit a full queue are
+// dropped and counted rather than holding up requests.
+//
+// Path segments and query values matching any of the `redact_keys` patterns are replaced by their HMAC-SHA256 keyed
+// with `redaction_salt` before the line is queued, so requests for the same key can still be correlated without the
+// key ever reaching the log file - and without the salt, hashes of guessed keys can't be matched against it. Patterns
+// match segments and values as they appear in the URL.
+
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use anyhow::anyhow;
+use crossbeam::channel::{bounded, Receiver, Sender, TrySendError};
+use hmac::{Hmac, Mac, NewMac};
+use regex::RegexSet;
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::conversion::current_ms;
+
+const QUEUED_LINES: usize = 10_000;
+
+pub struct AccessLog {
+    every: u64,
+    requests: AtomicU64,
+    redact: RegexSet,
+    salt: String,
+    lines: Option<Sender<Vec<u8>>>,
+    writer: Option<JoinHandle<()>>,
+    dropped: Arc<AtomicU64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Line<'a> {
+    ts: u128,
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    latency_ms: f64,
+    // None for streamed bodies
+    bytes: Option<u64>,
+    db: Option<&'a str>,
+}
+
+// request side of a log line, written once the response is ready
+pub struct Entry {
+    method: Method,
+    target: String,
+    started: Instant,
+    sampled: bool,
+}
+
+impl AccessLog {
+    pub fn new(every: u64, patterns: &[String], salt: &str) -> anyhow::Result<Self> {
+        let redact =
+            RegexSet::new(patterns).map_err(|e| anyhow!("Invalid redact_keys pattern - {}", e))?;
+        if !patterns.is_empty() && salt.is_empty() {
+            return Err(anyhow!("redaction_salt has to be set with redact_keys"));
+        }
+        let log = AccessLog {
+            every: every.max(1),
+            requests: AtomicU64::new(0),
+            redact,
+            salt: salt.into(),
+            lines: None,
+            writer: None,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        Ok(log.write_to(Box::new(io::stdout())))
+    }
+
+    // appends to access.log under the log path instead of stdout
+    pub fn to_file(self, log_path: &str) -> anyhow::Result<Self> {
+        let path = format!("{}/access.log", log_path);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| anyhow!("Can't open access log {} - {}", path, e))?;
+        Ok(self.write_to(Box::new(file)))
+    }
+
+    // the previous writer finishes its queued lines first
+    pub fn write_to(mut self, out: Box<dyn Write + Send>) -> Self {
+        self.stop();
+        let (lines, queued) = bounded(QUEUED_LINES);
+        let dropped = self.dropped.clone();
+        let writer = thread::Builder::new()
+            .name("access-log-thread".into())
+            .spawn(move || write_lines(queued, out, dropped))
+            .expect("Failed to register access log thread");
+        self.lines = Some(lines);
+        self.writer = Some(writer);
+        self
+    }
+
+    fn stop(&mut self) {
+        self.lines.take();
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                warn!("Access log thread panicked");
+            }
+        }
+    }
+
+    pub fn start(&self, req: &ServiceRequest) -> Entry {
+        let sampled = self.requests.fetch_add(1, Ordering::Relaxed) % self.every == 0;
+        // unsampled targets are redacted only if the request fails
+        let target = if sampled {
+            self.redact_target(req.path(), req.query_string())
+        } else {
+            String::new()
+        };
+        Entry {
+            method: req.method().clone(),
+            target,
+            started: Instant::now(),
+            sampled,
+        }
+    }
+
+    pub fn finish<B: MessageBody>(&self, entry: Entry, res: &ServiceResponse<B>) {
+        let status = res.status();
+        if !entry.sampled && !status.is_server_error() {
+            return;
+        }
+        let target = if entry.sampled {
+            entry.target
+        } else {
+            let req = res.request();
+            self.redact_target(req.path(), req.query_string())
+        };
+        let bytes = match res.response().body().size() {
+            BodySize::Sized(size) => Some(size as u64),
+            BodySize::Sized64(size) => Some(size),
+            BodySize::None | BodySize::Empty => Some(0),
+            BodySize::Stream => None,
+        };
+        let line = Line {
+            ts: current_ms().unwrap_or_default(),
+            method: entry.method.as_str(),
+            path: &target,
+            status: status.as_u16(),
+            latency_ms: entry.started.elapsed().as_secs_f64() * 1000.0,
+            bytes,
+            db: res.request().match_info().get("db_name"),
+        };
+        let mut line = match serde_json::to_vec(&line) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Can't serialize access log line, e = {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        if let Some(lines) = &self.lines {
+            if let Err(TrySendError::Full(_)) = lines.try_send(line) {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn redact_target(&self, path: &str, query: &str) -> String {
+        let path: Vec<String> = path.split('/').map(|s| self.redact(s)).collect();
+        let mut target = path.join("/");
+        if !query.is_empty() {
+            let query: Vec<String> = query
+                .split('&')
+                .map(|pair| match pair.find('=') {
+                    Some(idx) => format!("{}={}", &pair[..idx], self.redact(&pair[idx + 1..])),
+                    None => pair.into(),
+                })
+                .collect();
+            target.push('?');
+            target.push_str(&query.join("&"));
+        }
+        target
+    }
+
+    fn redact(&self, val: &str) -> String {
+        if val.is_empty() || !self.redact.is_match(val) {
+            return val.into();
+        }
+        let mut mac =
+            Hmac::<Sha256>::new_varkey(self.salt.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(val.as_bytes());
+        // 64 bits are plenty to tell keys apart in a log
+        let hash: String = mac.finalize().into_bytes()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        format!("#{}", hash)
+    }
+}
+
+// queued lines are written before the log goes away
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn write_lines(queued: Receiver<Vec<u8>>, out: Box<dyn Write + Send>, dropped: Arc<AtomicU64>) {
+    let mut out = BufWriter::new(out);
+    for line in queued.iter() {
+        let mut written = out.write_all(&line);
+        if queued.is_empty() {
+            written = written.and_then(|_| out.flush());
+            let dropped = dropped.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                warn!("Access log queue was full, {} lines dropped", dropped);
+            }
+        }
+        if let Err(e) = written {
+            warn!("Can't write access log, e = {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use actix_web::test::TestRequest;
+    use actix_web::HttpResponse;
+
+    use super::*;
+
+    fn access_log(patterns: &[&str]) -> AccessLog {
+        let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        AccessLog::new(1, &patterns, "salt").unwrap()
+    }
+
+    #[test]
+    fn should_hash_matching_keys() {
+        let log = access_log(&["^user:", "@"]);
+        let target = log.redact_target("/db_1/user:42", "");
+        assert!(target.starts_with("/db_1/#"));
+        assert!(!target.contains("42"));
+        assert_eq!(target, log.redact_target("/db_1/user:42", ""));
+        assert_ne!(target, log.redact_target("/db_1/user:43", ""));
+
+        assert_eq!("/db_1/order:1", log.redact_target("/db_1/order:1", ""));
+        let target = log.redact_target("/db_1/_range", "start=user:1&end=z&flag");
+        assert!(target.starts_with("/db_1/_range?start=#"));
+        assert!(target.ends_with("&end=z&flag"));
+    }
+
+    #[test]
+    fn should_salt_hashes() {
+        let patterns = vec!["^user:".to_string()];
+        let other = AccessLog::new(1, &patterns, "pepper").unwrap();
+        assert_ne!(
+            access_log(&["^user:"]).redact("user:1"),
+            other.redact("user:1")
+        );
+    }
+
+    #[test]
+    fn should_sample_requests() {
+        let log = AccessLog::new(3, &[], "").unwrap();
+        let sampled = (0..6)
+            .filter(|_| log.start(&TestRequest::default().to_srv_request()).sampled)
+            .count();
+        assert_eq!(2, sampled);
+    }
+
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Lines {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_write_json_lines() {
+        let lines = Lines::default();
+        let patterns = vec!["^user:".to_string()];
+        let log = AccessLog::new(1, &patterns, "salt")
+            .unwrap()
+            .write_to(Box::new(lines.clone()));
+        let req = TestRequest::get()
+            .uri("/db_1/user:42")
+            .param("db_name", "db_1")
+            .to_srv_request();
+        let entry = log.start(&req);
+        log.finish(entry, &req.into_response(HttpResponse::Ok().body("value")));
+        drop(log);
+
+        let out = lines.0.lock().unwrap();
+        assert_eq!(Some(&b'\n'), out.last());
+        let line: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!("GET", line["method"]);
+        assert!(line["path"].as_str().unwrap().starts_with("/db_1/#"));
+        assert_eq!(200, line["status"]);
+        assert_eq!(5, line["bytes"]);
+        assert_eq!("db_1", line["db"]);
+        assert!(line["latencyMs"].as_f64().is_some());
+        assert!(line["ts"].as_u64().is_some());
+    }
+
+    #[test]
+    fn should_reject_invalid_patterns() {
+        assert!(AccessLog::new(1, &["(".to_string()], "salt").is_err());
+    }
+
+    #[test]
+    fn should_require_salt_with_patterns() {
+        assert!(AccessLog::new(1, &["^user:".to_string()], "").is_err());
+        assert!(AccessLog::new(1, &[], "").is_ok());
+    }
+}
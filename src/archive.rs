@@ -0,0 +1,559 @@
+// Minimal ustar writer and reader used by database exports and imports.
+//
+// Each file is written as a 512 byte header followed by its content padded to a multiple of 512 bytes,
+// the archive ends with two zeroed blocks. Only regular files in a flat directory are supported which is
+// all a RocksDb checkpoint holds. Names over 100 bytes are split into the ustar prefix and name fields,
+// ones that don't fit either way are carried by a PAX header in front of the file's own.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use actix_web::web::Bytes;
+use anyhow::anyhow;
+
+use crate::errors::ErrorCtx;
+
+const BLOCK: usize = 512;
+const CHUNK_BYTES: u64 = 64 * 1024;
+// 11 octal digits
+const MAX_SIZE: u64 = 0o77_777_777_777;
+const NAME_BYTES: usize = 100;
+const PREFIX_BYTES: usize = 155;
+const PAX_NAME: &str = "././@PaxHeader";
+
+struct Entry {
+    file: File,
+    size: u64,
+    remaining: u64,
+}
+
+// streams all files of a directory as a tar archive and removes the directory once dropped
+pub struct TarStream {
+    dir: PathBuf,
+    prefix: String,
+    files: VecDeque<PathBuf>,
+    current: Option<Entry>,
+    size: u64,
+    // bytes of the archive still to be skipped
+    skip: u64,
+    keep_dir: bool,
+    finished: bool,
+    failed: bool,
+}
+
+impl TarStream {
+    // entries are named {prefix}/{file name}
+    pub fn new<P>(dir: P, prefix: &str) -> io::Result<Self>
+    where
+        P: Into<PathBuf>,
+    {
+        let dir = dir.into();
+        let mut files = vec![];
+        let mut size = BLOCK as u64 * 2;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                let len = fs::metadata(&path)?.len();
+                size += entry_size(&entry_name(prefix, &path), len)?;
+                files.push(path);
+            }
+        }
+        files.sort();
+
+        Ok(TarStream {
+            dir,
+            prefix: prefix.into(),
+            files: files.into(),
+            current: None,
+            size,
+            skip: 0,
+            keep_dir: false,
+            finished: false,
+            failed: false,
+        })
+    }
+
+    // length of the whole archive in bytes, the files mustn't change while it's streamed
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // streams the archive from offset on, entries before it aren't read
+    pub fn starting_at(mut self, offset: u64) -> Self {
+        self.skip = offset;
+        self
+    }
+
+    // the directory is left in place once dropped
+    pub fn keep_dir(mut self) -> Self {
+        self.keep_dir = true;
+        self
+    }
+
+    fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        if let Some(entry) = self.current.as_mut() {
+            let len = entry.remaining.min(CHUNK_BYTES) as usize;
+            let mut buf = vec![0; len];
+            entry.file.read_exact(&mut buf)?;
+            entry.remaining -= len as u64;
+            if entry.remaining == 0 {
+                buf.resize(len + padding(entry.size), 0);
+                self.current = None;
+            }
+            return Ok(Some(Bytes::from(buf)));
+        }
+
+        match self.files.pop_front() {
+            Some(path) => {
+                let mut file = File::open(&path)?;
+                let meta = file.metadata()?;
+                let name = entry_name(&self.prefix, &path);
+                let size = entry_size(&name, meta.len())?;
+                if self.skip >= size {
+                    self.skip -= size;
+                    return Ok(Some(Bytes::new()));
+                }
+                let mtime = meta
+                    .modified()?
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                let headers = headers(&name, meta.len(), mtime)?;
+                if meta.len() > 0 {
+                    // content before the offset is seeked over, the headers are cut by the caller
+                    let skipped = self
+                        .skip
+                        .saturating_sub(headers.len() as u64)
+                        .min(meta.len());
+                    file.seek(SeekFrom::Start(skipped))?;
+                    self.skip -= skipped;
+                    self.current = Some(Entry {
+                        file,
+                        size: meta.len(),
+                        remaining: meta.len() - skipped,
+                    });
+                }
+                Ok(Some(Bytes::from(headers)))
+            }
+            None if !self.finished => {
+                self.finished = true;
+                Ok(Some(Bytes::from(vec![0; BLOCK * 2])))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Iterator for TarStream {
+    type Item = io::Result<Bytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            match self.next_chunk() {
+                Ok(Some(chunk)) if chunk.len() as u64 <= self.skip => {
+                    self.skip -= chunk.len() as u64;
+                }
+                Ok(Some(chunk)) => {
+                    let at = self.skip as usize;
+                    self.skip = 0;
+                    return Some(Ok(chunk.slice(at..)));
+                }
+                Ok(None) => return None,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for TarStream {
+    fn drop(&mut self) {
+        if self.keep_dir {
+            return;
+        }
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            warn!("Failed to remove {:?}, e = {}", &self.dir, e);
+        }
+    }
+}
+
+// directory for uploaded files, removed with everything in it once dropped
+pub struct StagingDir {
+    path: PathBuf,
+}
+
+impl StagingDir {
+    pub fn create<P>(path: P) -> io::Result<Self>
+    where
+        P: Into<PathBuf>,
+    {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        Ok(StagingDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            warn!("Failed to remove {:?}, e = {}", &self.path, e);
+        }
+    }
+}
+
+// extracts regular files into dir - directories in entry names are dropped so nothing is written outside of it
+pub fn unpack<P, D>(archive: P, dir: D) -> anyhow::Result<Vec<PathBuf>>
+where
+    P: AsRef<Path>,
+    D: AsRef<Path>,
+{
+    let mut reader = BufReader::new(File::open(archive)?);
+    let mut files = vec![];
+    let mut h = [0; BLOCK];
+    // path of the next entry from a PAX header
+    let mut path = None;
+    loop {
+        reader
+            .read_exact(&mut h)
+            .map_err(|_| malformed("unexpected end of archive"))?;
+        if h.iter().all(|b| *b == 0) {
+            return Ok(files);
+        }
+
+        let size = parse_octal(&h[124..136])?;
+        let mut content = (&mut reader).take(size);
+        if h[156] == b'x' {
+            let mut records = String::new();
+            content
+                .read_to_string(&mut records)
+                .map_err(|_| malformed("PAX header is not valid utf8"))?;
+            path = pax_path(&records);
+        } else if h[156] == b'0' || h[156] == 0 {
+            let name = match path.take() {
+                Some(path) => path,
+                None => field_str(&h[..NAME_BYTES])?,
+            };
+            let name = Path::new(&name)
+                .file_name()
+                .ok_or_else(|| malformed("entry without a file name"))?;
+            let path = dir.as_ref().join(name);
+            let copied = io::copy(&mut content, &mut File::create(&path)?)?;
+            if copied != size {
+                return Err(malformed("unexpected end of entry"));
+            }
+            files.push(path);
+        } else {
+            path = None;
+            io::copy(&mut content, &mut io::sink())?;
+        }
+        io::copy(
+            &mut (&mut reader).take(padding(size) as u64),
+            &mut io::sink(),
+        )?;
+    }
+}
+
+fn entry_name(prefix: &str, path: &Path) -> String {
+    format!(
+        "{}/{}",
+        prefix,
+        path.file_name().unwrap_or_default().to_string_lossy()
+    )
+}
+
+// the file's header, preceded by a PAX header when its name doesn't fit a ustar one
+fn headers(name: &str, size: u64, mtime: u64) -> io::Result<Vec<u8>> {
+    if split_name(name).is_some() {
+        return Ok(header(name, size, mtime)?.to_vec());
+    }
+    let record = pax_record("path", name);
+    let mut headers = ustar("", PAX_NAME, record.len() as u64, mtime, b'x').to_vec();
+    headers.extend_from_slice(record.as_bytes());
+    headers.resize(headers.len() + padding(record.len() as u64), 0);
+    // readers without PAX support get the file under its name cut to fit
+    let file_name = name.rsplit('/').next().unwrap_or_default();
+    headers.extend_from_slice(&header(cut(file_name, NAME_BYTES), size, mtime)?);
+    Ok(headers)
+}
+
+fn header(name: &str, size: u64, mtime: u64) -> io::Result<[u8; BLOCK]> {
+    let (prefix, name) = split_name(name).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Entry name {} doesn't fit a ustar header", name),
+        )
+    })?;
+    if size > MAX_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Entry {} is larger than {} bytes", name, MAX_SIZE),
+        ));
+    }
+    Ok(ustar(prefix, name, size, mtime, b'0'))
+}
+
+fn ustar(prefix: &str, name: &str, size: u64, mtime: u64, kind: u8) -> [u8; BLOCK] {
+    let mut h = [0; BLOCK];
+    h[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut h[100..108], 0o644);
+    octal(&mut h[108..116], 0);
+    octal(&mut h[116..124], 0);
+    octal(&mut h[124..136], size);
+    octal(&mut h[136..148], mtime);
+    h[156] = kind;
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+    h[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // checksum is computed with its own field set to spaces
+    h[148..156].copy_from_slice(&[b' '; 8]);
+    let sum: u64 = h.iter().map(|b| u64::from(*b)).sum();
+    octal(&mut h[148..155], sum);
+    h
+}
+
+// (prefix, name) of a ustar header, split at a '/' when the name is too long for the name field
+fn split_name(name: &str) -> Option<(&str, &str)> {
+    if name.len() <= NAME_BYTES {
+        return Some(("", name));
+    }
+    name.match_indices('/')
+        .map(|(at, _)| (&name[..at], &name[at + 1..]))
+        .find(|(prefix, name)| prefix.len() <= PREFIX_BYTES && name.len() <= NAME_BYTES)
+}
+
+// a record is prefixed by its own length in bytes, those digits included
+fn pax_record(key: &str, value: &str) -> String {
+    let record = format!(" {}={}\n", key, value);
+    let mut len = record.len();
+    while len != digits(len) + record.len() {
+        len = digits(len) + record.len();
+    }
+    format!("{}{}", len, record)
+}
+
+fn pax_path(records: &str) -> Option<String> {
+    records.lines().find_map(|record| {
+        let (_, pair) = record.split_at(record.find(' ')? + 1);
+        pair.strip_prefix("path=").map(String::from)
+    })
+}
+
+fn digits(n: usize) -> usize {
+    n.to_string().len()
+}
+
+// at most max bytes, on a char boundary
+fn cut(name: &str, max: usize) -> &str {
+    let mut end = name.len().min(max);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+// zero padded octal digits followed by a NUL
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[field.len() - 1] = 0;
+}
+
+// headers and padded content
+fn entry_size(name: &str, size: u64) -> io::Result<u64> {
+    Ok(headers(name, size, 0)?.len() as u64 + size + padding(size) as u64)
+}
+
+fn padding(size: u64) -> usize {
+    (BLOCK - (size as usize % BLOCK)) % BLOCK
+}
+
+fn field_str(field: &[u8]) -> anyhow::Result<String> {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8(field[..end].to_vec()).map_err(|_| malformed("entry name is not valid utf8"))
+}
+
+fn parse_octal(field: &[u8]) -> anyhow::Result<u64> {
+    let digits = field_str(field)?;
+    u64::from_str_radix(digits.trim(), 8).map_err(|_| malformed("invalid entry size"))
+}
+
+fn malformed(reason: &str) -> anyhow::Error {
+    anyhow!(ErrorCtx::Validation(format!(
+        "Malformed archive - {}",
+        reason
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn should_write_ustar_header() {
+        let h = header("db/CURRENT", 16, 0).unwrap();
+        assert_eq!(b"db/CURRENT", &h[..10]);
+        assert_eq!(b"00000000020\0", &h[124..136]);
+        assert_eq!(b"ustar\0", &h[257..263]);
+
+        let stored: u64 =
+            u64::from_str_radix(std::str::from_utf8(&h[148..154]).unwrap(), 8).unwrap();
+        let expected: u64 = h[..148]
+            .iter()
+            .chain(&[b' '; 8])
+            .chain(&h[156..])
+            .map(|b| u64::from(*b))
+            .sum();
+        assert_eq!(expected, stored);
+    }
+
+    #[test]
+    fn should_stream_dir_and_remove_it() {
+        let dir = Path::new("./db/should_stream_dir_and_remove_it");
+        fs::create_dir_all(dir).unwrap();
+        File::create(dir.join("a.sst"))
+            .unwrap()
+            .write_all(&vec![7; 1000])
+            .unwrap();
+        File::create(dir.join("empty.log")).unwrap();
+
+        let archive: Vec<u8> = TarStream::new(dir, "db")
+            .unwrap()
+            .flat_map(|chunk| chunk.unwrap().to_vec())
+            .collect();
+
+        // header + 2 content blocks, empty file header, 2 end blocks
+        assert_eq!(BLOCK * 6, archive.len());
+        assert_eq!(b"db/a.sst", &archive[..8]);
+        assert_eq!(vec![7; 1000], archive[BLOCK..BLOCK + 1000].to_vec());
+        assert_eq!(b"db/empty.log", &archive[BLOCK * 3..BLOCK * 3 + 12]);
+        assert!(archive[BLOCK * 4..].iter().all(|b| *b == 0));
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn should_resume_archive_at_any_offset() {
+        let dir = Path::new("./db/should_resume_archive_at_any_offset");
+        fs::create_dir_all(dir).unwrap();
+        File::create(dir.join("a.sst"))
+            .unwrap()
+            .write_all(&vec![7; 1000])
+            .unwrap();
+        File::create(dir.join("b.log")).unwrap();
+        File::create(dir.join("c.sst"))
+            .unwrap()
+            .write_all(&vec![9; 512])
+            .unwrap();
+
+        let stream = TarStream::new(dir, "db").unwrap().keep_dir();
+        let size = stream.size();
+        let archive: Vec<u8> = stream.flat_map(|chunk| chunk.unwrap().to_vec()).collect();
+        assert_eq!(size, archive.len() as u64);
+        assert!(dir.exists());
+
+        for offset in (0..=size).step_by(97).chain(vec![512, 1024, 1536, size]) {
+            let rest: Vec<u8> = TarStream::new(dir, "db")
+                .unwrap()
+                .keep_dir()
+                .starting_at(offset)
+                .flat_map(|chunk| chunk.unwrap().to_vec())
+                .collect();
+            assert_eq!(
+                archive[offset as usize..].to_vec(),
+                rest,
+                "offset {}",
+                offset
+            );
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_stream_long_names() {
+        let dir = Path::new("./db/should_stream_long_names");
+        let staging = StagingDir::create("./db/should_stream_long_names_staging").unwrap();
+        fs::create_dir_all(dir).unwrap();
+        File::create(dir.join("a.sst"))
+            .unwrap()
+            .write_all(b"data")
+            .unwrap();
+
+        let split = "n".repeat(120);
+        let pax = "p".repeat(300);
+        for prefix in &[split.as_str(), pax.as_str()] {
+            let stream = TarStream::new(dir, prefix).unwrap().keep_dir();
+            let size = stream.size();
+            let archive: Vec<u8> = stream.flat_map(|chunk| chunk.unwrap().to_vec()).collect();
+            assert_eq!(size, archive.len() as u64);
+            if *prefix == split {
+                assert_eq!(b"a.sst", &archive[..5]);
+                assert_eq!(split.as_bytes(), &archive[345..465]);
+            } else {
+                assert_eq!(b'x', archive[156]);
+            }
+
+            let tar = staging.path().join("upload.tar");
+            File::create(&tar).unwrap().write_all(&archive).unwrap();
+            let files = unpack(&tar, staging.path()).unwrap();
+            assert_eq!(vec![staging.path().join("a.sst")], files);
+            assert_eq!(b"data".to_vec(), fs::read(&files[0]).unwrap());
+        }
+        assert_eq!("17 path=db/a.sst\n", pax_record("path", "db/a.sst"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_unpack_streamed_archive() {
+        let src = Path::new("./db/should_unpack_streamed_archive/src");
+        fs::create_dir_all(src).unwrap();
+        File::create(src.join("1.sst"))
+            .unwrap()
+            .write_all(&vec![1; 600])
+            .unwrap();
+        File::create(src.join("2.sst"))
+            .unwrap()
+            .write_all(b"two")
+            .unwrap();
+        let archive: Vec<u8> = TarStream::new(src, "db")
+            .unwrap()
+            .flat_map(|chunk| chunk.unwrap().to_vec())
+            .collect();
+
+        let staging = StagingDir::create("./db/should_unpack_streamed_archive/staging").unwrap();
+        let tar = staging.path().join("upload.tar");
+        File::create(&tar).unwrap().write_all(&archive).unwrap();
+        let files = unpack(&tar, staging.path()).unwrap();
+
+        assert_eq!(
+            vec![staging.path().join("1.sst"), staging.path().join("2.sst")],
+            files
+        );
+        assert_eq!(vec![1; 600], fs::read(&files[0]).unwrap());
+        assert_eq!(b"two".to_vec(), fs::read(&files[1]).unwrap());
+        assert!(unpack(&tar, staging.path()).is_ok());
+
+        File::create(&tar)
+            .unwrap()
+            .write_all(&archive[..700])
+            .unwrap();
+        assert!(unpack(&tar, staging.path()).is_err());
+
+        let dir = staging.path().to_path_buf();
+        drop(staging);
+        assert!(!dir.exists());
+        fs::remove_dir_all("./db/should_unpack_streamed_archive").unwrap();
+    }
+}
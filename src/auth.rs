@@ -0,0 +1,86 @@
+use actix_web::dev::Payload;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{web, FromRequest, HttpRequest};
+use anyhow::anyhow;
+use futures::future::{ready, Ready};
+
+use crate::errors::{ErrWrapper, ErrorCtx};
+
+const BEARER: &str = "Bearer ";
+
+pub struct AdminToken(Option<String>);
+
+// extractor guarding admin endpoints
+pub struct Authorized;
+
+// whether metrics endpoints require the admin token
+pub struct MetricsAuth(bool);
+
+// extractor guarding metrics endpoints when MetricsAuth is enabled
+pub struct MetricsAuthorized;
+
+impl AdminToken {
+    pub fn new(token: Option<String>) -> Self {
+        AdminToken(token)
+    }
+
+    fn token(&self) -> Option<&str> {
+        self.0.as_deref()
+    }
+}
+
+impl FromRequest for Authorized {
+    type Error = ErrWrapper;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authorize(req).map(|_| Authorized).map_err(ErrWrapper::from))
+    }
+}
+
+impl MetricsAuth {
+    pub fn new(required: bool) -> Self {
+        MetricsAuth(required)
+    }
+}
+
+impl FromRequest for MetricsAuthorized {
+    type Error = ErrWrapper;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let required = req
+            .app_data::<web::Data<MetricsAuth>>()
+            .map_or(false, |m| m.0);
+        let res = if required { authorize(req) } else { Ok(()) };
+        ready(res.map(|_| MetricsAuthorized).map_err(ErrWrapper::from))
+    }
+}
+
+fn authorize(req: &HttpRequest) -> anyhow::Result<()> {
+    let expected = req
+        .app_data::<web::Data<AdminToken>>()
+        .and_then(|t| t.token());
+
+    match expected {
+        None => Err(anyhow!(ErrorCtx::Forbidden(
+            "Admin endpoints are disabled - admin_token is not configured".into()
+        ))),
+        Some(expected) => {
+            let provided = req
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.strip_prefix(BEARER));
+            if provided == Some(expected) {
+                Ok(())
+            } else {
+                Err(anyhow!(ErrorCtx::Unauthorized(
+                    "Missing or invalid admin token".into()
+                )))
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::path::Path;
+
+use rocksdb::backup::{BackupEngine, BackupEngineInfo, BackupEngineOptions};
+use serde::Serialize;
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupInfo {
+    backup_id: u32,
+    // epoch seconds
+    timestamp: i64,
+    size: u64,
+    num_files: u32,
+    // erasures whose keys are still in the backup
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    erasures: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckpointInfo {
+    path: String,
+    // epoch milliseconds
+    created_at: u128,
+}
+
+impl From<&BackupEngineInfo> for BackupInfo {
+    fn from(info: &BackupEngineInfo) -> Self {
+        BackupInfo {
+            backup_id: info.backup_id,
+            timestamp: info.timestamp,
+            size: info.size,
+            num_files: info.num_files,
+            erasures: vec![],
+        }
+    }
+}
+
+impl BackupInfo {
+    pub fn id(&self) -> u32 {
+        self.backup_id
+    }
+
+    pub fn with_erasures(mut self, erasures: Vec<String>) -> Self {
+        self.erasures = erasures;
+        self
+    }
+}
+
+impl CheckpointInfo {
+    pub fn new(path: String, created_at: u128) -> Self {
+        CheckpointInfo { path, created_at }
+    }
+}
+
+// the engine holds a lock on the backup dir, it's opened per operation and dropped right after
+pub fn open_engine<P>(dir: P) -> anyhow::Result<BackupEngine>
+where
+    P: AsRef<Path>,
+{
+    Ok(BackupEngine::open(&BackupEngineOptions::default(), dir)?)
+}
+
+pub fn backups(engine: &BackupEngine) -> Vec<BackupInfo> {
+    let mut backups: Vec<BackupInfo> = engine
+        .get_backup_info()
+        .iter()
+        .map(BackupInfo::from)
+        .collect();
+    backups.sort_by_key(|b| b.backup_id);
+    backups
+}
@@ -0,0 +1,159 @@
+// Persistent change log of a db, read incrementally with GET /{db_name}/_changes?since=.
+//
+// Every change is appended as JSON to its own column family under an id that grows by one with each entry, puts
+// carry the value as it was written. Ids are assigned in the order changes are logged, so a consumer resuming
+// after the last id it saw misses nothing - unlike the RocksDb sequence number, which also advances with writes
+// that aren't changes (e.g. access stats). Entries older than the retention are purged by the sweeper, a consumer
+// falling further behind sees the log start past its cursor. An entry is written in the batch of the change it
+// logs, so a crash loses both or neither.
+
+use serde::{Deserialize, Serialize};
+
+use crate::events::EventKind;
+use crate::key_encoding::KeyEncoding;
+
+pub const CHANGELOG_CF: &str = "changelog";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LogEntry {
+    pub id: u64,
+    // ms since epoch
+    pub at: u128,
+    pub kind: EventKind,
+    // key and end are base64 encoded when either isn't UTF-8
+    pub key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_encoding: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    // base64 encoded value of puts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangesPage {
+    pub changes: Vec<LogEntry>,
+    // since of the next request
+    pub next: u64,
+    // id of the oldest retained entry, a since below it has missed changes
+    pub first: Option<u64>,
+}
+
+// a change as the write making it logs it
+#[derive(Debug, Clone, Copy)]
+pub struct Logged<'a> {
+    pub kind: EventKind,
+    pub key: &'a [u8],
+    // exclusive end of a range delete
+    pub end: Option<&'a [u8]>,
+    pub version: Option<u64>,
+    pub value: Option<&'a [u8]>,
+}
+
+impl<'a> Logged<'a> {
+    pub fn new(kind: EventKind, key: &'a [u8]) -> Self {
+        Logged {
+            kind,
+            key,
+            end: None,
+            version: None,
+            value: None,
+        }
+    }
+
+    // values of uploaded records are left out, they're read from the db
+    pub fn put(key: &'a [u8], version: Option<u64>, value: Option<&'a [u8]>) -> Self {
+        Logged {
+            version,
+            value,
+            ..Logged::new(EventKind::Put, key)
+        }
+    }
+
+    pub fn range(start: &'a [u8], end: &'a [u8]) -> Self {
+        Logged {
+            end: Some(end),
+            ..Logged::new(EventKind::DeleteRange, start)
+        }
+    }
+}
+
+impl LogEntry {
+    pub fn new(id: u64, at: u128, seq: Option<u64>, change: &Logged) -> Self {
+        let encoding = KeyEncoding::of_document(std::iter::once(change.key).chain(change.end));
+        // every key is valid in the encoding of the document
+        let encode = |key: &[u8]| encoding.encode(key).unwrap_or_default();
+        LogEntry {
+            id,
+            at,
+            kind: change.kind,
+            key: encode(change.key),
+            key_encoding: encoding.header_value().map(String::from),
+            end: change.end.map(encode),
+            version: change.version,
+            seq,
+            value: change.value.map(base64::encode),
+        }
+    }
+
+    // the raw key and range end
+    pub fn keys(&self) -> anyhow::Result<(Vec<u8>, Option<Vec<u8>>)> {
+        let encoding = KeyEncoding::from_document(self.key_encoding.as_deref())?;
+        let end = self.end.as_deref().map(|end| encoding.decode(end));
+        Ok((encoding.decode(&self.key)?, end.transpose()?))
+    }
+}
+
+// big endian so entries are iterated in id order
+pub fn entry_key(id: u64) -> [u8; 8] {
+    id.to_be_bytes()
+}
+
+pub fn entry_id(key: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&key[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_order_entries_by_id() {
+        assert!(entry_key(255) < entry_key(256));
+        assert!(entry_key(1) < entry_key(u64::MAX));
+        assert_eq!(256, entry_id(&entry_key(256)));
+    }
+
+    #[test]
+    fn should_serialize_entry() {
+        let change = Logged::put(b"k", Some(2), Some(b"v1"));
+        let entry = LogEntry::new(3, 1_600_000_000_000, Some(7), &change);
+        assert_eq!(
+            r#"{"id":3,"at":1600000000000,"kind":"put","key":"k","version":2,"seq":7,"value":"djE="}"#,
+            serde_json::to_string(&entry).unwrap()
+        );
+
+        let bytes = serde_json::to_vec(&entry).unwrap();
+        assert_eq!(entry, serde_json::from_slice(&bytes).unwrap());
+    }
+
+    #[test]
+    fn should_encode_binary_keys() {
+        let change = Logged::range(&[0, 255], b"z");
+        let entry = LogEntry::new(1, 1_600_000_000_000, None, &change);
+        assert_eq!(
+            r#"{"id":1,"at":1600000000000,"kind":"delete_range","key":"AP8","keyEncoding":"base64","end":"eg"}"#,
+            serde_json::to_string(&entry).unwrap()
+        );
+        assert_eq!((vec![0, 255], Some(b"z".to_vec())), entry.keys().unwrap());
+    }
+}
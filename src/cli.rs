@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+
+use actix_web::web::Bytes;
+use anyhow::anyhow;
+use rocksdb::{Options, SstFileWriter};
+use structopt::StructOpt;
+
+use crate::config::DbConfig;
+use crate::conversion::{current_ms, IntoBytes};
+use crate::db::{Data, DbManager, Order, Representation, WriteCondition};
+use crate::key_encoding::KeyEncoding;
+use crate::scan_snapshot::ScanCursor;
+use crate::wire::{self, WireRecord};
+use crate::{MAX_PAGE_SIZE, NO_TTL};
+
+// offline data operations - they open the data directory directly so the server has to be stopped,
+// RocksDb lock files make them fail otherwise
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    #[structopt(about = "Stores a record")]
+    Put {
+        #[structopt(flatten)]
+        target: Target,
+        key: String,
+        value: String,
+        #[structopt(long, help = "Time to live in milliseconds")]
+        ttl: Option<u128>,
+    },
+    #[structopt(about = "Writes a record value to stdout")]
+    Get {
+        #[structopt(flatten)]
+        target: Target,
+        key: String,
+    },
+    #[structopt(about = "Writes records as tab separated key and value lines to stdout")]
+    Scan {
+        #[structopt(flatten)]
+        target: Target,
+        #[structopt(long, help = "Max number of records")]
+        limit: Option<usize>,
+    },
+    #[structopt(about = "Exports all records to a file in the binary batch format")]
+    Export {
+        #[structopt(flatten)]
+        target: Target,
+        #[structopt(long, help = "Output file")]
+        out: String,
+    },
+    #[structopt(about = "Builds an SST file for _import from a file in the binary batch format")]
+    Sst {
+        #[structopt(long, help = "Input file in the binary batch format")]
+        input: String,
+        #[structopt(long, help = "Output SST file")]
+        out: String,
+        #[structopt(long, help = "Time to live in milliseconds")]
+        ttl: Option<u128>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Target {
+    #[structopt(long, help = "Data directory holding all databases")]
+    data_path: String,
+    #[structopt(long, help = "Database name")]
+    db: String,
+}
+
+pub async fn run(cmd: Command, mut db_cfg: DbConfig) -> anyhow::Result<()> {
+    let target = match &cmd {
+        Command::Sst { input, out, ttl } => return build_sst(input, out, *ttl),
+        Command::Put { target, .. }
+        | Command::Get { target, .. }
+        | Command::Scan { target, .. }
+        | Command::Export { target, .. } => target,
+    };
+    db_cfg.0.path = target.data_path.clone();
+    let db_man = DbManager::offline(db_cfg)?;
+    if !db_man.contains(&target.db) {
+        return Err(anyhow!(
+            "Db {} doesn't exist in {}",
+            target.db,
+            target.data_path
+        ));
+    }
+
+    match cmd {
+        Command::Put {
+            target,
+            key,
+            value,
+            ttl,
+        } => {
+            let ttl = expiration(ttl)?;
+            db_man
+                .store(
+                    &target.db,
+                    key.as_bytes(),
+                    Bytes::from(value),
+                    ttl,
+                    NO_TTL,
+                    Representation::default(),
+                    WriteCondition::Always,
+                )
+                .await?;
+        }
+        Command::Get { target, key } => match db_man.read(&target.db, key.as_bytes()).await? {
+            Some(data) => io::stdout().write_all(&data.into_value())?,
+            None => return Err(anyhow!("Key {} not found", key)),
+        },
+        Command::Scan { target, limit } => {
+            let mut stdout = io::stdout();
+            for_each_page(&db_man, &target.db, limit, |records| {
+                for r in records {
+                    let value = r.value.unwrap_or_default();
+                    writeln!(stdout, "{}\t{}", r.key, String::from_utf8_lossy(&value))?;
+                }
+                Ok(())
+            })
+            .await?;
+        }
+        Command::Export { target, out } => {
+            // records are written page by page, the count in the header once they're all known
+            let mut file = BufWriter::new(File::create(&out)?);
+            wire::write_header(&mut file, 0)?;
+            let mut count = 0;
+            for_each_page(&db_man, &target.db, None, |records| {
+                for record in &records {
+                    wire::write_record(&mut file, record)?;
+                }
+                count += records.len();
+                Ok(())
+            })
+            .await?;
+            let count = u32::try_from(count)
+                .map_err(|_| anyhow!("Db {} has too many records for a batch", target.db))?;
+            let mut file = file.into_inner()?;
+            file.seek(SeekFrom::Start(0))?;
+            wire::write_header(&mut file, count)?;
+            eprintln!("Exported {} records to {}", count, out);
+        }
+        Command::Sst { .. } => unreachable!("Handled before opening dbs"),
+    }
+    Ok(())
+}
+
+// SST files must be sorted by key, the last record of a key in the input wins
+// and records without a value are written as deletes
+fn build_sst(input: &str, out: &str, ttl: Option<u128>) -> anyhow::Result<()> {
+    let records: BTreeMap<String, Option<Vec<u8>>> = wire::decode(&fs::read(input)?)?
+        .into_iter()
+        .map(|r| (r.key, r.value))
+        .collect();
+    let ttl = expiration(ttl)?;
+
+    let mut writer = SstFileWriter::create(&Options::default());
+    writer.open(out)?;
+    for (key, value) in &records {
+        match value {
+            Some(value) => writer.put(key, Data::new(ttl, value.clone()).as_bytes()?)?,
+            None => writer.delete(key)?,
+        }
+    }
+    writer.finish()?;
+    eprintln!("Written {} records to {}", records.len(), out);
+    Ok(())
+}
+
+fn expiration(ttl: Option<u128>) -> anyhow::Result<u128> {
+    match ttl {
+        Some(ttl) => Ok(current_ms()? + ttl),
+        None => Ok(NO_TTL),
+    }
+}
+
+async fn for_each_page<F>(
+    db_man: &DbManager,
+    db_name: &str,
+    limit: Option<usize>,
+    mut f: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(Vec<WireRecord>) -> anyhow::Result<()>,
+{
+    let mut remaining = limit.unwrap_or(usize::MAX);
+    let mut cursor = None;
+    while remaining > 0 {
+        let page = db_man
+            .keys(
+                db_name,
+                cursor,
+                remaining.min(MAX_PAGE_SIZE),
+                KeyEncoding::Utf8,
+                vec![],
+                false,
+                Order::Asc,
+            )
+            .await?;
+        let (keys, next) = page.into_parts();
+        remaining -= keys.len();
+        // records expired or removed meanwhile come back without a value
+        let records = db_man
+            .multi_get(db_name, keys)
+            .await?
+            .into_iter()
+            .filter(|r| r.value.is_some())
+            .collect();
+        f(records)?;
+
+        match next {
+            Some(next) => cursor = Some(ScanCursor::parse(&next)?),
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_put() {
+        let cmd = Command::from_iter(&[
+            "rocky",
+            "put",
+            "--data-path",
+            "./db",
+            "--db",
+            "db_1",
+            "key",
+            "value",
+            "--ttl",
+            "100",
+        ]);
+
+        match cmd {
+            Command::Put {
+                target,
+                key,
+                value,
+                ttl,
+            } => {
+                assert_eq!("./db", target.data_path);
+                assert_eq!("db_1", target.db);
+                assert_eq!("key", key);
+                assert_eq!("value", value);
+                assert_eq!(Some(100), ttl);
+            }
+            _ => panic!("Put expected"),
+        }
+    }
+
+    #[test]
+    fn should_build_sst_from_batch() {
+        let dir = "./db/should_build_sst_from_batch";
+        fs::create_dir_all(dir).unwrap();
+        let input = format!("{}/batch.bin", dir);
+        let out = format!("{}/batch.sst", dir);
+        let batch = wire::encode(&[
+            WireRecord::new("k2".into(), Some(b"v2".to_vec())),
+            WireRecord::new("k1".into(), Some(b"v1".to_vec())),
+            WireRecord::new("k3".into(), None),
+        ]);
+        File::create(&input).unwrap().write_all(&batch).unwrap();
+
+        build_sst(&input, &out, None).unwrap();
+        assert!(fs::metadata(&out).unwrap().len() > 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_require_target() {
+        assert!(Command::from_iter_safe(&["rocky", "get", "key"]).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+// Transparent compression of record values at rest.
+//
+// Dbs with a compression codec compress values of at least compression_min_bytes when they're stored, the codec
+// is kept in the record envelope and values are decompressed when the envelope is read - everything past the
+// envelope sees plain values. A value that doesn't shrink is stored as is. Changing the codec of a db applies
+// to new writes only, existing records keep the codec they were written with.
+//
+// Clients can store values they compressed themselves with Content-Encoding gzip or zstd, the compressed bytes are
+// kept with the codec in the envelope. Values compressed with a codec the reader lists in Accept-Encoding are sent
+// as they're stored, with Content-Encoding, others are decompressed for the reader.
+//
+// Decompression is capped - a value expanding past the limit of the caller fails instead of taking the memory of
+// the service, a few KB of gzip or zstd can expand to gigabytes.
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+
+const ZSTD_DEFAULT_LEVEL: i32 = 3;
+const GZIP_DEFAULT_LEVEL: i32 = 6;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Zstd,
+    Lz4,
+    Gzip,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Compression {
+    codec: Codec,
+    level: i32,
+}
+
+// codecs a reader accepts, q=0 excludes a coding
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Accepted(Vec<Codec>);
+
+impl Codec {
+    // gzip and zstd are HTTP content codings, lz4 frames with a prepended size aren't
+    pub fn from_content_coding(coding: &str) -> Option<Self> {
+        match coding.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Codec::Gzip),
+            "zstd" => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn content_coding(self) -> Option<&'static str> {
+        match self {
+            Codec::Zstd => Some("zstd"),
+            Codec::Gzip => Some("gzip"),
+            Codec::Lz4 => None,
+        }
+    }
+
+    // 0 picks the codec default, lz4 has no levels
+    pub fn level_range(self) -> (i32, i32) {
+        match self {
+            Codec::Zstd => (0, 22),
+            Codec::Lz4 => (0, 0),
+            Codec::Gzip => (0, 9),
+        }
+    }
+}
+
+impl Compression {
+    pub fn new(codec: Codec, level: i32) -> Self {
+        let level = match (codec, level) {
+            (Codec::Zstd, 0) => ZSTD_DEFAULT_LEVEL,
+            (Codec::Gzip, 0) => GZIP_DEFAULT_LEVEL,
+            _ => level,
+        };
+        Compression { codec, level }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self.codec {
+            Codec::Zstd => zstd::encode_all(data, self.level),
+            Codec::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            Codec::Gzip => {
+                let mut encoder =
+                    GzEncoder::new(vec![], flate2::Compression::new(self.level as u32));
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    // fails once the value expands past limit bytes
+    pub fn decompress(&self, data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        match self.codec {
+            Codec::Zstd => read_limited(zstd::Decoder::new(data)?, limit),
+            Codec::Lz4 => {
+                // the prepended size is checked before it's allocated
+                let size = data
+                    .get(..4)
+                    .and_then(|size| size.try_into().ok())
+                    .map_or(0, |size| u32::from_le_bytes(size) as usize);
+                if size > limit {
+                    return Err(too_large(limit));
+                }
+                lz4_flex::decompress_size_prepended(data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
+            }
+            Codec::Gzip => read_limited(GzDecoder::new(data), limit),
+        }
+    }
+}
+
+fn read_limited(reader: impl Read, limit: usize) -> io::Result<Vec<u8>> {
+    let mut value = vec![];
+    reader.take(limit as u64 + 1).read_to_end(&mut value)?;
+    if value.len() > limit {
+        Err(too_large(limit))
+    } else {
+        Ok(value)
+    }
+}
+
+fn too_large(limit: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("decompressed value exceeds {} bytes", limit),
+    )
+}
+
+impl Accepted {
+    pub fn from_header(accept_encoding: &str) -> Self {
+        let mut accepted = vec![];
+        let mut excluded = vec![];
+        let mut any = false;
+        for item in accept_encoding.split(',') {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or_default().trim();
+            let refused = params.any(|p| {
+                p.trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .map_or(false, |q| q == 0.0)
+            });
+            match (coding, Codec::from_content_coding(coding)) {
+                (_, Some(codec)) if refused => excluded.push(codec),
+                (_, Some(codec)) => accepted.push(codec),
+                ("*", None) => any = !refused,
+                _ => {}
+            }
+        }
+        if any {
+            accepted.extend(&[Codec::Gzip, Codec::Zstd]);
+        }
+        accepted.retain(|codec| !excluded.contains(codec));
+        Accepted(accepted)
+    }
+
+    pub fn contains(&self, codec: Codec) -> bool {
+        self.0.contains(&codec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_round_trip_all_codecs() {
+        let value = b"text heavy value ".repeat(100);
+        for codec in &[Codec::Zstd, Codec::Lz4, Codec::Gzip] {
+            let compression = Compression::new(*codec, 0);
+            let compressed = compression.compress(&value).unwrap();
+            assert!(
+                compressed.len() < value.len(),
+                "{:?} should compress",
+                codec
+            );
+            assert_eq!(
+                value,
+                compression.decompress(&compressed, value.len()).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn should_default_levels() {
+        assert_eq!(ZSTD_DEFAULT_LEVEL, Compression::new(Codec::Zstd, 0).level);
+        assert_eq!(1, Compression::new(Codec::Gzip, 1).level);
+    }
+
+    #[test]
+    fn should_parse_accept_encoding() {
+        let accepted = Accepted::from_header("gzip, deflate, br");
+        assert!(accepted.contains(Codec::Gzip));
+        assert!(!accepted.contains(Codec::Zstd));
+        assert!(!accepted.contains(Codec::Lz4));
+
+        let accepted = Accepted::from_header("zstd;q=0.8, gzip;q=0");
+        assert!(accepted.contains(Codec::Zstd));
+        assert!(!accepted.contains(Codec::Gzip));
+
+        let accepted = Accepted::from_header("*, zstd;q=0");
+        assert!(accepted.contains(Codec::Gzip));
+        assert!(!accepted.contains(Codec::Zstd));
+
+        assert_eq!(Accepted::default(), Accepted::from_header("identity"));
+        assert_eq!(Some(Codec::Gzip), Codec::from_content_coding("X-GZIP"));
+        assert_eq!(None, Codec::from_content_coding("lz4"));
+    }
+
+    #[test]
+    fn should_fail_on_corrupt_value() {
+        let compression = Compression::new(Codec::Gzip, 0);
+        assert!(compression.decompress(b"not gzip", 1024).is_err());
+    }
+
+    #[test]
+    fn should_cap_decompressed_size() {
+        let value = vec![0; 1024 * 1024];
+        for codec in &[Codec::Zstd, Codec::Lz4, Codec::Gzip] {
+            let compression = Compression::new(*codec, 0);
+            let compressed = compression.compress(&value).unwrap();
+            assert!(
+                compression.decompress(&compressed, 1024).is_err(),
+                "{:?} should stop at the limit",
+                codec
+            );
+        }
+    }
+}
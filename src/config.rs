@@ -1,12 +1,98 @@
-use rocksdb::{BlockBasedIndexType, BlockBasedOptions, Cache, DBCompactionStyle, Options};
+use std::collections::{BTreeMap, HashMap};
+use std::net::ToSocketAddrs;
+use std::time::{Duration, Instant};
+
+use actix_web::http::{header, HeaderName, HeaderValue, KeepAlive};
+use anyhow::anyhow;
+use rocksdb::{
+    BlockBasedIndexType, BlockBasedOptions, Cache, DBCompactionStyle, DBCompressionType, Options,
+};
+use rustls::ServerConfig as TlsConfig;
 use serde::{Deserialize, Serialize};
 
+use crate::access_log::AccessLog;
+use crate::compression::{Codec, Compression};
+use crate::key_encoding::{KEY_ENCODING_HEADER, KEY_HEADER};
+use crate::maintenance::Schedule;
+use crate::operations::{Cancel, Operation};
+use crate::preflight::Preflight;
+use crate::quota::{Quota, INSUFFICIENT_STORAGE, TOO_MANY_REQUESTS};
+use crate::{
+    resp, tls, DEFAULT_VALUE_HEADER, EXPIRES_AT_HEADER, LAST_ACCESS_HEADER, READS_HEADER,
+    SOFT_EXPIRES_AT_HEADER, STALE_HEADER, TTL_REMAINING_HEADER, VALUE_SIZE_HEADER, VERSION_HEADER,
+    WRITES_HEADER,
+};
+
+// headers of read responses describing the record, configured response_headers can't replace them
+const MANAGED_HEADERS: [&str; 12] = [
+    VERSION_HEADER,
+    VALUE_SIZE_HEADER,
+    EXPIRES_AT_HEADER,
+    SOFT_EXPIRES_AT_HEADER,
+    TTL_REMAINING_HEADER,
+    STALE_HEADER,
+    DEFAULT_VALUE_HEADER,
+    READS_HEADER,
+    WRITES_HEADER,
+    LAST_ACCESS_HEADER,
+    KEY_HEADER,
+    KEY_ENCODING_HEADER,
+];
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ServiceConfig {
     ip: String,
     port: u16,
     workers: u8,
     dev_mode: bool,
+    #[serde(default)]
+    test_clock: bool,
+    #[serde(default)]
+    admin_token: Option<String>,
+    #[serde(default)]
+    metrics_auth: bool,
+    #[serde(default)]
+    maintenance_windows: Vec<String>,
+    #[serde(default)]
+    access_log: bool,
+    #[serde(default = "default_access_log_sample")]
+    access_log_sample: u64,
+    #[serde(default)]
+    redact_keys: Vec<String>,
+    #[serde(default)]
+    redaction_salt: String,
+    #[serde(default)]
+    erasure_signing_key: Option<String>,
+    #[serde(default = "default_min_fd_limit")]
+    min_fd_limit: u64,
+    #[serde(default = "default_raise_fd_limit")]
+    raise_fd_limit: bool,
+    #[serde(default = "default_min_free_disk_mb")]
+    min_free_disk_mb: u64,
+    #[serde(default)]
+    redis_port: Option<u16>,
+    #[serde(default = "default_redis_max_connections")]
+    redis_max_connections: usize,
+    #[serde(default = "default_redis_idle_timeout_secs")]
+    redis_idle_timeout_secs: u64,
+    #[serde(default)]
+    grpc_port: Option<u16>,
+    #[serde(default)]
+    graphql: bool,
+    #[serde(default)]
+    otlp_endpoint: Option<String>,
+    #[serde(default)]
+    tls_cert: Option<String>,
+    #[serde(default)]
+    tls_key: Option<String>,
+    #[serde(default = "default_keep_alive_secs")]
+    keep_alive_secs: usize,
+    #[serde(default = "default_client_timeout_ms")]
+    client_timeout_ms: u64,
+    #[serde(default = "default_max_connections")]
+    max_connections: usize,
+    #[serde(default = "default_backlog")]
+    backlog: i32,
 }
 
 #[derive(Debug)]
@@ -17,13 +103,73 @@ impl DbConfig {
         DbConfig(rocks_cfg)
     }
 
-    pub fn rocks_options(&self) -> Options {
-        self.0.options()
+    pub fn rocks_options(&self, cache: Option<&Cache>) -> Options {
+        self.0.options(cache)
+    }
+
+    // per db block cache, None when rocksdb defaults are used or cache is disabled
+    pub fn block_cache(&self) -> Option<Cache> {
+        if self.0.cache_defaults || !self.0.use_cache {
+            None
+        } else {
+            Some(Cache::new_lru_cache(self.0.cache_size).expect("Could not create Cache"))
+        }
+    }
+
+    pub fn cache_size(&self) -> usize {
+        self.0.cache_size
+    }
+
+    pub fn memory_limit(&self) -> u64 {
+        self.0.memory_limit_mb * 1024 * 1024
+    }
+
+    pub fn memory_check_interval(&self) -> Duration {
+        Duration::from_millis(self.0.memory_check_interval_ms)
+    }
+
+    pub fn recovery_check_interval(&self) -> Duration {
+        Duration::from_millis(self.0.recovery_check_interval_ms)
+    }
+
+    pub fn sweep_interval(&self) -> Duration {
+        Duration::from_millis(self.0.sweep_interval_ms)
+    }
+
+    pub fn usage_sample_interval(&self) -> Duration {
+        Duration::from_millis(self.0.usage_sample_interval_ms)
+    }
+
+    // bytes per second
+    pub fn delete_rate(&self) -> u64 {
+        self.0.delete_rate_mb_per_sec * 1024 * 1024
+    }
+
+    pub fn scan_limits(&self) -> ScanLimits {
+        ScanLimits {
+            max_bytes: self.0.scan_max_bytes,
+            max_time: Duration::from_millis(self.0.scan_max_time_ms),
+            cancel: None,
+        }
+    }
+
+    // how long a listing snapshot is pinned after its last page, 0 lists live data page by page
+    pub fn scan_snapshot_idle(&self) -> Option<Duration> {
+        match self.0.scan_snapshot_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    // an exact count running longer returns a cursor to continue from
+    pub fn count_max_time(&self) -> Duration {
+        Duration::from_millis(self.0.count_max_time_ms)
     }
 
     pub fn root_db_options(&self) -> Options {
         let mut opts = Options::default();
         opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
 
         opts
     }
@@ -32,9 +178,107 @@ impl DbConfig {
         self.0.path.as_ref()
     }
 
+    // every directory db files are written under
+    pub fn data_paths(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("data", self.0.path.clone()),
+            ("backup", self.0.backup_path.clone()),
+            ("checkpoint", self.0.checkpoint_path.clone()),
+        ]
+    }
+
     pub fn db_path(&self, db_name: &str) -> String {
         format!("{}/{}", self.path(), db_name)
     }
+
+    pub fn backup_path(&self, db_name: &str) -> String {
+        format!("{}/{}", self.0.backup_path, db_name)
+    }
+
+    // 0 keeps all backups
+    pub fn backups_to_keep(&self) -> usize {
+        self.0.backups_to_keep
+    }
+
+    // identifies this deployment in crdt states
+    pub fn replica_id(&self) -> &str {
+        &self.0.replica_id
+    }
+
+    pub fn checkpoint_path(&self, db_name: &str) -> String {
+        format!("{}/{}", self.0.checkpoint_path, db_name)
+    }
+
+    // how long an export checkpoint is kept for resuming after its last download ended, 0 doesn't keep it
+    pub fn export_resume_window(&self) -> u128 {
+        u128::from(self.0.export_resume_ms)
+    }
+
+    pub fn stale_window(&self, db_name: &str) -> u128 {
+        self.0
+            .databases
+            .get(db_name)
+            .map_or(0, DbSettings::stale_window)
+    }
+
+    pub fn ttl_jitter_pct(&self, db_name: &str) -> u8 {
+        self.0
+            .databases
+            .get(db_name)
+            .map_or(0, |s| s.ttl_jitter_pct)
+    }
+
+    pub fn quota(&self, db_name: &str) -> Quota {
+        self.0
+            .databases
+            .get(db_name)
+            .map_or_else(|| DbSettings::default().quota(), DbSettings::quota)
+    }
+
+    pub fn settings(&self, db_name: &str) -> DbSettings {
+        self.0.databases.get(db_name).cloned().unwrap_or_default()
+    }
+
+    pub fn key_policy(&self, db_name: &str) -> KeyPolicy {
+        self.0
+            .databases
+            .get(db_name)
+            .map_or(KeyPolicy::Lenient, |settings| settings.key_policy)
+    }
+
+    // borrowed instead of cloned with the settings, they're added to every read
+    pub fn response_headers(&self, db_name: &str) -> Option<&BTreeMap<String, String>> {
+        self.0
+            .databases
+            .get(db_name)
+            .map(|settings| &settings.response_headers)
+    }
+
+    // settings that would otherwise be silently replaced by defaults or fail at runtime
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if compaction_style(&self.0.compaction_style).is_none() {
+            problems.push(format!(
+                "Unknown compaction_style {}",
+                self.0.compaction_style
+            ));
+        }
+        if index_type(&self.0.index_type).is_none() {
+            problems.push(format!("Unknown index_type {}", self.0.index_type));
+        }
+
+        let mut names: Vec<&String> = self.0.databases.keys().collect();
+        names.sort();
+        for name in names {
+            problems.extend(
+                self.0.databases[name]
+                    .validate()
+                    .into_iter()
+                    .map(|p| format!("databases.{}: {}", name, p)),
+            );
+        }
+        problems
+    }
 }
 
 impl ServiceConfig {
@@ -42,13 +286,163 @@ impl ServiceConfig {
         format!("{}:{}", self.ip, self.port)
     }
 
+    // None when the redis protocol listener is disabled
+    pub fn redis_address(&self) -> Option<String> {
+        self.redis_port.map(|port| format!("{}:{}", self.ip, port))
+    }
+
+    pub fn redis_limits(&self) -> resp::Limits {
+        resp::Limits {
+            max_connections: self.redis_max_connections,
+            idle_timeout: match self.redis_idle_timeout_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+        }
+    }
+
+    // None when the gRPC API is disabled
+    pub fn grpc_address(&self) -> Option<String> {
+        self.grpc_port.map(|port| format!("{}:{}", self.ip, port))
+    }
+
+    pub fn graphql(&self) -> bool {
+        self.graphql
+    }
+
+    // None when traces aren't exported
+    pub fn otlp_endpoint(&self) -> Option<&str> {
+        self.otlp_endpoint.as_deref()
+    }
+
     pub fn dev_mode(&self) -> bool {
         self.dev_mode
     }
 
+    // serves POST /_test/advance_time
+    pub fn test_clock(&self) -> bool {
+        self.test_clock
+    }
+
     pub fn workers(&self) -> usize {
         self.workers as usize
     }
+
+    // 0 closes connections after every response
+    pub fn keep_alive(&self) -> KeepAlive {
+        if self.keep_alive_secs == 0 {
+            KeepAlive::Disabled
+        } else {
+            KeepAlive::Timeout(self.keep_alive_secs)
+        }
+    }
+
+    // time a client has to send the request head, 0 disables it
+    pub fn client_timeout(&self) -> u64 {
+        self.client_timeout_ms
+    }
+
+    // per worker
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    pub fn backlog(&self) -> i32 {
+        self.backlog
+    }
+
+    pub fn admin_token(&self) -> Option<String> {
+        self.admin_token.clone()
+    }
+
+    pub fn erasure_signing_key(&self) -> Option<String> {
+        self.erasure_signing_key.clone()
+    }
+
+    pub fn metrics_auth(&self) -> bool {
+        self.metrics_auth
+    }
+
+    // paths are filled in from the db config and command line
+    pub fn preflight(&self) -> Preflight {
+        Preflight {
+            min_fd_limit: self.min_fd_limit,
+            raise_fd_limit: self.raise_fd_limit,
+            min_free_bytes: self.min_free_disk_mb * 1024 * 1024,
+            paths: vec![],
+        }
+    }
+
+    pub fn maintenance_windows(&self) -> &[String] {
+        &self.maintenance_windows
+    }
+
+    // None when access logging is off
+    pub fn access_log(&self) -> anyhow::Result<Option<AccessLog>> {
+        if !self.access_log {
+            return Ok(None);
+        }
+        AccessLog::new(
+            self.access_log_sample,
+            &self.redact_keys,
+            &self.redaction_salt,
+        )
+        .map(Some)
+    }
+
+    // None when HTTP is served in plain text
+    pub fn tls(&self) -> anyhow::Result<Option<TlsConfig>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => tls::server_config(cert, key).map(Some),
+            (None, None) => Ok(None),
+            _ => Err(anyhow!("tls_cert and tls_key have to be set together")),
+        }
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.bind_address().to_socket_addrs().is_err() {
+            problems.push(format!(
+                "Can't resolve bind address {}",
+                self.bind_address()
+            ));
+        }
+        if self.workers == 0 {
+            problems.push("workers must be at least 1".into());
+        }
+        if self.max_connections == 0 {
+            problems.push("max_connections must be at least 1".into());
+        }
+        if self.backlog <= 0 {
+            problems.push("backlog must be at least 1".into());
+        }
+        if self.redis_max_connections == 0 {
+            problems.push("redis_max_connections must be at least 1".into());
+        }
+        if self.redis_port == Some(self.port) {
+            problems.push(format!("redis_port {} is the HTTP port", self.port));
+        }
+        if let Some(port) = self.grpc_port {
+            if port == self.port || Some(port) == self.redis_port {
+                problems.push(format!("grpc_port {} is already used", port));
+            }
+        }
+        if let Some(endpoint) = &self.otlp_endpoint {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                problems.push(format!("otlp_endpoint {} must be an http(s) url", endpoint));
+            }
+        }
+        if let Err(e) = Schedule::parse(&self.maintenance_windows) {
+            problems.push(e.to_string());
+        }
+        if let Err(e) = self.access_log() {
+            problems.push(e.to_string());
+        }
+        if let Err(e) = self.tls() {
+            problems.push(e.to_string());
+        }
+        problems
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -74,6 +468,366 @@ pub struct RocksDbConfig {
     bloom_filter_bits_per_key: i32,
     bloom_filter_block_based: bool,
     index_type: String,
+    #[serde(default)]
+    memory_limit_mb: u64,
+    #[serde(default = "default_memory_check_interval_ms")]
+    memory_check_interval_ms: u64,
+    #[serde(default = "default_recovery_check_interval_ms")]
+    recovery_check_interval_ms: u64,
+    #[serde(default = "default_sweep_interval_ms")]
+    sweep_interval_ms: u64,
+    #[serde(default = "default_usage_sample_interval_ms")]
+    usage_sample_interval_ms: u64,
+    #[serde(default = "default_scan_max_bytes")]
+    scan_max_bytes: usize,
+    #[serde(default = "default_scan_max_time_ms")]
+    scan_max_time_ms: u64,
+    #[serde(default = "default_count_max_time_ms")]
+    count_max_time_ms: u64,
+    #[serde(default = "default_scan_snapshot_ms")]
+    scan_snapshot_ms: u64,
+    #[serde(default)]
+    delete_rate_mb_per_sec: u64,
+    #[serde(default = "default_backup_path")]
+    pub backup_path: String,
+    #[serde(default)]
+    backups_to_keep: usize,
+    #[serde(default = "default_checkpoint_path")]
+    pub checkpoint_path: String,
+    #[serde(default = "default_export_resume_ms")]
+    export_resume_ms: u64,
+    #[serde(default = "default_replica_id")]
+    replica_id: String,
+    #[serde(default)]
+    pub databases: HashMap<String, DbSettings>,
+}
+
+// rocksdb options of a single db replacing the configured ones, sent when the db is opened
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RocksOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    write_buffer_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compaction_style: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compression: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScanLimits {
+    max_bytes: usize,
+    max_time: Duration,
+    cancel: Option<Cancel>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DbSettings {
+    pub durability: Durability,
+    pub access_stats: bool,
+    pub access_stats_sample: u64,
+    pub max_size_mb: u64,
+    pub eviction: Eviction,
+    pub default_value: Option<String>,
+    pub fallback_url: Option<String>,
+    pub ttl_jitter_pct: u8,
+    pub stale_window_ms: u64,
+    pub interactive_txns: bool,
+    pub undelete_window_ms: u64,
+    pub expiration_webhook: Option<String>,
+    pub replicate_to: Option<String>,
+    pub replication_conflict: ConflictPolicy,
+    pub write_concern: WriteConcern,
+    pub replication_ack_timeout_ms: u64,
+    pub crdts: bool,
+    pub change_log: bool,
+    pub change_log_retention_ms: u64,
+    pub scan_cache_ms: u64,
+    pub compression: Option<Codec>,
+    pub compression_level: i32,
+    pub compression_min_bytes: usize,
+    pub max_keys: u64,
+    pub max_bytes: u64,
+    pub quota_status: u16,
+    pub key_delimiter: String,
+    pub key_policy: KeyPolicy,
+    pub multipart_uploads: bool,
+    pub upload_expiry_ms: u64,
+    pub json: bool,
+    pub indexes: Vec<String>,
+    pub response_headers: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    Full,
+    None,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Eviction {
+    Lru,
+    Lfu,
+}
+
+// which UTF-8 keys are accepted in the URL path, binary-safe routes take any key
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyPolicy {
+    Lenient,
+    Strict,
+}
+
+// how replicated changes are applied over local records
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    // the record written last wins
+    Lww,
+    // replicated changes always win, for passive copies
+    Overwrite,
+}
+
+// when a write is answered
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WriteConcern {
+    // once it's committed locally
+    Local,
+    // once the replica accepted it too
+    Replicated,
+}
+
+impl Default for DbSettings {
+    fn default() -> Self {
+        DbSettings {
+            durability: Durability::Full,
+            access_stats: false,
+            access_stats_sample: 1,
+            max_size_mb: 0,
+            eviction: Eviction::Lru,
+            default_value: None,
+            fallback_url: None,
+            ttl_jitter_pct: 0,
+            stale_window_ms: 0,
+            interactive_txns: false,
+            undelete_window_ms: 0,
+            expiration_webhook: None,
+            replicate_to: None,
+            replication_conflict: ConflictPolicy::Lww,
+            write_concern: WriteConcern::Local,
+            replication_ack_timeout_ms: 5000,
+            crdts: false,
+            change_log: false,
+            change_log_retention_ms: 86_400_000,
+            scan_cache_ms: 0,
+            compression: None,
+            compression_level: 0,
+            compression_min_bytes: 256,
+            max_keys: 0,
+            max_bytes: 0,
+            quota_status: INSUFFICIENT_STORAGE,
+            key_delimiter: "/".into(),
+            key_policy: KeyPolicy::Lenient,
+            multipart_uploads: false,
+            upload_expiry_ms: 86_400_000,
+            json: false,
+            indexes: vec![],
+            response_headers: BTreeMap::new(),
+        }
+    }
+}
+
+impl ScanLimits {
+    pub fn exceeded(&self, bytes: usize, started: Instant) -> bool {
+        bytes >= self.max_bytes || started.elapsed() >= self.max_time
+    }
+
+    // a scan under the limits stops with an error once the operation is cancelled
+    pub fn cancellable(self, operation: &Operation) -> Self {
+        ScanLimits {
+            cancel: Some(operation.cancel()),
+            ..self
+        }
+    }
+
+    pub fn check_cancelled(&self) -> anyhow::Result<()> {
+        match &self.cancel {
+            Some(cancel) => cancel.check(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl RocksOverrides {
+    pub fn is_empty(&self) -> bool {
+        self == &RocksOverrides::default()
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.write_buffer_size == Some(0) {
+            problems.push("write_buffer_size must be greater than 0".into());
+        }
+        if let Some(style) = &self.compaction_style {
+            if compaction_style(style).is_none() {
+                problems.push(format!("Unknown compaction_style {}", style));
+            }
+        }
+        if let Some(compression) = &self.compression {
+            if compression_type(compression).is_none() {
+                problems.push(format!("Unknown compression {}", compression));
+            }
+        }
+        problems
+    }
+
+    pub fn apply(&self, opts: &mut Options) {
+        if let Some(size) = self.write_buffer_size {
+            opts.set_write_buffer_size(size);
+        }
+        if let Some(style) = &self.compaction_style {
+            opts.set_compaction_style(get_compaction_style(style));
+        }
+        if let Some(compression) = self.compression.as_deref().and_then(compression_type) {
+            opts.set_compression_type(compression);
+        }
+    }
+}
+
+impl DbSettings {
+    pub fn max_size(&self) -> u64 {
+        self.max_size_mb * 1024 * 1024
+    }
+
+    pub fn stale_window(&self) -> u128 {
+        u128::from(self.stale_window_ms)
+    }
+
+    pub fn undelete_window(&self) -> u128 {
+        u128::from(self.undelete_window_ms)
+    }
+
+    pub fn replication_ack_timeout(&self) -> Duration {
+        Duration::from_millis(self.replication_ack_timeout_ms)
+    }
+
+    // 0 keeps the whole log
+    pub fn change_log_retention(&self) -> u128 {
+        u128::from(self.change_log_retention_ms)
+    }
+
+    // uploads not completed by then are purged
+    pub fn upload_expiry(&self) -> u128 {
+        u128::from(self.upload_expiry_ms)
+    }
+
+    // 0 disables caching
+    pub fn scan_cache(&self) -> Duration {
+        Duration::from_millis(self.scan_cache_ms)
+    }
+
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+            .map(|codec| Compression::new(codec, self.compression_level))
+    }
+
+    pub fn quota(&self) -> Quota {
+        Quota {
+            max_keys: self.max_keys,
+            max_bytes: self.max_bytes,
+            status: self.quota_status,
+        }
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.quota_status != INSUFFICIENT_STORAGE && self.quota_status != TOO_MANY_REQUESTS {
+            problems.push(format!(
+                "quota_status {} is neither {} nor {}",
+                self.quota_status, INSUFFICIENT_STORAGE, TOO_MANY_REQUESTS
+            ));
+        }
+        if self.ttl_jitter_pct > 100 {
+            problems.push(format!(
+                "ttl_jitter_pct {} is over 100",
+                self.ttl_jitter_pct
+            ));
+        }
+        if !self.indexes.is_empty() && !self.json {
+            problems.push("indexes need json = true".into());
+        }
+        if self.multipart_uploads && self.json {
+            problems.push("multipart_uploads can't be enabled for json dbs".into());
+        }
+        if self.key_delimiter.is_empty() {
+            problems.push("key_delimiter can't be empty".into());
+        }
+        if self.write_concern == WriteConcern::Replicated && self.replicate_to.is_none() {
+            problems.push("write_concern replicated needs replicate_to".into());
+        }
+        if self.access_stats_sample == 0 {
+            problems.push("access_stats_sample must be at least 1".into());
+        }
+        if let Some(codec) = self.compression {
+            let (min, max) = codec.level_range();
+            if self.compression_level < min || self.compression_level > max {
+                problems.push(format!(
+                    "compression_level {} of {:?} is out of {}..={}",
+                    self.compression_level, codec, min, max
+                ));
+            }
+        }
+        let urls = [
+            ("fallback_url", &self.fallback_url),
+            ("expiration_webhook", &self.expiration_webhook),
+            ("replicate_to", &self.replicate_to),
+        ];
+        for (name, url) in urls.iter() {
+            if let Some(url) = url {
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    problems.push(format!("{} {} is not an http(s) url", name, url));
+                }
+            }
+        }
+        for (name, value) in &self.response_headers {
+            match HeaderName::from_bytes(name.as_bytes()) {
+                Err(_) => problems.push(format!("response_headers {} isn't a header name", name)),
+                Ok(header) if is_managed(&header) => {
+                    problems.push(format!("response_headers {} is set by rocky", name))
+                }
+                Ok(_) => {}
+            }
+            if HeaderValue::from_str(value).is_err() {
+                problems.push(format!(
+                    "response_headers {} value {} isn't a header value",
+                    name, value
+                ));
+            }
+        }
+        problems
+    }
+}
+
+fn is_managed(name: &HeaderName) -> bool {
+    let standard = [
+        header::CONTENT_TYPE,
+        header::CONTENT_LENGTH,
+        header::CONTENT_ENCODING,
+        header::TRANSFER_ENCODING,
+        header::ETAG,
+        header::VARY,
+    ];
+    standard.contains(name) || MANAGED_HEADERS.contains(&name.as_str())
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::Full
+    }
 }
 
 impl Default for RocksDbConfig {
@@ -100,6 +854,22 @@ impl Default for RocksDbConfig {
             bloom_filter_bits_per_key: 8,
             bloom_filter_block_based: true,
             index_type: "HashSearch".to_string(),
+            memory_limit_mb: 0,
+            memory_check_interval_ms: default_memory_check_interval_ms(),
+            recovery_check_interval_ms: default_recovery_check_interval_ms(),
+            sweep_interval_ms: default_sweep_interval_ms(),
+            usage_sample_interval_ms: default_usage_sample_interval_ms(),
+            scan_max_bytes: default_scan_max_bytes(),
+            scan_max_time_ms: default_scan_max_time_ms(),
+            count_max_time_ms: default_count_max_time_ms(),
+            scan_snapshot_ms: default_scan_snapshot_ms(),
+            delete_rate_mb_per_sec: 0,
+            backup_path: default_backup_path(),
+            backups_to_keep: 0,
+            checkpoint_path: default_checkpoint_path(),
+            export_resume_ms: default_export_resume_ms(),
+            replica_id: default_replica_id(),
+            databases: HashMap::new(),
         }
     }
 }
@@ -111,12 +881,36 @@ impl Default for ServiceConfig {
             port: 8080,
             workers: num_cpus::get() as u8,
             dev_mode: true,
+            test_clock: false,
+            admin_token: None,
+            metrics_auth: false,
+            maintenance_windows: vec![],
+            access_log: false,
+            access_log_sample: default_access_log_sample(),
+            redact_keys: vec![],
+            redaction_salt: String::new(),
+            erasure_signing_key: None,
+            min_fd_limit: default_min_fd_limit(),
+            raise_fd_limit: default_raise_fd_limit(),
+            min_free_disk_mb: default_min_free_disk_mb(),
+            redis_port: None,
+            redis_max_connections: default_redis_max_connections(),
+            redis_idle_timeout_secs: default_redis_idle_timeout_secs(),
+            grpc_port: None,
+            graphql: false,
+            otlp_endpoint: None,
+            tls_cert: None,
+            tls_key: None,
+            keep_alive_secs: default_keep_alive_secs(),
+            client_timeout_ms: default_client_timeout_ms(),
+            max_connections: default_max_connections(),
+            backlog: default_backlog(),
         }
     }
 }
 
 impl RocksDbConfig {
-    pub fn options(&self) -> Options {
+    pub fn options(&self, cache: Option<&Cache>) -> Options {
         let mut opts = Options::default();
         opts.set_max_open_files(self.max_open_files);
         opts.set_use_fsync(self.fsync);
@@ -130,13 +924,13 @@ impl RocksDbConfig {
         opts.set_level_zero_slowdown_writes_trigger(self.level_zero_slowdown_writes_trigger);
         opts.set_compaction_style(get_compaction_style(&self.compaction_style));
         opts.create_if_missing(true);
-        opts.set_block_based_table_factory(&block_based_opts(self));
+        opts.set_block_based_table_factory(&block_based_opts(self, cache));
 
         opts
     }
 }
 
-fn block_based_opts(cfg: &RocksDbConfig) -> BlockBasedOptions {
+fn block_based_opts(cfg: &RocksDbConfig, cache: Option<&Cache>) -> BlockBasedOptions {
     let mut opts = BlockBasedOptions::default();
     if cfg.cache_defaults {
         opts
@@ -144,9 +938,12 @@ fn block_based_opts(cfg: &RocksDbConfig) -> BlockBasedOptions {
         opts.disable_cache();
         opts
     } else {
-        opts.set_block_cache(
-            &Cache::new_lru_cache(cfg.cache_size).expect("Could not create Cache"),
-        );
+        match cache {
+            Some(cache) => opts.set_block_cache(cache),
+            None => opts.set_block_cache(
+                &Cache::new_lru_cache(cfg.cache_size).expect("Could not create Cache"),
+            ),
+        }
         opts.set_block_size(cfg.block_cache);
         opts.set_bloom_filter(cfg.bloom_filter_bits_per_key, cfg.bloom_filter_block_based);
         opts.set_index_type(get_index_type(&cfg.index_type));
@@ -154,6 +951,96 @@ fn block_based_opts(cfg: &RocksDbConfig) -> BlockBasedOptions {
     }
 }
 
+fn default_access_log_sample() -> u64 {
+    1
+}
+
+fn default_min_fd_limit() -> u64 {
+    4096
+}
+
+fn default_raise_fd_limit() -> bool {
+    true
+}
+
+fn default_min_free_disk_mb() -> u64 {
+    512
+}
+
+// actix-web defaults
+fn default_keep_alive_secs() -> usize {
+    5
+}
+
+fn default_client_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_max_connections() -> usize {
+    25_000
+}
+
+fn default_backlog() -> i32 {
+    2048
+}
+
+// a thread per connection
+fn default_redis_max_connections() -> usize {
+    1024
+}
+
+fn default_redis_idle_timeout_secs() -> u64 {
+    300
+}
+
+fn default_memory_check_interval_ms() -> u64 {
+    5000
+}
+
+fn default_recovery_check_interval_ms() -> u64 {
+    10_000
+}
+
+fn default_sweep_interval_ms() -> u64 {
+    15 * 60 * 1000
+}
+
+fn default_usage_sample_interval_ms() -> u64 {
+    60 * 60 * 1000
+}
+
+fn default_backup_path() -> String {
+    "./backup".into()
+}
+
+fn default_checkpoint_path() -> String {
+    "./checkpoint".into()
+}
+
+fn default_export_resume_ms() -> u64 {
+    60 * 60 * 1000
+}
+
+fn default_replica_id() -> String {
+    "rocky".into()
+}
+
+fn default_scan_max_bytes() -> usize {
+    4 * 1024 * 1024
+}
+
+fn default_scan_max_time_ms() -> u64 {
+    1_000
+}
+
+fn default_count_max_time_ms() -> u64 {
+    10_000
+}
+
+fn default_scan_snapshot_ms() -> u64 {
+    60_000
+}
+
 pub fn load_db_config(cfg_path: &str) -> anyhow::Result<DbConfig> {
     let rocks_cfg = confy::load_path(format!("{}/db_config.toml", cfg_path))?;
     Ok(DbConfig::new(rocks_cfg))
@@ -166,33 +1053,160 @@ pub fn load_service_config(cfg_path: &str) -> anyhow::Result<ServiceConfig> {
     ))?)
 }
 
-fn get_compaction_style(s: &str) -> DBCompactionStyle {
+fn compaction_style(s: &str) -> Option<DBCompactionStyle> {
     match s.to_lowercase().as_str() {
-        "level" => DBCompactionStyle::Level,
-        "universal" => DBCompactionStyle::Universal,
-        "fifo" => DBCompactionStyle::Fifo,
-        _ => {
-            error!(
-                "Unknown compaction style {} - fallback to default {:?}",
-                &s,
-                DBCompactionStyle::Level
-            );
+        "level" => Some(DBCompactionStyle::Level),
+        "universal" => Some(DBCompactionStyle::Universal),
+        "fifo" => Some(DBCompactionStyle::Fifo),
+        _ => None,
+    }
+}
+
+fn get_compaction_style(s: &str) -> DBCompactionStyle {
+    compaction_style(s).unwrap_or_else(|| {
+        error!(
+            "Unknown compaction style {} - fallback to default {:?}",
+            &s,
             DBCompactionStyle::Level
-        }
+        );
+        DBCompactionStyle::Level
+    })
+}
+
+fn compression_type(s: &str) -> Option<DBCompressionType> {
+    match s.to_lowercase().as_str() {
+        "none" => Some(DBCompressionType::None),
+        "snappy" => Some(DBCompressionType::Snappy),
+        "zlib" => Some(DBCompressionType::Zlib),
+        "lz4" => Some(DBCompressionType::Lz4),
+        "zstd" => Some(DBCompressionType::Zstd),
+        _ => None,
     }
 }
 
-fn get_index_type(s: &str) -> BlockBasedIndexType {
+fn index_type(s: &str) -> Option<BlockBasedIndexType> {
     match s.to_lowercase().as_str() {
-        "hashsearch" => BlockBasedIndexType::HashSearch,
-        "binarysearch" => BlockBasedIndexType::BinarySearch,
-        "twolevelindexsearch" => BlockBasedIndexType::TwoLevelIndexSearch,
-        _ => {
-            error!(
-                "Unknown block based index type {} - fallback to default {}",
-                &s, "BinarySearch"
+        "hashsearch" => Some(BlockBasedIndexType::HashSearch),
+        "binarysearch" => Some(BlockBasedIndexType::BinarySearch),
+        "twolevelindexsearch" => Some(BlockBasedIndexType::TwoLevelIndexSearch),
+        _ => None,
+    }
+}
+
+fn get_index_type(s: &str) -> BlockBasedIndexType {
+    index_type(s).unwrap_or_else(|| {
+        error!(
+            "Unknown block based index type {} - fallback to default {}",
+            &s, "BinarySearch"
+        );
+        BlockBasedIndexType::BinarySearch
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_exceed_scan_limits() {
+        let limits = ScanLimits {
+            max_bytes: 10,
+            max_time: Duration::from_secs(60),
+            cancel: None,
+        };
+        assert!(!limits.exceeded(9, Instant::now()));
+        assert!(limits.exceeded(10, Instant::now()));
+
+        let limits = ScanLimits {
+            max_bytes: 10,
+            max_time: Duration::from_millis(0),
+            cancel: None,
+        };
+        assert!(limits.exceeded(0, Instant::now()));
+    }
+
+    #[test]
+    fn should_report_invalid_settings() {
+        let mut cfg = RocksDbConfig::default();
+        cfg.compaction_style = "sideways".into();
+        cfg.databases.insert(
+            "db_1".into(),
+            DbSettings {
+                ttl_jitter_pct: 150,
+                replicate_to: Some("replica:8080".into()),
+                response_headers: vec![
+                    ("Cache-Control".into(), "max-age=60".into()),
+                    ("ETag".into(), "\"1\"".into()),
+                    ("X-Tenant".into(), "a\nb".into()),
+                ]
+                .into_iter()
+                .collect(),
+                ..DbSettings::default()
+            },
+        );
+        cfg.databases.insert("db_2".into(), DbSettings::default());
+
+        assert_eq!(
+            vec![
+                "Unknown compaction_style sideways",
+                "databases.db_1: ttl_jitter_pct 150 is over 100",
+                "databases.db_1: replicate_to replica:8080 is not an http(s) url",
+                "databases.db_1: response_headers ETag is set by rocky",
+                "databases.db_1: response_headers X-Tenant value a\nb isn't a header value",
+            ],
+            DbConfig::new(cfg).validate()
+        );
+        assert!(DbConfig::new(RocksDbConfig::default())
+            .validate()
+            .is_empty());
+    }
+
+    #[test]
+    fn should_keep_record_headers() {
+        let settings = |name: &str| DbSettings {
+            response_headers: vec![(name.into(), "1".into())].into_iter().collect(),
+            ..DbSettings::default()
+        };
+
+        for name in &["Last-Access", "reads", "Key-Encoding", "X-Stale", "Vary"] {
+            assert_eq!(
+                vec![format!("response_headers {} is set by rocky", name)],
+                settings(name).validate()
             );
-            BlockBasedIndexType::BinarySearch
         }
+        assert!(settings("Cache-Control").validate().is_empty());
+    }
+
+    #[test]
+    fn should_require_replica_for_replicated_writes() {
+        let replicated = |replicate_to: Option<&str>| DbSettings {
+            write_concern: WriteConcern::Replicated,
+            replicate_to: replicate_to.map(String::from),
+            ..DbSettings::default()
+        };
+        assert_eq!(
+            vec!["write_concern replicated needs replicate_to"],
+            replicated(None).validate()
+        );
+        assert!(replicated(Some("http://replica:8080"))
+            .validate()
+            .is_empty());
+    }
+
+    #[test]
+    fn should_report_invalid_overrides() {
+        let overrides: RocksOverrides = serde_json::from_str(
+            r#"{"write_buffer_size": 0, "compaction_style": "sideways", "compression": "lz4"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                "write_buffer_size must be greater than 0",
+                "Unknown compaction_style sideways",
+            ],
+            overrides.validate()
+        );
+        assert!(serde_json::from_str::<RocksOverrides>(r#"{"max_open_files": 10}"#).is_err());
+        assert!(RocksOverrides::default().is_empty());
     }
 }
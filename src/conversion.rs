@@ -1,8 +1,13 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use actix_web::http::HeaderValue;
+use anyhow::anyhow;
 
-use crate::db::Data;
+use crate::db::{
+    CompressedData, ContentTypeData, Data, LegacyData, SoftTtlData, VersionedData, WrittenAtData,
+};
+use crate::errors::ErrorCtx;
 
 pub trait IntoBytes<T> {
     fn as_bytes(&self) -> bincode::Result<Vec<u8>>;
@@ -14,16 +19,48 @@ pub trait FromBytes<T> {
 
 impl IntoBytes<Data> for Data {
     fn as_bytes(&self) -> bincode::Result<Vec<u8>> {
-        bincode::serialize(self)
+        self.to_bytes()
     }
 }
 
 impl FromBytes<Data> for Vec<u8> {
     fn as_struct(&self) -> bincode::Result<Data> {
-        bincode::deserialize(self)
+        // newest layout first - older layouts are prefixes of the newer ones, a value that fails to
+        // decompress is an error rather than an older layout
+        match bincode::deserialize::<Data>(self) {
+            Ok(data) => data.decompressed(),
+            Err(e) => match bincode::deserialize::<CompressedData>(self) {
+                Ok(compressed) => Data::from(compressed).decompressed(),
+                Err(_) => bincode::deserialize::<ContentTypeData>(self)
+                    .map(Data::from)
+                    .or_else(|_| bincode::deserialize::<WrittenAtData>(self).map(Data::from))
+                    .or_else(|_| bincode::deserialize::<SoftTtlData>(self).map(Data::from))
+                    .or_else(|_| bincode::deserialize::<VersionedData>(self).map(Data::from))
+                    .or_else(|_| bincode::deserialize::<LegacyData>(self).map(Data::from))
+                    .map_err(|_| e),
+            },
+        }
     }
 }
 
+// the value is left compressed for Data::for_reader, older layouts are decompressed
+pub fn as_stored(bytes: &[u8]) -> bincode::Result<Data> {
+    match bincode::deserialize::<Data>(bytes) {
+        Ok(data) => Ok(data),
+        Err(_) => bytes.to_vec().as_struct(),
+    }
+}
+
+// every layout starts with the ttl, it's read without decoding or decompressing the value
+pub fn ttl_of(bytes: &[u8]) -> bincode::Result<u128> {
+    bincode::deserialize(bytes)
+}
+
+// records in an older layout are still read, --migrate-and-exit rewrites them in the current one
+pub fn is_current_layout(bytes: &[u8]) -> bool {
+    bincode::deserialize::<Data>(bytes).is_ok()
+}
+
 pub fn bytes_to_str(bytes: &[u8]) -> anyhow::Result<String> {
     Ok(String::from_utf8(bytes.to_vec())?)
 }
@@ -32,8 +69,72 @@ pub fn convert(h: &HeaderValue) -> anyhow::Result<u128> {
     Ok(h.to_str()?.parse::<u128>()?)
 }
 
+pub fn encode_cursor(key: &[u8]) -> String {
+    base64::encode_config(key, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn decode_cursor(cursor: &str) -> anyhow::Result<Vec<u8>> {
+    base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| anyhow!(ErrorCtx::Validation(format!("Invalid cursor {}", cursor))))
+}
+
+pub fn to_etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+// If-None-Match holds * or a list of etags, weak ones compare equal too
+pub fn etag_matches(h: &HeaderValue, version: u64) -> bool {
+    let etag = to_etag(version);
+    h.to_str().map_or(false, |val| {
+        val.split(',')
+            .map(str::trim)
+            .any(|t| t == "*" || t.strip_prefix("W/").unwrap_or(t) == etag)
+    })
+}
+
+// accepts both quoted etags and bare versions
+pub fn parse_etag(h: &HeaderValue) -> anyhow::Result<u64> {
+    let val = h.to_str()?.trim();
+    let val = val.strip_prefix("W/").unwrap_or(val).trim_matches('"');
+    val.parse::<u64>()
+        .map_err(|_| anyhow!(ErrorCtx::Validation(format!("Invalid etag {}", val))))
+}
+
+pub fn parse_version(h: &HeaderValue) -> anyhow::Result<u64> {
+    let val = h.to_str()?.trim();
+    val.parse::<u64>()
+        .map_err(|_| anyhow!(ErrorCtx::Validation(format!("Invalid version {}", val))))
+}
+
+// shifted forward by POST /_test/advance_time in dev mode so TTLs can be tested without sleeping,
+// it's process wide and never goes back
+static CLOCK_OFFSET_MS: AtomicU64 = AtomicU64::new(0);
+
 pub fn current_ms() -> anyhow::Result<u128> {
-    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis())
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    Ok(now + u128::from(CLOCK_OFFSET_MS.load(Ordering::Relaxed)))
+}
+
+// returns the total offset
+pub fn advance_clock(ms: u64) -> u64 {
+    CLOCK_OFFSET_MS.fetch_add(ms, Ordering::Relaxed) + ms
+}
+
+// accepts "500ms", "30s", "2m" and bare seconds
+pub fn parse_duration(val: &str) -> anyhow::Result<Duration> {
+    let invalid = || anyhow!(ErrorCtx::Validation(format!("Invalid duration {}", val)));
+    let val = val.trim();
+    let (num, unit) = match val.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => val.split_at(idx),
+        None => (val, "s"),
+    };
+    let num = num.parse::<u64>().map_err(|_| invalid())?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(num)),
+        "s" => Ok(Duration::from_secs(num)),
+        "m" => Ok(Duration::from_secs(num * 60)),
+        _ => Err(invalid()),
+    }
 }
 
 #[cfg(test)]
@@ -64,6 +165,56 @@ mod tests {
         assert!(res.is_ok());
     }
 
+    #[test]
+    fn should_read_ttl_of_any_layout() {
+        let bytes = Data::new(42, b"data".to_vec()).as_bytes().unwrap();
+        assert_eq!(42, ttl_of(&bytes).unwrap());
+        let legacy = bincode::serialize(&(7u128, b"data".to_vec())).unwrap();
+        assert_eq!(7, ttl_of(&legacy).unwrap());
+        assert!(ttl_of(b"short").is_err());
+    }
+
+    #[test]
+    fn should_convert_etag() {
+        assert_eq!("\"42\"", to_etag(42));
+        assert_eq!(42, parse_etag(&HeaderValue::from_static("\"42\"")).unwrap());
+        assert_eq!(
+            42,
+            parse_etag(&HeaderValue::from_static("W/\"42\"")).unwrap()
+        );
+        assert_eq!(42, parse_etag(&HeaderValue::from_static("42")).unwrap());
+        assert!(parse_etag(&HeaderValue::from_static("\"abc\"")).is_err());
+    }
+
+    #[test]
+    fn should_match_etags() {
+        assert!(etag_matches(&HeaderValue::from_static("\"42\""), 42));
+        assert!(etag_matches(
+            &HeaderValue::from_static("\"1\", W/\"42\""),
+            42
+        ));
+        assert!(etag_matches(&HeaderValue::from_static("*"), 42));
+        assert!(!etag_matches(&HeaderValue::from_static("\"41\""), 42));
+        assert!(!etag_matches(&HeaderValue::from_static("42"), 42));
+    }
+
+    #[test]
+    fn should_encode_and_decode_cursor() {
+        let cursor = encode_cursor(b"record_1");
+        assert_eq!(b"record_1".to_vec(), decode_cursor(&cursor).unwrap());
+        assert!(decode_cursor("not a cursor!").is_err());
+    }
+
+    #[test]
+    fn should_parse_duration() {
+        assert_eq!(Duration::from_millis(500), parse_duration("500ms").unwrap());
+        assert_eq!(Duration::from_secs(30), parse_duration("30s").unwrap());
+        assert_eq!(Duration::from_secs(30), parse_duration("30").unwrap());
+        assert_eq!(Duration::from_secs(120), parse_duration("2m").unwrap());
+        assert!(parse_duration("2h").is_err());
+        assert!(parse_duration("s").is_err());
+    }
+
     #[test]
     fn should_convert_header() {
         let header_val = convert(&HeaderValue::from_str("42").unwrap());
@@ -0,0 +1,414 @@
+// Conflict-free replicated counters and sets, kept in their own column family.
+//
+// Every update is a RocksDb merge of a small state that is joined with the stored one, so updates don't read
+// before writing (except for set removals, which remove what was observed). Each deployment has its own
+// replica_id, its own increments are summed while states merged from other replicas are joined entry-wise by
+// maximum - so merging the same remote state twice changes nothing. Sets are observed-remove sets: every add
+// is tagged uniquely and a remove drops only the tags it has seen, a concurrent add wins.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::anyhow;
+use rocksdb::MergeOperands;
+use serde::{Deserialize, Serialize};
+
+use crate::conversion::current_ms;
+use crate::errors::ErrorCtx;
+
+pub const CRDT_CF: &str = "crdts";
+pub const CRDT_OPERATOR: &str = "crdt-operator";
+
+static TAGS: AtomicU64 = AtomicU64::new(0);
+
+// counts by replica
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Counts {
+    // increments of this deployment, summed
+    own: BTreeMap<String, u64>,
+    // merged from other replicas, joined by max
+    others: BTreeMap<String, u64>,
+}
+
+// add tags by element
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct OrSet {
+    adds: BTreeMap<String, BTreeSet<String>>,
+    removes: BTreeMap<String, BTreeSet<String>>,
+}
+
+// stored form
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Crdt {
+    GCounter(Counts),
+    PnCounter { p: Counts, n: Counts },
+    OrSet(OrSet),
+}
+
+// local update
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CrdtOp {
+    GCounter {
+        by: u64,
+    },
+    PnCounter {
+        by: i64,
+    },
+    OrSet {
+        #[serde(default)]
+        add: Vec<String>,
+        #[serde(default)]
+        remove: Vec<String>,
+    },
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CrdtValue {
+    GCounter { value: u64 },
+    PnCounter { value: i64 },
+    OrSet { elements: Vec<String> },
+}
+
+// exchanged between replicas, counts include the increments of the replica sending it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CrdtState {
+    GCounter {
+        counts: BTreeMap<String, u64>,
+    },
+    PnCounter {
+        p: BTreeMap<String, u64>,
+        n: BTreeMap<String, u64>,
+    },
+    OrSet {
+        adds: BTreeMap<String, BTreeSet<String>>,
+        removes: BTreeMap<String, BTreeSet<String>>,
+    },
+}
+
+impl Counts {
+    fn own(replica_id: &str, by: u64) -> Self {
+        let mut counts = Counts::default();
+        counts.own.insert(replica_id.into(), by);
+        counts
+    }
+
+    // own entries are left out, no one else increments them
+    fn others(replica_id: &str, counts: BTreeMap<String, u64>) -> Self {
+        Counts {
+            own: BTreeMap::new(),
+            others: counts
+                .into_iter()
+                .filter(|(r, _)| r != replica_id)
+                .collect(),
+        }
+    }
+
+    fn join(mut self, other: Counts) -> Self {
+        for (replica, count) in other.own {
+            *self.own.entry(replica).or_default() += count;
+        }
+        for (replica, count) in other.others {
+            let entry = self.others.entry(replica).or_default();
+            *entry = (*entry).max(count);
+        }
+        self
+    }
+
+    fn total(&self) -> u64 {
+        self.all().values().sum()
+    }
+
+    fn all(&self) -> BTreeMap<String, u64> {
+        let mut all = self.others.clone();
+        all.extend(self.own.iter().map(|(r, c)| (r.clone(), *c)));
+        all
+    }
+}
+
+impl OrSet {
+    fn join(mut self, other: OrSet) -> Self {
+        for (element, tags) in other.adds {
+            self.adds.entry(element).or_default().extend(tags);
+        }
+        for (element, tags) in other.removes {
+            self.removes.entry(element).or_default().extend(tags);
+        }
+        self
+    }
+
+    fn elements(&self) -> Vec<String> {
+        self.adds
+            .iter()
+            .filter(|(element, tags)| match self.removes.get(*element) {
+                Some(removed) => !tags.is_subset(removed),
+                None => !tags.is_empty(),
+            })
+            .map(|(element, _)| element.clone())
+            .collect()
+    }
+}
+
+impl Crdt {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Crdt::GCounter(_) => "gcounter",
+            Crdt::PnCounter { .. } => "pncounter",
+            Crdt::OrSet(_) => "orset",
+        }
+    }
+
+    // mismatching types are rejected before they're written
+    pub fn join(self, other: Crdt) -> Self {
+        match (self, other) {
+            (Crdt::GCounter(a), Crdt::GCounter(b)) => Crdt::GCounter(a.join(b)),
+            (Crdt::PnCounter { p, n }, Crdt::PnCounter { p: bp, n: bn }) => Crdt::PnCounter {
+                p: p.join(bp),
+                n: n.join(bn),
+            },
+            (Crdt::OrSet(a), Crdt::OrSet(b)) => Crdt::OrSet(a.join(b)),
+            (current, other) => {
+                error!(
+                    "Crdt merge:: Can't merge {} into {}",
+                    other.type_name(),
+                    current.type_name()
+                );
+                current
+            }
+        }
+    }
+
+    // an update of the same type as the current value, if there is one
+    pub fn check_type(current: Option<&Crdt>, update: &Crdt, key: &str) -> anyhow::Result<()> {
+        match current {
+            Some(current) if current.type_name() != update.type_name() => {
+                Err(anyhow!(ErrorCtx::Conflict(format!(
+                    "Key {} holds a {}, not a {}",
+                    key,
+                    current.type_name(),
+                    update.type_name()
+                ))))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn value(&self) -> CrdtValue {
+        match self {
+            Crdt::GCounter(counts) => CrdtValue::GCounter {
+                value: counts.total(),
+            },
+            Crdt::PnCounter { p, n } => CrdtValue::PnCounter {
+                value: (i128::from(p.total()) - i128::from(n.total())) as i64,
+            },
+            Crdt::OrSet(set) => CrdtValue::OrSet {
+                elements: set.elements(),
+            },
+        }
+    }
+
+    pub fn state(&self) -> CrdtState {
+        match self {
+            Crdt::GCounter(counts) => CrdtState::GCounter {
+                counts: counts.all(),
+            },
+            Crdt::PnCounter { p, n } => CrdtState::PnCounter {
+                p: p.all(),
+                n: n.all(),
+            },
+            Crdt::OrSet(set) => CrdtState::OrSet {
+                adds: set.adds.clone(),
+                removes: set.removes.clone(),
+            },
+        }
+    }
+}
+
+impl CrdtOp {
+    pub fn from_json(body: &[u8]) -> anyhow::Result<Self> {
+        serde_json::from_slice(body).map_err(|e| {
+            anyhow!(ErrorCtx::Validation(format!(
+                "Malformed crdt update - {}",
+                e
+            )))
+        })
+    }
+
+    // set removals need the current value to know which adds they observed
+    pub fn into_update(self, replica_id: &str, current: Option<&Crdt>) -> anyhow::Result<Crdt> {
+        match self {
+            CrdtOp::GCounter { by: 0 } | CrdtOp::PnCounter { by: 0 } => Err(anyhow!(
+                ErrorCtx::Validation("Counters can't be incremented by 0".into())
+            )),
+            CrdtOp::GCounter { by } => Ok(Crdt::GCounter(Counts::own(replica_id, by))),
+            CrdtOp::PnCounter { by } if by > 0 => Ok(Crdt::PnCounter {
+                p: Counts::own(replica_id, by as u64),
+                n: Counts::default(),
+            }),
+            CrdtOp::PnCounter { by } => Ok(Crdt::PnCounter {
+                p: Counts::default(),
+                n: Counts::own(replica_id, by.unsigned_abs()),
+            }),
+            CrdtOp::OrSet { add, remove } => {
+                let mut set = OrSet::default();
+                if let Some(Crdt::OrSet(current)) = current {
+                    for element in remove {
+                        if let Some(tags) = current.adds.get(&element) {
+                            set.removes.insert(element, tags.clone());
+                        }
+                    }
+                }
+                for element in add {
+                    set.adds.entry(element).or_default().insert(tag(replica_id));
+                }
+                Ok(Crdt::OrSet(set))
+            }
+        }
+    }
+}
+
+impl CrdtState {
+    pub fn from_json(body: &[u8]) -> anyhow::Result<Self> {
+        serde_json::from_slice(body).map_err(|e| {
+            anyhow!(ErrorCtx::Validation(format!(
+                "Malformed crdt state - {}",
+                e
+            )))
+        })
+    }
+
+    pub fn into_update(self, replica_id: &str) -> Crdt {
+        match self {
+            CrdtState::GCounter { counts } => Crdt::GCounter(Counts::others(replica_id, counts)),
+            CrdtState::PnCounter { p, n } => Crdt::PnCounter {
+                p: Counts::others(replica_id, p),
+                n: Counts::others(replica_id, n),
+            },
+            CrdtState::OrSet { adds, removes } => Crdt::OrSet(OrSet { adds, removes }),
+        }
+    }
+}
+
+// unique across replicas and restarts
+fn tag(replica_id: &str) -> String {
+    format!(
+        "{}:{}:{}",
+        replica_id,
+        current_ms().unwrap_or_default(),
+        TAGS.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+pub fn crdt_merge(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &mut MergeOperands,
+) -> Option<Vec<u8>> {
+    let merged = existing
+        .into_iter()
+        .chain(operands)
+        .filter_map(|bytes| match bincode::deserialize::<Crdt>(bytes) {
+            Ok(crdt) => Some(crdt),
+            Err(e) => {
+                error!("Crdt merge:: Can't deserialize value, e = {}", e);
+                None
+            }
+        })
+        .fold(None, |merged: Option<Crdt>, next| match merged {
+            Some(merged) => Some(merged.join(next)),
+            None => Some(next),
+        })?;
+
+    bincode::serialize(&merged).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(entries: &[(&str, u64)]) -> BTreeMap<String, u64> {
+        entries.iter().map(|(r, c)| (r.to_string(), *c)).collect()
+    }
+
+    #[test]
+    fn should_sum_own_increments_and_max_merged_ones() {
+        let incr = |by| CrdtOp::GCounter { by }.into_update("dc1", None).unwrap();
+        let remote = |c| {
+            CrdtState::GCounter {
+                counts: counts(&[("dc1", 100), ("dc2", c)]),
+            }
+            .into_update("dc1")
+        };
+
+        let crdt = incr(2).join(incr(3)).join(remote(5)).join(remote(4));
+        assert_eq!(CrdtValue::GCounter { value: 10 }, crdt.value());
+        assert_eq!(
+            CrdtState::GCounter {
+                counts: counts(&[("dc1", 5), ("dc2", 5)])
+            },
+            crdt.state()
+        );
+    }
+
+    #[test]
+    fn should_count_down() {
+        let update = |by| CrdtOp::PnCounter { by }.into_update("dc1", None).unwrap();
+        let crdt = update(5).join(update(-7));
+        assert_eq!(CrdtValue::PnCounter { value: -2 }, crdt.value());
+        assert!(CrdtOp::PnCounter { by: 0 }
+            .into_update("dc1", None)
+            .is_err());
+    }
+
+    #[test]
+    fn should_let_concurrent_add_win() {
+        let add = |e: &str| CrdtOp::OrSet {
+            add: vec![e.into()],
+            remove: vec![],
+        };
+        let set = add("a").into_update("dc1", None).unwrap();
+        let set = set.join(add("b").into_update("dc1", None).unwrap());
+        assert_eq!(
+            CrdtValue::OrSet {
+                elements: vec!["a".into(), "b".into()]
+            },
+            set.value()
+        );
+
+        // dc2 adds "a" again without having seen the remove
+        let concurrent = add("a").into_update("dc2", None).unwrap();
+        let remove = CrdtOp::OrSet {
+            add: vec![],
+            remove: vec!["a".into(), "c".into()],
+        }
+        .into_update("dc1", Some(&set))
+        .unwrap();
+
+        let removed = set.clone().join(remove.clone());
+        assert_eq!(
+            CrdtValue::OrSet {
+                elements: vec!["b".into()]
+            },
+            removed.value()
+        );
+        let merged = removed.join(concurrent);
+        assert_eq!(
+            CrdtValue::OrSet {
+                elements: vec!["a".into(), "b".into()]
+            },
+            merged.value()
+        );
+    }
+
+    #[test]
+    fn should_reject_other_type() {
+        let counter = Crdt::GCounter(Counts::default());
+        let set = Crdt::OrSet(OrSet::default());
+        assert!(Crdt::check_type(Some(&counter), &set, "k").is_err());
+        assert!(Crdt::check_type(Some(&set), &set, "k").is_ok());
+        assert!(Crdt::check_type(None, &set, "k").is_ok());
+        assert_eq!(counter.clone(), counter.clone().join(set));
+    }
+}
@@ -1,41 +1,361 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
-use std::path::Path;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{mpsc, Arc, Mutex, MutexGuard};
-use std::{fs, thread};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use actix_web::web::Bytes;
+use actix_web::client::Client;
+use actix_web::error::BlockingError;
+use actix_web::rt::time::timeout;
+use actix_web::rt::System;
+use actix_web::web::{self, Bytes};
 use anyhow::anyhow;
 
 use crossbeam::sync::{ShardedLock, ShardedLockReadGuard, ShardedLockWriteGuard};
-use rocksdb::{CompactionDecision, IteratorMode, Options, DB};
+use futures::StreamExt;
+use regex::RegexSet;
+use rocksdb::backup::{BackupEngine, RestoreOptions};
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::{
+    Cache, ColumnFamily, ColumnFamilyDescriptor, CompactionDecision, DBRawIterator, Direction, Env,
+    IteratorMode, MergeOperands, Options, WriteBatch, WriteOptions, DB,
+};
 use serde::{Deserialize, Serialize};
+use tracing::instrument;
 
-use crate::config::DbConfig;
-use crate::conversion::{bytes_to_str, current_ms, FromBytes, IntoBytes};
+use crate::archive::StagingDir;
+use crate::backup::{backups, open_engine, BackupInfo, CheckpointInfo};
+use crate::changelog::{entry_id, entry_key, ChangesPage, LogEntry, Logged, CHANGELOG_CF};
+use crate::compression::{Accepted, Codec, Compression};
+use crate::config::{
+    ConflictPolicy, DbConfig, DbSettings, Durability, RocksOverrides, ScanLimits, WriteConcern,
+};
+use crate::conversion::{
+    as_stored, bytes_to_str, current_ms, decode_cursor, encode_cursor, is_current_layout, ttl_of,
+    FromBytes, IntoBytes,
+};
+use crate::crdt::{crdt_merge, Crdt, CrdtOp, CrdtState, CrdtValue, CRDT_CF, CRDT_OPERATOR};
+use crate::deletion::{dir_size, remove_dir_throttled, update, Deletion, Deletions};
+use crate::erasure::{
+    annotate_backups, backup_annotations, DbErasure, ErasureReport, ErasureRequest, Erasures,
+};
 use crate::errors::ErrorCtx;
+use crate::events::{Event, EventBus, EventKind, Filter};
+use crate::export::{dir_name, Export, Exports};
+use crate::filter::ScanFilter;
+use crate::forecast::{forecast, Forecast, Sample, UsageHistory};
+use crate::index::{check_json, entries, entry_keys, holds, lookup_prefix, INDEX_CF};
+use crate::internals::{
+    approximate_size, live_files, manifest, parse_int, parse_level_stats, parse_lines,
+    ApproximateSize, CompressionStats, Internals, LevelCompression, LiveFileInfo, ManifestInfo,
+    BACKGROUND_ERRORS, CF_STATS, CUR_SIZE_ALL_MEM_TABLES, DELAYED_WRITE_RATE,
+    ESTIMATE_LIVE_DATA_SIZE, ESTIMATE_NUM_KEYS, IS_WRITE_STOPPED, LEVEL_STATS, NUM_FILES_AT_LEVEL,
+    NUM_LEVELS, PENDING_COMPACTION_BYTES, RUNNING_COMPACTIONS, RUNNING_FLUSHES,
+    TABLE_PROPERTIES_AT_LEVEL,
+};
+use crate::key_encoding::{display_key, KeyEncoding};
+use crate::lifecycle::Lifecycle;
+use crate::maintenance::Schedule;
+use crate::memory::{pressure, rss_bytes, Pressure};
+use crate::metrics::{
+    EXPIRED_READS, KEY_OPERATIONS, MEMORY_PRESSURE_ACTIONS, PROCESS_RSS, READ_DECOMPRESSIONS,
+    REPLICATION_LAG, SWEPT_KEYS,
+};
+use crate::namespace::{prefix_end, Listing};
+use crate::operations::{Cancel, Operation, OperationInfo, OperationKind, Operations};
+use crate::outbound::{Kind, Outbox, PendingDelivery, OUTBOUND_CF};
+use crate::patch::{apply as apply_patch, PatchFormat};
+use crate::quota::{QuotaInfo, QuotaUsage};
+use crate::replication::{
+    replication_target, reship, ship, Change, Replicas, Replicated, ReplicationResult, Shipped,
+    Shipper, MAX_BATCH, REPLICATION_BUFFER, STOP_CHECK_INTERVAL,
+};
+use crate::rock::{ReadGuard, Rock, WriteGuard};
+use crate::scan_cache::ScanCache;
+use crate::scan_snapshot::{pinned_cursor, Pinned, Pins, ScanCursor};
+use crate::snapshot::{SnapshotId, Snapshots};
+use crate::stats::{
+    rank, stats_merge, victims, Access, AccessStats, KeyStats, Sampler, STATS_CF, STATS_OPERATOR,
+};
+use crate::txn::{Sessions, TxnAction, TxnResult, TxnWrite};
+use crate::upload::{
+    check_part_number, check_parts, is_upload_id, meta_key, not_found as upload_not_found,
+    part_key, part_number, upload_id, upload_range, Chunks, PartInfo, Upload, UploadInfo,
+    UPLOADS_CF,
+};
+use crate::versioning::is_version;
+use crate::webhook::{Expiration, Webhooks};
+use crate::wire::WireRecord;
+use crate::NO_TTL;
 
 const ROOT_DB_NAME: &str = "root";
+const DEFAULT_CF: &str = "default";
+// rocksdb overrides of dbs by name, kept in the root db
+const OVERRIDES_CF: &str = "overrides";
+const APPEND_OPERATOR: &str = "append-operator";
+// deleted records kept for undelete, by key
+const TOMBSTONE_CF: &str = "tombstones";
+const INITIAL_RECOVERY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECOVERY_BACKOFF: Duration = Duration::from_secs(60);
+const RELEASE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(30);
+// how often deferred jobs recheck a maintenance schedule, it can be replaced at runtime
+const MAINTENANCE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// records rewritten per write batch by --migrate-and-exit
+const MIGRATION_BATCH_SIZE: usize = 1000;
+// names readiness probe files, overlapping probes must not remove each other's
+static READINESS_PROBES: AtomicU64 = AtomicU64::new(0);
+// largest value of a single store over any of the APIs, values sent compressed are checked decompressed too
+pub const MAX_VALUE_BYTES: usize = 256 * 1024;
+// appends grow records past MAX_VALUE_BYTES, reads decompress them up to this size
+const MAX_RECORD_BYTES: usize = 64 * 1024 * 1024;
+// compressed values from this size up are decompressed for reads on the blocking pool
+const OFFLOAD_DECOMPRESSION_BYTES: usize = 64 * 1024;
 
 type SafeRW<T> = Arc<ShardedLock<T>>;
 
 #[derive(Clone)]
 struct Db {
-    rock: SafeRW<DB>,
+    // listing snapshots reference the db, declared first so they're released before it's dropped
+    pins: Arc<Pins>,
+    rock: Arc<Rock>,
+    // in memory env of ephemeral dbs, has to outlive the db
+    env: Option<Arc<Env>>,
+    durability: Durability,
+    compression: Option<Compression>,
+    compression_min_bytes: usize,
+    // values have to be JSON, indexes are JSON fields
+    json: bool,
+    indexes: Vec<String>,
+    // multi page listings read a pinned snapshot
+    pin_listings: bool,
+    // reapplied when the db is reopened after a background error
+    overrides: RocksOverrides,
+    cache: Option<Arc<Mutex<Cache>>>,
+    sampler: Option<Sampler>,
+    opened_at: u128,
+    // id of the next change log entry
+    next_change: Arc<Mutex<u64>>,
+    // every column family the db was opened with
+    cf_names: Arc<Vec<String>>,
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DbInfo {
+    pub name: String,
+    pub path: String,
+    pub opened_at: u128,
+    pub ephemeral: bool,
+}
+
+// ttl is the hard expiration, after the soft one the record is still readable but stale
 #[derive(Serialize, Deserialize)]
 pub struct Data {
     ttl: u128,
     data: Vec<u8>,
+    version: u64,
+    soft_ttl: u128,
+    // ms since epoch, resolves replication conflicts - 0 for records written before it was tracked
+    written_at: u128,
+    // media type of the stored value, returned on reads
+    content_type: Option<String>,
+    // codec the value is compressed with at rest, values are plain in memory unless encoded
+    compression: Option<Compression>,
+    // the value is held by the chunks of a completed upload, data is empty until they're read
+    chunks: Option<Chunks>,
+    // the value is still compressed - sent as is to readers accepting the codec, or stored as sent by the client
+    #[serde(skip)]
+    encoded: bool,
+}
+
+// serialized form of Data, borrows the value or holds its compressed copy
+#[derive(Serialize)]
+struct StoredData<'a> {
+    ttl: u128,
+    data: Cow<'a, [u8]>,
+    version: u64,
+    soft_ttl: u128,
+    written_at: u128,
+    content_type: &'a Option<String>,
+    compression: Option<Compression>,
+    chunks: &'a Option<Chunks>,
+}
+
+// envelope layout used before chunked values
+#[derive(Deserialize)]
+pub struct CompressedData {
+    ttl: u128,
+    data: Vec<u8>,
+    version: u64,
+    soft_ttl: u128,
+    written_at: u128,
+    content_type: Option<String>,
+    compression: Option<Compression>,
+}
+
+// envelope layout used before value compression
+#[derive(Deserialize)]
+pub struct ContentTypeData {
+    ttl: u128,
+    data: Vec<u8>,
+    version: u64,
+    soft_ttl: u128,
+    written_at: u128,
+    content_type: Option<String>,
+}
+
+// envelope layout used before content types
+#[derive(Deserialize)]
+pub struct WrittenAtData {
+    ttl: u128,
+    data: Vec<u8>,
+    version: u64,
+    soft_ttl: u128,
+    written_at: u128,
+}
+
+// envelope layout used before write timestamps
+#[derive(Deserialize)]
+pub struct SoftTtlData {
+    ttl: u128,
+    data: Vec<u8>,
+    version: u64,
+    soft_ttl: u128,
+}
+
+// envelope layout used before soft ttl
+#[derive(Deserialize)]
+pub struct VersionedData {
+    ttl: u128,
+    data: Vec<u8>,
+    version: u64,
+}
+
+// envelope layout used before record versioning
+#[derive(Deserialize)]
+pub struct LegacyData {
+    ttl: u128,
+    data: Vec<u8>,
+}
+
+// media type and content coding of a value as the client sent it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Representation {
+    pub content_type: Option<String>,
+    pub encoding: Option<Codec>,
+}
+
+// of _keys and _scan listings, keys compare bytewise
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+impl Default for Order {
+    fn default() -> Self {
+        Order::Asc
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteCondition {
+    Always,
+    IfVersion(u64),
+    IfExists,
+    // like IfExists, but a missing key is reported as not found instead of a failed precondition
+    IfPresent,
+    IfAbsent,
+}
+
+// a deleted record as it was stored, purged by the sweeper after purge_at
+#[derive(Serialize, Deserialize)]
+struct Tombstone {
+    purge_at: u128,
+    record: Vec<u8>,
+}
+
+// writes hold the write lock, so the latest sequence number read under it belongs to that write
+#[derive(Debug, Clone, Copy)]
+pub struct Stored {
+    pub version: u64,
+    pub seq: u64,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyPage {
+    keys: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    namespaces: Vec<String>,
+    cursor: Option<String>,
+}
+
+impl KeyPage {
+    pub fn into_parts(self) -> (Vec<String>, Option<String>) {
+        (self.keys, self.cursor)
+    }
+}
+
+// an exact count cut short by the time limit has a cursor to continue from, counts of all pages add up
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyCount {
+    count: u64,
+    approximate: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ScanPage {
+    records: Vec<Record>,
+    cursor: Option<String>,
+}
+
+// value is base64 encoded, left out are the fields not asked for
+#[derive(Serialize, Clone)]
+pub struct Record {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
 }
 
 pub struct DbManager {
-    pub db_cfg: DbConfig,
+    pub db_cfg: Arc<DbConfig>,
     root_db: Db,
     dbs: SafeRW<HashMap<String, Db>>,
+    recovering: SafeRW<HashSet<String>>,
+    deletions: Deletions,
+    erasures: Erasures,
+    pub events: Arc<EventBus>,
+    maintenance: SafeRW<Schedule>,
+    txns: Sessions,
+    usage: Arc<UsageHistory>,
+    webhooks: Arc<Webhooks>,
+    outbox: Arc<Outbox>,
+    replicas: Arc<Replicas>,
+    scan_cache: ScanCache,
+    snapshots: Snapshots,
+    exports: Arc<Exports>,
+    operations: Arc<Operations>,
+    lifecycle: Lifecycle,
+    backup_lock: Arc<Mutex<()>>,
     tx: Mutex<Sender<BoxedFnOnce>>,
+    deletion_tx: Mutex<Sender<BoxedFnOnce>>,
+    // false for offline commands, nothing runs in the background or replicates
+    background: bool,
 }
 
 pub struct BoxedFnOnce {
@@ -59,306 +379,5603 @@ impl BoxedFnOnce {
 
 impl Data {
     pub fn new(ttl: u128, data: Vec<u8>) -> Self {
-        Data { ttl, data }
+        Data {
+            ttl,
+            data,
+            version: 1,
+            soft_ttl: NO_TTL,
+            written_at: current_ms().unwrap_or_default(),
+            content_type: None,
+            compression: None,
+            chunks: None,
+            encoded: false,
+        }
     }
-}
 
-impl Db {
-    fn new<P>(path: P, opts: &Options) -> anyhow::Result<Self>
-    where
-        P: AsRef<Path>,
-    {
-        let rock = DB::open(&opts, path)?;
-        Ok(Db {
-            rock: Arc::new(ShardedLock::new(rock)),
-        })
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
     }
 
-    fn put<V>(&self, key: &str, val: V) -> anyhow::Result<()>
-    where
-        V: AsRef<[u8]>,
-    {
-        Ok(self.w_lock().put(key, val)?)
+    // a value that doesn't shrink is stored uncompressed
+    pub fn to_bytes(&self) -> bincode::Result<Vec<u8>> {
+        let (data, compression) = match self.compression {
+            Some(compression) if self.encoded => {
+                (Cow::Borrowed(self.data.as_slice()), Some(compression))
+            }
+            Some(compression) => {
+                let compressed = compression.compress(&self.data)?;
+                if compressed.len() < self.data.len() {
+                    (Cow::Owned(compressed), Some(compression))
+                } else {
+                    (Cow::Borrowed(self.data.as_slice()), None)
+                }
+            }
+            None => (Cow::Borrowed(self.data.as_slice()), None),
+        };
+        bincode::serialize(&StoredData {
+            ttl: self.ttl,
+            data,
+            version: self.version,
+            soft_ttl: self.soft_ttl,
+            written_at: self.written_at,
+            content_type: &self.content_type,
+            compression,
+            chunks: &self.chunks,
+        })
     }
 
-    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
-        Ok(self.r_lock().get(key)?)
+    pub fn with_chunks(mut self, chunks: Chunks) -> Self {
+        self.chunks = Some(chunks);
+        self
     }
 
-    fn remove(&self, key: &str) -> anyhow::Result<()> {
-        self.w_lock().delete(key).map_err(anyhow::Error::from)
+    pub fn is_chunked(&self) -> bool {
+        self.chunks.is_some()
     }
 
-    fn close<P>(&self, path: P) -> anyhow::Result<()>
-    where
-        P: AsRef<Path>,
-    {
-        Ok(DB::destroy(&Options::default(), path)?)
+    // the codec is kept, the value is compressed again when written back
+    pub fn decompressed(mut self) -> bincode::Result<Self> {
+        if let Some(compression) = self.compression {
+            self.data = compression.decompress(&self.data, MAX_RECORD_BYTES)?;
+        }
+        self.encoded = false;
+        Ok(self)
     }
 
-    fn r_lock(&self) -> ShardedLockReadGuard<'_, DB> {
-        self.rock.read().expect("Can't acquire read lock")
+    // a value compressed by the client, stored as it was sent
+    pub fn with_encoding(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self.encoded = true;
+        self
     }
 
-    fn w_lock(&self) -> ShardedLockWriteGuard<'_, DB> {
-        self.rock.write().expect("Can't acquire write lock")
+    // whether for_reader has to decompress the value
+    pub fn decompresses_for(&self, accepted: &Accepted) -> bool {
+        match self.compression {
+            Some(compression) => !accepted.contains(compression.codec()),
+            None => false,
+        }
     }
-}
-
-impl DbManager {
-    pub fn new(db_cfg: DbConfig) -> anyhow::Result<Self> {
-        db_cfg
-            .rocks_options()
-            .set_compaction_filter("expiration-filter", compaction_filter);
-
-        let root_db = open_root_db(&db_cfg)?;
-        let (tx, rx) = mpsc::channel::<BoxedFnOnce>();
-
-        let db_manager = DbManager {
-            db_cfg,
-            root_db,
-            dbs: Arc::new(ShardedLock::new(HashMap::new())),
-            tx: Mutex::new(tx),
-        };
-        db_manager.open_dbs();
-        db_manager.reg_receiver_thread(rx);
 
-        Ok(db_manager)
+    // left compressed for a reader accepting the codec, decompressed otherwise
+    pub fn for_reader(mut self, accepted: &Accepted) -> bincode::Result<Self> {
+        match self.compression {
+            Some(compression) if accepted.contains(compression.codec()) => {
+                self.encoded = true;
+                Ok(self)
+            }
+            _ => self.decompressed(),
+        }
     }
 
-    // will panic in main thread and prevent startup
-    fn open_dbs(&self) {
-        info!("Initializing dbs from root ...");
-        self.root_db
-            .r_lock()
-            .iterator(IteratorMode::Start)
-            .map(|(k, v)| {
-                (
-                    bytes_to_str(&k).expect("Failed to read from db"),
-                    bytes_to_str(&v).expect("Failed to read from db"),
-                )
-            })
-            .for_each(|(name, path)| {
-                info!("Initializing db = {} on path = {}", &name, &path);
-                self.open_on_path(name, path).expect("Failed to open db");
-            });
+    pub fn codec(&self) -> Option<Codec> {
+        self.compression.map(|c| c.codec())
     }
 
-    fn reg_receiver_thread(&self, rx: Receiver<BoxedFnOnce>) {
-        thread::Builder::new()
-            .name("async-expire-thread".into())
-            .spawn(move || {
-                for boxed in rx {
-                    boxed.invoke()
-                }
-            })
-            .expect("Failed to register receiver thread");
+    // content coding of a value that's still compressed
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        self.codec()
+            .filter(|_| self.encoded)
+            .and_then(Codec::content_coding)
     }
 
-    pub async fn open(&self, db_name: String) -> anyhow::Result<()> {
-        if self.contains(&db_name) {
-            warn!("Db {} already exists", &db_name);
-            Err(anyhow!(ErrorCtx::Validation(format!(
-                "Database {} already exists",
-                db_name
-            ))))
-        } else {
-            let path = format!("{}/{}", self.db_cfg.path(), db_name);
-            info!("Opening Db = {} on path = {}", &db_name, &path);
+    pub fn with_content_type(mut self, content_type: Option<String>) -> Self {
+        self.content_type = content_type;
+        self
+    }
 
-            self.root_db.put(&db_name, &path)?;
-            Ok(self.open_on_path(db_name, path)?)
-        }
+    pub fn with_written_at(mut self, written_at: u128) -> Self {
+        self.written_at = written_at;
+        self
     }
 
-    fn open_on_path(&self, db_name: String, path: String) -> anyhow::Result<()> {
-        let db = Db::new(&path, &self.db_cfg.rocks_options())?;
-        self.w_lock().insert(db_name, db);
-        Ok(())
+    pub fn with_version(mut self, version: u64) -> Self {
+        self.version = version;
+        self
     }
 
-    pub async fn close(&self, db_name: String) -> anyhow::Result<()> {
-        if self.not_contains(&db_name) {
-            Err(anyhow!(ErrorCtx::Validation(format!(
-                "Can't close {} db - doesn't exist",
-                &db_name
-            ))))
+    // a soft ttl past the hard one is capped, the record is gone by then anyway
+    pub fn with_soft_ttl(mut self, soft_ttl: u128) -> Self {
+        self.soft_ttl = if self.ttl != NO_TTL && soft_ttl > self.ttl {
+            self.ttl
         } else {
-            if let Some(db) = self.w_lock().remove(&db_name) {
-                info!("Closing db = {} ...", &db_name);
-                let path = self.db_cfg.db_path(&db_name);
-                self.root_db.w_lock().delete(&db_name)?;
-                self.try_close_async(db, db_name, path);
-            }
+            soft_ttl
+        };
+        self
+    }
 
-            Ok(())
-        }
+    pub fn soft_ttl(&self) -> u128 {
+        self.soft_ttl
     }
 
-    fn try_close_async(&self, db: Db, db_name: String, path: String) {
-        let _ = self
-            .tx_mutex()
-            .send(BoxedFnOnce::new(move || match db.close(&db_name) {
-                Ok(_) => {
-                    info!("Db = {} closed. Deleting db files...", &db_name);
-                    remove_files(path);
-                }
-                Err(e) => error!("Error closing db = {}, e = {}", &db_name, e),
-            }));
+    pub fn version(&self) -> u64 {
+        self.version
     }
 
-    pub async fn store(
-        &self,
-        db_name: &str,
-        key: &str,
-        val: Bytes,
-        ttl: u128,
-    ) -> anyhow::Result<()> {
-        let bytes = Data::new(ttl, val.to_vec()).as_bytes()?;
-        match self.w_lock().get(db_name) {
-            Some(db) => db.put(&key, bytes),
-            None => Err(not_exists(db_name)),
-        }
+    pub fn ttl(&self) -> u128 {
+        self.ttl
     }
 
-    pub async fn read(&self, db_name: &str, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
-        match self.r_lock().get(db_name) {
-            Some(db) => {
-                if let Some(bytes) = db.get(&key)? {
-                    let data = bytes.as_struct()?;
-                    if is_expired(data.ttl)? {
-                        self.expire(db, key);
-                        Ok(None)
-                    } else {
-                        Ok(Some(data.data))
-                    }
-                } else {
-                    Ok(None)
-                }
-            }
-            None => Err(not_exists(db_name)),
-        }
+    pub fn written_at(&self) -> u128 {
+        self.written_at
     }
 
-    fn expire(&self, db: &Db, key: &str) {
-        let db = db.clone();
-        let key = key.to_string();
-        let _ = self.tx_mutex().send(BoxedFnOnce::new(move || {
-            if let Err(e) = db.w_lock().delete(&key) {
-                error!("Failed to expire key = {}, e = {}", key, e);
-            }
-        }));
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
     }
 
-    pub async fn remove(&self, db_name: &str, key: &str) -> anyhow::Result<()> {
-        match self.w_lock().get(db_name) {
-            Some(db) => db.remove(&key),
-            None => Err(not_exists(db_name)),
+    // None for records without ttl
+    pub fn remaining_ttl(&self) -> anyhow::Result<Option<u128>> {
+        if self.ttl == NO_TTL {
+            Ok(None)
+        } else {
+            Ok(Some(self.ttl.saturating_sub(current_ms()?)))
         }
     }
 
-    pub fn contains(&self, db_name: &str) -> bool {
-        self.r_lock().contains_key(db_name)
+    pub fn size(&self) -> usize {
+        self.data.len()
     }
 
-    fn not_contains(&self, db_name: &str) -> bool {
-        !self.contains(db_name)
+    pub fn into_value(self) -> Vec<u8> {
+        self.data
     }
 
-    fn tx_mutex(&self) -> MutexGuard<'_, Sender<BoxedFnOnce>> {
-        self.tx
-            .lock()
-            .expect("Failed to acquire channel sender lock")
+    // operand version counts appends it carries, an expired value is replaced instead of appended to
+    fn append(mut self, operand: Data) -> Self {
+        if is_expired(self.ttl).unwrap_or(false) {
+            self.data.clear();
+            self.chunks = None;
+            self.ttl = NO_TTL;
+            self.soft_ttl = NO_TTL;
+        }
+        self.data.extend(operand.data);
+        self.version += operand.version;
+        self.written_at = self.written_at.max(operand.written_at);
+        self
     }
+}
 
-    fn r_lock(&self) -> ShardedLockReadGuard<'_, HashMap<String, Db>> {
-        self.dbs.read().expect("Can't acquire read lock")
+impl From<CompressedData> for Data {
+    fn from(compressed: CompressedData) -> Self {
+        Data::new(compressed.ttl, compressed.data)
+            .with_version(compressed.version)
+            .with_soft_ttl(compressed.soft_ttl)
+            .with_written_at(compressed.written_at)
+            .with_content_type(compressed.content_type)
+            .with_compression(compressed.compression)
     }
+}
 
-    fn w_lock(&self) -> ShardedLockWriteGuard<'_, HashMap<String, Db>> {
-        self.dbs.write().expect("Can't acquire write lock")
+impl From<ContentTypeData> for Data {
+    fn from(typed: ContentTypeData) -> Self {
+        Data::new(typed.ttl, typed.data)
+            .with_version(typed.version)
+            .with_soft_ttl(typed.soft_ttl)
+            .with_written_at(typed.written_at)
+            .with_content_type(typed.content_type)
     }
 }
 
-fn open_root_db(db_cfg: &DbConfig) -> anyhow::Result<Db> {
-    Db::new(db_cfg.db_path(ROOT_DB_NAME), &db_cfg.root_db_options())
+impl From<WrittenAtData> for Data {
+    fn from(written: WrittenAtData) -> Self {
+        Data::new(written.ttl, written.data)
+            .with_version(written.version)
+            .with_soft_ttl(written.soft_ttl)
+            .with_written_at(written.written_at)
+    }
 }
 
-fn not_exists(db_name: &str) -> anyhow::Error {
-    anyhow!(ErrorCtx::Validation(format!(
-        "Db {} - doesn't exist",
-        &db_name
-    )))
+impl From<SoftTtlData> for Data {
+    fn from(soft: SoftTtlData) -> Self {
+        Data::new(soft.ttl, soft.data)
+            .with_version(soft.version)
+            .with_soft_ttl(soft.soft_ttl)
+            .with_written_at(0)
+    }
 }
 
-fn is_expired(ttl: u128) -> anyhow::Result<bool> {
-    if ttl == 0 {
-        Ok(false)
-    } else {
-        Ok(ttl < current_ms()?)
+impl From<VersionedData> for Data {
+    fn from(versioned: VersionedData) -> Self {
+        Data::new(versioned.ttl, versioned.data)
+            .with_version(versioned.version)
+            .with_written_at(0)
     }
 }
 
-fn remove_files<P>(path: P)
-where
-    P: AsRef<Path> + Debug,
-{
-    if let Err(e) = fs::remove_dir_all(&path) {
-        error!(
-            "Failed to remove db files on path = {:?}, err = {}",
-            path, e
-        );
-    } else {
-        info!("Removed files for db on path {:?}", path);
+impl From<LegacyData> for Data {
+    fn from(legacy: LegacyData) -> Self {
+        Data::new(legacy.ttl, legacy.data).with_written_at(0)
     }
 }
 
-fn compaction_filter(_level: u32, _key: &[u8], value: &[u8]) -> CompactionDecision {
-    info!(
-        "Running compaction filter in thread {:?}",
-        thread::current()
-    );
-    if let Ok(data) = value.to_vec().as_struct() {
-        if let Ok(expired) = is_expired(data.ttl) {
-            if expired {
-                CompactionDecision::Remove
-            } else {
-                CompactionDecision::Keep
-            }
-        } else {
-            CompactionDecision::Remove
+impl WriteCondition {
+    fn check(&self, key: &[u8], current: Option<u64>) -> anyhow::Result<()> {
+        let key = display_key(key);
+        match (self, current) {
+            (WriteCondition::Always, _) => Ok(()),
+            (WriteCondition::IfExists, Some(_)) => Ok(()),
+            (WriteCondition::IfPresent, Some(_)) => Ok(()),
+            (WriteCondition::IfPresent, None) => Err(anyhow!(ErrorCtx::NotFound(format!(
+                "Key {} doesn't exist",
+                key
+            )))),
+            (WriteCondition::IfAbsent, None) => Ok(()),
+            (WriteCondition::IfAbsent, Some(_)) => Err(anyhow!(ErrorCtx::Conflict(format!(
+                "Key {} already exists",
+                key
+            )))),
+            (WriteCondition::IfVersion(expected), Some(version)) if *expected == version => Ok(()),
+            // versions start at 1, expecting 0 means the key must not exist
+            (WriteCondition::IfVersion(0), None) => Ok(()),
+            (_, Some(version)) => Err(anyhow!(ErrorCtx::PreconditionFailed(format!(
+                "Key {} is at version {}",
+                key, version
+            )))),
+            (_, None) => Err(anyhow!(ErrorCtx::PreconditionFailed(format!(
+                "Key {} doesn't exist",
+                key
+            )))),
         }
-    } else {
-        error!("Compaction job:: Can't deserialize record - will be discarded.");
-        CompactionDecision::Remove
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    const ONE_DAY_MS: u128 = 1000 * 60 * 60 * 24;
-
-    #[test]
-    fn should_remove_expired() {
-        let bytes = Data::new(1, b"data".to_vec()).as_bytes().unwrap();
+impl Db {
+    fn new<P>(
+        path: P,
+        opts: &Options,
+        cfs: Vec<ColumnFamilyDescriptor>,
+        durability: Durability,
+        cache: Option<Cache>,
+        sampler: Option<Sampler>,
+        env: Option<Env>,
+    ) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let rock = DB::open_cf_descriptors(&opts, path, cfs)?;
+        let next_change = match rock.cf_handle(CHANGELOG_CF) {
+            Some(cf) => rock
+                .iterator_cf(cf, IteratorMode::End)
+                .next()
+                .map_or(1, |(k, _)| entry_id(&k) + 1),
+            None => 1,
+        };
+        Ok(Db {
+            pins: Arc::new(Pins::default()),
+            rock: Arc::new(Rock::new(rock)),
+            env: env.map(Arc::new),
+            durability,
+            compression: None,
+            compression_min_bytes: 0,
+            json: false,
+            indexes: vec![],
+            pin_listings: false,
+            overrides: RocksOverrides::default(),
+            cache: cache.map(|c| Arc::new(Mutex::new(c))),
+            sampler,
+            opened_at: current_ms()?,
+            next_change: Arc::new(Mutex::new(next_change)),
+            cf_names: Arc::new(vec![DEFAULT_CF.into()]),
+        })
+    }
 
-        match compaction_filter(0, &[0], &bytes) {
-            CompactionDecision::Remove => {}
-            _ => panic!("Should have removed expired record"),
+    fn check_value(&self, value: &[u8]) -> anyhow::Result<()> {
+        if self.json {
+            check_json(value)?;
         }
+        Ok(())
     }
 
-    #[test]
-    fn should_keep_not_expired() {
-        let bytes = Data::new(current_ms().unwrap() + ONE_DAY_MS, b"data".to_vec())
-            .as_bytes()
-            .unwrap();
+    // value of the record the index entries were written for, None when nothing is indexed
+    fn indexed_value(&self, rock: &DB, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        if self.indexes.is_empty() {
+            return Ok(None);
+        }
+        Ok(rock
+            .get(key)?
+            .map(|b| b.as_struct())
+            .transpose()?
+            .map(|d: Data| d.data))
+    }
 
-        match compaction_filter(0, &[0], &bytes) {
-            CompactionDecision::Keep => {}
-            _ => panic!("Should have kept non expired record"),
+    // replaces index entries of the previous value with the ones of the new value, in the same batch
+    fn reindex(
+        &self,
+        rock: &DB,
+        batch: &mut WriteBatch,
+        key: &[u8],
+        previous: Option<&[u8]>,
+        value: Option<&[u8]>,
+    ) {
+        let cf = match rock.cf_handle(INDEX_CF) {
+            Some(cf) if !self.indexes.is_empty() => cf,
+            _ => return,
+        };
+        for entry in previous.map_or_else(Vec::new, |v| entries(&self.indexes, key, v)) {
+            batch.delete_cf(cf, entry);
         }
+        for entry in value.map_or_else(Vec::new, |v| entries(&self.indexes, key, v)) {
+            batch.put_cf(cf, entry, b"");
+        }
+    }
+
+    // keys of records holding the value of the field, entries left by records since removed otherwise are dropped
+    fn query(
+        &self,
+        field: &str,
+        value: &str,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+        encoding: KeyEncoding,
+    ) -> anyhow::Result<KeyPage> {
+        if !self.indexes.iter().any(|f| f == field) {
+            return Err(anyhow!(ErrorCtx::Validation(format!(
+                "Field {} isn't indexed",
+                field
+            ))));
+        }
+        let rock = self.r_lock();
+        let cf = match rock.cf_handle(INDEX_CF) {
+            Some(cf) => cf,
+            None => return Err(anyhow!("Index column family is missing")),
+        };
+
+        let prefix = lookup_prefix(field, value);
+        let start = cursor
+            .filter(|c| c > &prefix)
+            .unwrap_or_else(|| prefix.clone());
+        let mut page = KeyPage {
+            keys: Vec::with_capacity(limit),
+            namespaces: vec![],
+            cursor: None,
+        };
+        let mut stale = WriteBatch::default();
+        for (entry, _) in rock.iterator_cf(cf, IteratorMode::From(&start, Direction::Forward)) {
+            if !entry.starts_with(&prefix) {
+                break;
+            }
+            if page.keys.len() == limit {
+                page.cursor = Some(encode_cursor(&entry));
+                break;
+            }
+            let key = &entry[prefix.len()..];
+            let data: Option<Data> = rock.get(key)?.map(|b| b.as_struct()).transpose()?;
+            match data {
+                Some(data) if holds(&data.data, field, value) => {
+                    if !is_expired(data.ttl)? {
+                        page.keys.push(encoding.encode(key)?);
+                    }
+                }
+                _ => stale.delete_cf(cf, &entry),
+            }
+        }
+
+        if !stale.is_empty() {
+            rock.write_opt(stale, &self.write_opts())?;
+        }
+        Ok(page)
+    }
+
+    // values under the db's min size aren't worth compressing
+    fn new_data(&self, ttl: u128, value: Vec<u8>) -> Data {
+        let compression = self
+            .compression
+            .filter(|_| value.len() >= self.compression_min_bytes);
+        Data::new(ttl, value).with_compression(compression)
+    }
+
+    fn put<V>(&self, key: &str, val: V) -> anyhow::Result<()>
+    where
+        V: AsRef<[u8]>,
+    {
+        Ok(self.w_lock().put_opt(key, val, &self.write_opts())?)
+    }
+
+    // the write lock is held from version check until the write, so concurrent stores are serialized
+    // an encoded value is checked and indexed decompressed, but stored as it was sent
+    fn store(
+        &self,
+        key: &[u8],
+        val: Vec<u8>,
+        ttl: u128,
+        soft_ttl: u128,
+        repr: Representation,
+        condition: WriteCondition,
+    ) -> anyhow::Result<Stored> {
+        let compression = repr.encoding.map(|codec| Compression::new(codec, 0));
+        let decoded = match compression {
+            Some(compression) => {
+                Some(compression.decompress(&val, MAX_VALUE_BYTES).map_err(|e| {
+                    anyhow!(ErrorCtx::Validation(format!(
+                        "Can't decode {:?} value - {}",
+                        compression.codec(),
+                        e
+                    )))
+                })?)
+            }
+            None => None,
+        };
+        let plain = decoded.as_deref().unwrap_or(&val);
+        self.check_value(plain)?;
+        let rock = self.w_lock();
+        let previous: Option<Data> = rock.get(key)?.map(|b| b.as_struct()).transpose()?;
+        let current = match &previous {
+            Some(data) if !is_expired(data.ttl)? => Some(data.version),
+            _ => None,
+        };
+        condition.check(key, current)?;
+
+        let mut batch = WriteBatch::default();
+        self.reindex(
+            &rock,
+            &mut batch,
+            key,
+            previous.as_ref().map(|d| d.data.as_slice()),
+            Some(plain),
+        );
+        let version = current.map_or(1, |v| v + 1);
+        let data = match compression {
+            Some(compression) => Data::new(ttl, val).with_encoding(compression),
+            None => self.new_data(ttl, val),
+        }
+        .with_version(version)
+        .with_soft_ttl(soft_ttl)
+        .with_content_type(repr.content_type);
+        batch.put(key, data.as_bytes()?);
+        let plain = decoded.as_deref().unwrap_or(data.data.as_slice());
+        let seq = self.commit(
+            &rock,
+            batch,
+            &[Logged::put(key, Some(version), Some(plain))],
+        )?;
+
+        Ok(Stored { version, seq })
+    }
+
+    // the patched value keeps expiration and content type of the record, a missing record is patched as null
+    fn patch(
+        &self,
+        key: &[u8],
+        format: PatchFormat,
+        patch: &[u8],
+        condition: WriteCondition,
+    ) -> anyhow::Result<Stored> {
+        if !self.json {
+            return Err(anyhow!(ErrorCtx::Validation(
+                "Patches are applied only in json dbs".into()
+            )));
+        }
+        let rock = self.w_lock();
+        let stored: Option<Data> = rock.get(key)?.map(|b| b.as_struct()).transpose()?;
+        let live = match &stored {
+            Some(data) => !is_expired(data.ttl)?,
+            None => false,
+        };
+        let previous = stored.as_ref().filter(|_| live);
+        condition.check(key, previous.map(|d| d.version))?;
+
+        let val = apply_patch(format, previous.map(|d| d.data.as_slice()), patch)?;
+        let mut batch = WriteBatch::default();
+        self.reindex(
+            &rock,
+            &mut batch,
+            key,
+            stored.as_ref().map(|d| d.data.as_slice()),
+            Some(&val),
+        );
+        let version = previous.map_or(1, |d| d.version + 1);
+        let data = match previous {
+            Some(previous) => self
+                .new_data(previous.ttl, val)
+                .with_soft_ttl(previous.soft_ttl)
+                .with_content_type(previous.content_type.clone()),
+            None => self.new_data(NO_TTL, val),
+        }
+        .with_version(version);
+        batch.put(key, data.as_bytes()?);
+        let seq = self.commit(
+            &rock,
+            batch,
+            &[Logged::put(key, Some(version), Some(data.data.as_slice()))],
+        )?;
+
+        Ok(Stored { version, seq })
+    }
+
+    // applied atomically, records without a value are deleted - returns versions of stored records
+    // and the sequence number of the batch
+    fn write_batch(
+        &self,
+        records: &[WireRecord],
+        ttl: u128,
+        jitter_pct: u8,
+    ) -> anyhow::Result<(Vec<Option<u64>>, u64)> {
+        let now = current_ms()?;
+        let rock = self.w_lock();
+        let stats_cf = rock.cf_handle(STATS_CF);
+        let mut batch = WriteBatch::default();
+        let mut versions = Vec::with_capacity(records.len());
+        let mut logged = Vec::with_capacity(records.len());
+        for record in records {
+            if let Some(value) = &record.value {
+                self.check_value(value)?;
+            }
+            let previous: Option<Data> =
+                rock.get(&record.key)?.map(|b| b.as_struct()).transpose()?;
+            self.reindex(
+                &rock,
+                &mut batch,
+                record.key.as_bytes(),
+                previous.as_ref().map(|d| d.data.as_slice()),
+                record.value.as_deref(),
+            );
+            match &record.value {
+                Some(value) => {
+                    let current = match previous {
+                        Some(data) if !is_expired(data.ttl)? => data.version,
+                        _ => 0,
+                    };
+                    let ttl = jittered(ttl, now, jitter_pct, &record.key);
+                    let data = self.new_data(ttl, value.clone()).with_version(current + 1);
+                    batch.put(&record.key, data.as_bytes()?);
+                    versions.push(Some(current + 1));
+                    logged.push(Logged::put(
+                        record.key.as_bytes(),
+                        Some(current + 1),
+                        Some(value.as_slice()),
+                    ));
+                }
+                None => {
+                    batch.delete(&record.key);
+                    if let Some(cf) = stats_cf {
+                        batch.delete_cf(cf, &record.key);
+                    }
+                    versions.push(None);
+                    logged.push(Logged::new(EventKind::Delete, record.key.as_bytes()));
+                }
+            }
+        }
+
+        let seq = self.commit(&rock, batch, &logged)?;
+        Ok((versions, seq))
+    }
+
+    // all conditions are checked before anything is written, a failed one aborts the whole transaction
+    fn txn(&self, writes: &[TxnWrite], jitter_pct: u8) -> anyhow::Result<(Vec<Option<u64>>, u64)> {
+        let now = current_ms()?;
+        let rock = self.w_lock();
+        let stats_cf = rock.cf_handle(STATS_CF);
+        let mut batch = WriteBatch::default();
+        let mut versions = Vec::with_capacity(writes.len());
+        let mut logged = Vec::with_capacity(writes.len());
+        // versions and values as left by earlier operations on the same key, the values index entries exist for
+        let mut pending: HashMap<&str, (Option<u64>, Option<Vec<u8>>)> = HashMap::new();
+        for write in writes {
+            let (current, previous) = match pending.remove(write.key.as_str()) {
+                Some(pending) => pending,
+                None => match rock.get(&write.key)? {
+                    Some(bytes) => {
+                        let data: Data = bytes.as_struct()?;
+                        if is_expired(data.ttl)? {
+                            (None, Some(data.data))
+                        } else {
+                            (Some(data.version), Some(data.data))
+                        }
+                    }
+                    None => (None, None),
+                },
+            };
+            if let Some(expected) = write.expected_version {
+                WriteCondition::IfVersion(expected).check(&write.key, current)?;
+            }
+
+            let key = write.key.as_bytes();
+            match &write.action {
+                TxnAction::Put(value) => {
+                    self.check_value(value)?;
+                    self.reindex(&rock, &mut batch, key, previous.as_deref(), Some(value));
+                    let version = current.unwrap_or(0) + 1;
+                    let ttl = jittered(write.ttl, now, jitter_pct, &write.key);
+                    let data = self.new_data(ttl, value.clone()).with_version(version);
+                    batch.put(&write.key, data.as_bytes()?);
+                    pending.insert(&write.key, (Some(version), Some(value.clone())));
+                    versions.push(Some(version));
+                    logged.push(Logged::put(key, Some(version), Some(value.as_slice())));
+                }
+                TxnAction::Delete => {
+                    self.reindex(&rock, &mut batch, key, previous.as_deref(), None);
+                    batch.delete(&write.key);
+                    if let Some(cf) = stats_cf {
+                        batch.delete_cf(cf, &write.key);
+                    }
+                    pending.insert(&write.key, (None, None));
+                    versions.push(None);
+                    logged.push(Logged::new(EventKind::Delete, key));
+                }
+                TxnAction::Check => {
+                    pending.insert(&write.key, (current, previous));
+                    versions.push(None);
+                }
+            }
+        }
+
+        let seq = self.commit(&rock, batch, &logged)?;
+        Ok((versions, seq))
+    }
+
+    // missing and expired keys are returned without a value
+    fn multi_get(&self, keys: Vec<String>) -> anyhow::Result<Vec<WireRecord>> {
+        let rock = self.r_lock();
+        keys.into_iter()
+            .map(|key| {
+                let value = match rock.get(&key)? {
+                    Some(bytes) => {
+                        let data = bytes.as_struct()?;
+                        if is_expired(data.ttl)? {
+                            None
+                        } else {
+                            Some(assembled(&rock, data)?.data)
+                        }
+                    }
+                    None => None,
+                };
+                Ok(WireRecord::new(key, value))
+            })
+            .collect()
+    }
+
+    // live records of the keys in the same order, None for missing and expired ones
+    fn read_many(&self, keys: &[Vec<u8>]) -> anyhow::Result<Vec<Option<Data>>> {
+        let rock = self.r_lock();
+        keys.iter()
+            .map(|key| match rock.get(key)? {
+                Some(bytes) => {
+                    let data = bytes.as_struct()?;
+                    if is_expired(data.ttl)? {
+                        Ok(None)
+                    } else {
+                        Ok(Some(assembled(&rock, data)?))
+                    }
+                }
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    fn append(&self, key: &[u8], val: Vec<u8>) -> anyhow::Result<u64> {
+        if self.json {
+            return Err(anyhow!(ErrorCtx::Validation(
+                "Appends would break JSON values, use a JSON Patch".into()
+            )));
+        }
+        let operand = Data::new(NO_TTL, val);
+        let rock = self.w_lock();
+        let logged = rock.cf_handle(CHANGELOG_CF).is_some();
+        let current: Option<Data> = if logged || rock.cf_handle(UPLOADS_CF).is_some() {
+            rock.get(key)?.map(|b| b.as_struct()).transpose()?
+        } else {
+            None
+        };
+        if let Some(data) = &current {
+            if data.is_chunked() && !is_expired(data.ttl)? {
+                return Err(anyhow!(ErrorCtx::Conflict(format!(
+                    "Key {} holds an uploaded value, it can't be appended to",
+                    display_key(key)
+                ))));
+            }
+        }
+
+        let mut batch = WriteBatch::default();
+        batch.merge(key, operand.as_bytes()?);
+        // the value as the merge leaves it, built only for the change log
+        let merged = match current {
+            Some(current) if logged => Some(current.append(operand)),
+            None if logged => Some(operand),
+            _ => None,
+        };
+        let value = merged.as_ref().map(|data| data.data.as_slice());
+        self.commit(&rock, batch, &[Logged::put(key, None, value)])
+    }
+
+    // only the expiration is replaced, value and version are kept as they are
+    fn touch(&self, key: &[u8], ttl: u128) -> anyhow::Result<Option<u64>> {
+        let rock = self.w_lock();
+        match rock.get(key)? {
+            Some(bytes) => {
+                let mut data = bytes.as_struct()?;
+                if is_expired(data.ttl)? {
+                    return Ok(None);
+                }
+
+                data.ttl = ttl;
+                data.written_at = current_ms()?;
+                let mut batch = WriteBatch::default();
+                batch.put(key, data.as_bytes()?);
+                let seq =
+                    self.commit(&rock, batch, &[Logged::put(key, Some(data.version), None)])?;
+                Ok(Some(seq))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // moves the stored record as is (value, ttl, version) along with its access stats and index entries
+    fn rename(&self, from: &[u8], to: &[u8]) -> anyhow::Result<Option<Stored>> {
+        let rock = self.w_lock();
+        let (bytes, data) = match rock.get(from)? {
+            Some(bytes) => {
+                let data: Data = bytes.as_struct()?;
+                if is_expired(data.ttl)? {
+                    return Ok(None);
+                }
+                (bytes, data)
+            }
+            None => return Ok(None),
+        };
+        let existing: Option<Data> = rock.get(to)?.map(|b| b.as_struct()).transpose()?;
+        if let Some(existing) = &existing {
+            if !is_expired(existing.ttl)? {
+                return Err(anyhow!(ErrorCtx::PreconditionFailed(format!(
+                    "Key {} already exists",
+                    display_key(to)
+                ))));
+            }
+        }
+        // json dbs have no uploads
+        if !data.is_chunked() {
+            self.check_value(&data.data)?;
+        }
+
+        let mut batch = WriteBatch::default();
+        self.reindex(&rock, &mut batch, from, Some(&data.data), None);
+        self.reindex(
+            &rock,
+            &mut batch,
+            to,
+            existing.as_ref().map(|d| d.data.as_slice()),
+            Some(&data.data),
+        );
+        batch.put(to, bytes);
+        batch.delete(from);
+        // the chunks now belong to the record under its new key
+        if let Some(chunks) = &data.chunks {
+            let cf = uploads_cf(&rock)?;
+            if let Some(meta) = rock.get_cf(cf, meta_key(&chunks.upload_id))? {
+                let mut upload: Upload = bincode::deserialize(&meta)?;
+                upload.key = to.to_vec();
+                batch.put_cf(
+                    cf,
+                    meta_key(&chunks.upload_id),
+                    bincode::serialize(&upload)?,
+                );
+            }
+        }
+        if let Some(cf) = rock.cf_handle(STATS_CF) {
+            match rock.get_cf(cf, from)? {
+                Some(stats) => batch.put_cf(cf, to, stats),
+                None => batch.delete_cf(cf, to),
+            }
+            batch.delete_cf(cf, from);
+        }
+        let value = Some(data.data.as_slice()).filter(|_| !data.is_chunked());
+        let logged = [
+            Logged::new(EventKind::Delete, from),
+            Logged::put(to, Some(data.version), value),
+        ];
+        let seq = self.commit(&rock, batch, &logged)?;
+        Ok(Some(Stored {
+            version: data.version,
+            seq,
+        }))
+    }
+
+    fn get(&self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.r_lock().get(key)?)
+    }
+
+    fn assemble(&self, data: Data) -> anyhow::Result<Data> {
+        assembled(&self.r_lock(), data)
+    }
+
+    fn keys(
+        &self,
+        cursor: Option<ScanCursor>,
+        limit: usize,
+        limits: &ScanLimits,
+        encoding: KeyEncoding,
+        listing: &Listing,
+        order: Order,
+    ) -> anyhow::Result<KeyPage> {
+        let id = cursor.as_ref().and_then(|c| c.snapshot);
+        let pinned = self.listing_snapshot(id)?;
+        let rock = self.r_lock();
+        let iter = match &pinned {
+            Some(pinned) => pinned.raw_iterator(),
+            None => rock.raw_iterator(),
+        };
+        let mut page = Db::list_keys(
+            iter,
+            cursor.map(|c| c.key),
+            limit,
+            limits,
+            encoding,
+            listing,
+            order,
+        )?;
+        page.cursor = self.next_cursor(page.cursor, pinned.as_ref(), id);
+        Ok(page)
+    }
+
+    // the snapshot a listing page is read from, None reads live data
+    fn listing_snapshot(&self, id: Option<u64>) -> anyhow::Result<Option<Arc<Pinned>>> {
+        if id.is_some() || self.pin_listings {
+            self.pins.snapshot(&self.rock, id).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    // the snapshot of a listing with more pages stays pinned for them
+    fn next_cursor(
+        &self,
+        cursor: Option<String>,
+        pinned: Option<&Arc<Pinned>>,
+        id: Option<u64>,
+    ) -> Option<String> {
+        match (cursor, pinned) {
+            (Some(cursor), Some(pinned)) => Some(pinned_cursor(self.pins.keep(pinned, id), cursor)),
+            (cursor, _) => cursor,
+        }
+    }
+
+    // a namespace counts as one entry of the page, it's listed even if all its records are expired
+    fn list_keys(
+        mut iter: DBRawIterator,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+        limits: &ScanLimits,
+        encoding: KeyEncoding,
+        listing: &Listing,
+        order: Order,
+    ) -> anyhow::Result<KeyPage> {
+        let end = prefix_end(listing.prefix());
+        match (order, cursor) {
+            (Order::Asc, Some(from)) if from.as_slice() > listing.prefix() => iter.seek(from),
+            (Order::Asc, _) => iter.seek(listing.prefix()),
+            (Order::Desc, Some(from)) if end.as_ref().map_or(true, |end| &from < end) => {
+                iter.seek_for_prev(from)
+            }
+            (Order::Desc, _) => match &end {
+                Some(end) => seek_before(&mut iter, end),
+                None => iter.seek_to_last(),
+            },
+        }
+
+        let started = Instant::now();
+        let mut bytes = 0;
+        let mut page = KeyPage {
+            keys: Vec::with_capacity(limit),
+            namespaces: vec![],
+            cursor: None,
+        };
+        while let (Some(k), Some(v)) = (iter.key(), iter.value()) {
+            limits.check_cancelled()?;
+            if !k.starts_with(listing.prefix()) {
+                break;
+            }
+            let listed = page.keys.len() + page.namespaces.len();
+            if listed == limit || (listed > 0 && limits.exceeded(bytes, started)) {
+                page.cursor = Some(encode_cursor(k));
+                return Ok(page);
+            }
+
+            if let Some(namespace) = listing.namespace(k) {
+                let namespace = namespace.to_vec();
+                bytes += namespace.len();
+                page.namespaces.push(encoding.encode(&namespace)?);
+                // skips the rest of the namespace
+                match (order, prefix_end(&namespace)) {
+                    (Order::Asc, Some(end)) => iter.seek(end),
+                    (Order::Asc, None) => break,
+                    (Order::Desc, _) => seek_before(&mut iter, &namespace),
+                }
+                continue;
+            }
+
+            let data: Data = v.to_vec().as_struct()?;
+            if !is_expired(data.ttl)? {
+                bytes += k.len();
+                page.keys.push(encoding.encode(k)?);
+            }
+            match order {
+                Order::Asc => iter.next(),
+                Order::Desc => iter.prev(),
+            }
+        }
+
+        iter.status()?;
+        Ok(page)
+    }
+
+    // expired records that aren't swept yet are skipped
+    fn count(
+        &self,
+        cursor: Option<Vec<u8>>,
+        max_time: Duration,
+        cancel: &Cancel,
+    ) -> anyhow::Result<KeyCount> {
+        let rock = self.r_lock();
+        let mode = match &cursor {
+            Some(from) => IteratorMode::From(from.as_slice(), Direction::Forward),
+            None => IteratorMode::Start,
+        };
+
+        let started = Instant::now();
+        let mut count = 0;
+        for (k, v) in rock.iterator(mode) {
+            cancel.check()?;
+            if count > 0 && started.elapsed() >= max_time {
+                return Ok(KeyCount {
+                    count,
+                    approximate: false,
+                    cursor: Some(encode_cursor(&k)),
+                });
+            }
+            let data: Data = v.to_vec().as_struct()?;
+            if !is_expired(data.ttl)? {
+                count += 1;
+            }
+        }
+
+        Ok(KeyCount {
+            count,
+            approximate: false,
+            cursor: None,
+        })
+    }
+
+    // includes expired and overwritten records not compacted yet
+    fn estimate_count(&self) -> anyhow::Result<KeyCount> {
+        Ok(KeyCount {
+            count: parse_int(&self.property(ESTIMATE_NUM_KEYS)?),
+            approximate: true,
+            cursor: None,
+        })
+    }
+
+    fn scan(
+        &self,
+        cursor: Option<ScanCursor>,
+        limit: usize,
+        limits: &ScanLimits,
+        encoding: KeyEncoding,
+        filter: &ScanFilter,
+        order: Order,
+    ) -> anyhow::Result<ScanPage> {
+        let id = cursor.as_ref().and_then(|c| c.snapshot);
+        let pinned = self.listing_snapshot(id)?;
+        let from = cursor.map(|c| c.key);
+        let (records, cursor) =
+            self.page(pinned.as_deref(), from, limit, limits, order, |k, data| {
+                if !filter.matches(&data.data) {
+                    return Ok(None);
+                }
+                let fields = filter.fields();
+                let record = Record {
+                    key: if fields.key() {
+                        Some(encoding.encode(k)?)
+                    } else {
+                        None
+                    },
+                    value: if fields.value() {
+                        Some(base64::encode(&data.data))
+                    } else {
+                        None
+                    },
+                };
+                Ok(Some(record))
+            })?;
+        Ok(ScanPage {
+            records,
+            cursor: self.next_cursor(cursor, pinned.as_ref(), id),
+        })
+    }
+
+    // cursor is the first key of the next page, a page is cut short once it exceeds the scan limits
+    // but always moves past at least one record so the client keeps making progress, entries skipped by
+    // the entry fn count towards the limits
+    fn page<T, F>(
+        &self,
+        pinned: Option<&Pinned>,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+        limits: &ScanLimits,
+        order: Order,
+        entry: F,
+    ) -> anyhow::Result<(Vec<T>, Option<String>)>
+    where
+        F: Fn(&[u8], Data) -> anyhow::Result<Option<T>>,
+    {
+        let rock = self.r_lock();
+        let mode = match (&cursor, order) {
+            (Some(from), Order::Asc) => IteratorMode::From(from.as_slice(), Direction::Forward),
+            (Some(from), Order::Desc) => IteratorMode::From(from.as_slice(), Direction::Reverse),
+            (None, Order::Asc) => IteratorMode::Start,
+            (None, Order::Desc) => IteratorMode::End,
+        };
+
+        let iter = match pinned {
+            Some(pinned) => pinned.iterator(mode),
+            None => rock.iterator(mode),
+        };
+
+        let started = Instant::now();
+        let mut bytes = 0;
+        let mut entries = Vec::with_capacity(limit);
+        for (k, v) in iter {
+            limits.check_cancelled()?;
+            let data = v.to_vec().as_struct()?;
+            if is_expired(data.ttl)? {
+                continue;
+            }
+            if entries.len() == limit || (bytes > 0 && limits.exceeded(bytes, started)) {
+                return Ok((entries, Some(encode_cursor(&k))));
+            }
+            let data = assembled(&rock, data)?;
+            bytes += k.len() + data.data.len();
+            if let Some(e) = entry(&k, data)? {
+                entries.push(e);
+            }
+        }
+
+        Ok((entries, None))
+    }
+
+    // raw keys and values under the prefix, the cursor is the first key of the next page
+    fn entries(
+        &self,
+        prefix: &[u8],
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+        limits: &ScanLimits,
+    ) -> anyhow::Result<(Vec<(Vec<u8>, Vec<u8>)>, Option<Vec<u8>>)> {
+        let rock = self.r_lock();
+        let from = cursor.unwrap_or_else(|| prefix.to_vec());
+        let started = Instant::now();
+        let mut bytes = 0;
+        let mut entries = Vec::with_capacity(limit);
+        for (k, v) in rock.iterator(IteratorMode::From(&from, Direction::Forward)) {
+            limits.check_cancelled()?;
+            if !k.starts_with(prefix) {
+                break;
+            }
+            let data = v.to_vec().as_struct()?;
+            if is_expired(data.ttl)? {
+                continue;
+            }
+            if entries.len() == limit || (bytes > 0 && limits.exceeded(bytes, started)) {
+                return Ok((entries, Some(k.to_vec())));
+            }
+            let data = assembled(&rock, data)?;
+            bytes += k.len() + data.data.len();
+            entries.push((k.to_vec(), data.data));
+        }
+
+        Ok((entries, None))
+    }
+
+    // logged as the given kind of removal - a delete, expiration or eviction
+    fn remove(&self, key: &[u8], kind: EventKind) -> anyhow::Result<u64> {
+        let rock = self.w_lock();
+        let mut batch = WriteBatch::default();
+        let previous = self.indexed_value(&rock, key)?;
+        self.reindex(&rock, &mut batch, key, previous.as_deref(), None);
+        batch.delete(key);
+        if let Some(cf) = rock.cf_handle(STATS_CF) {
+            batch.delete_cf(cf, key);
+        }
+
+        self.commit(&rock, batch, &[Logged::new(kind, key)])
+    }
+
+    // deletes the record but keeps it in the tombstones column family until purge_at so it can be undeleted
+    fn bury(&self, key: &[u8], purge_at: u128) -> anyhow::Result<u64> {
+        let rock = self.w_lock();
+        let mut batch = WriteBatch::default();
+        self.delete_into(&rock, &mut batch, key, Some(purge_at))?;
+        self.commit(&rock, batch, &[Logged::new(EventKind::Delete, key)])
+    }
+
+    // all keys are deleted by a single write, returns which of them held a live record
+    fn remove_many(
+        &self,
+        keys: &[Vec<u8>],
+        purge_at: Option<u128>,
+    ) -> anyhow::Result<(Vec<bool>, u64)> {
+        let rock = self.w_lock();
+        let mut batch = WriteBatch::default();
+        let mut deleted = HashMap::with_capacity(keys.len());
+        let mut logged = Vec::with_capacity(keys.len());
+        for key in keys {
+            if !deleted.contains_key(key) {
+                let live = self.delete_into(&rock, &mut batch, key, purge_at)?;
+                deleted.insert(key, live);
+                logged.push(Logged::new(EventKind::Delete, key));
+            }
+        }
+
+        let seq = self.commit(&rock, batch, &logged)?;
+        let found = keys.iter().map(|key| deleted[key]).collect();
+        Ok((found, seq))
+    }
+
+    // a live record is kept in the tombstones column family until purge_at when given, true if there was one
+    fn delete_into(
+        &self,
+        rock: &DB,
+        batch: &mut WriteBatch,
+        key: &[u8],
+        purge_at: Option<u128>,
+    ) -> anyhow::Result<bool> {
+        let previous = self.indexed_value(rock, key)?;
+        self.reindex(rock, batch, key, previous.as_deref(), None);
+        let live = match rock.get(key)? {
+            Some(record) => {
+                let live = !is_expired(record.as_struct()?.ttl)?;
+                if let (true, Some(purge_at), Some(cf)) =
+                    (live, purge_at, rock.cf_handle(TOMBSTONE_CF))
+                {
+                    let tombstone = Tombstone { purge_at, record };
+                    batch.put_cf(cf, key, bincode::serialize(&tombstone)?);
+                }
+                live
+            }
+            None => false,
+        };
+        batch.delete(key);
+        if let Some(cf) = rock.cf_handle(STATS_CF) {
+            batch.delete_cf(cf, key);
+        }
+        Ok(live)
+    }
+
+    // None without a tombstone, an existing record under the key is a failed precondition
+    fn undelete(&self, key: &[u8]) -> anyhow::Result<Option<Stored>> {
+        let rock = self.w_lock();
+        let cf = match rock.cf_handle(TOMBSTONE_CF) {
+            Some(cf) => cf,
+            None => return Ok(None),
+        };
+        let tombstone: Tombstone = match rock.get_cf(cf, key)? {
+            Some(bytes) => bincode::deserialize(&bytes)?,
+            None => return Ok(None),
+        };
+        let data: Data = tombstone.record.as_struct()?;
+        if tombstone.purge_at <= current_ms()? || is_expired(data.ttl)? {
+            return Ok(None);
+        }
+        if let Some(existing) = rock.get(key)? {
+            if !is_expired(existing.as_struct()?.ttl)? {
+                return Err(anyhow!(ErrorCtx::PreconditionFailed(format!(
+                    "Key {} has been written since it was deleted",
+                    display_key(key)
+                ))));
+            }
+        }
+
+        let mut batch = WriteBatch::default();
+        let previous = self.indexed_value(&rock, key)?;
+        self.reindex(
+            &rock,
+            &mut batch,
+            key,
+            previous.as_deref(),
+            Some(&data.data),
+        );
+        batch.put(key, tombstone.record);
+        batch.delete_cf(cf, key);
+        let value = Some(data.data.as_slice()).filter(|_| !data.is_chunked());
+        let seq = self.commit(&rock, batch, &[Logged::put(key, Some(data.version), value)])?;
+        Ok(Some(Stored {
+            version: data.version,
+            seq,
+        }))
+    }
+
+    fn purge_tombstones(&self) -> anyhow::Result<usize> {
+        let now = current_ms()?;
+        let rock = self.w_lock();
+        let cf = match rock.cf_handle(TOMBSTONE_CF) {
+            Some(cf) => cf,
+            None => return Ok(0),
+        };
+
+        let mut batch = WriteBatch::default();
+        for (k, v) in rock.iterator_cf(cf, IteratorMode::Start) {
+            let tombstone: Tombstone = bincode::deserialize(&v)?;
+            if tombstone.purge_at <= now {
+                batch.delete_cf(cf, k);
+            }
+        }
+        let purged = batch.len();
+        if purged > 0 {
+            rock.write_opt(batch, &self.write_opts())?;
+        }
+        Ok(purged)
+    }
+
+    fn begin_upload(&self, upload_id: &str, upload: &Upload) -> anyhow::Result<()> {
+        let rock = self.w_lock();
+        let cf = uploads_cf(&rock)?;
+        rock.put_cf_opt(
+            cf,
+            meta_key(upload_id),
+            bincode::serialize(upload)?,
+            &self.write_opts(),
+        )?;
+        Ok(())
+    }
+
+    fn upload(&self, upload_id: &str, key: &[u8]) -> anyhow::Result<(Upload, Vec<PartInfo>)> {
+        let rock = self.r_lock();
+        let cf = uploads_cf(&rock)?;
+        let upload = started_upload(&rock, cf, upload_id, key)?;
+        let parts = upload_parts(&rock, cf, upload_id)
+            .map(|(number, value)| PartInfo {
+                number,
+                size: value.len(),
+            })
+            .collect();
+        Ok((upload, parts))
+    }
+
+    // a retried part replaces the previous one
+    fn upload_part(
+        &self,
+        upload_id: &str,
+        key: &[u8],
+        number: u32,
+        value: &[u8],
+    ) -> anyhow::Result<()> {
+        let rock = self.w_lock();
+        let cf = uploads_cf(&rock)?;
+        started_upload(&rock, cf, upload_id, key)?;
+        rock.put_cf_opt(cf, part_key(upload_id, number), value, &self.write_opts())?;
+        Ok(())
+    }
+
+    // the parts become the chunks of the record, written in one batch with the completed upload
+    fn complete_upload(
+        &self,
+        upload_id: &str,
+        key: &[u8],
+        ttl: u128,
+        soft_ttl: u128,
+        condition: WriteCondition,
+    ) -> anyhow::Result<Stored> {
+        let rock = self.w_lock();
+        let cf = uploads_cf(&rock)?;
+        let mut upload = started_upload(&rock, cf, upload_id, key)?;
+        let parts: Vec<PartInfo> = upload_parts(&rock, cf, upload_id)
+            .map(|(number, value)| PartInfo {
+                number,
+                size: value.len(),
+            })
+            .collect();
+        let size = check_parts(&parts)?;
+
+        let current = match rock.get(key)? {
+            Some(bytes) => {
+                let data: Data = bytes.as_struct()?;
+                if is_expired(data.ttl)? {
+                    None
+                } else {
+                    Some(data.version)
+                }
+            }
+            None => None,
+        };
+        condition.check(key, current)?;
+
+        let version = current.map_or(1, |v| v + 1);
+        let chunks = Chunks {
+            upload_id: upload_id.into(),
+            parts: parts.len() as u32,
+            size,
+        };
+        let bytes = Data::new(ttl, vec![])
+            .with_version(version)
+            .with_soft_ttl(soft_ttl)
+            .with_content_type(upload.content_type.clone())
+            .with_chunks(chunks)
+            .as_bytes()?;
+        upload.completed = true;
+        let mut batch = WriteBatch::default();
+        batch.put(key, bytes);
+        batch.put_cf(cf, meta_key(upload_id), bincode::serialize(&upload)?);
+        let seq = self.commit(&rock, batch, &[Logged::put(key, Some(version), None)])?;
+
+        Ok(Stored { version, seq })
+    }
+
+    fn abort_upload(&self, upload_id: &str, key: &[u8]) -> anyhow::Result<()> {
+        let rock = self.w_lock();
+        let cf = uploads_cf(&rock)?;
+        started_upload(&rock, cf, upload_id, key)?;
+        let (start, end) = upload_range(upload_id);
+        let mut batch = WriteBatch::default();
+        batch.delete_range_cf(cf, start, end);
+        rock.write_opt(batch, &self.write_opts())?;
+        Ok(())
+    }
+
+    // uploads started before now - expiry and chunks no record or tombstone refers to anymore are dropped with all
+    // their parts, returns how many were purged - parts are skipped over without being read
+    fn purge_uploads(&self, expiry: u128) -> anyhow::Result<usize> {
+        let now = current_ms()?;
+        let rock = self.w_lock();
+        let cf = match rock.cf_handle(UPLOADS_CF) {
+            Some(cf) => cf,
+            None => return Ok(0),
+        };
+
+        let mut batch = WriteBatch::default();
+        let mut iter = rock.raw_iterator_cf(cf);
+        iter.seek_to_first();
+        while let Some(k) = iter.key() {
+            let upload_id = match k.iter().position(|b| *b == b'/') {
+                Some(pos) => String::from_utf8_lossy(&k[..pos]).into_owned(),
+                None => String::from_utf8_lossy(k).into_owned(),
+            };
+            let purge = match iter.value() {
+                // parts left without their upload
+                Some(_) if part_number(k).is_some() => true,
+                Some(v) => match bincode::deserialize::<Upload>(v) {
+                    Ok(upload) if upload.completed => {
+                        match holds_chunks(&rock, &upload.key, &upload_id) {
+                            Ok(held) => !held,
+                            Err(e) => {
+                                error!("Can't check chunks of upload {} - {}", upload_id, e);
+                                false
+                            }
+                        }
+                    }
+                    Ok(upload) => upload.created_at + expiry <= now,
+                    Err(e) => {
+                        error!("Skipping upload {} - {}", upload_id, e);
+                        false
+                    }
+                },
+                None => false,
+            };
+            let (start, end) = upload_range(&upload_id);
+            if purge {
+                batch.delete_range_cf(cf, &start, &end);
+            }
+            iter.seek(&end);
+        }
+        iter.status()?;
+
+        let purged = batch.len();
+        if purged > 0 {
+            rock.write_opt(batch, &self.write_opts())?;
+        }
+        Ok(purged)
+    }
+
+    // end key is exclusive
+    fn remove_range(&self, start: &[u8], end: &[u8]) -> anyhow::Result<u64> {
+        let rock = self.w_lock();
+        let mut batch = WriteBatch::default();
+        batch.delete_range(start, end);
+        // range deleted records can't be undeleted, index entries are keyed by value and dropped by queries
+        for cf_name in &[STATS_CF, TOMBSTONE_CF] {
+            if let Some(cf) = rock.cf_handle(cf_name) {
+                batch.delete_range_cf(cf, start, end);
+            }
+        }
+
+        self.commit(&rock, batch, &[Logged::range(start, end)])
+    }
+
+    // the record as it is now, a delete when it's gone - None for events that aren't replicated
+    fn change(&self, event: &Event) -> anyhow::Result<Option<Change>> {
+        match (event.kind, &event.end) {
+            (EventKind::DeleteRange, Some(end)) => {
+                Ok(Some(Change::delete_range(&event.key, end, current_ms()?)))
+            }
+            (EventKind::Put, _) | (EventKind::Delete, _) => match self.get(&event.key)? {
+                Some(bytes) => {
+                    let data = self.assemble(bytes.as_struct()?)?;
+                    Ok(Some(
+                        Change::put(
+                            &event.key,
+                            &data.data,
+                            data.ttl,
+                            data.soft_ttl,
+                            data.written_at,
+                        )
+                        .with_content_type(data.content_type()),
+                    ))
+                }
+                None => Ok(Some(Change::delete(&event.key, current_ms()?))),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    fn changes(
+        &self,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+        limits: &ScanLimits,
+    ) -> anyhow::Result<(Vec<Change>, Option<String>)> {
+        self.page(None, cursor, limit, limits, Order::Asc, |k, data| {
+            let change = Change::put(k, &data.data, data.ttl, data.soft_ttl, data.written_at)
+                .with_content_type(data.content_type());
+            Ok(Some(change))
+        })
+    }
+
+    // applies a change shipped from another deployment, None when the local record wins
+    fn apply(&self, change: &Replicated, policy: ConflictPolicy) -> anyhow::Result<Option<Stored>> {
+        if let Some(value) = &change.value {
+            self.check_value(value)?;
+        }
+        let rock = self.w_lock();
+        let stored: Option<Data> = rock.get(&change.key)?.map(|b| b.as_struct()).transpose()?;
+        let local = match &stored {
+            Some(data) if !is_expired(data.ttl)? => Some(data),
+            _ => None,
+        };
+        if !change.wins(policy, local.map(|d| (d.written_at, d.data.as_slice()))) {
+            return Ok(None);
+        }
+
+        let mut batch = WriteBatch::default();
+        self.reindex(
+            &rock,
+            &mut batch,
+            &change.key,
+            stored.as_ref().map(|d| d.data.as_slice()),
+            change.value.as_deref(),
+        );
+        let version = match &change.value {
+            Some(value) => {
+                let version = local.map_or(1, |d| d.version + 1);
+                let data = self
+                    .new_data(change.ttl, value.clone())
+                    .with_version(version)
+                    .with_soft_ttl(change.soft_ttl)
+                    .with_written_at(change.written_at)
+                    .with_content_type(change.content_type.clone());
+                batch.put(&change.key, data.as_bytes()?);
+                version
+            }
+            None => {
+                batch.delete(&change.key);
+                if let Some(cf) = rock.cf_handle(STATS_CF) {
+                    batch.delete_cf(cf, &change.key);
+                }
+                0
+            }
+        };
+
+        let logged = match &change.value {
+            Some(value) => Logged::put(&change.key, Some(version), Some(value.as_slice())),
+            None => Logged::new(EventKind::Delete, &change.key),
+        };
+        let seq = self.commit(&rock, batch, &[logged])?;
+        Ok(Some(Stored { version, seq }))
+    }
+
+    // writes the batch along with log entries of the changes it makes, so an entry exists exactly when its change
+    // does - called under the write lock, entries get ids in the order of their writes and the sequence number the
+    // batch ends at, each of its operations takes one
+    fn commit(&self, rock: &DB, mut batch: WriteBatch, changes: &[Logged]) -> anyhow::Result<u64> {
+        let cf = match rock.cf_handle(CHANGELOG_CF) {
+            Some(cf) if !changes.is_empty() => cf,
+            _ => {
+                rock.write_opt(batch, &self.write_opts())?;
+                return Ok(rock.latest_sequence_number());
+            }
+        };
+
+        let mut next = self
+            .next_change
+            .lock()
+            .expect("Can't acquire change log lock");
+        let seq = rock.latest_sequence_number() + (batch.len() + changes.len()) as u64;
+        let at = current_ms()?;
+        for (id, change) in (*next..).zip(changes) {
+            let entry = LogEntry::new(id, at, Some(seq), change);
+            batch.put_cf(cf, entry_key(id), serde_json::to_vec(&entry)?);
+        }
+        rock.write_opt(batch, &self.write_opts())?;
+        *next += changes.len() as u64;
+        Ok(rock.latest_sequence_number())
+    }
+
+    fn change_log(&self, since: u64, limit: usize) -> anyhow::Result<ChangesPage> {
+        let rock = self.r_lock();
+        let cf = rock.cf_handle(CHANGELOG_CF).ok_or_else(|| {
+            anyhow!(ErrorCtx::Validation(
+                "Change log is not enabled for this db".into()
+            ))
+        })?;
+        let first = rock
+            .iterator_cf(cf, IteratorMode::Start)
+            .next()
+            .map(|(k, _)| entry_id(&k));
+
+        let from = entry_key(since.saturating_add(1));
+        let changes = rock
+            .iterator_cf(cf, IteratorMode::From(&from, Direction::Forward))
+            .take(limit)
+            .map(|(_, v)| Ok(serde_json::from_slice::<LogEntry>(&v)?))
+            .collect::<anyhow::Result<Vec<LogEntry>>>()?;
+        let next = changes.last().map_or(since, |e| e.id);
+        Ok(ChangesPage {
+            changes,
+            next,
+            first,
+        })
+    }
+
+    // entries are logged in time order, purging stops at the first one within the retention
+    fn purge_change_log(&self, retention: u128) -> anyhow::Result<usize> {
+        let cutoff = current_ms()?.saturating_sub(retention);
+        let rock = self.w_lock();
+        let cf = match rock.cf_handle(CHANGELOG_CF) {
+            Some(cf) => cf,
+            None => return Ok(0),
+        };
+
+        let mut purged = 0;
+        let mut end = None;
+        for (k, v) in rock.iterator_cf(cf, IteratorMode::Start) {
+            let entry: LogEntry = serde_json::from_slice(&v)?;
+            if entry.at >= cutoff {
+                break;
+            }
+            purged += 1;
+            end = Some(entry_id(&k) + 1);
+        }
+        if let Some(end) = end {
+            let mut batch = WriteBatch::default();
+            batch.delete_range_cf(cf, entry_key(0), entry_key(end));
+            rock.write_opt(batch, &self.write_opts())?;
+        }
+        Ok(purged)
+    }
+
+    // matching entries of every column family are collected from a snapshot and deleted by a single batch, so
+    // writes are blocked only while it's written - deletes are only markers in sst files, the erased values are
+    // dropped for good by compaction
+    fn erase(&self, db_name: &str, matcher: &RegexSet) -> anyhow::Result<DbErasure> {
+        let mut erased = DbErasure {
+            db: db_name.into(),
+            ..DbErasure::default()
+        };
+        let rock = self.rock.db();
+        let snapshot = rock.snapshot();
+        let mut batch = WriteBatch::default();
+        for cf_name in self.cf_names.iter() {
+            let cf = match rock.cf_handle(cf_name) {
+                Some(cf) => cf,
+                None => continue,
+            };
+            for (k, v) in snapshot.iterator_cf(cf, IteratorMode::Start) {
+                if !entry_matches(cf_name, matcher, &k, &v)? {
+                    continue;
+                }
+                match cf_name.as_str() {
+                    DEFAULT_CF => erased.records += 1,
+                    TOMBSTONE_CF => erased.tombstones += 1,
+                    CHANGELOG_CF => erased.change_log_entries += 1,
+                    _ => {}
+                }
+                if cf_name == UPLOADS_CF {
+                    let (start, end) = upload_range(&String::from_utf8_lossy(&k));
+                    batch.delete_range_cf(cf, start, end);
+                } else {
+                    batch.delete_cf(cf, &k);
+                }
+            }
+        }
+        drop(snapshot);
+        self.w_lock().write_opt(batch, &self.write_opts())?;
+
+        self.compact(None)?;
+
+        erased.verified = !self.holds_any(matcher)?;
+        Ok(erased)
+    }
+
+    // compacted under the read lock so writes aren't blocked for the whole compaction, a cancel stops it before
+    // the next column family
+    fn compact(&self, cancel: Option<&Cancel>) -> anyhow::Result<()> {
+        let rock = self.r_lock();
+        for cf_name in self.cf_names.iter() {
+            if let Some(cancel) = cancel {
+                cancel.check()?;
+            }
+            if let Some(cf) = rock.cf_handle(cf_name) {
+                rock.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
+            }
+        }
+        Ok(())
+    }
+
+    // scanned without the lock, like a snapshot
+    fn holds_any(&self, matcher: &RegexSet) -> anyhow::Result<bool> {
+        let rock = self.rock.db();
+        for cf_name in self.cf_names.iter() {
+            if let Some(cf) = rock.cf_handle(cf_name) {
+                for (k, v) in rock.iterator_cf(cf, IteratorMode::Start) {
+                    if entry_matches(cf_name, matcher, &k, &v)? {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    // access stats merges advance the sequence too, so it isn't contiguous across record writes
+    fn seq(&self) -> u64 {
+        self.r_lock().latest_sequence_number()
+    }
+
+    // stats are merged under the read lock - they are not part of the record and must not block reads
+    fn record(&self, key: &[u8], access: Access) {
+        let op = match access {
+            Access::Read => "read",
+            Access::Write => "write",
+        };
+        KEY_OPERATIONS.with_label_values(&[op]).inc();
+        if let Some(stats) = self.sampler.as_ref().and_then(|s| s.sample(access)) {
+            let rock = self.r_lock();
+            if let (Some(cf), Ok(bytes)) = (rock.cf_handle(STATS_CF), bincode::serialize(&stats)) {
+                if let Err(e) = rock.merge_cf_opt(cf, key, bytes, &self.write_opts()) {
+                    error!(
+                        "Failed to record access stats for key = {}, e = {}",
+                        display_key(key),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    fn access_stats(&self, key: &[u8]) -> anyhow::Result<Option<AccessStats>> {
+        let rock = self.r_lock();
+        match rock.cf_handle(STATS_CF) {
+            Some(cf) => match rock.get_cf(cf, key)? {
+                Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+                None => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn all_stats(&self) -> anyhow::Result<HashMap<Vec<u8>, AccessStats>> {
+        let rock = self.r_lock();
+        let mut stats = HashMap::new();
+        if let Some(cf) = rock.cf_handle(STATS_CF) {
+            for (k, v) in rock.iterator_cf(cf, IteratorMode::Start) {
+                stats.insert(k.to_vec(), bincode::deserialize(&v)?);
+            }
+        }
+        Ok(stats)
+    }
+
+    // expiration is checked again under the write lock in case the key was rewritten meanwhile
+    // records within the stale window are kept so they can still be served as stale
+    fn remove_if_expired(&self, key: &[u8], stale_window: u128) -> anyhow::Result<bool> {
+        let expired = match self.w_lock().get(key)? {
+            Some(bytes) => is_expired_after(ttl_of(&bytes)?, stale_window)?,
+            None => false,
+        };
+        if expired {
+            self.remove(key, EventKind::Expire)?;
+        }
+        Ok(expired)
+    }
+
+    // removes expired records and evicts the least used ones while the db is over its size cap
+    // expired keys are returned with their expiry time, records that can't be read are logged and skipped
+    fn sweep(&self, settings: &DbSettings) -> anyhow::Result<(Vec<(Vec<u8>, u128)>, Vec<Vec<u8>>)> {
+        let stale_window = settings.stale_window();
+        let max_size = settings.max_size();
+        let mut expired = vec![];
+        let mut live = vec![];
+        for (k, v) in self.r_lock().iterator(IteratorMode::Start) {
+            let ttl = match ttl_of(&v) {
+                Ok(ttl) => ttl,
+                Err(e) => {
+                    warn!(
+                        "Skipping unreadable record, key = {}, e = {}",
+                        display_key(&k),
+                        e
+                    );
+                    continue;
+                }
+            };
+            if is_expired_after(ttl, stale_window)? {
+                expired.push((k.to_vec(), ttl));
+            } else if max_size > 0 {
+                // sizes are only needed for eviction
+                live.push((k.to_vec(), (k.len() + v.len()) as u64));
+            }
+        }
+
+        let mut purged = vec![];
+        for (key, ttl) in expired {
+            match self.remove_if_expired(&key, stale_window) {
+                Ok(true) => purged.push((key, ttl)),
+                Ok(false) => {}
+                Err(e) => warn!(
+                    "Can't purge expired record, key = {}, e = {}",
+                    display_key(&key),
+                    e
+                ),
+            }
+        }
+
+        let mut evicted = vec![];
+        if max_size > 0 {
+            for key in victims(live, &self.all_stats()?, max_size, settings.eviction) {
+                self.remove(&key, EventKind::Evict)?;
+                evicted.push(key);
+            }
+        }
+
+        Ok((purged, evicted))
+    }
+
+    fn crdt(&self, key: &[u8]) -> anyhow::Result<Option<Crdt>> {
+        let rock = self.r_lock();
+        match rock.get_cf(crdt_cf(&rock)?, key)? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    // the update is built from the current value and merged under the write lock, returns the new value
+    fn update_crdt<F>(&self, key: &[u8], update: F) -> anyhow::Result<Crdt>
+    where
+        F: FnOnce(Option<&Crdt>) -> anyhow::Result<Crdt>,
+    {
+        let rock = self.w_lock();
+        let cf = crdt_cf(&rock)?;
+        let current: Option<Crdt> = match rock.get_cf(cf, key)? {
+            Some(bytes) => Some(bincode::deserialize(&bytes)?),
+            None => None,
+        };
+        let update = update(current.as_ref())?;
+        Crdt::check_type(current.as_ref(), &update, &display_key(key))?;
+
+        rock.merge_cf_opt(cf, key, bincode::serialize(&update)?, &self.write_opts())?;
+        Ok(match current {
+            Some(current) => current.join(update),
+            None => update,
+        })
+    }
+
+    fn hot_keys(&self, limit: usize, cold: bool) -> anyhow::Result<Vec<KeyStats>> {
+        let rock = self.r_lock();
+        let cf = rock.cf_handle(STATS_CF).ok_or_else(|| {
+            anyhow!(ErrorCtx::Validation(
+                "Access stats are not enabled for this db".into()
+            ))
+        })?;
+
+        let mut stats = vec![];
+        for (k, v) in rock.iterator_cf(cf, IteratorMode::Start) {
+            stats.push(KeyStats::new(bytes_to_str(&k)?, bincode::deserialize(&v)?));
+        }
+
+        Ok(rank(stats, limit, cold))
+    }
+
+    fn property(&self, name: &str) -> anyhow::Result<String> {
+        Ok(self.raw_property(name)?.unwrap_or_default())
+    }
+
+    // None for properties unknown to RocksDb
+    fn raw_property(&self, name: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.r_lock().property_value(name)?)
+    }
+
+    fn internals(&self) -> anyhow::Result<Internals> {
+        Ok(Internals {
+            levels: parse_level_stats(&self.property(LEVEL_STATS)?),
+            cf_stats: parse_lines(&self.property(CF_STATS)?),
+            pending_compaction_bytes: parse_int(&self.property(PENDING_COMPACTION_BYTES)?),
+            write_stopped: parse_int(&self.property(IS_WRITE_STOPPED)?) == 1,
+            delayed_write_rate: parse_int(&self.property(DELAYED_WRITE_RATE)?),
+            running_compactions: parse_int(&self.property(RUNNING_COMPACTIONS)?),
+            running_flushes: parse_int(&self.property(RUNNING_FLUSHES)?),
+        })
+    }
+
+    // memtables count too, they're not in the live data until flushed
+    // staged parts and chunks of uploaded values count towards the bytes
+    fn quota_usage(&self) -> anyhow::Result<QuotaUsage> {
+        Ok(QuotaUsage {
+            keys: parse_int(&self.property(ESTIMATE_NUM_KEYS)?),
+            bytes: parse_int(&self.property(ESTIMATE_LIVE_DATA_SIZE)?)
+                + parse_int(&self.property(CUR_SIZE_ALL_MEM_TABLES)?)
+                + self.uploads_size()?,
+        })
+    }
+
+    fn uploads_size(&self) -> anyhow::Result<u64> {
+        let rock = self.r_lock();
+        let cf = match rock.cf_handle(UPLOADS_CF) {
+            Some(cf) => cf,
+            None => return Ok(0),
+        };
+        let mut size = 0;
+        for name in &[ESTIMATE_LIVE_DATA_SIZE, CUR_SIZE_ALL_MEM_TABLES] {
+            size += parse_int(&rock.property_value_cf(cf, name)?.unwrap_or_default());
+        }
+        Ok(size)
+    }
+
+    // levels without files are left out
+    fn compression_stats(&self) -> anyhow::Result<CompressionStats> {
+        let mut levels = vec![];
+        for level in 0..NUM_LEVELS {
+            let files = parse_int(&self.property(&format!("{}{}", NUM_FILES_AT_LEVEL, level))?);
+            if files > 0 {
+                let properties =
+                    self.property(&format!("{}{}", TABLE_PROPERTIES_AT_LEVEL, level))?;
+                levels.push(LevelCompression::parse(level, files, &properties));
+            }
+        }
+        Ok(CompressionStats::new(levels))
+    }
+
+    // rewrites records stored in an older envelope layout in the current one - value, ttl and version are
+    // kept as is, so it isn't a change and nothing is published - a cancel stops it before the next batch
+    fn migrate(&self, cancel: &Cancel) -> anyhow::Result<u64> {
+        let rock = self.w_lock();
+        let mut migrated = 0;
+        let mut batch = WriteBatch::default();
+        for (k, v) in rock.iterator(IteratorMode::Start) {
+            if is_current_layout(&v) {
+                continue;
+            }
+            let data: Data = v.to_vec().as_struct()?;
+            batch.put(&k, data.as_bytes()?);
+            migrated += 1;
+            if batch.len() >= MIGRATION_BATCH_SIZE {
+                cancel.check()?;
+                rock.write_opt(std::mem::take(&mut batch), &self.write_opts())?;
+            }
+        }
+        if !batch.is_empty() {
+            rock.write_opt(batch, &self.write_opts())?;
+        }
+        Ok(migrated)
+    }
+
+    fn live_files(&self) -> anyhow::Result<Vec<LiveFileInfo>> {
+        Ok(live_files(&self.r_lock().live_files()?))
+    }
+
+    fn manifest(&self) -> anyhow::Result<ManifestInfo> {
+        let rock = self.r_lock();
+        manifest(rock.path(), &rock.live_files()?)
+    }
+
+    fn approximate_size(&self, start: &str, end: &str) -> anyhow::Result<ApproximateSize> {
+        Ok(approximate_size(&self.r_lock().live_files()?, start, end))
+    }
+
+    // reads go on, writes wait until the files added since the previous backup are copied
+    fn backup(&self, dir: &str, keep: usize) -> anyhow::Result<Vec<BackupInfo>> {
+        let mut engine = open_engine(dir)?;
+        {
+            let rock = self.r_lock();
+            // memtables of dbs with WAL disabled would be missing from the backup otherwise
+            rock.flush()?;
+            engine.create_new_backup(&rock)?;
+        }
+        if keep > 0 {
+            engine.purge_old_backups(keep)?;
+        }
+        Ok(backups(&engine))
+    }
+
+    // live SST files are hard linked instead of copied, writes wait only for the memtable flush
+    fn checkpoint(&self, dir: &str) -> anyhow::Result<()> {
+        let rock = self.r_lock();
+        Ok(Checkpoint::new(&rock)?.create_checkpoint(dir)?)
+    }
+
+    // ingested records replace existing ones with the same keys, files are copied into the db
+    fn ingest(&self, files: Vec<PathBuf>) -> anyhow::Result<u64> {
+        let rock = self.w_lock();
+        rock.ingest_external_file(files)
+            .map_err(|e| anyhow!(ErrorCtx::Validation(format!("Can't ingest files - {}", e))))?;
+        Ok(rock.latest_sequence_number())
+    }
+
+    fn background_errors(&self) -> anyhow::Result<u64> {
+        Ok(parse_int(&self.property(BACKGROUND_ERRORS)?))
+    }
+
+    fn path(&self) -> PathBuf {
+        self.r_lock().path().to_path_buf()
+    }
+
+    fn is_ephemeral(&self) -> bool {
+        self.env.is_some()
+    }
+
+    // listing snapshots reference the handle, they're released with the db
+    fn release_pins(&self) {
+        self.pins.release_all();
+    }
+
+    // true when no request or background job holds this handle
+    fn is_released(&self) -> bool {
+        Arc::strong_count(&self.rock) == 1
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        Ok(self.r_lock().flush()?)
+    }
+
+    fn set_cache_capacity(&self, capacity: usize) {
+        if let Some(cache) = &self.cache {
+            cache
+                .lock()
+                .expect("Can't acquire cache lock")
+                .set_capacity(capacity);
+        }
+    }
+
+    // with durability "none" WAL is skipped and writes are persisted only on memtable flush
+    fn write_opts(&self) -> WriteOptions {
+        let mut opts = WriteOptions::default();
+        if self.durability == Durability::None {
+            opts.disable_wal(true);
+        }
+
+        opts
+    }
+
+    fn r_lock(&self) -> ReadGuard<'_> {
+        self.rock.read()
+    }
+
+    fn w_lock(&self) -> WriteGuard<'_> {
+        self.rock.write()
+    }
+}
+
+impl DbManager {
+    pub fn new(db_cfg: DbConfig) -> anyhow::Result<Self> {
+        DbManager::build(db_cfg, true)
+    }
+
+    // for the CLI - expired records are left for the server's sweeper and webhooks are only queued
+    pub fn offline(db_cfg: DbConfig) -> anyhow::Result<Self> {
+        DbManager::build(db_cfg, false)
+    }
+
+    fn build(db_cfg: DbConfig, background: bool) -> anyhow::Result<Self> {
+        let root_db = open_root_db(&db_cfg)?;
+        let outbox = Arc::new(Outbox::open(root_db.rock.clone())?);
+        let webhooks = if background {
+            Webhooks::start(outbox.clone())
+        } else {
+            Webhooks::queued(outbox.clone())
+        };
+        let (tx, rx) = mpsc::channel::<BoxedFnOnce>();
+        let (deletion_tx, deletion_rx) = mpsc::channel::<BoxedFnOnce>();
+
+        let db_manager = DbManager {
+            db_cfg: Arc::new(db_cfg),
+            root_db,
+            dbs: Arc::new(ShardedLock::new(HashMap::new())),
+            recovering: Arc::new(ShardedLock::new(HashSet::new())),
+            deletions: Arc::new(Mutex::new(HashMap::new())),
+            erasures: Arc::new(Mutex::new(HashMap::new())),
+            events: Arc::new(EventBus::default()),
+            maintenance: Arc::new(ShardedLock::new(Schedule::default())),
+            txns: Sessions::default(),
+            usage: Arc::new(UsageHistory::default()),
+            webhooks: Arc::new(webhooks),
+            outbox,
+            replicas: Arc::new(Replicas::default()),
+            scan_cache: ScanCache::default(),
+            snapshots: Snapshots::default(),
+            exports: Arc::new(Exports::default()),
+            operations: Arc::new(Operations::default()),
+            lifecycle: Lifecycle::default(),
+            backup_lock: Arc::new(Mutex::new(())),
+            tx: Mutex::new(tx),
+            deletion_tx: Mutex::new(deletion_tx),
+            background,
+        };
+        db_manager.open_dbs();
+        if !background {
+            return Ok(db_manager);
+        }
+        db_manager.reg_receiver_thread(rx, "async-expire-thread");
+        // dropped dbs are deleted on their own thread so a slow throttled deletion doesn't hold up expiration
+        db_manager.reg_receiver_thread(deletion_rx, "async-deletion-thread");
+        db_manager.reg_memory_guard_thread();
+        db_manager.reg_recovery_thread();
+        db_manager.reg_sweeper_thread();
+        db_manager.reg_usage_sampler_thread();
+
+        Ok(db_manager)
+    }
+
+    // will panic in main thread and prevent startup
+    fn open_dbs(&self) {
+        info!("Initializing dbs from root ...");
+        self.root_db
+            .r_lock()
+            .iterator(IteratorMode::Start)
+            .map(|(k, v)| {
+                (
+                    bytes_to_str(&k).expect("Failed to read from db"),
+                    bytes_to_str(&v).expect("Failed to read from db"),
+                )
+            })
+            .for_each(|(name, path)| {
+                info!("Initializing db = {} on path = {}", &name, &path);
+                let overrides = self
+                    .stored_overrides(&name)
+                    .expect("Failed to read db overrides");
+                self.open_on_path(name, path, false, &overrides)
+                    .expect("Failed to open db");
+            });
+    }
+
+    // the default is stored as no entry
+    fn store_overrides(&self, db_name: &str, overrides: &RocksOverrides) -> anyhow::Result<()> {
+        let rock = self.root_db.w_lock();
+        let cf = overrides_cf(&rock)?;
+        if overrides.is_empty() {
+            rock.delete_cf(cf, db_name)?;
+        } else {
+            rock.put_cf(cf, db_name, serde_json::to_vec(overrides)?)?;
+        }
+        Ok(())
+    }
+
+    fn stored_overrides(&self, db_name: &str) -> anyhow::Result<RocksOverrides> {
+        let rock = self.root_db.r_lock();
+        match rock.get_cf(overrides_cf(&rock)?, db_name)? {
+            Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            None => Ok(RocksOverrides::default()),
+        }
+    }
+
+    fn reg_receiver_thread(&self, rx: Receiver<BoxedFnOnce>, name: &str) {
+        thread::Builder::new()
+            .name(name.into())
+            .spawn(move || {
+                for boxed in rx {
+                    boxed.invoke()
+                }
+            })
+            .expect("Failed to register receiver thread");
+    }
+
+    // flushes memtables and halves block caches while process RSS is near the configured limit
+    fn reg_memory_guard_thread(&self) {
+        let limit = self.db_cfg.memory_limit();
+        if limit == 0 {
+            return;
+        }
+
+        let weak_dbs = Arc::downgrade(&self.dbs);
+        let interval = self.db_cfg.memory_check_interval();
+        let cache_size = self.db_cfg.cache_size();
+        thread::Builder::new()
+            .name("memory-guard-thread".into())
+            .spawn(move || {
+                let mut shrunk = false;
+                while let Some(dbs) = Weak::upgrade(&weak_dbs) {
+                    match rss_bytes() {
+                        Ok(rss) => {
+                            PROCESS_RSS.set(rss as i64);
+                            shrunk = relieve_memory(&dbs, rss, limit, cache_size, shrunk);
+                        }
+                        Err(e) => error!("Memory guard can't read process RSS, e = {}", e),
+                    }
+                    drop(dbs);
+                    thread::sleep(interval);
+                }
+            })
+            .expect("Failed to register memory guard thread");
+    }
+
+    fn reg_sweeper_thread(&self) {
+        let interval = self.db_cfg.sweep_interval();
+        if interval.as_millis() == 0 {
+            return;
+        }
+
+        let weak_dbs = Arc::downgrade(&self.dbs);
+        let db_cfg = self.db_cfg.clone();
+        let events = self.events.clone();
+        let usage = self.usage.clone();
+        let webhooks = self.webhooks.clone();
+        let maintenance = self.maintenance.clone();
+        let exports = self.exports.clone();
+        thread::Builder::new()
+            .name("sweeper-thread".into())
+            .spawn(move || {
+                while let Some(dbs) = Weak::upgrade(&weak_dbs) {
+                    if !is_maintenance_open(&maintenance) {
+                        drop(dbs);
+                        thread::sleep(interval);
+                        continue;
+                    }
+
+                    // sweeping can take a while, don't block open/close meanwhile
+                    let snapshot: Vec<(String, Db)> = dbs
+                        .read()
+                        .expect("Can't acquire read lock")
+                        .iter()
+                        .map(|(name, db)| (name.clone(), db.clone()))
+                        .collect();
+                    drop(dbs);
+
+                    for (name, db) in snapshot {
+                        sweep(&db_cfg, &events, &usage, &webhooks, &name, &db);
+                        purge_exports(&db_cfg, &exports, &name);
+                    }
+                    thread::sleep(interval);
+                }
+            })
+            .expect("Failed to register sweeper thread");
+    }
+
+    // disk usage history feeding the forecast
+    fn reg_usage_sampler_thread(&self) {
+        let interval = self.db_cfg.usage_sample_interval();
+        if interval.as_millis() == 0 {
+            return;
+        }
+
+        let weak_dbs = Arc::downgrade(&self.dbs);
+        let db_cfg = self.db_cfg.clone();
+        let usage = self.usage.clone();
+        thread::Builder::new()
+            .name("usage-sampler-thread".into())
+            .spawn(move || {
+                while let Some(dbs) = Weak::upgrade(&weak_dbs) {
+                    let snapshot: Vec<(String, Db)> = dbs
+                        .read()
+                        .expect("Can't acquire read lock")
+                        .iter()
+                        .map(|(name, db)| (name.clone(), db.clone()))
+                        .collect();
+                    drop(dbs);
+
+                    for (name, db) in snapshot {
+                        match usage_sample(&db_cfg, &usage, &name, &db) {
+                            Ok(sample) => usage.push(&name, sample),
+                            Err(e) => error!("Failed to sample usage of db = {}, e = {}", name, e),
+                        }
+                    }
+                    thread::sleep(interval);
+                }
+            })
+            .expect("Failed to register usage sampler thread");
+    }
+
+    // watches background error counters and reopens failed dbs
+    fn reg_recovery_thread(&self) {
+        let interval = self.db_cfg.recovery_check_interval();
+        if interval.as_millis() == 0 {
+            return;
+        }
+
+        let weak_dbs = Arc::downgrade(&self.dbs);
+        let recovering = self.recovering.clone();
+        let db_cfg = self.db_cfg.clone();
+        thread::Builder::new()
+            .name("recovery-thread".into())
+            .spawn(move || {
+                let mut seen_errors = HashMap::new();
+                while let Some(dbs) = Weak::upgrade(&weak_dbs) {
+                    for (name, db) in failed_dbs(&dbs, &mut seen_errors) {
+                        recover_async(&dbs, &recovering, &db_cfg, name, db);
+                    }
+                    drop(dbs);
+                    thread::sleep(interval);
+                }
+            })
+            .expect("Failed to register recovery thread");
+    }
+
+    // ephemeral dbs live in memory only, they aren't registered in root so they're gone after a restart
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn open(
+        &self,
+        db_name: String,
+        ephemeral: bool,
+        overrides: RocksOverrides,
+    ) -> anyhow::Result<()> {
+        let _transition = self.lifecycle.begin(&db_name).await;
+        let problems = overrides.validate();
+        if !problems.is_empty() {
+            Err(anyhow!(ErrorCtx::Validation(problems.join(", "))))
+        } else if db_name.starts_with('_') {
+            Err(anyhow!(ErrorCtx::Validation(format!(
+                "Database name {} is reserved - names can't start with _",
+                db_name
+            ))))
+        } else if is_version(&db_name) {
+            Err(anyhow!(ErrorCtx::Validation(format!(
+                "Database name {} is reserved for API versions",
+                db_name
+            ))))
+        } else if self.is_recovering(&db_name) {
+            Err(self.missing(&db_name))
+        } else if self.deleting(&db_name) {
+            Err(anyhow!(ErrorCtx::Validation(format!(
+                "Database {} is still being deleted",
+                db_name
+            ))))
+        } else if self.contains(&db_name) {
+            warn!("Db {} already exists", &db_name);
+            Err(anyhow!(ErrorCtx::Validation(format!(
+                "Database {} already exists",
+                db_name
+            ))))
+        } else {
+            let path = format!("{}/{}", self.db_cfg.path(), db_name);
+            if ephemeral {
+                info!("Opening ephemeral Db = {}", &db_name);
+            } else {
+                info!("Opening Db = {} on path = {}", &db_name, &path);
+                self.store_overrides(&db_name, &overrides)?;
+                self.root_db.put(&db_name, &path)?;
+            }
+            Ok(self.open_on_path(db_name, path, ephemeral, &overrides)?)
+        }
+    }
+
+    fn open_on_path(
+        &self,
+        db_name: String,
+        path: String,
+        ephemeral: bool,
+        overrides: &RocksOverrides,
+    ) -> anyhow::Result<()> {
+        if !overrides.is_empty() {
+            info!("Db = {} is opened with overrides {:?}", &db_name, overrides);
+        }
+        let db = open_db(&self.db_cfg, &db_name, &path, ephemeral, overrides)?;
+        self.w_lock().insert(db_name.clone(), db);
+        self.start_replication(&db_name);
+        Ok(())
+    }
+
+    // ships changes of the db to the deployment it's replicated to until the db is closed
+    fn start_replication(&self, db_name: &str) {
+        let url = match self.db_cfg.settings(db_name).replicate_to {
+            Some(url) if self.background => url,
+            _ => return,
+        };
+        let shipper = self.replicas.start(db_name);
+        let weak_dbs = Arc::downgrade(&self.dbs);
+        let events = self.events.clone();
+        let limits = self.db_cfg.scan_limits();
+        let outbox = self.outbox.clone();
+        let db_name = db_name.to_string();
+        info!("Replicating db = {} to {}", &db_name, &url);
+        thread::Builder::new()
+            .name("replication-thread".into())
+            .spawn(move || {
+                let mut runner = System::new("replication");
+                let shipping = replicate(
+                    weak_dbs,
+                    events,
+                    limits,
+                    outbox,
+                    shipper.clone(),
+                    &db_name,
+                    &url,
+                );
+                let expiring = shipper.expire_acks();
+                futures::pin_mut!(shipping, expiring);
+                runner.block_on(futures::future::select(shipping, expiring));
+                let _ = REPLICATION_LAG.remove_label_values(&[db_name.as_str()]);
+                info!("Stopped replicating db = {}", &db_name);
+            })
+            .expect("Failed to register replication thread");
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn close(&self, db_name: String) -> anyhow::Result<()> {
+        let _transition = self.lifecycle.begin(&db_name).await;
+        if self.not_contains(&db_name) {
+            Err(anyhow!(ErrorCtx::Validation(format!(
+                "Can't close {} db - doesn't exist",
+                &db_name
+            ))))
+        } else {
+            if let Some(db) = self.w_lock().remove(&db_name) {
+                info!("Closing db = {} ...", &db_name);
+                db.release_pins();
+                let path = self.db_cfg.db_path(&db_name);
+                self.root_db.w_lock().delete(&db_name)?;
+                self.store_overrides(&db_name, &RocksOverrides::default())?;
+                self.txns.remove_db(&db_name);
+                self.usage.remove_db(&db_name);
+                self.scan_cache.remove_db(&db_name);
+                self.replicas.stop(&db_name);
+                // memory of ephemeral dbs is freed once requests in flight drop their handles
+                if !db.is_ephemeral() {
+                    self.try_close_async(db, db_name, path);
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    fn try_close_async(&self, db: Db, db_name: String, path: String) {
+        let total_bytes = dir_size(&path).unwrap_or_default();
+        let started_at = current_ms().unwrap_or_default();
+        self.deletions
+            .lock()
+            .expect("Can't acquire deletions lock")
+            .insert(
+                db_name.clone(),
+                Deletion::new(db_name.clone(), path.clone(), total_bytes, started_at),
+            );
+
+        let deletions = self.deletions.clone();
+        let rate = self.db_cfg.delete_rate();
+        let maintenance = self.maintenance.clone();
+        let _ = self
+            .deletion_tx
+            .lock()
+            .expect("Can't acquire deletion tx lock")
+            .send(BoxedFnOnce::new(move || {
+                // requests still in flight hold the handle, files can be deleted only after it's dropped
+                while !db.is_released() {
+                    thread::sleep(RELEASE_CHECK_INTERVAL);
+                }
+                drop(db);
+
+                // deleting files is heavy on io, the db stays listed in deletions until a window opens
+                while !is_maintenance_open(&maintenance) {
+                    thread::sleep(MAINTENANCE_CHECK_INTERVAL);
+                }
+
+                info!(
+                    "Db = {} closed. Deleting {} bytes of db files...",
+                    &db_name, total_bytes
+                );
+                remove_files(&deletions, &db_name, path, rate);
+                deletions
+                    .lock()
+                    .expect("Can't acquire deletions lock")
+                    .remove(&db_name);
+            }));
+    }
+
+    // heavy background jobs run only while the schedule is open
+    pub fn set_maintenance(&self, schedule: Schedule) {
+        *self
+            .maintenance
+            .write()
+            .expect("Can't acquire maintenance lock") = schedule;
+    }
+
+    // dbs are erased one after another on their own thread, the report is completed and signed once all are done
+    pub fn erase(
+        &self,
+        request: ErasureRequest,
+        signing_key: String,
+    ) -> anyhow::Result<ErasureReport> {
+        let matcher = request.matcher()?;
+        let dbs: Vec<(String, Db)> = {
+            let dbs = self.r_lock();
+            if request.dbs.is_empty() {
+                let mut all: Vec<(String, Db)> = dbs
+                    .iter()
+                    .map(|(name, db)| (name.clone(), db.clone()))
+                    .collect();
+                all.sort_by(|a, b| a.0.cmp(&b.0));
+                all
+            } else {
+                request
+                    .dbs
+                    .iter()
+                    .map(|name| match dbs.get(name) {
+                        Some(db) => Ok((name.clone(), db.clone())),
+                        None => Err(self.missing(name)),
+                    })
+                    .collect::<anyhow::Result<_>>()?
+            }
+        };
+
+        let requested_at = current_ms()?;
+        let report = {
+            let mut erasures = self.erasures.lock().expect("Can't acquire erasures lock");
+            let mut id = requested_at;
+            while erasures.contains_key(&id.to_string()) {
+                id += 1;
+            }
+            let report = ErasureReport::new(id.to_string(), requested_at, request.patterns);
+            erasures.insert(report.id().to_string(), report.clone());
+            report
+        };
+
+        let erasures = self.erasures.clone();
+        let db_cfg = self.db_cfg.clone();
+        let backup_lock = self.backup_lock.clone();
+        let id = report.id().to_string();
+        thread::Builder::new()
+            .name("erasure-thread".into())
+            .spawn(move || {
+                let mut erased = vec![];
+                let mut errors = vec![];
+                for (name, db) in &dbs {
+                    match erase_db(&db_cfg, &backup_lock, name, db, &matcher, &id) {
+                        Ok(db_erasure) => erased.push(db_erasure),
+                        Err(e) => {
+                            error!("Failed to erase keys of db = {} - {}", name, e);
+                            errors.push(format!("{} - {}", name, e));
+                        }
+                    }
+                }
+
+                let error = if errors.is_empty() {
+                    None
+                } else {
+                    Some(errors.join("; "))
+                };
+                let mut erasures = erasures.lock().expect("Can't acquire erasures lock");
+                if let Some(report) = erasures.get_mut(&id) {
+                    let completed_at = current_ms().unwrap_or_default();
+                    match report.complete(erased, error, completed_at, &signing_key) {
+                        Ok(()) => info!("Erasure {} completed", &id),
+                        Err(e) => error!("Failed to sign erasure {} report - {}", &id, e),
+                    }
+                }
+            })?;
+
+        Ok(report)
+    }
+
+    pub fn erasure(&self, id: &str) -> anyhow::Result<ErasureReport> {
+        self.erasures
+            .lock()
+            .expect("Can't acquire erasures lock")
+            .get(id)
+            .cloned()
+            .ok_or_else(|| anyhow!(ErrorCtx::NotFound(format!("Erasure {} doesn't exist", id))))
+    }
+
+    pub fn start_operation(&self, kind: OperationKind, db_name: &str) -> Operation {
+        self.operations.start(kind, db_name)
+    }
+
+    pub fn operations(&self) -> Vec<OperationInfo> {
+        self.operations.list()
+    }
+
+    pub fn cancel_operation(&self, id: u64) -> anyhow::Result<()> {
+        if self.operations.cancel(id) {
+            Ok(())
+        } else {
+            Err(anyhow!(ErrorCtx::NotFound(format!(
+                "No running operation {}",
+                id
+            ))))
+        }
+    }
+
+    // deliveries to webhooks and replicas not accepted yet
+    pub fn outbound(&self) -> anyhow::Result<Vec<PendingDelivery>> {
+        self.outbox.list()
+    }
+
+    pub fn deletions(&self) -> Vec<Deletion> {
+        self.deletions
+            .lock()
+            .expect("Can't acquire deletions lock")
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    fn jitter(&self, db_name: &str, key: &str, ttl: u128) -> anyhow::Result<u128> {
+        match self.db_cfg.ttl_jitter_pct(db_name) {
+            0 => Ok(ttl),
+            pct => Ok(jittered(ttl, current_ms()?, pct, key)),
+        }
+    }
+
+    // writes to a db at its quota are rejected, reads and deletes still go through
+    fn check_quota(&self, db_name: &str, db: &Db) -> anyhow::Result<()> {
+        let quota = self.db_cfg.quota(db_name);
+        if quota.is_unlimited() {
+            return Ok(());
+        }
+        quota.check(db_name, db.quota_usage()?)
+    }
+
+    // with write_concern = "replicated" a write is answered once the replica has it - one it doesn't acknowledge
+    // in time stays committed locally and is still shipped
+    async fn replicated(&self, db_name: &str, seq: u64) -> anyhow::Result<()> {
+        let settings = self.db_cfg.settings(db_name);
+        if settings.write_concern == WriteConcern::Local
+            || self
+                .replicas
+                .acknowledged(db_name, seq, settings.replication_ack_timeout())
+                .await
+        {
+            return Ok(());
+        }
+        Err(anyhow!(ErrorCtx::Unavailable(format!(
+            "Write {} to db {} is committed locally, but its replica didn't acknowledge it within {} ms",
+            seq, db_name, settings.replication_ack_timeout_ms
+        ))))
+    }
+
+    fn deleting(&self, db_name: &str) -> bool {
+        self.deletions
+            .lock()
+            .expect("Can't acquire deletions lock")
+            .contains_key(db_name)
+    }
+
+    #[instrument(skip_all, fields(db = %db_name, key = %display_key(key)))]
+    pub async fn store(
+        &self,
+        db_name: &str,
+        key: &[u8],
+        val: Bytes,
+        ttl: u128,
+        soft_ttl: u128,
+        repr: Representation,
+        condition: WriteCondition,
+    ) -> anyhow::Result<Stored> {
+        if val.len() > MAX_VALUE_BYTES {
+            return Err(anyhow!(ErrorCtx::Validation(format!(
+                "Value exceeds {} bytes",
+                MAX_VALUE_BYTES
+            ))));
+        }
+        let stored = match self.w_lock().get(db_name) {
+            Some(db) => {
+                self.check_quota(db_name, db)?;
+                let display = display_key(key);
+                let ttl = self.jitter(db_name, &display, ttl)?;
+                let stored = db.store(key, val.to_vec(), ttl, soft_ttl, repr, condition)?;
+                db.record(key, Access::Write);
+                self.events.publish(
+                    Event::new(db_name, key, EventKind::Put)
+                        .with_version(stored.version)
+                        .with_seq(stored.seq),
+                );
+                stored
+            }
+            None => return Err(self.missing(db_name)),
+        };
+        self.replicated(db_name, stored.seq).await?;
+        Ok(stored)
+    }
+
+    #[instrument(skip_all, fields(db = %db_name, key = %display_key(key)))]
+    pub async fn patch(
+        &self,
+        db_name: &str,
+        key: &[u8],
+        format: PatchFormat,
+        patch: Bytes,
+        condition: WriteCondition,
+    ) -> anyhow::Result<Stored> {
+        let stored = match self.w_lock().get(db_name) {
+            Some(db) => {
+                self.check_quota(db_name, db)?;
+                let stored = db.patch(key, format, &patch, condition)?;
+                db.record(key, Access::Write);
+                self.events.publish(
+                    Event::new(db_name, key, EventKind::Put)
+                        .with_version(stored.version)
+                        .with_seq(stored.seq),
+                );
+                stored
+            }
+            None => return Err(self.missing(db_name)),
+        };
+        self.replicated(db_name, stored.seq).await?;
+        Ok(stored)
+    }
+
+    #[instrument(skip_all, fields(db = %db_name, key = %display_key(key)))]
+    pub async fn begin_upload(
+        &self,
+        db_name: &str,
+        key: &[u8],
+        content_type: Option<String>,
+    ) -> anyhow::Result<UploadInfo> {
+        match self.r_lock().get(db_name) {
+            Some(db) => {
+                let upload = Upload::new(key, content_type)?;
+                let upload_id = upload_id();
+                db.begin_upload(&upload_id, &upload)?;
+                Ok(UploadInfo {
+                    upload_id,
+                    created_at: upload.created_at,
+                    parts: vec![],
+                })
+            }
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    #[instrument(skip_all, fields(db = %db_name, key = %display_key(key)))]
+    pub async fn upload(
+        &self,
+        db_name: &str,
+        key: &[u8],
+        upload_id: &str,
+    ) -> anyhow::Result<UploadInfo> {
+        match self.r_lock().get(db_name) {
+            Some(db) => {
+                let (upload, parts) = db.upload(upload_id, key)?;
+                Ok(UploadInfo {
+                    upload_id: upload_id.into(),
+                    created_at: upload.created_at,
+                    parts,
+                })
+            }
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    // parts count towards the quota only once they're assembled
+    #[instrument(skip_all, fields(db = %db_name, key = %display_key(key)))]
+    pub async fn upload_part(
+        &self,
+        db_name: &str,
+        key: &[u8],
+        upload_id: &str,
+        number: u32,
+        value: Bytes,
+    ) -> anyhow::Result<PartInfo> {
+        check_part_number(number)?;
+        match self.r_lock().get(db_name) {
+            Some(db) => {
+                self.check_quota(db_name, db)?;
+                db.upload_part(upload_id, key, number, &value)?;
+                Ok(PartInfo {
+                    number,
+                    size: value.len(),
+                })
+            }
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    #[instrument(skip_all, fields(db = %db_name, key = %display_key(key)))]
+    pub async fn complete_upload(
+        &self,
+        db_name: &str,
+        key: &[u8],
+        upload_id: &str,
+        ttl: u128,
+        soft_ttl: u128,
+        condition: WriteCondition,
+    ) -> anyhow::Result<Stored> {
+        let stored = match self.w_lock().get(db_name) {
+            Some(db) => {
+                self.check_quota(db_name, db)?;
+                let display = display_key(key);
+                let ttl = self.jitter(db_name, &display, ttl)?;
+                let stored = db.complete_upload(upload_id, key, ttl, soft_ttl, condition)?;
+                db.record(key, Access::Write);
+                self.events.publish(
+                    Event::new(db_name, key, EventKind::Put)
+                        .with_version(stored.version)
+                        .with_seq(stored.seq),
+                );
+                stored
+            }
+            None => return Err(self.missing(db_name)),
+        };
+        self.replicated(db_name, stored.seq).await?;
+        Ok(stored)
+    }
+
+    #[instrument(skip_all, fields(db = %db_name, key = %display_key(key)))]
+    pub async fn abort_upload(
+        &self,
+        db_name: &str,
+        key: &[u8],
+        upload_id: &str,
+    ) -> anyhow::Result<()> {
+        match self.r_lock().get(db_name) {
+            Some(db) => db.abort_upload(upload_id, key),
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    #[instrument(skip_all, fields(db = %db_name, key = %display_key(key)))]
+    pub async fn append(&self, db_name: &str, key: &[u8], val: Bytes) -> anyhow::Result<u64> {
+        let seq = match self.w_lock().get(db_name) {
+            Some(db) => {
+                self.check_quota(db_name, db)?;
+                let seq = db.append(key, val.to_vec())?;
+                db.record(key, Access::Write);
+                self.events
+                    .publish(Event::new(db_name, key, EventKind::Put).with_seq(seq));
+                seq
+            }
+            None => return Err(self.missing(db_name)),
+        };
+        self.replicated(db_name, seq).await?;
+        Ok(seq)
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn write_batch(
+        &self,
+        db_name: &str,
+        records: Vec<WireRecord>,
+        ttl: u128,
+    ) -> anyhow::Result<u64> {
+        let seq = match self.w_lock().get(db_name) {
+            Some(db) => {
+                if records.iter().any(|r| r.value.is_some()) {
+                    self.check_quota(db_name, db)?;
+                }
+                let jitter_pct = self.db_cfg.ttl_jitter_pct(db_name);
+                let (versions, seq) = db.write_batch(&records, ttl, jitter_pct)?;
+                for (record, version) in records.iter().zip(versions) {
+                    let event = match version {
+                        Some(version) => {
+                            db.record(record.key.as_bytes(), Access::Write);
+                            Event::new(db_name, &record.key, EventKind::Put).with_version(version)
+                        }
+                        None => Event::new(db_name, &record.key, EventKind::Delete),
+                    };
+                    self.events.publish(event.with_seq(seq));
+                }
+                seq
+            }
+            None => return Err(self.missing(db_name)),
+        };
+        self.replicated(db_name, seq).await?;
+        Ok(seq)
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn txn(&self, db_name: &str, writes: Vec<TxnWrite>) -> anyhow::Result<TxnResult> {
+        let (versions, seq) = match self.w_lock().get(db_name) {
+            Some(db) => {
+                if writes.iter().any(|w| matches!(w.action, TxnAction::Put(_))) {
+                    self.check_quota(db_name, db)?;
+                }
+                let jitter_pct = self.db_cfg.ttl_jitter_pct(db_name);
+                let (versions, seq) = db.txn(&writes, jitter_pct)?;
+                for (write, version) in writes.iter().zip(&versions) {
+                    let event = match (&write.action, version) {
+                        (TxnAction::Put(_), Some(version)) => {
+                            db.record(write.key.as_bytes(), Access::Write);
+                            Event::new(db_name, &write.key, EventKind::Put).with_version(*version)
+                        }
+                        (TxnAction::Delete, _) => {
+                            Event::new(db_name, &write.key, EventKind::Delete)
+                        }
+                        _ => continue,
+                    };
+                    self.events.publish(event.with_seq(seq));
+                }
+                (versions, seq)
+            }
+            None => return Err(self.missing(db_name)),
+        };
+        self.replicated(db_name, seq).await?;
+        Ok(TxnResult { versions, seq })
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn begin_txn(&self, db_name: &str) -> anyhow::Result<String> {
+        if self.not_contains(db_name) {
+            return Err(self.missing(db_name));
+        }
+        if !self.db_cfg.settings(db_name).interactive_txns {
+            return Err(anyhow!(ErrorCtx::Validation(format!(
+                "Interactive transactions are disabled for db {}",
+                db_name
+            ))));
+        }
+        self.txns.begin(db_name)
+    }
+
+    // sees the transaction's own writes, other keys are read from the db and their version is checked on commit
+    #[instrument(skip_all, fields(db = %db_name, key = %key))]
+    pub async fn txn_read(
+        &self,
+        db_name: &str,
+        token: &str,
+        key: &str,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        if let Some(written) = self.txns.written(db_name, token, key)? {
+            return Ok(written);
+        }
+
+        let data = self.read(db_name, key.as_bytes()).await?;
+        let version = data.as_ref().map_or(0, Data::version);
+        self.txns.record_read(db_name, token, key, version)?;
+        Ok(data.map(Data::into_value))
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn txn_write(
+        &self,
+        db_name: &str,
+        token: &str,
+        write: TxnWrite,
+    ) -> anyhow::Result<()> {
+        self.txns.write(db_name, token, write)
+    }
+
+    // a record read by the transaction and changed since fails the commit with a failed precondition
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn commit_txn(&self, db_name: &str, token: &str) -> anyhow::Result<TxnResult> {
+        let writes = self.txns.commit(db_name, token)?;
+        self.txn(db_name, writes).await
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn rollback_txn(&self, db_name: &str, token: &str) -> anyhow::Result<()> {
+        self.txns.rollback(db_name, token)
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn multi_get(
+        &self,
+        db_name: &str,
+        keys: Vec<String>,
+    ) -> anyhow::Result<Vec<WireRecord>> {
+        match self.r_lock().get(db_name) {
+            Some(db) => {
+                let records = db.multi_get(keys)?;
+                records
+                    .iter()
+                    .filter(|r| r.value.is_some())
+                    .for_each(|r| db.record(r.key.as_bytes(), Access::Read));
+                Ok(records)
+            }
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn read_many(
+        &self,
+        db_name: &str,
+        keys: Vec<Vec<u8>>,
+    ) -> anyhow::Result<Vec<Option<Data>>> {
+        match self.r_lock().get(db_name) {
+            Some(db) => {
+                let records = db.read_many(&keys)?;
+                keys.iter()
+                    .zip(&records)
+                    .filter(|(_, data)| data.is_some())
+                    .for_each(|(key, _)| db.record(key, Access::Read));
+                Ok(records)
+            }
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    // returns the sequence number of the write, None when the key doesn't exist
+    #[instrument(skip_all, fields(db = %db_name, key = %display_key(key)))]
+    pub async fn touch(&self, db_name: &str, key: &[u8], ttl: u128) -> anyhow::Result<Option<u64>> {
+        match self.w_lock().get(db_name) {
+            Some(db) => {
+                let ttl = self.jitter(db_name, &display_key(key), ttl)?;
+                let seq = db.touch(key, ttl)?;
+                if seq.is_some() {
+                    db.record(key, Access::Write);
+                }
+                Ok(seq)
+            }
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    // None when the source key doesn't exist, an existing target is a failed precondition
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn rename(
+        &self,
+        db_name: &str,
+        from: &[u8],
+        to: &[u8],
+    ) -> anyhow::Result<Option<Stored>> {
+        if from == to {
+            return Err(anyhow!(ErrorCtx::Validation(format!(
+                "Can't rename key {} to itself",
+                display_key(from)
+            ))));
+        }
+
+        let stored = match self.w_lock().get(db_name) {
+            Some(db) => {
+                let stored = db.rename(from, to)?;
+                if let Some(stored) = &stored {
+                    self.events
+                        .publish(Event::new(db_name, from, EventKind::Delete).with_seq(stored.seq));
+                    self.events.publish(
+                        Event::new(db_name, to, EventKind::Put)
+                            .with_version(stored.version)
+                            .with_seq(stored.seq),
+                    );
+                }
+                stored
+            }
+            None => return Err(self.missing(db_name)),
+        };
+        if let Some(stored) = &stored {
+            self.replicated(db_name, stored.seq).await?;
+        }
+        Ok(stored)
+    }
+
+    #[instrument(skip_all, fields(db = %db_name, key = %display_key(key)))]
+    pub async fn read(&self, db_name: &str, key: &[u8]) -> anyhow::Result<Option<Data>> {
+        match self.lookup(db_name, key, 0)? {
+            Some((data, _)) => Ok(Some(for_reader(data, &Accepted::default()).await?)),
+            None => Ok(None),
+        }
+    }
+
+    // records past their soft ttl and recently expired ones within the stale window of the db are returned
+    // as stale (true) and a refresh event is published, so read-through setups can reload them while
+    // readers are served
+    // values compressed with a codec the reader accepts are returned compressed
+    #[instrument(skip_all, fields(db = %db_name, key = %display_key(key)))]
+    pub async fn read_stale(
+        &self,
+        db_name: &str,
+        key: &[u8],
+        accepted: &Accepted,
+    ) -> anyhow::Result<Option<(Data, bool)>> {
+        match self.lookup(db_name, key, self.db_cfg.stale_window(db_name))? {
+            Some((data, stale)) => Ok(Some((for_reader(data, accepted).await?, stale))),
+            None => Ok(None),
+        }
+    }
+
+    // the value is returned as stored, still compressed
+    fn lookup(
+        &self,
+        db_name: &str,
+        key: &[u8],
+        stale_window: u128,
+    ) -> anyhow::Result<Option<(Data, bool)>> {
+        match self.r_lock().get(db_name) {
+            Some(db) => {
+                if let Some(bytes) = db.get(key)? {
+                    let data = as_stored(&bytes)?;
+                    if !is_expired(data.ttl)? {
+                        db.record(key, Access::Read);
+                        let stale = is_expired(data.soft_ttl)?;
+                        if stale {
+                            self.events
+                                .publish(Event::new(db_name, key, EventKind::Refresh));
+                        }
+                        Ok(Some((db.assemble(data)?, stale)))
+                    } else if is_expired_after(data.ttl, stale_window)? {
+                        EXPIRED_READS.inc();
+                        self.expire(db, key);
+                        self.events
+                            .publish(Event::new(db_name, key, EventKind::Expire));
+                        if let Some(url) = &self.db_cfg.settings(db_name).expiration_webhook {
+                            self.webhooks
+                                .notify(url, Expiration::new(db_name, key, data.ttl));
+                        }
+                        Ok(None)
+                    } else {
+                        db.record(key, Access::Read);
+                        self.events
+                            .publish(Event::new(db_name, key, EventKind::Refresh));
+                        Ok(Some((db.assemble(data)?, true)))
+                    }
+                } else {
+                    Ok(None)
+                }
+            }
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    fn expire(&self, db: &Db, key: &[u8]) {
+        let db = db.clone();
+        let key = key.to_vec();
+        let _ = self.tx_mutex().send(BoxedFnOnce::new(move || {
+            if let Err(e) = db.remove(&key, EventKind::Expire) {
+                error!("Failed to expire key = {}, e = {}", display_key(&key), e);
+            }
+        }));
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn keys(
+        &self,
+        db_name: &str,
+        cursor: Option<ScanCursor>,
+        limit: usize,
+        encoding: KeyEncoding,
+        prefix: Vec<u8>,
+        namespaces: bool,
+        order: Order,
+    ) -> anyhow::Result<KeyPage> {
+        let delimiter = if namespaces {
+            Some(self.db_cfg.settings(db_name).key_delimiter.into_bytes())
+        } else {
+            None
+        };
+        let listing = Listing::new(prefix, delimiter);
+        match self.r_lock().get(db_name) {
+            Some(db) => {
+                let query = format!(
+                    "keys:{}:{}:{:?}:{:?}:{:?}",
+                    scan_cursor_key(&cursor),
+                    limit,
+                    encoding,
+                    listing,
+                    order
+                );
+                self.cached(db_name, db, query, || {
+                    let operation = self.operations.start(OperationKind::Keys, db_name);
+                    db.keys(
+                        cursor,
+                        limit,
+                        &self.db_cfg.scan_limits().cancellable(&operation),
+                        encoding,
+                        &listing,
+                        order,
+                    )
+                })
+            }
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn query(
+        &self,
+        db_name: &str,
+        field: &str,
+        value: &str,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+        encoding: KeyEncoding,
+    ) -> anyhow::Result<KeyPage> {
+        match self.r_lock().get(db_name) {
+            Some(db) => {
+                let query = format!(
+                    "query:{}:{}:{}:{}:{:?}",
+                    field,
+                    value,
+                    cursor_key(&cursor),
+                    limit,
+                    encoding
+                );
+                self.cached(db_name, db, query, || {
+                    db.query(field, value, cursor, limit, encoding)
+                })
+            }
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn count(
+        &self,
+        db_name: &str,
+        cursor: Option<Vec<u8>>,
+        approx: bool,
+    ) -> anyhow::Result<KeyCount> {
+        match self.r_lock().get(db_name) {
+            Some(db) if approx => db.estimate_count(),
+            Some(db) => {
+                let query = format!("count:{}", cursor_key(&cursor));
+                self.cached(db_name, db, query, || {
+                    let operation = self.operations.start(OperationKind::Count, db_name);
+                    db.count(cursor, self.db_cfg.count_max_time(), &operation.cancel())
+                })
+            }
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    // results are reused while the db hasn't advanced, the sequence is read first so a write racing the
+    // computation leaves an entry that can't hit
+    fn cached<T, F>(&self, db_name: &str, db: &Db, query: String, compute: F) -> anyhow::Result<T>
+    where
+        T: Clone + Send + Sync + 'static,
+        F: FnOnce() -> anyhow::Result<T>,
+    {
+        let ttl = self.db_cfg.settings(db_name).scan_cache();
+        if ttl.as_millis() == 0 {
+            return compute();
+        }
+
+        let seq = db.seq();
+        if let Some(hit) = self.scan_cache.get(db_name, &query, seq, ttl) {
+            return Ok(hit);
+        }
+        let value = compute()?;
+        self.scan_cache.put(db_name, query, seq, value.clone());
+        Ok(value)
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn scan(
+        &self,
+        db_name: &str,
+        cursor: Option<ScanCursor>,
+        limit: usize,
+        encoding: KeyEncoding,
+        filter: ScanFilter,
+        order: Order,
+    ) -> anyhow::Result<ScanPage> {
+        match self.r_lock().get(db_name) {
+            Some(db) => {
+                let query = format!(
+                    "scan:{}:{}:{:?}:{}:{:?}",
+                    scan_cursor_key(&cursor),
+                    limit,
+                    encoding,
+                    filter.cache_key(),
+                    order
+                );
+                self.cached(db_name, db, query, || {
+                    let operation = self.operations.start(OperationKind::Scan, db_name);
+                    db.scan(
+                        cursor,
+                        limit,
+                        &self.db_cfg.scan_limits().cancellable(&operation),
+                        encoding,
+                        &filter,
+                        order,
+                    )
+                })
+            }
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    // pages of raw records for streaming clients, not cached since values aren't encoded
+    pub fn entries(
+        &self,
+        db_name: &str,
+        prefix: &[u8],
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+    ) -> anyhow::Result<(Vec<(Vec<u8>, Vec<u8>)>, Option<Vec<u8>>)> {
+        match self.r_lock().get(db_name) {
+            Some(db) => {
+                let operation = self.operations.start(OperationKind::Scan, db_name);
+                db.entries(
+                    prefix,
+                    cursor,
+                    limit,
+                    &self.db_cfg.scan_limits().cancellable(&operation),
+                )
+            }
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    #[instrument(skip_all, fields(db = %db_name, key = %display_key(key)))]
+    pub async fn access_stats(
+        &self,
+        db_name: &str,
+        key: &[u8],
+    ) -> anyhow::Result<Option<AccessStats>> {
+        match self.r_lock().get(db_name) {
+            Some(db) => db.access_stats(key),
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    #[instrument(skip_all, fields(db = %db_name, key = %display_key(key)))]
+    pub async fn crdt(&self, db_name: &str, key: &[u8]) -> anyhow::Result<Option<Crdt>> {
+        match self.r_lock().get(db_name) {
+            Some(db) => db.crdt(key),
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    #[instrument(skip_all, fields(db = %db_name, key = %display_key(key)))]
+    pub async fn update_crdt(
+        &self,
+        db_name: &str,
+        key: &[u8],
+        op: CrdtOp,
+    ) -> anyhow::Result<CrdtValue> {
+        let replica_id = self.db_cfg.replica_id();
+        match self.w_lock().get(db_name) {
+            Some(db) => {
+                self.check_quota(db_name, db)?;
+                Ok(db
+                    .update_crdt(key, |current| op.into_update(replica_id, current))?
+                    .value())
+            }
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    // joins the state of another replica
+    #[instrument(skip_all, fields(db = %db_name, key = %display_key(key)))]
+    pub async fn merge_crdt(
+        &self,
+        db_name: &str,
+        key: &[u8],
+        state: CrdtState,
+    ) -> anyhow::Result<CrdtValue> {
+        let update = state.into_update(self.db_cfg.replica_id());
+        match self.w_lock().get(db_name) {
+            Some(db) => {
+                self.check_quota(db_name, db)?;
+                Ok(db.update_crdt(key, |_| Ok(update))?.value())
+            }
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn hot_keys(
+        &self,
+        db_name: &str,
+        limit: usize,
+        cold: bool,
+    ) -> anyhow::Result<Vec<KeyStats>> {
+        match self.r_lock().get(db_name) {
+            Some(db) => db.hot_keys(limit, cold),
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    // with an undelete window the record is kept as a tombstone until the window passes
+    #[instrument(skip_all, fields(db = %db_name, key = %display_key(key)))]
+    pub async fn remove(&self, db_name: &str, key: &[u8]) -> anyhow::Result<u64> {
+        let window = self.db_cfg.settings(db_name).undelete_window();
+        let seq = match self.w_lock().get(db_name) {
+            Some(db) => {
+                let seq = if window > 0 {
+                    db.bury(key, current_ms()? + window)?
+                } else {
+                    db.remove(key, EventKind::Delete)?
+                };
+                KEY_OPERATIONS.with_label_values(&["delete"]).inc();
+                self.events
+                    .publish(Event::new(db_name, key, EventKind::Delete).with_seq(seq));
+                seq
+            }
+            None => return Err(self.missing(db_name)),
+        };
+        self.replicated(db_name, seq).await?;
+        Ok(seq)
+    }
+
+    // in key order, whether each key held a live record
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn remove_many(
+        &self,
+        db_name: &str,
+        keys: Vec<Vec<u8>>,
+    ) -> anyhow::Result<(Vec<bool>, u64)> {
+        let window = self.db_cfg.settings(db_name).undelete_window();
+        let (found, seq) = match self.w_lock().get(db_name) {
+            Some(db) => {
+                let purge_at = if window > 0 {
+                    Some(current_ms()? + window)
+                } else {
+                    None
+                };
+                let (found, seq) = db.remove_many(&keys, purge_at)?;
+                KEY_OPERATIONS
+                    .with_label_values(&["delete"])
+                    .inc_by(keys.len() as u64);
+                let mut published = HashSet::with_capacity(keys.len());
+                for key in keys.iter().filter(|key| published.insert(*key)) {
+                    self.events
+                        .publish(Event::new(db_name, key, EventKind::Delete).with_seq(seq));
+                }
+                (found, seq)
+            }
+            None => return Err(self.missing(db_name)),
+        };
+        self.replicated(db_name, seq).await?;
+        Ok((found, seq))
+    }
+
+    // None when there's no tombstone for the key or its window has passed
+    #[instrument(skip_all, fields(db = %db_name, key = %display_key(key)))]
+    pub async fn undelete(&self, db_name: &str, key: &[u8]) -> anyhow::Result<Option<Stored>> {
+        let stored = match self.w_lock().get(db_name) {
+            Some(db) => {
+                self.check_quota(db_name, db)?;
+                let stored = db.undelete(key)?;
+                if let Some(stored) = &stored {
+                    self.events.publish(
+                        Event::new(db_name, key, EventKind::Put)
+                            .with_version(stored.version)
+                            .with_seq(stored.seq),
+                    );
+                }
+                stored
+            }
+            None => return Err(self.missing(db_name)),
+        };
+        if let Some(stored) = &stored {
+            self.replicated(db_name, stored.seq).await?;
+        }
+        Ok(stored)
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn remove_range(&self, db_name: &str, start: &str, end: &str) -> anyhow::Result<u64> {
+        if start >= end {
+            return Err(anyhow!(ErrorCtx::Validation(format!(
+                "Range start {} must be lower than range end {}",
+                start, end
+            ))));
+        }
+
+        self.delete_range(db_name, start.as_bytes(), end.as_bytes())
+            .await
+    }
+
+    // everything under the prefix, as a range delete up to the first key past it
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn remove_prefix(&self, db_name: &str, prefix: &[u8]) -> anyhow::Result<u64> {
+        match prefix_end(prefix) {
+            Some(end) => self.delete_range(db_name, prefix, &end).await,
+            None => Err(anyhow!(ErrorCtx::Validation(format!(
+                "Prefix {} matches the whole key space, use a range delete",
+                display_key(prefix)
+            )))),
+        }
+    }
+
+    async fn delete_range(&self, db_name: &str, start: &[u8], end: &[u8]) -> anyhow::Result<u64> {
+        let seq = match self.w_lock().get(db_name) {
+            Some(db) => {
+                let seq = db.remove_range(start, end)?;
+                self.events.publish(
+                    Event::new(db_name, start, EventKind::DeleteRange)
+                        .with_end(end)
+                        .with_seq(seq),
+                );
+                seq
+            }
+            None => return Err(self.missing(db_name)),
+        };
+        self.replicated(db_name, seq).await?;
+        Ok(seq)
+    }
+
+    // changes shipped from another deployment are applied in order by the db's conflict policy,
+    // range deletes are applied as they come
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn replicate(
+        &self,
+        db_name: &str,
+        changes: Vec<Change>,
+    ) -> anyhow::Result<ReplicationResult> {
+        let changes = changes
+            .into_iter()
+            .map(Change::decode)
+            .collect::<anyhow::Result<Vec<Replicated>>>()?;
+        let policy = self.db_cfg.settings(db_name).replication_conflict;
+        match self.w_lock().get(db_name) {
+            Some(db) => {
+                let mut result = ReplicationResult::default();
+                for change in changes {
+                    if let Some(end) = &change.end {
+                        let seq = db.remove_range(&change.key, end)?;
+                        self.events.publish(
+                            Event::new(db_name, &change.key, EventKind::DeleteRange)
+                                .with_end(end)
+                                .with_seq(seq),
+                        );
+                        result.applied += 1;
+                        continue;
+                    }
+
+                    match db.apply(&change, policy)? {
+                        Some(stored) => {
+                            let event = match change.value {
+                                Some(_) => Event::new(db_name, &change.key, EventKind::Put)
+                                    .with_version(stored.version),
+                                None => Event::new(db_name, &change.key, EventKind::Delete),
+                            };
+                            self.events.publish(event.with_seq(stored.seq));
+                            result.applied += 1;
+                        }
+                        None => result.skipped += 1,
+                    }
+                }
+                Ok(result)
+            }
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    // the current state is sampled on request so a forecast is available right after startup
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn forecast(&self, db_name: &str, days: u32) -> anyhow::Result<Forecast> {
+        let db = match self.r_lock().get(db_name) {
+            Some(db) => db.clone(),
+            None => return Err(self.missing(db_name)),
+        };
+        let mut samples = self.usage.samples(db_name);
+        samples.push(usage_sample(&self.db_cfg, &self.usage, db_name, &db)?);
+        Ok(forecast(&samples, days))
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn change_log(
+        &self,
+        db_name: &str,
+        since: u64,
+        limit: usize,
+    ) -> anyhow::Result<ChangesPage> {
+        match self.r_lock().get(db_name) {
+            Some(db) => db.change_log(since, limit),
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    // changes of keys starting with the prefix, of all keys without one
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn watch(
+        &self,
+        db_name: &str,
+        prefix: Option<&str>,
+    ) -> anyhow::Result<futures::channel::mpsc::Receiver<Event>> {
+        if self.not_contains(db_name) {
+            return Err(self.missing(db_name));
+        }
+        let filter = match prefix {
+            Some(prefix) => Filter::prefix(db_name, prefix),
+            None => Filter::db(db_name),
+        };
+        Ok(self.events.subscribe(filter))
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn seq(&self, db_name: &str) -> anyhow::Result<u64> {
+        match self.r_lock().get(db_name) {
+            Some(db) => Ok(db.seq()),
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn internals(&self, db_name: &str) -> anyhow::Result<Internals> {
+        match self.r_lock().get(db_name) {
+            Some(db) => db.internals(),
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn quota(&self, db_name: &str) -> anyhow::Result<QuotaInfo> {
+        match self.r_lock().get(db_name) {
+            Some(db) => Ok(QuotaInfo {
+                quota: self.db_cfg.quota(db_name),
+                usage: db.quota_usage()?,
+            }),
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn compression_stats(&self, db_name: &str) -> anyhow::Result<CompressionStats> {
+        match self.r_lock().get(db_name) {
+            Some(db) => db.compression_stats(),
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    // number of migrated records by db
+    pub fn migrate(&self) -> anyhow::Result<Vec<(String, u64)>> {
+        let dbs = self.r_lock();
+        let mut names: Vec<&String> = dbs.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| {
+                info!("Migrating db = {}", name);
+                let operation = self.operations.start(OperationKind::Migration, name);
+                Ok((name.clone(), dbs[name].migrate(&operation.cancel())?))
+            })
+            .collect()
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn live_files(&self, db_name: &str) -> anyhow::Result<Vec<LiveFileInfo>> {
+        match self.r_lock().get(db_name) {
+            Some(db) => db.live_files(),
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn property(&self, db_name: &str, name: &str) -> anyhow::Result<Option<String>> {
+        match self.r_lock().get(db_name) {
+            Some(db) => db.raw_property(name),
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn backup(&self, db_name: &str) -> anyhow::Result<Vec<BackupInfo>> {
+        let db = self.on_disk(db_name)?;
+
+        let _guard = self.backup_lock.lock().expect("Can't acquire backup lock");
+        let dir = self.db_cfg.backup_path(db_name);
+        info!("Creating backup of db = {} in {}", db_name, &dir);
+        annotated(&dir, db.backup(&dir, self.db_cfg.backups_to_keep())?)
+    }
+
+    // full compaction of every column family, records removed by the compaction filter are gone afterwards
+    #[instrument(skip_all, fields(db = %db_name))]
+    // compacted on its own thread once the maintenance schedule is open, listed in operations meanwhile
+    pub fn compact(&self, db_name: &str) -> anyhow::Result<OperationInfo> {
+        let db = match self.r_lock().get(db_name) {
+            Some(db) => db.clone(),
+            None => return Err(self.missing(db_name)),
+        };
+        let operation = self.operations.start(OperationKind::Compaction, db_name);
+        let started = operation.info();
+        let maintenance = self.maintenance.clone();
+        let db_name = db_name.to_string();
+        thread::Builder::new()
+            .name("compaction-thread".into())
+            .spawn(move || {
+                while !is_maintenance_open(&maintenance) {
+                    if operation.is_cancelled() {
+                        info!("Compaction of db = {} cancelled", &db_name);
+                        return;
+                    }
+                    thread::sleep(MAINTENANCE_CHECK_INTERVAL);
+                }
+
+                info!("Compacting db = {}", &db_name);
+                match db.compact(Some(&operation.cancel())) {
+                    Ok(()) => info!("Db = {} compacted", &db_name),
+                    Err(e) => warn!("Compaction of db = {} stopped, e = {}", &db_name, e),
+                }
+            })?;
+        Ok(started)
+    }
+
+    // each checkpoint gets its own directory, they're never removed by the service
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn checkpoint(&self, db_name: &str) -> anyhow::Result<CheckpointInfo> {
+        let db = self.on_disk(db_name)?;
+
+        let parent = self.db_cfg.checkpoint_path(db_name);
+        fs::create_dir_all(&parent)?;
+        let created_at = current_ms()?;
+        let dir = format!("{}/{}", parent, created_at);
+        info!("Creating checkpoint of db = {} in {}", db_name, &dir);
+        db.checkpoint(&dir)?;
+        Ok(CheckpointInfo::new(dir, created_at))
+    }
+
+    // exports are streamed from a checkpoint kept for resuming, a resume of an export that's gone starts a new one
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn export(
+        &self,
+        db_name: &str,
+        resume: Option<(&str, u64)>,
+    ) -> anyhow::Result<Export> {
+        let db = self.on_disk(db_name)?;
+        let parent = self.db_cfg.checkpoint_path(db_name);
+        let keep = self.db_cfg.export_resume_window() > 0;
+
+        if let Some((id, offset)) = resume {
+            let dir = format!("{}/{}", parent, dir_name(id));
+            if let Some(export) = self.exports.stream(id, dir.into(), db_name, offset, keep)? {
+                info!("Resuming export = {} of db = {} at {}", id, db_name, offset);
+                return Ok(export);
+            }
+        }
+
+        fs::create_dir_all(&parent)?;
+        let mut created_at = current_ms()?;
+        while Path::new(&format!("{}/{}", parent, dir_name(&created_at.to_string()))).exists() {
+            created_at += 1;
+        }
+        let id = created_at.to_string();
+        let dir = format!("{}/{}", parent, dir_name(&id));
+        info!("Exporting db = {} from {}", db_name, &dir);
+        db.checkpoint(&dir)?;
+        self.exports
+            .stream(&id, dir.clone().into(), db_name, 0, keep)?
+            .ok_or_else(|| anyhow!("Export checkpoint {} is missing", dir))
+    }
+
+    // uploads are staged in the data path so ingestion stays on the same file system,
+    // names starting with _ can't clash with dbs
+    pub fn import_staging(&self, db_name: &str) -> anyhow::Result<StagingDir> {
+        self.on_disk(db_name)?;
+        Ok(StagingDir::create(format!(
+            "{}/_import/{}-{}",
+            self.db_cfg.path(),
+            db_name,
+            current_ms()?
+        ))?)
+    }
+
+    // returns the sequence number after ingestion, no events are published for ingested records
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn import(&self, db_name: &str, files: Vec<PathBuf>) -> anyhow::Result<u64> {
+        if files.is_empty() {
+            return Err(anyhow!(ErrorCtx::Validation(
+                "No SST files to import".into()
+            )));
+        }
+
+        match self.r_lock().get(db_name) {
+            Some(db) => {
+                info!("Ingesting {} files into db = {}", files.len(), db_name);
+                db.ingest(files)
+            }
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    // the db is unavailable while its files are replaced, requests get 503 meanwhile - it blocks until requests in
+    // flight release the db, so it's called off the async workers
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub fn restore(&self, db_name: &str, backup_id: Option<u32>) -> anyhow::Result<()> {
+        let _transition = futures::executor::block_on(self.lifecycle.begin(db_name));
+        let _guard = self.backup_lock.lock().expect("Can't acquire backup lock");
+        let dir = self.db_cfg.backup_path(db_name);
+        self.on_disk(db_name)?;
+        let mut engine = open_engine(&dir)?;
+        let existing = backups(&engine);
+        if existing.is_empty() {
+            return Err(anyhow!(ErrorCtx::Validation(format!(
+                "Db {} has no backups",
+                db_name
+            ))));
+        }
+        if let Some(id) = backup_id {
+            if !existing.iter().any(|b| b.id() == id) {
+                return Err(anyhow!(ErrorCtx::Validation(format!(
+                    "Backup {} of db {} doesn't exist",
+                    id, db_name
+                ))));
+            }
+        }
+
+        self.recovering
+            .write()
+            .expect("Can't acquire write lock")
+            .insert(db_name.to_string());
+        let res = self.restore_db(&mut engine, db_name, backup_id);
+        self.recovering
+            .write()
+            .expect("Can't acquire write lock")
+            .remove(db_name);
+        res
+    }
+
+    fn restore_db(
+        &self,
+        engine: &mut BackupEngine,
+        db_name: &str,
+        backup_id: Option<u32>,
+    ) -> anyhow::Result<()> {
+        let db = match self.w_lock().remove(db_name) {
+            Some(db) => db,
+            None => return Err(not_exists(db_name)),
+        };
+        db.release_pins();
+        let path = db.path();
+
+        // requests in flight hold the handle, the db lock file is released only after it's dropped
+        let started = Instant::now();
+        while !db.is_released() {
+            if started.elapsed() > RELEASE_TIMEOUT {
+                // the released pins stay released, listings pin anew
+                let db = Db {
+                    pins: Default::default(),
+                    ..db
+                };
+                self.w_lock().insert(db_name.to_string(), db);
+                return Err(anyhow!(ErrorCtx::Unavailable(format!(
+                    "Db {} is busy, try restoring again",
+                    db_name
+                ))));
+            }
+            thread::sleep(RELEASE_CHECK_INTERVAL);
+        }
+        drop(db);
+
+        info!(
+            "Restoring db = {} from backup = {:?}",
+            db_name,
+            backup_id.map_or("latest".to_string(), |id| id.to_string())
+        );
+        let opts = RestoreOptions::default();
+        let restored = match backup_id {
+            Some(id) => engine.restore_from_backup(&path, &path, &opts, id),
+            None => engine.restore_from_latest_backup(&path, &path, &opts),
+        };
+        if let Err(e) = &restored {
+            error!("Failed to restore db = {}, e = {}", db_name, e);
+        }
+
+        // reopened either way, a failed restore leaves the previous files in place
+        let overrides = self.stored_overrides(db_name)?;
+        self.open_on_path(
+            db_name.to_string(),
+            path.to_string_lossy().into(),
+            false,
+            &overrides,
+        )?;
+        Ok(restored?)
+    }
+
+    // snapshots are read from their own files, the db doesn't have to be open
+    #[instrument(skip_all, fields(db = %db_name, key = %key))]
+    pub async fn snapshot_read(
+        &self,
+        id: &str,
+        db_name: &str,
+        key: &str,
+    ) -> anyhow::Result<Option<Data>> {
+        let rock = self.mount_snapshot(id, db_name)?;
+        match rock.get(key)? {
+            Some(bytes) => Ok(Some(bytes.as_struct()?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn unmount_snapshot(&self, id: &str, db_name: &str) -> bool {
+        self.snapshots.unmount(id, db_name)
+    }
+
+    fn mount_snapshot(&self, id: &str, db_name: &str) -> anyhow::Result<Arc<DB>> {
+        if db_name.starts_with('.') || db_name.starts_with('_') {
+            return Err(anyhow!(ErrorCtx::Validation(format!(
+                "Invalid database name {}",
+                db_name
+            ))));
+        }
+        let snapshot = SnapshotId::parse(id)?;
+        let dir = format!(
+            "{}/{}",
+            self.db_cfg.checkpoint_path(db_name),
+            snapshot.dir_name()
+        );
+        if let SnapshotId::Backup(backup_id) = snapshot {
+            self.restore_snapshot(db_name, backup_id, &dir)?;
+        }
+        if !Path::new(&dir).exists() {
+            return Err(anyhow!(ErrorCtx::NotFound(format!(
+                "Snapshot {} of db {} doesn't exist",
+                id, db_name
+            ))));
+        }
+        self.snapshots
+            .mount(id, db_name, &dir, &db_options(&self.db_cfg, None))
+    }
+
+    // a backup is restored next to the checkpoints once, a missing backup leaves the dir missing
+    fn restore_snapshot(&self, db_name: &str, backup_id: u32, dir: &str) -> anyhow::Result<()> {
+        let _guard = self.backup_lock.lock().expect("Can't acquire backup lock");
+        let backup_dir = self.db_cfg.backup_path(db_name);
+        if Path::new(dir).exists() || !Path::new(&backup_dir).exists() {
+            return Ok(());
+        }
+        let mut engine = open_engine(&backup_dir)?;
+        if !backups(&engine).iter().any(|b| b.id() == backup_id) {
+            return Ok(());
+        }
+
+        info!(
+            "Restoring backup = {} of db = {} into {}",
+            backup_id, db_name, dir
+        );
+        let restored = engine.restore_from_backup(dir, dir, &RestoreOptions::default(), backup_id);
+        if restored.is_err() {
+            let _ = fs::remove_dir_all(dir);
+        }
+        Ok(restored?)
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn backups(&self, db_name: &str) -> anyhow::Result<Vec<BackupInfo>> {
+        if self.not_contains(db_name) {
+            return Err(self.missing(db_name));
+        }
+
+        let _guard = self.backup_lock.lock().expect("Can't acquire backup lock");
+        let dir = self.db_cfg.backup_path(db_name);
+        if !Path::new(&dir).exists() {
+            return Ok(vec![]);
+        }
+        annotated(&dir, backups(&open_engine(&dir)?))
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn manifest(&self, db_name: &str) -> anyhow::Result<ManifestInfo> {
+        self.on_disk(db_name)?.manifest()
+    }
+
+    #[instrument(skip_all, fields(db = %db_name))]
+    pub async fn approximate_size(
+        &self,
+        db_name: &str,
+        start: &str,
+        end: &str,
+    ) -> anyhow::Result<ApproximateSize> {
+        match self.r_lock().get(db_name) {
+            Some(db) => db.approximate_size(start, end),
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    // a db being recovered or restored is temporarily unavailable rather than missing
+    fn missing(&self, db_name: &str) -> anyhow::Error {
+        if self.is_recovering(db_name) {
+            anyhow!(ErrorCtx::Unavailable(format!(
+                "Db {} is being recovered and is temporarily unavailable",
+                db_name
+            )))
+        } else {
+            not_exists(db_name)
+        }
+    }
+
+    fn is_recovering(&self, db_name: &str) -> bool {
+        self.recovering
+            .read()
+            .expect("Can't acquire read lock")
+            .contains(db_name)
+    }
+
+    // reasons the instance can't serve traffic, empty when it's ready
+    pub fn readiness(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let registered: Vec<String> = self
+            .root_db
+            .r_lock()
+            .iterator(IteratorMode::Start)
+            .filter_map(|(k, _)| bytes_to_str(&k).ok())
+            .collect();
+        if let Err(e) = self.root_db.get(b"") {
+            problems.push(format!("Root db isn't readable - {}", e));
+        }
+        for name in registered {
+            if self.is_recovering(&name) {
+                problems.push(format!("Db {} is recovering", name));
+            } else if self.not_contains(&name) {
+                problems.push(format!("Db {} isn't loaded", name));
+            }
+        }
+        let probe = Path::new(self.db_cfg.path()).join(format!(
+            ".ready-{}-{}",
+            std::process::id(),
+            READINESS_PROBES.fetch_add(1, Ordering::Relaxed)
+        ));
+        if let Err(e) = fs::write(&probe, b"").and_then(|_| fs::remove_file(&probe)) {
+            problems.push(format!("Data path isn't writable - {}", e));
+        }
+        // the receiver is dropped when the thread dies
+        if self.tx_mutex().send(BoxedFnOnce::new(|| {})).is_err() {
+            problems.push("Expiration thread isn't running".into());
+        }
+        problems
+    }
+
+    pub fn open_count(&self) -> usize {
+        self.r_lock().len()
+    }
+
+    pub fn list(&self) -> Vec<DbInfo> {
+        let mut dbs: Vec<DbInfo> = self
+            .r_lock()
+            .iter()
+            .map(|(name, db)| DbInfo {
+                name: name.clone(),
+                path: db.path().to_string_lossy().into(),
+                opened_at: db.opened_at,
+                ephemeral: db.is_ephemeral(),
+            })
+            .collect();
+        dbs.sort_by(|a, b| a.name.cmp(&b.name));
+        dbs
+    }
+
+    pub fn contains(&self, db_name: &str) -> bool {
+        self.r_lock().contains_key(db_name)
+    }
+
+    // operations on db files, ephemeral dbs have none
+    fn on_disk(&self, db_name: &str) -> anyhow::Result<Db> {
+        match self.r_lock().get(db_name) {
+            Some(db) if db.is_ephemeral() => Err(anyhow!(ErrorCtx::Validation(format!(
+                "Db {} is ephemeral and has no files",
+                db_name
+            )))),
+            Some(db) => Ok(db.clone()),
+            None => Err(self.missing(db_name)),
+        }
+    }
+
+    fn not_contains(&self, db_name: &str) -> bool {
+        !self.contains(db_name)
+    }
+
+    fn tx_mutex(&self) -> MutexGuard<'_, Sender<BoxedFnOnce>> {
+        self.tx
+            .lock()
+            .expect("Failed to acquire channel sender lock")
+    }
+
+    fn r_lock(&self) -> ShardedLockReadGuard<'_, HashMap<String, Db>> {
+        self.dbs.read().expect("Can't acquire read lock")
+    }
+
+    fn w_lock(&self) -> ShardedLockWriteGuard<'_, HashMap<String, Db>> {
+        self.dbs.write().expect("Can't acquire write lock")
+    }
+}
+
+fn open_db(
+    db_cfg: &DbConfig,
+    db_name: &str,
+    path: &str,
+    ephemeral: bool,
+    overrides: &RocksOverrides,
+) -> anyhow::Result<Db> {
+    let settings = db_cfg.settings(db_name);
+    if settings.durability == Durability::None {
+        info!("Db = {} will be opened with WAL disabled", db_name);
+    }
+
+    let cache = db_cfg.block_cache();
+    let mut opts = db_options(db_cfg, cache.as_ref());
+    overrides.apply(&mut opts);
+    let env = if ephemeral {
+        let env = Env::mem_env()?;
+        opts.set_env(&env);
+        Some(env)
+    } else {
+        None
+    };
+
+    // every existing column family has to be opened, even if its feature was switched off
+    let mut cfs: Vec<String> = DB::list_cf(&opts, path).unwrap_or_default();
+    if !cfs.iter().any(|cf| cf == DEFAULT_CF) {
+        cfs.push(DEFAULT_CF.into());
+    }
+    if settings.access_stats && !cfs.iter().any(|cf| cf == STATS_CF) {
+        cfs.push(STATS_CF.into());
+    }
+    if settings.undelete_window() > 0 && !cfs.iter().any(|cf| cf == TOMBSTONE_CF) {
+        cfs.push(TOMBSTONE_CF.into());
+    }
+    if settings.crdts && !cfs.iter().any(|cf| cf == CRDT_CF) {
+        cfs.push(CRDT_CF.into());
+    }
+    if settings.change_log && !cfs.iter().any(|cf| cf == CHANGELOG_CF) {
+        cfs.push(CHANGELOG_CF.into());
+    }
+    if !settings.indexes.is_empty() && !cfs.iter().any(|cf| cf == INDEX_CF) {
+        cfs.push(INDEX_CF.into());
+    }
+    if settings.multipart_uploads && !cfs.iter().any(|cf| cf == UPLOADS_CF) {
+        cfs.push(UPLOADS_CF.into());
+    }
+    let cf_names = Arc::new(cfs.clone());
+    let cfs = cfs
+        .into_iter()
+        .map(|cf| cf_descriptor(db_cfg, cache.as_ref(), overrides, cf))
+        .collect();
+
+    let sampler = if settings.access_stats {
+        Some(Sampler::new(settings.access_stats_sample))
+    } else {
+        None
+    };
+
+    let db = Db::new(path, &opts, cfs, settings.durability, cache, sampler, env)?;
+    Ok(Db {
+        compression: settings.compression(),
+        compression_min_bytes: settings.compression_min_bytes,
+        json: settings.json,
+        indexes: settings.indexes,
+        pin_listings: db_cfg.scan_snapshot_idle().is_some(),
+        overrides: overrides.clone(),
+        cf_names,
+        ..db
+    })
+}
+
+fn db_options(db_cfg: &DbConfig, cache: Option<&Cache>) -> Options {
+    let mut opts = db_cfg.rocks_options(cache);
+    opts.create_missing_column_families(true);
+    opts.set_merge_operator_associative(APPEND_OPERATOR, append_merge);
+    opts
+}
+
+fn cf_descriptor(
+    db_cfg: &DbConfig,
+    cache: Option<&Cache>,
+    overrides: &RocksOverrides,
+    name: String,
+) -> ColumnFamilyDescriptor {
+    match name.as_str() {
+        // records live in the default column family, expired ones are dropped when they're compacted
+        DEFAULT_CF => {
+            let mut opts = db_options(db_cfg, cache);
+            opts.set_compaction_filter("expiration-filter", compaction_filter);
+            overrides.apply(&mut opts);
+            ColumnFamilyDescriptor::new(name, opts)
+        }
+        STATS_CF => {
+            let mut opts = Options::default();
+            opts.set_merge_operator_associative(STATS_OPERATOR, stats_merge);
+            ColumnFamilyDescriptor::new(name, opts)
+        }
+        CRDT_CF => {
+            let mut opts = Options::default();
+            opts.set_merge_operator_associative(CRDT_OPERATOR, crdt_merge);
+            ColumnFamilyDescriptor::new(name, opts)
+        }
+        _ => ColumnFamilyDescriptor::new(name, Options::default()),
+    }
+}
+
+fn crdt_cf(rock: &DB) -> anyhow::Result<&ColumnFamily> {
+    rock.cf_handle(CRDT_CF).ok_or_else(|| {
+        anyhow!(ErrorCtx::Validation(
+            "Crdts are not enabled for this db".into()
+        ))
+    })
+}
+
+fn overrides_cf(rock: &DB) -> anyhow::Result<&ColumnFamily> {
+    rock.cf_handle(OVERRIDES_CF)
+        .ok_or_else(|| anyhow!("Missing {} column family", OVERRIDES_CF))
+}
+
+fn uploads_cf(rock: &DB) -> anyhow::Result<&ColumnFamily> {
+    rock.cf_handle(UPLOADS_CF).ok_or_else(|| {
+        anyhow!(ErrorCtx::Validation(
+            "Multipart uploads are not enabled for this db".into()
+        ))
+    })
+}
+
+fn started_upload(
+    rock: &DB,
+    cf: &ColumnFamily,
+    upload_id: &str,
+    key: &[u8],
+) -> anyhow::Result<Upload> {
+    if !is_upload_id(upload_id) {
+        return Err(upload_not_found(upload_id, key));
+    }
+    match rock.get_cf(cf, meta_key(upload_id))? {
+        Some(bytes) => {
+            let upload: Upload = bincode::deserialize(&bytes)?;
+            upload.check_key(upload_id, key)?;
+            Ok(upload)
+        }
+        None => Err(upload_not_found(upload_id, key)),
+    }
+}
+
+// whether the record or the tombstone under key holds the chunks of the upload
+fn holds_chunks(rock: &DB, key: &[u8], upload_id: &str) -> anyhow::Result<bool> {
+    let tombstone = match rock.cf_handle(TOMBSTONE_CF) {
+        Some(cf) => match rock.get_cf(cf, key)? {
+            Some(bytes) => Some(bincode::deserialize::<Tombstone>(&bytes)?.record),
+            None => None,
+        },
+        None => None,
+    };
+    for bytes in rock.get(key)?.into_iter().chain(tombstone) {
+        let data: Data = bytes.as_struct()?;
+        if data.chunks.map_or(false, |c| c.upload_id == upload_id) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// the value of a record completed from an upload is read back from its chunks, others are returned as they are
+// large values are decompressed off the worker, so it keeps serving other requests meanwhile
+async fn for_reader(data: Data, accepted: &Accepted) -> anyhow::Result<Data> {
+    if !data.decompresses_for(accepted) {
+        return Ok(data.for_reader(accepted)?);
+    }
+    if data.size() < OFFLOAD_DECOMPRESSION_BYTES {
+        READ_DECOMPRESSIONS.with_label_values(&["inline"]).inc();
+        return Ok(data.for_reader(accepted)?);
+    }
+
+    READ_DECOMPRESSIONS.with_label_values(&["blocking"]).inc();
+    let accepted = accepted.clone();
+    match web::block(move || data.for_reader(&accepted)).await {
+        Ok(data) => Ok(data),
+        Err(BlockingError::Error(e)) => Err(e.into()),
+        Err(BlockingError::Canceled) => Err(anyhow!("Decompression was canceled")),
+    }
+}
+
+fn assembled(rock: &DB, mut data: Data) -> anyhow::Result<Data> {
+    let chunks = match data.chunks.take() {
+        Some(chunks) => chunks,
+        None => return Ok(data),
+    };
+    let cf = uploads_cf(rock)?;
+    let mut value = Vec::with_capacity(chunks.size as usize);
+    for (_, part) in upload_parts(rock, cf, &chunks.upload_id).take(chunks.parts as usize) {
+        value.extend_from_slice(&part);
+    }
+    if value.len() as u64 != chunks.size {
+        return Err(anyhow!(
+            "Chunks of upload {} are missing, read {} of {} bytes",
+            chunks.upload_id,
+            value.len(),
+            chunks.size
+        ));
+    }
+    data.data = value;
+    Ok(data)
+}
+
+// ordered by part number
+fn upload_parts<'a>(
+    rock: &'a DB,
+    cf: &ColumnFamily,
+    upload_id: &str,
+) -> impl Iterator<Item = (u32, Box<[u8]>)> + 'a {
+    let (start, end) = upload_range(upload_id);
+    rock.iterator_cf(cf, IteratorMode::From(&start, Direction::Forward))
+        .take_while(move |(k, _)| k.as_ref() < end.as_slice())
+        .filter_map(|(k, v)| part_number(&k).map(|number| (number, v)))
+}
+
+// backups can't be rewritten, every backup still listed once the db is erased was taken before it finished
+fn erase_db(
+    db_cfg: &DbConfig,
+    backup_lock: &Mutex<()>,
+    db_name: &str,
+    db: &Db,
+    matcher: &RegexSet,
+    erasure_id: &str,
+) -> anyhow::Result<DbErasure> {
+    info!("Erasing keys of db = {}", db_name);
+    let mut erased = db.erase(db_name, matcher)?;
+    if db.is_ephemeral() {
+        return Ok(erased);
+    }
+    erased.checkpoints = checkpoint_dirs(&db_cfg.checkpoint_path(db_name))?;
+    let dir = db_cfg.backup_path(db_name);
+    if !Path::new(&dir).exists() {
+        return Ok(erased);
+    }
+
+    let _guard = backup_lock.lock().expect("Can't acquire backup lock");
+    erased.backups = backups(&open_engine(&dir)?)
+        .iter()
+        .map(BackupInfo::id)
+        .collect();
+    annotate_backups(&dir, &erased.backups, erasure_id)?;
+    Ok(erased)
+}
+
+// checkpoints, export checkpoints and restored snapshots are all kept under the checkpoint path of the db
+fn checkpoint_dirs(parent: &str) -> anyhow::Result<Vec<String>> {
+    if !Path::new(parent).exists() {
+        return Ok(vec![]);
+    }
+    let mut dirs = vec![];
+    for entry in fs::read_dir(parent)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+// erasures whose keys a backup still holds
+fn annotated(dir: &str, backups: Vec<BackupInfo>) -> anyhow::Result<Vec<BackupInfo>> {
+    let mut annotations = backup_annotations(dir)?;
+    Ok(backups
+        .into_iter()
+        .map(|b| {
+            let erasures = annotations.remove(&b.id()).unwrap_or_default();
+            b.with_erasures(erasures)
+        })
+        .collect())
+}
+
+fn key_matches(matcher: &RegexSet, key: &[u8]) -> bool {
+    matcher.is_match(&String::from_utf8_lossy(key))
+}
+
+// whether an entry of the column family holds a matching key - the change log and uploads hold it in their values,
+// uploads are matched by their meta entry only, index entries end with it and the other column families are keyed
+// by record keys
+fn entry_matches(cf_name: &str, matcher: &RegexSet, k: &[u8], v: &[u8]) -> anyhow::Result<bool> {
+    match cf_name {
+        CHANGELOG_CF => log_entry_matches(matcher, v),
+        UPLOADS_CF if part_number(k).is_some() => Ok(false),
+        UPLOADS_CF => Ok(key_matches(
+            matcher,
+            &bincode::deserialize::<Upload>(v)?.key,
+        )),
+        INDEX_CF => Ok(entry_keys(k).any(|key| key_matches(matcher, key))),
+        _ => Ok(key_matches(matcher, k)),
+    }
+}
+
+// range deletes are logged with both ends, either one may be an erased key
+fn log_entry_matches(matcher: &RegexSet, bytes: &[u8]) -> anyhow::Result<bool> {
+    let (key, end) = serde_json::from_slice::<LogEntry>(bytes)?.keys()?;
+    Ok(key_matches(matcher, &key) || end.map_or(false, |end| key_matches(matcher, &end)))
+}
+
+fn cursor_key(cursor: &Option<Vec<u8>>) -> String {
+    cursor.as_deref().map(encode_cursor).unwrap_or_default()
+}
+
+// to the last key before the given one
+fn seek_before(iter: &mut DBRawIterator, key: &[u8]) {
+    iter.seek_for_prev(key);
+    if iter.key() == Some(key) {
+        iter.prev();
+    }
+}
+
+fn scan_cursor_key(cursor: &Option<ScanCursor>) -> String {
+    cursor.as_ref().map(ScanCursor::encode).unwrap_or_default()
+}
+
+fn open_root_db(db_cfg: &DbConfig) -> anyhow::Result<Db> {
+    let path = db_cfg.db_path(ROOT_DB_NAME);
+    let opts = db_cfg.root_db_options();
+    let mut cfs: Vec<String> = DB::list_cf(&opts, &path).unwrap_or_default();
+    for cf in &[DEFAULT_CF, OUTBOUND_CF, OVERRIDES_CF] {
+        if !cfs.iter().any(|existing| existing == cf) {
+            cfs.push(cf.to_string());
+        }
+    }
+    Db::new(
+        path,
+        &opts,
+        cfs.into_iter()
+            .map(|cf| ColumnFamilyDescriptor::new(cf, Options::default()))
+            .collect(),
+        Durability::Full,
+        None,
+        None,
+        None,
+    )
+}
+
+fn is_maintenance_open(maintenance: &ShardedLock<Schedule>) -> bool {
+    maintenance
+        .read()
+        .expect("Can't acquire maintenance lock")
+        .is_open(current_ms().unwrap_or_default())
+}
+
+fn usage_sample(
+    db_cfg: &DbConfig,
+    usage: &UsageHistory,
+    db_name: &str,
+    db: &Db,
+) -> anyhow::Result<Sample> {
+    Ok(Sample {
+        at: current_ms()?,
+        bytes: dir_size(db_cfg.db_path(db_name))?,
+        seq: db.seq(),
+        expired: usage.expired(db_name),
+    })
+}
+
+fn sweep(
+    db_cfg: &DbConfig,
+    events: &EventBus,
+    usage: &UsageHistory,
+    webhooks: &Webhooks,
+    db_name: &str,
+    db: &Db,
+) {
+    let settings = db_cfg.settings(db_name);
+    match db.sweep(&settings) {
+        Ok((purged, evicted)) => {
+            usage.record_expired(db_name, purged.len());
+            if !purged.is_empty() || !evicted.is_empty() {
+                info!(
+                    "Swept db = {}, expired = {}, evicted = {}",
+                    db_name,
+                    purged.len(),
+                    evicted.len()
+                );
+            }
+            SWEPT_KEYS
+                .with_label_values(&["expired"])
+                .inc_by(purged.len() as u64);
+            SWEPT_KEYS
+                .with_label_values(&["evicted"])
+                .inc_by(evicted.len() as u64);
+            for (key, ttl) in purged {
+                events.publish(Event::new(db_name, &key, EventKind::Expire));
+                if let Some(url) = &settings.expiration_webhook {
+                    webhooks.notify(url, Expiration::new(db_name, &key, ttl));
+                }
+            }
+            for key in evicted {
+                events.publish(Event::new(db_name, &key, EventKind::Evict));
+            }
+        }
+        Err(e) => error!("Failed to sweep db = {}, e = {}", db_name, e),
+    }
+
+    match db.purge_tombstones() {
+        Ok(purged) => {
+            if purged > 0 {
+                info!("Purged {} tombstones of db = {}", purged, db_name);
+            }
+            SWEPT_KEYS
+                .with_label_values(&["tombstone"])
+                .inc_by(purged as u64);
+        }
+        Err(e) => error!("Failed to purge tombstones of db = {}, e = {}", db_name, e),
+    }
+
+    match db.purge_uploads(settings.upload_expiry()) {
+        Ok(purged) => {
+            if purged > 0 {
+                info!("Purged {} abandoned uploads of db = {}", purged, db_name);
+            }
+        }
+        Err(e) => error!("Failed to purge uploads of db = {}, e = {}", db_name, e),
+    }
+
+    if settings.change_log && settings.change_log_retention() > 0 {
+        match db.purge_change_log(settings.change_log_retention()) {
+            Ok(purged) => {
+                if purged > 0 {
+                    info!("Purged {} change log entries of db = {}", purged, db_name);
+                }
+            }
+            Err(e) => error!("Failed to purge change log of db = {}, e = {}", db_name, e),
+        }
+    }
+
+    if let Some(idle) = db_cfg.scan_snapshot_idle() {
+        let released = db.pins.purge(idle);
+        if released > 0 {
+            info!(
+                "Released {} listing snapshots of db = {}",
+                released, db_name
+            );
+        }
+    }
+}
+
+fn purge_exports(db_cfg: &DbConfig, exports: &Exports, db_name: &str) {
+    match exports.purge(
+        &db_cfg.checkpoint_path(db_name),
+        db_cfg.export_resume_window(),
+    ) {
+        Ok(purged) => {
+            if purged > 0 {
+                info!("Purged {} exports of db = {}", purged, db_name);
+            }
+        }
+        Err(e) => error!("Failed to purge exports of db = {}, e = {}", db_name, e),
+    }
+}
+
+// ships batches a previous run left queued, resends the whole db, then ships changes as they're written until the
+// shipper is stopped or the db closed - a shipper dropped by the event bus for falling behind, or one whose batch
+// was dead-lettered, starts over with a resend
+async fn replicate(
+    dbs: Weak<ShardedLock<HashMap<String, Db>>>,
+    events: Arc<EventBus>,
+    limits: ScanLimits,
+    outbox: Arc<Outbox>,
+    shipper: Arc<Shipper>,
+    db_name: &str,
+    url: &str,
+) {
+    let client = Client::default();
+    let replicated = || {
+        if shipper.is_running() {
+            Weak::upgrade(&dbs).and_then(|dbs| {
+                dbs.read()
+                    .expect("Can't acquire read lock")
+                    .get(db_name)
+                    .cloned()
+            })
+        } else {
+            None
+        }
+    };
+
+    let leftovers = outbox
+        .scheduled(Kind::Replication, &replication_target(url, db_name))
+        .unwrap_or_else(|e| {
+            error!(
+                "Failed to read queued replication batches of db = {}, e = {}",
+                db_name, e
+            );
+            vec![]
+        });
+    for delivery in leftovers {
+        if reship(&client, &outbox, delivery, &shipper).await == Shipped::Stopped {
+            return;
+        }
+    }
+
+    'resend: loop {
+        // subscribed before the resend so nothing written meanwhile is missed
+        let mut rx = events.subscribe_with_buffer(Filter::db(db_name), REPLICATION_BUFFER);
+        let written = match replicated() {
+            Some(db) => db.seq(),
+            None => return,
+        };
+        let mut cursor = None;
+        loop {
+            let page = match replicated() {
+                Some(db) => db.changes(cursor.take(), MAX_BATCH, &limits),
+                None => return,
+            };
+            let (changes, next) = match page {
+                Ok(page) => page,
+                Err(e) => {
+                    error!("Failed to resend db = {}, e = {}", db_name, e);
+                    break;
+                }
+            };
+            if !changes.is_empty() {
+                match ship(&client, &outbox, url, db_name, &changes, false, &shipper).await {
+                    Shipped::Delivered => {}
+                    Shipped::DeadLettered => continue 'resend,
+                    Shipped::Stopped => return,
+                }
+            }
+            match next.map(|next| decode_cursor(&next)) {
+                Some(Ok(next)) => cursor = Some(next),
+                Some(Err(_)) => break,
+                None => {
+                    // the remote has everything written before the resend started
+                    shipper.acknowledge(written);
+                    break;
+                }
+            }
+        }
+
+        loop {
+            let event = match timeout(STOP_CHECK_INTERVAL, rx.next()).await {
+                Ok(Some(event)) => event,
+                Ok(None) => {
+                    warn!(
+                        "Replication of db = {} fell behind, resending the db",
+                        db_name
+                    );
+                    break;
+                }
+                Err(_) if shipper.is_running() => continue,
+                Err(_) => return,
+            };
+            let mut batch = vec![event];
+            while batch.len() < MAX_BATCH {
+                match rx.try_next() {
+                    Ok(Some(event)) => batch.push(event),
+                    _ => break,
+                }
+            }
+
+            let acked = batch.iter().filter_map(|e| e.seq).max();
+            let changes = match replicated() {
+                Some(db) => batch
+                    .iter()
+                    .map(|e| db.change(e))
+                    .collect::<anyhow::Result<Vec<Option<Change>>>>(),
+                None => return,
+            };
+            match changes {
+                Ok(changes) => {
+                    let changes: Vec<Change> = changes.into_iter().flatten().collect();
+                    if !changes.is_empty() {
+                        match ship(&client, &outbox, url, db_name, &changes, true, &shipper).await {
+                            Shipped::Delivered => {}
+                            Shipped::DeadLettered => continue 'resend,
+                            Shipped::Stopped => return,
+                        }
+                    }
+                    if let Some(seq) = acked {
+                        shipper.acknowledge(seq);
+                    }
+                }
+                Err(e) => error!(
+                    "Failed to read changes of db = {} for replication, e = {}",
+                    db_name, e
+                ),
+            }
+        }
+    }
+}
+
+// returns whether caches are left shrunk
+fn relieve_memory(
+    dbs: &ShardedLock<HashMap<String, Db>>,
+    rss: u64,
+    limit: u64,
+    cache_size: usize,
+    shrunk: bool,
+) -> bool {
+    let dbs = dbs.read().expect("Can't acquire read lock");
+    match pressure(rss, limit) {
+        Pressure::High => {
+            warn!(
+                "Process RSS = {} is near memory limit = {} - flushing memtables",
+                rss, limit
+            );
+            dbs.iter().for_each(|(name, db)| {
+                if let Err(e) = db.flush() {
+                    error!("Failed to flush db = {}, e = {}", name, e);
+                }
+            });
+            MEMORY_PRESSURE_ACTIONS.with_label_values(&["flush"]).inc();
+
+            if !shrunk {
+                warn!("Shrinking block caches to {} bytes", cache_size / 2);
+                dbs.values()
+                    .for_each(|db| db.set_cache_capacity(cache_size / 2));
+                MEMORY_PRESSURE_ACTIONS
+                    .with_label_values(&["shrink_cache"])
+                    .inc();
+            }
+            true
+        }
+        Pressure::Recovered if shrunk => {
+            info!(
+                "Memory pressure recovered, RSS = {} - restoring block caches",
+                rss
+            );
+            dbs.values()
+                .for_each(|db| db.set_cache_capacity(cache_size));
+            MEMORY_PRESSURE_ACTIONS
+                .with_label_values(&["restore_cache"])
+                .inc();
+            false
+        }
+        _ => shrunk,
+    }
+}
+
+// removes dbs with new background errors from serving
+fn failed_dbs(
+    dbs: &ShardedLock<HashMap<String, Db>>,
+    seen_errors: &mut HashMap<String, u64>,
+) -> Vec<(String, Db)> {
+    let failed: Vec<String> = dbs
+        .read()
+        .expect("Can't acquire read lock")
+        .iter()
+        // reopening an ephemeral db would lose its data, it keeps serving instead
+        .filter(|(_, db)| !db.is_ephemeral())
+        .filter_map(|(name, db)| {
+            let errors = db.background_errors().unwrap_or_default();
+            let seen = seen_errors.insert(name.clone(), errors).unwrap_or(errors);
+            if errors > seen {
+                error!(
+                    "Db = {} reported background error, total errors = {}",
+                    name, errors
+                );
+                Some(name.clone())
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    let mut dbs = dbs.write().expect("Can't acquire write lock");
+    failed
+        .into_iter()
+        .filter_map(|name| {
+            seen_errors.remove(&name);
+            dbs.remove(&name).map(|db| (name, db))
+        })
+        .collect()
+}
+
+fn recover_async(
+    dbs: &SafeRW<HashMap<String, Db>>,
+    recovering: &SafeRW<HashSet<String>>,
+    db_cfg: &Arc<DbConfig>,
+    db_name: String,
+    db: Db,
+) {
+    recovering
+        .write()
+        .expect("Can't acquire write lock")
+        .insert(db_name.clone());
+
+    let dbs = dbs.clone();
+    let recovering = recovering.clone();
+    let db_cfg = db_cfg.clone();
+    let spawned = thread::Builder::new()
+        .name(format!("recovery-{}", &db_name))
+        .spawn(move || {
+            let path = db.path();
+            let overrides = db.overrides.clone();
+            db.release_pins();
+            let mut failed = Some(db);
+            let mut backoff = INITIAL_RECOVERY_BACKOFF;
+            loop {
+                thread::sleep(backoff);
+                // old handle has to be dropped before the db lock file can be acquired again
+                if failed.as_ref().map_or(false, Db::is_released) {
+                    failed = None;
+                }
+                if failed.is_none() {
+                    match open_db(
+                        &db_cfg,
+                        &db_name,
+                        &path.to_string_lossy(),
+                        false,
+                        &overrides,
+                    ) {
+                        Ok(db) => {
+                            info!("Db = {} reopened after background error", &db_name);
+                            dbs.write()
+                                .expect("Can't acquire write lock")
+                                .insert(db_name.clone(), db);
+                            break;
+                        }
+                        Err(e) => error!(
+                            "Failed to reopen db = {}, retrying in {:?}, e = {}",
+                            &db_name,
+                            next_recovery_backoff(backoff),
+                            e
+                        ),
+                    }
+                }
+                backoff = next_recovery_backoff(backoff);
+            }
+            recovering
+                .write()
+                .expect("Can't acquire write lock")
+                .remove(&db_name);
+        });
+
+    if let Err(e) = spawned {
+        error!("Failed to spawn recovery thread, e = {}", e);
+    }
+}
+
+// reopen attempts back off exponentially, capped so a fixed disk is picked up within a minute
+fn next_recovery_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(MAX_RECOVERY_BACKOFF)
+}
+
+fn not_exists(db_name: &str) -> anyhow::Error {
+    anyhow!(ErrorCtx::Validation(format!(
+        "Db {} - doesn't exist",
+        &db_name
+    )))
+}
+
+// shortens the remaining ttl by up to pct percent, so records written together don't expire in the
+// same second - spread is derived from the key and write time, records never live longer than requested
+fn jittered(ttl: u128, now: u128, pct: u8, key: &str) -> u128 {
+    if ttl == NO_TTL || pct == 0 {
+        return ttl;
+    }
+
+    let max = ttl.saturating_sub(now) * u128::from(pct.min(100)) / 100;
+    let mut hasher = DefaultHasher::new();
+    (key, now).hash(&mut hasher);
+    ttl - u128::from(hasher.finish()) % (max + 1)
+}
+
+fn is_expired(ttl: u128) -> anyhow::Result<bool> {
+    is_expired_after(ttl, 0)
+}
+
+fn is_expired_after(ttl: u128, grace: u128) -> anyhow::Result<bool> {
+    if ttl == 0 {
+        Ok(false)
+    } else {
+        Ok(ttl + grace < current_ms()?)
+    }
+}
+
+fn remove_files<P>(deletions: &Deletions, db_name: &str, path: P, rate: u64)
+where
+    P: AsRef<Path> + Debug,
+{
+    let progress = |deleted| update(deletions, db_name, deleted);
+    if let Err(e) = remove_dir_throttled(&path, rate, progress) {
+        error!(
+            "Failed to remove db files on path = {:?}, err = {}",
+            path, e
+        );
+    } else {
+        info!("Removed files for db on path {:?}", path);
+    }
+}
+
+// existing value and operands are all envelopes so the same fold serves full and partial merges
+fn append_merge(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &mut MergeOperands,
+) -> Option<Vec<u8>> {
+    let mut merged: Option<Data> = None;
+    for bytes in existing.into_iter().chain(operands) {
+        let next = match bytes.to_vec().as_struct() {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Append merge:: Can't deserialize record, e = {}", e);
+                return None;
+            }
+        };
+        merged = Some(match merged {
+            Some(merged) => merged.append(next),
+            None => next,
+        });
+    }
+
+    merged.and_then(|data| data.as_bytes().ok())
+}
+
+fn compaction_filter(_level: u32, _key: &[u8], value: &[u8]) -> CompactionDecision {
+    if let Ok(data) = value.to_vec().as_struct() {
+        if let Ok(expired) = is_expired(data.ttl) {
+            if expired {
+                CompactionDecision::Remove
+            } else {
+                CompactionDecision::Keep
+            }
+        } else {
+            CompactionDecision::Remove
+        }
+    } else {
+        error!("Compaction job:: Can't deserialize record - will be discarded.");
+        CompactionDecision::Remove
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::Codec;
+
+    use super::*;
+
+    const ONE_DAY_MS: u128 = 1000 * 60 * 60 * 24;
+
+    #[test]
+    fn should_spread_expiration_within_jitter() {
+        let now = 1_000_000;
+        let ttl = now + 10_000;
+        let expirations: HashSet<u128> = (0..100)
+            .map(|i| jittered(ttl, now, 20, &format!("key_{}", i)))
+            .collect();
+
+        assert!(expirations.len() > 1);
+        assert!(expirations.iter().all(|e| *e <= ttl && *e >= ttl - 2_000));
+        assert_eq!(ttl, jittered(ttl, now, 0, "key"));
+        assert_eq!(NO_TTL, jittered(NO_TTL, now, 20, "key"));
+    }
+
+    #[test]
+    fn should_read_legacy_record() {
+        #[derive(Serialize)]
+        struct Legacy {
+            ttl: u128,
+            data: Vec<u8>,
+        }
+
+        let bytes = bincode::serialize(&Legacy {
+            ttl: 1,
+            data: b"data".to_vec(),
+        })
+        .unwrap();
+
+        let data = bytes.as_struct().expect("Legacy record should be readable");
+        assert_eq!(1, data.version);
+        assert_eq!(b"data".to_vec(), data.data);
+    }
+
+    #[test]
+    fn should_check_write_condition() {
+        assert!(WriteCondition::Always.check("k", None).is_ok());
+        assert!(WriteCondition::IfExists.check("k", Some(1)).is_ok());
+        assert!(WriteCondition::IfExists.check("k", None).is_err());
+        assert!(WriteCondition::IfVersion(2).check("k", Some(2)).is_ok());
+        assert!(WriteCondition::IfVersion(1).check("k", Some(2)).is_err());
+        assert!(WriteCondition::IfVersion(0).check("k", None).is_ok());
+        assert!(WriteCondition::IfVersion(0).check("k", Some(1)).is_err());
+        assert!(WriteCondition::IfAbsent.check("k", None).is_ok());
+        assert!(WriteCondition::IfAbsent.check("k", Some(1)).is_err());
+        assert!(WriteCondition::IfPresent.check("k", Some(1)).is_ok());
+        assert!(WriteCondition::IfPresent.check("k", None).is_err());
+    }
+
+    #[test]
+    fn should_append_to_value() {
+        let merged = Data::new(NO_TTL, b"abc".to_vec())
+            .with_version(3)
+            .append(Data::new(NO_TTL, b"def".to_vec()));
+
+        assert_eq!(b"abcdef".to_vec(), merged.data);
+        assert_eq!(4, merged.version);
+    }
+
+    #[test]
+    fn should_replace_expired_value_on_append() {
+        let merged = Data::new(1, b"abc".to_vec())
+            .with_version(3)
+            .append(Data::new(NO_TTL, b"def".to_vec()));
+
+        assert_eq!(b"def".to_vec(), merged.data);
+        assert_eq!(NO_TTL, merged.ttl);
+        assert_eq!(4, merged.version);
+    }
+
+    #[test]
+    fn should_calc_remaining_ttl() {
+        let data = Data::new(NO_TTL, b"data".to_vec());
+        assert_eq!(None, data.remaining_ttl().unwrap());
+
+        let data = Data::new(current_ms().unwrap() + ONE_DAY_MS, b"data".to_vec());
+        let remaining = data.remaining_ttl().unwrap().unwrap();
+        assert!(remaining > 0 && remaining <= ONE_DAY_MS);
+
+        let data = Data::new(1, b"data".to_vec());
+        assert_eq!(Some(0), data.remaining_ttl().unwrap());
+    }
+
+    #[test]
+    fn should_read_versioned_record() {
+        #[derive(Serialize)]
+        struct Versioned {
+            ttl: u128,
+            data: Vec<u8>,
+            version: u64,
+        }
+
+        let bytes = bincode::serialize(&Versioned {
+            ttl: 0,
+            data: b"data".to_vec(),
+            version: 7,
+        })
+        .unwrap();
+        let data: Data = bytes.as_struct().unwrap();
+        assert_eq!(7, data.version());
+        assert_eq!(NO_TTL, data.soft_ttl());
+        assert_eq!(b"data".to_vec(), data.into_value());
+    }
+
+    #[test]
+    fn should_read_soft_ttl_record() {
+        #[derive(Serialize)]
+        struct SoftTtl {
+            ttl: u128,
+            data: Vec<u8>,
+            version: u64,
+            soft_ttl: u128,
+        }
+
+        let bytes = bincode::serialize(&SoftTtl {
+            ttl: 0,
+            data: b"data".to_vec(),
+            version: 3,
+            soft_ttl: 5,
+        })
+        .unwrap();
+        let data: Data = bytes.as_struct().unwrap();
+        assert_eq!(3, data.version());
+        assert_eq!(5, data.soft_ttl());
+        assert_eq!(0, data.written_at());
+
+        let data: Data = Data::new(NO_TTL, vec![])
+            .as_bytes()
+            .unwrap()
+            .as_struct()
+            .unwrap();
+        assert!(data.written_at() > 0);
+    }
+
+    #[test]
+    fn should_read_written_at_record() {
+        #[derive(Serialize)]
+        struct WrittenAt {
+            ttl: u128,
+            data: Vec<u8>,
+            version: u64,
+            soft_ttl: u128,
+            written_at: u128,
+        }
+
+        let bytes = bincode::serialize(&WrittenAt {
+            ttl: 0,
+            data: b"data".to_vec(),
+            version: 3,
+            soft_ttl: 5,
+            written_at: 7,
+        })
+        .unwrap();
+        let data: Data = bytes.as_struct().unwrap();
+        assert_eq!(3, data.version());
+        assert_eq!(7, data.written_at());
+        assert_eq!(None, data.content_type());
+        assert!(!is_current_layout(&bytes));
+        assert!(is_current_layout(&data.as_bytes().unwrap()));
+
+        let data: Data = Data::new(NO_TTL, vec![])
+            .with_content_type(Some("application/json".into()))
+            .as_bytes()
+            .unwrap()
+            .as_struct()
+            .unwrap();
+        assert_eq!(Some("application/json"), data.content_type());
+    }
+
+    #[test]
+    fn should_compress_values_at_rest() {
+        let value = b"text heavy value ".repeat(100);
+        let compression = Some(Compression::new(Codec::Zstd, 0));
+        let data = Data::new(NO_TTL, value.clone())
+            .with_content_type(Some("text/plain".into()))
+            .with_compression(compression);
+        let bytes = data.as_bytes().unwrap();
+        assert!(bytes.len() < value.len());
+        assert!(is_current_layout(&bytes));
+
+        let data: Data = bytes.as_struct().unwrap();
+        assert_eq!(compression, data.compression);
+        assert_eq!(Some("text/plain"), data.content_type());
+        assert_eq!(value, data.into_value());
+
+        // doesn't shrink
+        let data = Data::new(NO_TTL, b"v".to_vec()).with_compression(compression);
+        let data: Data = data.as_bytes().unwrap().as_struct().unwrap();
+        assert_eq!(None, data.compression);
+        assert_eq!(b"v".to_vec(), data.into_value());
+    }
+
+    #[test]
+    fn should_read_content_type_record() {
+        #[derive(Serialize)]
+        struct ContentType {
+            ttl: u128,
+            data: Vec<u8>,
+            version: u64,
+            soft_ttl: u128,
+            written_at: u128,
+            content_type: Option<String>,
+        }
+
+        let bytes = bincode::serialize(&ContentType {
+            ttl: 0,
+            data: b"data".to_vec(),
+            version: 3,
+            soft_ttl: 5,
+            written_at: 7,
+            content_type: Some("text/plain".into()),
+        })
+        .unwrap();
+        assert!(!is_current_layout(&bytes));
+        let data: Data = bytes.as_struct().unwrap();
+        assert_eq!(3, data.version());
+        assert_eq!(Some("text/plain"), data.content_type());
+        assert_eq!(None, data.compression);
+        assert_eq!(b"data".to_vec(), data.into_value());
+    }
+
+    #[test]
+    fn should_read_compressed_record() {
+        #[derive(Serialize)]
+        struct Compressed {
+            ttl: u128,
+            data: Vec<u8>,
+            version: u64,
+            soft_ttl: u128,
+            written_at: u128,
+            content_type: Option<String>,
+            compression: Option<Compression>,
+        }
+
+        let value = b"text heavy value ".repeat(100);
+        let compression = Compression::new(Codec::Gzip, 0);
+        let bytes = bincode::serialize(&Compressed {
+            ttl: 0,
+            data: compression.compress(&value).unwrap(),
+            version: 3,
+            soft_ttl: 5,
+            written_at: 7,
+            content_type: None,
+            compression: Some(compression),
+        })
+        .unwrap();
+        assert!(!is_current_layout(&bytes));
+        let data: Data = bytes.as_struct().unwrap();
+        assert_eq!(3, data.version());
+        assert_eq!(Some(compression), data.compression);
+        assert!(!data.is_chunked());
+        assert_eq!(value, data.into_value());
+    }
+
+    #[test]
+    fn should_decompress_large_values_on_blocking_pool() {
+        let mut cfg = DbConfig::new_per_test_defaults();
+        cfg.0.databases.insert(
+            "test_db".into(),
+            DbSettings {
+                compression: Some(Codec::Zstd),
+                ..DbSettings::default()
+            },
+        );
+        let db_man = DbManager::new(cfg).unwrap();
+        futures::executor::block_on(db_man.open(
+            "test_db".into(),
+            false,
+            RocksOverrides::default(),
+        ))
+        .unwrap();
+        // hex digits of a pseudo random sequence halve when compressed, still over the offload size
+        let mut seed = 7u64;
+        let value: Vec<u8> = (0..MAX_VALUE_BYTES)
+            .map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                b"0123456789abcdef"[(seed >> 60) as usize]
+            })
+            .collect();
+        let db = db_man.r_lock().get("test_db").unwrap().clone();
+        db.store(
+            b"big",
+            value.clone(),
+            NO_TTL,
+            NO_TTL,
+            Representation::default(),
+            WriteCondition::Always,
+        )
+        .unwrap();
+        let stored = as_stored(&db.get(b"big").unwrap().unwrap()).unwrap();
+        assert!(stored.size() >= OFFLOAD_DECOMPRESSION_BYTES);
+        assert!(stored.size() < value.len());
+
+        let offloaded = READ_DECOMPRESSIONS.with_label_values(&["blocking"]).get();
+        let data = futures::executor::block_on(db_man.read("test_db", b"big"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, data.into_value());
+        assert!(READ_DECOMPRESSIONS.with_label_values(&["blocking"]).get() > offloaded);
+    }
+
+    #[test]
+    fn should_cap_soft_ttl_at_hard_ttl() {
+        let data = Data::new(100, vec![]).with_soft_ttl(200);
+        assert_eq!(100, data.soft_ttl());
+
+        let data = Data::new(NO_TTL, vec![]).with_soft_ttl(200);
+        assert_eq!(200, data.soft_ttl());
+    }
+
+    #[test]
+    fn should_keep_soft_expired() {
+        let bytes = Data::new(current_ms().unwrap() + ONE_DAY_MS, b"data".to_vec())
+            .with_soft_ttl(1)
+            .as_bytes()
+            .unwrap();
+
+        match compaction_filter(0, &[0], &bytes) {
+            CompactionDecision::Keep => {}
+            _ => panic!("Should have kept soft expired record"),
+        }
+    }
+
+    #[test]
+    fn should_remove_expired() {
+        let bytes = Data::new(1, b"data".to_vec()).as_bytes().unwrap();
+
+        match compaction_filter(0, &[0], &bytes) {
+            CompactionDecision::Remove => {}
+            _ => panic!("Should have removed expired record"),
+        }
+    }
+
+    #[test]
+    fn should_keep_not_expired() {
+        let bytes = Data::new(current_ms().unwrap() + ONE_DAY_MS, b"data".to_vec())
+            .as_bytes()
+            .unwrap();
+
+        match compaction_filter(0, &[0], &bytes) {
+            CompactionDecision::Keep => {}
+            _ => panic!("Should have kept non expired record"),
+        }
+    }
+
+    #[test]
+    fn should_report_readiness() {
+        let db_man = DbManager::new(DbConfig::new_per_test_defaults()).unwrap();
+        futures::executor::block_on(db_man.open(
+            "test_db".into(),
+            false,
+            RocksOverrides::default(),
+        ))
+        .unwrap();
+        assert!(db_man.readiness().is_empty());
+
+        db_man.recovering.write().unwrap().insert("test_db".into());
+        assert_eq!(vec!["Db test_db is recovering"], db_man.readiness());
+    }
+
+    #[test]
+    fn should_drop_expired_on_compaction() {
+        let db_man = DbManager::new(DbConfig::new_per_test_defaults()).unwrap();
+        futures::executor::block_on(db_man.open(
+            "test_db".into(),
+            false,
+            RocksOverrides::default(),
+        ))
+        .unwrap();
+        let db = db_man.r_lock().get("test_db").unwrap().clone();
+        let expired = Data::new(1, b"gone".to_vec());
+        let live = Data::new(current_ms().unwrap() + ONE_DAY_MS, b"live".to_vec());
+        db.w_lock()
+            .put(b"expired", expired.as_bytes().unwrap())
+            .unwrap();
+        db.w_lock().put(b"live", live.as_bytes().unwrap()).unwrap();
+        db.flush().unwrap();
+
+        db.compact(None).unwrap();
+        assert!(db.r_lock().get(b"expired").unwrap().is_none());
+        assert!(db.r_lock().get(b"live").unwrap().is_some());
+    }
+
+    #[test]
+    fn should_skip_unreadable_records_when_sweeping() {
+        let db_man = DbManager::new(DbConfig::new_per_test_defaults()).unwrap();
+        futures::executor::block_on(db_man.open(
+            "test_db".into(),
+            false,
+            RocksOverrides::default(),
+        ))
+        .unwrap();
+        let db = db_man.r_lock().get("test_db").unwrap().clone();
+        let expired = Data::new(1, b"gone".to_vec());
+        db.w_lock()
+            .put(b"expired", expired.as_bytes().unwrap())
+            .unwrap();
+        db.w_lock().put(b"broken", b"short").unwrap();
+
+        let (purged, evicted) = db.sweep(&DbSettings::default()).unwrap();
+        assert_eq!(vec![(b"expired".to_vec(), 1)], purged);
+        assert!(evicted.is_empty());
+        assert!(db.r_lock().get(b"broken").unwrap().is_some());
+    }
+
+    #[test]
+    fn should_purge_chunks_no_record_refers_to() {
+        let mut cfg = DbConfig::new_per_test_defaults();
+        cfg.0.databases.insert(
+            "test_db".into(),
+            DbSettings {
+                multipart_uploads: true,
+                ..DbSettings::default()
+            },
+        );
+        let db_man = DbManager::new(cfg).unwrap();
+        futures::executor::block_on(db_man.open(
+            "test_db".into(),
+            false,
+            RocksOverrides::default(),
+        ))
+        .unwrap();
+        let db = db_man.r_lock().get("test_db").unwrap().clone();
+        let id = upload_id();
+        db.begin_upload(&id, &Upload::new(b"big", None).unwrap())
+            .unwrap();
+        db.upload_part(&id, b"big", 2, b"second").unwrap();
+        db.upload_part(&id, b"big", 1, b"first ").unwrap();
+        db.complete_upload(&id, b"big", NO_TTL, NO_TTL, WriteCondition::Always)
+            .unwrap();
+
+        assert_eq!(0, db.purge_uploads(0).unwrap());
+        let data: Data = db.get(b"big").unwrap().unwrap().as_struct().unwrap();
+        assert!(data.is_chunked());
+        assert_eq!(b"first second".to_vec(), db.assemble(data).unwrap().data);
+
+        db.remove(b"big", EventKind::Delete).unwrap();
+        assert_eq!(1, db.purge_uploads(0).unwrap());
+        let rock = db.r_lock();
+        let cf = uploads_cf(&rock).unwrap();
+        assert!(rock.iterator_cf(cf, IteratorMode::Start).next().is_none());
+    }
+
+    #[test]
+    fn should_erase_index_entries() {
+        let mut cfg = DbConfig::new_per_test_defaults();
+        cfg.0.databases.insert(
+            "test_db".into(),
+            DbSettings {
+                json: true,
+                indexes: vec!["email".into()],
+                ..DbSettings::default()
+            },
+        );
+        let db_man = DbManager::new(cfg).unwrap();
+        futures::executor::block_on(db_man.open(
+            "test_db".into(),
+            false,
+            RocksOverrides::default(),
+        ))
+        .unwrap();
+        let db = db_man.r_lock().get("test_db").unwrap().clone();
+        for (key, email) in &[("user:1", "a@b.c"), ("user:2", "d@e.f")] {
+            let value = format!(r#"{{"email":"{}"}}"#, email).into_bytes();
+            db.store(
+                key.as_bytes(),
+                value,
+                NO_TTL,
+                NO_TTL,
+                Representation::default(),
+                WriteCondition::Always,
+            )
+            .unwrap();
+        }
+
+        let matcher = RegexSet::new(&["^user:1$"]).unwrap();
+        assert!(db.holds_any(&matcher).unwrap());
+        let erased = db.erase("test_db", &matcher).unwrap();
+        assert_eq!(1, erased.records);
+        assert!(erased.verified);
+
+        let rock = db.r_lock();
+        let cf = rock.cf_handle(INDEX_CF).unwrap();
+        let left: Vec<Box<[u8]>> = rock
+            .iterator_cf(cf, IteratorMode::Start)
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(1, left.len());
+        assert!(left[0].ends_with(b"user:2"));
+    }
+
+    #[test]
+    fn should_index_txn_rename_and_replicated_writes() {
+        let mut cfg = DbConfig::new_per_test_defaults();
+        cfg.0.databases.insert(
+            "test_db".into(),
+            DbSettings {
+                json: true,
+                indexes: vec!["city".into()],
+                ..DbSettings::default()
+            },
+        );
+        let db_man = DbManager::new(cfg).unwrap();
+        futures::executor::block_on(db_man.open(
+            "test_db".into(),
+            false,
+            RocksOverrides::default(),
+        ))
+        .unwrap();
+        let db = db_man.r_lock().get("test_db").unwrap().clone();
+        let query = |city: &str| {
+            db.query("city", city, None, 10, KeyEncoding::Utf8)
+                .unwrap()
+                .keys
+        };
+        let put = |key: &str, value: &str| TxnWrite {
+            key: key.into(),
+            action: TxnAction::Put(value.as_bytes().to_vec()),
+            ttl: NO_TTL,
+            expected_version: None,
+        };
+
+        db.txn(
+            &[
+                put("user:1", r#"{"city":"Zagreb"}"#),
+                put("user:1", r#"{"city":"Split"}"#),
+                put("user:2", r#"{"city":"Split"}"#),
+            ],
+            0,
+        )
+        .unwrap();
+        assert!(db.txn(&[put("user:3", "not json")], 0).is_err());
+        assert_eq!(vec!["user:1", "user:2"], query("Split"));
+        assert!(query("Zagreb").is_empty());
+
+        db.rename(b"user:2", b"user:3").unwrap().unwrap();
+        // queries drop stale entries themselves, none may be left for them
+        let entries = {
+            let rock = db.r_lock();
+            let cf = rock.cf_handle(INDEX_CF).unwrap();
+            rock.iterator_cf(cf, IteratorMode::Start).count()
+        };
+        assert_eq!(2, entries);
+        assert_eq!(vec!["user:1", "user:3"], query("Split"));
+
+        let change = |value: &str| Replicated {
+            key: b"user:1".to_vec(),
+            value: Some(value.as_bytes().to_vec()),
+            end: None,
+            ttl: NO_TTL,
+            soft_ttl: NO_TTL,
+            written_at: current_ms().unwrap() + 1000,
+            content_type: None,
+        };
+        db.apply(&change(r#"{"city":"Osijek"}"#), ConflictPolicy::Overwrite)
+            .unwrap()
+            .unwrap();
+        assert!(db
+            .apply(&change("not json"), ConflictPolicy::Overwrite)
+            .is_err());
+        assert_eq!(vec!["user:1"], query("Osijek"));
+        assert_eq!(vec!["user:3"], query("Split"));
+    }
+
+    #[test]
+    fn should_log_changes_with_their_write() {
+        let mut cfg = DbConfig::new_per_test_defaults();
+        cfg.0.databases.insert(
+            "test_db".into(),
+            DbSettings {
+                change_log: true,
+                ..DbSettings::default()
+            },
+        );
+        let db_man = DbManager::new(cfg).unwrap();
+        futures::executor::block_on(db_man.open(
+            "test_db".into(),
+            false,
+            RocksOverrides::default(),
+        ))
+        .unwrap();
+        let db = db_man.r_lock().get("test_db").unwrap().clone();
+        let stored = db
+            .store(
+                b"k",
+                b"v1".to_vec(),
+                NO_TTL,
+                NO_TTL,
+                Representation::default(),
+                WriteCondition::Always,
+            )
+            .unwrap();
+        db.append(b"k", b"v2".to_vec()).unwrap();
+        let seq = db.remove(b"k", EventKind::Expire).unwrap();
+
+        let page = db.change_log(0, 10).unwrap();
+        assert_eq!(3, page.next);
+        let entry = &page.changes[0];
+        assert_eq!((EventKind::Put, Some(1)), (entry.kind, entry.version));
+        assert_eq!(Some(stored.seq), entry.seq);
+        assert_eq!(Some(base64::encode("v1")), entry.value);
+        assert_eq!(Some(base64::encode("v1v2")), page.changes[1].value);
+        assert_eq!(EventKind::Expire, page.changes[2].kind);
+        assert_eq!(Some(seq), page.changes[2].seq);
+    }
+
+    #[test]
+    fn should_log_touch() {
+        let mut cfg = DbConfig::new_per_test_defaults();
+        cfg.0.databases.insert(
+            "test_db".into(),
+            DbSettings {
+                change_log: true,
+                ..DbSettings::default()
+            },
+        );
+        let db_man = DbManager::new(cfg).unwrap();
+        futures::executor::block_on(db_man.open(
+            "test_db".into(),
+            false,
+            RocksOverrides::default(),
+        ))
+        .unwrap();
+        let db = db_man.r_lock().get("test_db").unwrap().clone();
+        db.store(
+            b"k",
+            b"v".to_vec(),
+            NO_TTL,
+            NO_TTL,
+            Representation::default(),
+            WriteCondition::Always,
+        )
+        .unwrap();
+        let seq = db.touch(b"k", current_ms().unwrap() + ONE_DAY_MS).unwrap();
+
+        let page = db.change_log(0, 10).unwrap();
+        assert_eq!(2, page.next);
+        let entry = &page.changes[1];
+        assert_eq!((EventKind::Put, Some(1)), (entry.kind, entry.version));
+        assert_eq!(seq, entry.seq);
+        assert_eq!(None, db.touch(b"missing", NO_TTL).unwrap());
+    }
+
+    #[test]
+    fn should_keep_unlogged_writes_across_reopen() {
+        let mut cfg = DbConfig::new_per_test_defaults();
+        cfg.0.databases.insert(
+            "test_db".into(),
+            DbSettings {
+                durability: Durability::None,
+                ..DbSettings::default()
+            },
+        );
+        let db_man = DbManager::offline(cfg).unwrap();
+        futures::executor::block_on(db_man.open(
+            "test_db".into(),
+            false,
+            RocksOverrides::default(),
+        ))
+        .unwrap();
+        let db = db_man.w_lock().remove("test_db").unwrap();
+        db.store(
+            b"k",
+            b"v".to_vec(),
+            NO_TTL,
+            NO_TTL,
+            Representation::default(),
+            WriteCondition::Always,
+        )
+        .unwrap();
+        // the memtable is flushed when the last handle closes the db
+        drop(db);
+
+        let path = db_man.db_cfg.db_path("test_db");
+        let db = open_db(
+            &db_man.db_cfg,
+            "test_db",
+            &path,
+            false,
+            &RocksOverrides::default(),
+        )
+        .unwrap();
+        let stored = as_stored(&db.get(b"k").unwrap().unwrap()).unwrap();
+        assert_eq!(b"v".to_vec(), stored.data);
+    }
+
+    #[test]
+    fn should_back_off_recovery_up_to_max() {
+        let schedule: Vec<u64> = (0..8)
+            .scan(INITIAL_RECOVERY_BACKOFF, |backoff, _| {
+                let current = *backoff;
+                *backoff = next_recovery_backoff(current);
+                Some(current.as_secs())
+            })
+            .collect();
+
+        assert_eq!(vec![1, 2, 4, 8, 16, 32, 60, 60], schedule);
+    }
+
+    #[test]
+    fn should_reopen_failed_db_once_released() {
+        let db_man = DbManager::offline(DbConfig::new_per_test_defaults()).unwrap();
+        futures::executor::block_on(db_man.open(
+            "test_db".into(),
+            false,
+            RocksOverrides::default(),
+        ))
+        .unwrap();
+        let mut seen_errors = HashMap::new();
+        assert!(failed_dbs(&db_man.dbs, &mut seen_errors).is_empty());
+        assert_eq!(Some(&0), seen_errors.get("test_db"));
+
+        let db = db_man.w_lock().remove("test_db").unwrap();
+        // a request still holding the old handle keeps the first attempt from reopening
+        let held = db.clone();
+        recover_async(
+            &db_man.dbs,
+            &db_man.recovering,
+            &db_man.db_cfg,
+            "test_db".into(),
+            db,
+        );
+        thread::sleep(INITIAL_RECOVERY_BACKOFF + Duration::from_millis(500));
+        assert!(db_man.is_recovering("test_db"));
+        assert!(db_man.not_contains("test_db"));
+
+        drop(held);
+        let deadline = Instant::now() + MAX_RECOVERY_BACKOFF;
+        while db_man.is_recovering("test_db") && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert!(!db_man.is_recovering("test_db"));
+        assert!(db_man.contains("test_db"));
+    }
+
+    #[test]
+    fn should_drop_tombstones_in_deleted_range() {
+        let mut cfg = DbConfig::new_per_test_defaults();
+        cfg.0.databases.insert(
+            "test_db".into(),
+            DbSettings {
+                undelete_window_ms: 60_000,
+                ..DbSettings::default()
+            },
+        );
+        let db_man = DbManager::offline(cfg).unwrap();
+        futures::executor::block_on(db_man.open(
+            "test_db".into(),
+            false,
+            RocksOverrides::default(),
+        ))
+        .unwrap();
+        let db = db_man.r_lock().get("test_db").unwrap().clone();
+        let purge_at = current_ms().unwrap() + ONE_DAY_MS;
+        for key in &[b"a", b"z"] {
+            db.store(
+                *key,
+                b"v".to_vec(),
+                NO_TTL,
+                NO_TTL,
+                Representation::default(),
+                WriteCondition::Always,
+            )
+            .unwrap();
+            db.bury(*key, purge_at).unwrap();
+        }
+
+        db.remove_range(b"a", b"m").unwrap();
+        assert!(db.undelete(b"a").unwrap().is_none());
+        assert!(db.undelete(b"z").unwrap().is_some());
     }
 }
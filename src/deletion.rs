@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+
+// large files are truncated in chunks before unlinking so the file system frees extents gradually
+const CHUNK_BYTES: u64 = 4 * 1024 * 1024;
+
+pub type Deletions = Arc<Mutex<HashMap<String, Deletion>>>;
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Deletion {
+    name: String,
+    path: String,
+    total_bytes: u64,
+    deleted_bytes: u64,
+    started_at: u128,
+}
+
+impl Deletion {
+    pub fn new(name: String, path: String, total_bytes: u64, started_at: u128) -> Self {
+        Deletion {
+            name,
+            path,
+            total_bytes,
+            deleted_bytes: 0,
+            started_at,
+        }
+    }
+}
+
+pub fn dir_size<P>(path: P) -> io::Result<u64>
+where
+    P: AsRef<Path>,
+{
+    Ok(files(path.as_ref())?
+        .iter()
+        .filter_map(|f| fs::metadata(f).ok())
+        .map(|m| m.len())
+        .sum())
+}
+
+// rate is in bytes per second, 0 deletes without throttling
+pub fn remove_dir_throttled<P, F>(path: P, rate: u64, mut progress: F) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    F: FnMut(u64),
+{
+    for file in files(path.as_ref())? {
+        let mut len = fs::metadata(&file)?.len();
+        if len > CHUNK_BYTES {
+            let f = OpenOptions::new().write(true).open(&file)?;
+            while len > CHUNK_BYTES {
+                len -= CHUNK_BYTES;
+                f.set_len(len)?;
+                progress(CHUNK_BYTES);
+                throttle(CHUNK_BYTES, rate);
+            }
+        }
+        fs::remove_file(&file)?;
+        progress(len);
+        throttle(len, rate);
+    }
+
+    // only empty directories are left
+    fs::remove_dir_all(path)
+}
+
+pub fn update(deletions: &Deletions, name: &str, deleted: u64) {
+    if let Some(d) = deletions
+        .lock()
+        .expect("Can't acquire deletions lock")
+        .get_mut(name)
+    {
+        d.deleted_bytes += deleted;
+    }
+}
+
+fn files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(self::files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+fn throttle(bytes: u64, rate: u64) {
+    if rate > 0 && bytes > 0 {
+        thread::sleep(Duration::from_secs_f64(bytes as f64 / rate as f64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn should_remove_dir_in_chunks() {
+        let dir = Path::new("./db/should_remove_dir_in_chunks");
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        File::create(dir.join("big.sst"))
+            .unwrap()
+            .write_all(&vec![0; (CHUNK_BYTES * 2 + 10) as usize])
+            .unwrap();
+        File::create(dir.join("nested/small.log"))
+            .unwrap()
+            .write_all(b"small")
+            .unwrap();
+
+        let total = dir_size(dir).unwrap();
+        assert_eq!(CHUNK_BYTES * 2 + 15, total);
+
+        let mut steps = vec![];
+        remove_dir_throttled(dir, 0, |bytes| steps.push(bytes)).unwrap();
+
+        assert!(!dir.exists());
+        assert_eq!(total, steps.iter().sum::<u64>());
+        assert_eq!(4, steps.len());
+    }
+}
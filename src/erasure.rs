@@ -0,0 +1,249 @@
+// Erasure of keys matching patterns across databases, e.g. all records of a user on a GDPR request.
+//
+// POST /_erasure schedules the erasure and returns its report right away, the work runs on its own thread.
+// Matching keys are deleted from every column family - records, tombstones kept for undelete, access stats,
+// crdts, uploads, index entries and the change log - then the db is compacted so the values are gone from sst
+// files too and rescanned to verify nothing matches anymore. Backups can't be rewritten - backups taken before
+// the erasure are annotated with its id and listed in the report, checkpoint directories of the db are listed as
+// well. The completed report is signed with HMAC-SHA256 so it can be archived as proof.
+// Reports are kept in memory only, erasure publishes no events so replicas have to be erased separately.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use hmac::{Hmac, Mac, NewMac};
+use regex::RegexSet;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::errors::ErrorCtx;
+
+const ANNOTATIONS_FILE: &str = "erasures.json";
+
+pub type Erasures = Arc<Mutex<HashMap<String, ErasureReport>>>;
+
+#[derive(Deserialize, Debug)]
+pub struct ErasureRequest {
+    pub patterns: Vec<String>,
+    // all open dbs when empty
+    #[serde(default)]
+    pub dbs: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ErasureStatus {
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DbErasure {
+    pub db: String,
+    pub records: u64,
+    pub tombstones: u64,
+    pub change_log_entries: u64,
+    // no key matches after compaction
+    pub verified: bool,
+    // backups still holding erased records
+    pub backups: Vec<u32>,
+    // checkpoints, export checkpoints and restored snapshots still holding erased records
+    pub checkpoints: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ErasureReport {
+    id: String,
+    requested_at: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    completed_at: Option<u128>,
+    status: ErasureStatus,
+    patterns: Vec<String>,
+    dbs: Vec<DbErasure>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    // hex HMAC-SHA256 of the report serialized without it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+// key completed reports are signed with
+pub struct SigningKey(Option<String>);
+
+impl ErasureRequest {
+    pub fn from_json(bytes: &[u8]) -> anyhow::Result<Self> {
+        let request: ErasureRequest = serde_json::from_slice(bytes).map_err(|e| {
+            anyhow!(ErrorCtx::Validation(format!(
+                "Malformed erasure request - {}",
+                e
+            )))
+        })?;
+        if request.patterns.is_empty() {
+            return Err(anyhow!(ErrorCtx::Validation(
+                "Erasure needs at least one pattern".into()
+            )));
+        }
+        Ok(request)
+    }
+
+    pub fn matcher(&self) -> anyhow::Result<RegexSet> {
+        RegexSet::new(&self.patterns)
+            .map_err(|e| anyhow!(ErrorCtx::Validation(format!("Invalid pattern - {}", e))))
+    }
+}
+
+impl ErasureReport {
+    pub fn new(id: String, requested_at: u128, patterns: Vec<String>) -> Self {
+        ErasureReport {
+            id,
+            requested_at,
+            completed_at: None,
+            status: ErasureStatus::Running,
+            patterns,
+            dbs: vec![],
+            error: None,
+            signature: None,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn complete(
+        &mut self,
+        dbs: Vec<DbErasure>,
+        error: Option<String>,
+        completed_at: u128,
+        key: &str,
+    ) -> anyhow::Result<()> {
+        self.status = if error.is_none() && dbs.iter().all(|db| db.verified) {
+            ErasureStatus::Completed
+        } else {
+            ErasureStatus::Failed
+        };
+        self.dbs = dbs;
+        self.error = error;
+        self.completed_at = Some(completed_at);
+        self.signature = None;
+        self.signature = Some(sign(&serde_json::to_vec(self)?, key));
+        Ok(())
+    }
+}
+
+impl SigningKey {
+    pub fn new(key: Option<String>) -> Self {
+        SigningKey(key)
+    }
+
+    pub fn get(&self) -> anyhow::Result<String> {
+        self.0.clone().ok_or_else(|| {
+            anyhow!(ErrorCtx::Validation(
+                "Erasure needs erasure_signing_key in service_config.toml".into()
+            ))
+        })
+    }
+}
+
+fn sign(bytes: &[u8], key: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(key.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(bytes);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// erasure ids by backup id, kept next to the backups of a db
+pub fn annotate_backups(dir: &str, backup_ids: &[u32], erasure_id: &str) -> anyhow::Result<()> {
+    if backup_ids.is_empty() {
+        return Ok(());
+    }
+    let mut annotations = backup_annotations(dir)?;
+    for id in backup_ids {
+        annotations
+            .entry(*id)
+            .or_default()
+            .push(erasure_id.to_string());
+    }
+    let path = Path::new(dir).join(ANNOTATIONS_FILE);
+    fs::write(path, serde_json::to_vec(&annotations)?)?;
+    Ok(())
+}
+
+pub fn backup_annotations(dir: &str) -> anyhow::Result<BTreeMap<u32, Vec<String>>> {
+    let path = Path::new(dir).join(ANNOTATIONS_FILE);
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_request() {
+        let request = ErasureRequest::from_json(br#"{"patterns": ["^user:42"]}"#).unwrap();
+        assert!(request.dbs.is_empty());
+        assert!(request.matcher().unwrap().is_match("user:42:orders"));
+
+        assert!(ErasureRequest::from_json(br#"{"patterns": []}"#).is_err());
+        let request = ErasureRequest::from_json(br#"{"patterns": ["("]}"#).unwrap();
+        assert!(request.matcher().is_err());
+    }
+
+    #[test]
+    fn should_sign_completed_report() {
+        let mut report = ErasureReport::new("1".into(), 100, vec!["^user:".into()]);
+        let erased = DbErasure {
+            db: "db_1".into(),
+            records: 2,
+            verified: true,
+            ..DbErasure::default()
+        };
+        report
+            .complete(vec![erased.clone()], None, 200, "key")
+            .unwrap();
+        assert_eq!(ErasureStatus::Completed, report.status);
+
+        let signature = report.signature.take().unwrap();
+        assert_eq!(64, signature.len());
+        assert_eq!(
+            signature,
+            sign(&serde_json::to_vec(&report).unwrap(), "key")
+        );
+        assert_ne!(
+            signature,
+            sign(&serde_json::to_vec(&report).unwrap(), "other")
+        );
+
+        let unverified = DbErasure {
+            verified: false,
+            ..erased
+        };
+        report.complete(vec![unverified], None, 200, "key").unwrap();
+        assert_eq!(ErasureStatus::Failed, report.status);
+    }
+
+    #[test]
+    fn should_annotate_backups() {
+        let dir = "./db/should_annotate_backups";
+        fs::create_dir_all(dir).unwrap();
+        annotate_backups(dir, &[1, 2], "e1").unwrap();
+        annotate_backups(dir, &[2], "e2").unwrap();
+
+        let annotations = backup_annotations(dir).unwrap();
+        assert_eq!(vec!["e1".to_string()], annotations[&1]);
+        assert_eq!(vec!["e1".to_string(), "e2".to_string()], annotations[&2]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
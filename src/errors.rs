@@ -17,6 +17,16 @@ pub struct ErrWrapper {
 #[derive(Debug)]
 pub enum ErrorCtx {
     Validation(String),
+    PreconditionFailed(String),
+    Unauthorized(String),
+    Forbidden(String),
+    Unavailable(String),
+    NotFound(String),
+    Conflict(String),
+    TooManyRequests(String),
+    InsufficientStorage(String),
+    Gone(String),
+    PayloadTooLarge(String),
 }
 
 impl std::error::Error for ErrorCtx {}
@@ -35,6 +45,16 @@ impl Display for ErrorCtx {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             ErrorCtx::Validation(msg) => write!(f, "Validation error: {}", msg),
+            ErrorCtx::PreconditionFailed(msg) => write!(f, "Precondition failed: {}", msg),
+            ErrorCtx::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
+            ErrorCtx::Forbidden(msg) => write!(f, "Forbidden: {}", msg),
+            ErrorCtx::Unavailable(msg) => write!(f, "Unavailable: {}", msg),
+            ErrorCtx::NotFound(msg) => write!(f, "Not found: {}", msg),
+            ErrorCtx::Conflict(msg) => write!(f, "Conflict: {}", msg),
+            ErrorCtx::TooManyRequests(msg) => write!(f, "Too many requests: {}", msg),
+            ErrorCtx::InsufficientStorage(msg) => write!(f, "Insufficient storage: {}", msg),
+            ErrorCtx::Gone(msg) => write!(f, "Gone: {}", msg),
+            ErrorCtx::PayloadTooLarge(msg) => write!(f, "Payload too large: {}", msg),
         }
     }
 }
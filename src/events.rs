@@ -0,0 +1,297 @@
+use std::borrow::Cow;
+use std::sync::{Mutex, MutexGuard};
+
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use serde::ser::Error;
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::key_encoding::{display_key, KeyEncoding};
+
+// events buffered per subscriber - a subscriber that falls further behind is disconnected
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Put,
+    Delete,
+    DeleteRange,
+    Expire,
+    Evict,
+    // a stale record was served, it should be reloaded
+    Refresh,
+}
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub db: String,
+    pub key: Vec<u8>,
+    pub kind: EventKind,
+    // None when the version isn't known without an extra read (e.g. merges)
+    pub version: Option<u64>,
+    // exclusive end of a range delete
+    pub end: Option<Vec<u8>>,
+    // None for expirations and evictions done in the background
+    pub seq: Option<u64>,
+}
+
+// an event as it's sent to subscribers
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EventJson<'a> {
+    db: &'a str,
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_encoding: Option<&'static str>,
+    kind: EventKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    db: String,
+    key: Option<Vec<u8>>,
+    prefix: Option<Vec<u8>>,
+}
+
+struct Subscriber {
+    filter: Filter,
+    tx: Sender<Event>,
+}
+
+// in process pub/sub for record changes, publishing never blocks writers
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl Event {
+    pub fn new(db: &str, key: impl AsRef<[u8]>, kind: EventKind) -> Self {
+        Event {
+            db: db.into(),
+            key: key.as_ref().to_vec(),
+            kind,
+            version: None,
+            end: None,
+            seq: None,
+        }
+    }
+
+    pub fn with_version(mut self, version: u64) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    pub fn with_end(mut self, end: impl AsRef<[u8]>) -> Self {
+        self.end = Some(end.as_ref().to_vec());
+        self
+    }
+
+    pub fn with_seq(mut self, seq: u64) -> Self {
+        self.seq = Some(seq);
+        self
+    }
+
+    // server-sent events message
+    pub fn to_sse(&self) -> serde_json::Result<String> {
+        Ok(format!(
+            "event: {}\ndata: {}\n\n",
+            self.kind.name(),
+            serde_json::to_string(self)?
+        ))
+    }
+
+    // for logs
+    pub fn display_key(&self) -> Cow<'_, str> {
+        display_key(&self.key)
+    }
+}
+
+impl Serialize for Event {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let encoding = KeyEncoding::of_document(
+            std::iter::once(self.key.as_slice()).chain(self.end.as_deref()),
+        );
+        let encode = |key: &[u8]| encoding.encode(key).map_err(S::Error::custom);
+        EventJson {
+            db: &self.db,
+            key: encode(&self.key)?,
+            key_encoding: encoding.header_value(),
+            kind: self.kind,
+            version: self.version,
+            end: self.end.as_deref().map(encode).transpose()?,
+            seq: self.seq,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Put => "put",
+            EventKind::Delete => "delete",
+            EventKind::DeleteRange => "delete_range",
+            EventKind::Expire => "expire",
+            EventKind::Evict => "evict",
+            EventKind::Refresh => "refresh",
+        }
+    }
+}
+
+impl Filter {
+    pub fn db(db: &str) -> Self {
+        Filter {
+            db: db.into(),
+            key: None,
+            prefix: None,
+        }
+    }
+
+    pub fn key(db: &str, key: impl AsRef<[u8]>) -> Self {
+        Filter {
+            key: Some(key.as_ref().to_vec()),
+            ..Filter::db(db)
+        }
+    }
+
+    pub fn prefix(db: &str, prefix: impl AsRef<[u8]>) -> Self {
+        Filter {
+            prefix: Some(prefix.as_ref().to_vec()),
+            ..Filter::db(db)
+        }
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        if self.db != event.db {
+            return false;
+        }
+        match (&self.key, &self.prefix, &event.end) {
+            (Some(key), _, None) => key == &event.key,
+            (Some(key), _, Some(end)) => key >= &event.key && key < end,
+            (None, Some(prefix), None) => event.key.starts_with(prefix),
+            // keys with the prefix are contiguous, a range starting past them can't hold any
+            (None, Some(prefix), Some(end)) => {
+                event.key.starts_with(prefix) || (&event.key < prefix && end > prefix)
+            }
+            (None, None, _) => true,
+        }
+    }
+}
+
+impl EventBus {
+    pub fn subscribe(&self, filter: Filter) -> Receiver<Event> {
+        self.subscribe_with_buffer(filter, SUBSCRIBER_BUFFER)
+    }
+
+    pub fn subscribe_with_buffer(&self, filter: Filter, buffer: usize) -> Receiver<Event> {
+        let (tx, rx) = channel(buffer);
+        self.lock().push(Subscriber { filter, tx });
+        rx
+    }
+
+    pub fn publish(&self, event: Event) {
+        let mut subscribers = self.lock();
+        let mut i = 0;
+        while i < subscribers.len() {
+            let s = &mut subscribers[i];
+            let keep = if s.filter.matches(&event) {
+                match s.tx.try_send(event.clone()) {
+                    Ok(_) => true,
+                    Err(e) if e.is_full() => {
+                        warn!(
+                            "Disconnecting slow event subscriber for db = {}",
+                            s.filter.db
+                        );
+                        false
+                    }
+                    Err(_) => false,
+                }
+            } else {
+                !s.tx.is_closed()
+            };
+
+            if keep {
+                i += 1;
+            } else {
+                subscribers.swap_remove(i);
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Subscriber>> {
+        self.subscribers
+            .lock()
+            .expect("Can't acquire subscribers lock")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[test]
+    fn should_deliver_matching_events() {
+        let bus = EventBus::default();
+        let mut key_rx = bus.subscribe(Filter::key("db", "k1"));
+        let mut db_rx = bus.subscribe(Filter::db("db"));
+
+        bus.publish(Event::new("db", "k2", EventKind::Put).with_version(1));
+        bus.publish(Event::new("other", "k1", EventKind::Put));
+        bus.publish(Event::new("db", "k0", EventKind::DeleteRange).with_end("k5"));
+
+        let event = futures::executor::block_on(key_rx.next()).unwrap();
+        assert_eq!(EventKind::DeleteRange, event.kind);
+        assert!(key_rx.try_next().is_err());
+
+        let event = futures::executor::block_on(db_rx.next()).unwrap();
+        assert_eq!(b"k2".to_vec(), event.key);
+        assert_eq!(Some(1), event.version);
+    }
+
+    #[test]
+    fn should_match_key_prefix() {
+        let filter = Filter::prefix("db", "user:");
+        assert!(filter.matches(&Event::new("db", "user:1", EventKind::Put)));
+        assert!(!filter.matches(&Event::new("db", "order:1", EventKind::Put)));
+        assert!(!filter.matches(&Event::new("other", "user:1", EventKind::Put)));
+
+        let range = |start, end| Event::new("db", start, EventKind::DeleteRange).with_end(end);
+        assert!(filter.matches(&range("a", "z")));
+        assert!(filter.matches(&range("user:5", "z")));
+        assert!(!filter.matches(&range("a", "user:")));
+        assert!(!filter.matches(&range("user;", "z")));
+    }
+
+    #[test]
+    fn should_format_sse() {
+        let event = Event::new("db", "k", EventKind::Expire);
+        assert_eq!(
+            "event: expire\ndata: {\"db\":\"db\",\"key\":\"k\",\"kind\":\"expire\"}\n\n",
+            event.to_sse().unwrap()
+        );
+
+        let event = Event::new("db", [0u8, 255], EventKind::DeleteRange).with_end("z");
+        assert_eq!(
+            r#"{"db":"db","key":"AP8","keyEncoding":"base64","kind":"delete_range","end":"eg"}"#,
+            serde_json::to_string(&event).unwrap()
+        );
+    }
+
+    #[test]
+    fn should_drop_closed_subscribers() {
+        let bus = EventBus::default();
+        let rx = bus.subscribe(Filter::db("db"));
+        drop(rx);
+
+        bus.publish(Event::new("db", "k", EventKind::Delete));
+        assert!(bus.lock().is_empty());
+    }
+}
@@ -0,0 +1,252 @@
+// Resumable database exports.
+//
+// An export is streamed from a checkpoint under checkpoint_path/{db}/export-{id}, the response carries its length
+// and the id as ETag. A client that lost the connection asks for the rest with Range: bytes={received}- and
+// If-Range: {etag}, which is served from the same checkpoint as long as it's kept - a dropped 50 GB download goes on
+// from where it stopped. A checkpoint is kept for export_resume_ms after its last stream ended and removed by the
+// sweeper afterwards, with export_resume_ms = 0 it's removed as soon as its stream ends.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use actix_web::web::Bytes;
+use anyhow::anyhow;
+
+use crate::archive::TarStream;
+use crate::conversion::current_ms;
+use crate::errors::ErrorCtx;
+
+const DIR_PREFIX: &str = "export-";
+
+#[derive(Default)]
+pub struct Exports {
+    active: Mutex<HashMap<PathBuf, Activity>>,
+}
+
+#[derive(Default)]
+struct Activity {
+    streams: usize,
+    ended_at: u128,
+}
+
+// a stream of an export archive, from the start or from an offset
+pub struct Export {
+    pub id: String,
+    pub offset: u64,
+    pub size: u64,
+    stream: ExportStream,
+}
+
+pub struct ExportStream {
+    tar: TarStream,
+    dir: PathBuf,
+    keep: bool,
+    exports: Arc<Exports>,
+}
+
+pub fn dir_name(id: &str) -> String {
+    format!("{}{}", DIR_PREFIX, id)
+}
+
+// ids are created_at of the checkpoint, anything else can't name an export dir
+pub fn is_export_id(id: &str) -> bool {
+    !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit())
+}
+
+pub fn export_etag(id: &str) -> String {
+    format!("\"{}{}\"", DIR_PREFIX, id)
+}
+
+// the export id of an If-Range value, None for anything but an export etag
+pub fn parse_export_etag(etag: &str) -> Option<&str> {
+    etag.strip_prefix('"')?
+        .strip_suffix('"')?
+        .strip_prefix(DIR_PREFIX)
+        .filter(|id| is_export_id(id))
+}
+
+// only a single open ended range is supported - bytes={offset}-
+pub fn parse_range(range: &str) -> Option<u64> {
+    range
+        .strip_prefix("bytes=")?
+        .strip_suffix('-')?
+        .parse()
+        .ok()
+}
+
+impl Exports {
+    // starts streaming the export in dir, Ok(None) if it was removed meanwhile
+    pub fn stream(
+        self: &Arc<Self>,
+        id: &str,
+        dir: PathBuf,
+        prefix: &str,
+        offset: u64,
+        keep: bool,
+    ) -> anyhow::Result<Option<Export>> {
+        let mut active = self.active.lock().expect("Can't acquire exports lock");
+        if !dir.is_dir() {
+            return Ok(None);
+        }
+        let tar = TarStream::new(&dir, prefix)?.keep_dir();
+        if offset >= tar.size() {
+            return Err(anyhow!(ErrorCtx::Validation(format!(
+                "Range starts at {} past the end of export {} of {} bytes",
+                offset,
+                id,
+                tar.size()
+            ))));
+        }
+
+        active.entry(dir.clone()).or_default().streams += 1;
+        Ok(Some(Export {
+            id: id.into(),
+            offset,
+            size: tar.size(),
+            stream: ExportStream {
+                tar: tar.starting_at(offset),
+                dir,
+                keep,
+                exports: self.clone(),
+            },
+        }))
+    }
+
+    // removes exports under parent nobody streamed for the resume window, returns how many were removed
+    pub fn purge(&self, parent: &str, resume_window: u128) -> anyhow::Result<usize> {
+        let mut active = self.active.lock().expect("Can't acquire exports lock");
+        let entries = match fs::read_dir(parent) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let now = current_ms()?;
+        let mut purged = 0;
+        for entry in entries {
+            let dir = entry?.path();
+            let is_export = dir
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map_or(false, |name| name.starts_with(DIR_PREFIX));
+            if !is_export || !dir.is_dir() {
+                continue;
+            }
+            // exports left over from before a restart count from their last modification
+            let ended_at = match active.get(&dir) {
+                Some(activity) if activity.streams > 0 => continue,
+                Some(activity) => activity.ended_at,
+                None => modified_ms(&dir)?,
+            };
+            if now.saturating_sub(ended_at) < resume_window {
+                continue;
+            }
+
+            remove(&dir);
+            active.remove(&dir);
+            purged += 1;
+        }
+        Ok(purged)
+    }
+
+    fn ended(&self, dir: &Path, keep: bool) {
+        let mut active = self.active.lock().expect("Can't acquire exports lock");
+        let idle = match active.get_mut(dir) {
+            Some(activity) => {
+                activity.streams -= 1;
+                activity.ended_at = current_ms().unwrap_or_default();
+                activity.streams == 0
+            }
+            None => true,
+        };
+        if idle && !keep {
+            remove(dir);
+            active.remove(dir);
+        }
+    }
+}
+
+impl Export {
+    pub fn stream(self) -> ExportStream {
+        self.stream
+    }
+}
+
+impl Iterator for ExportStream {
+    type Item = io::Result<Bytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.tar.next()
+    }
+}
+
+impl Drop for ExportStream {
+    fn drop(&mut self) {
+        self.exports.ended(&self.dir, self.keep);
+    }
+}
+
+fn modified_ms(dir: &Path) -> anyhow::Result<u128> {
+    Ok(fs::metadata(dir)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis()))
+}
+
+fn remove(dir: &Path) {
+    if let Err(e) = fs::remove_dir_all(dir) {
+        warn!("Failed to remove {:?}, e = {}", dir, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_resume_headers() {
+        assert_eq!(
+            Some("1602959000000"),
+            parse_export_etag(&export_etag("1602959000000"))
+        );
+        assert_eq!(None, parse_export_etag("\"1602959000000\""));
+        assert_eq!(None, parse_export_etag("\"export-../x\""));
+        assert_eq!(None, parse_export_etag("export-1602959000000"));
+
+        assert_eq!(Some(0), parse_range("bytes=0-"));
+        assert_eq!(Some(1024), parse_range("bytes=1024-"));
+        assert_eq!(None, parse_range("bytes=0-99"));
+        assert_eq!(None, parse_range("bytes=-500"));
+    }
+
+    #[test]
+    fn should_keep_exports_for_resume_window() {
+        let parent = "./db/should_keep_exports_for_resume_window";
+        let dir = PathBuf::from(format!("{}/{}", parent, dir_name("1")));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("CURRENT"), b"MANIFEST-000001").unwrap();
+        let exports = Arc::new(Exports::default());
+
+        let export = exports
+            .stream("1", dir.clone(), "db", 0, true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(export.size, 512 * 4);
+        let mut stream = export.stream();
+        stream.next();
+        assert_eq!(0, exports.purge(parent, 0).unwrap());
+        drop(stream);
+
+        assert!(dir.exists());
+        assert_eq!(0, exports.purge(parent, 60_000).unwrap());
+        assert!(exports.stream("1", dir.clone(), "db", 2048, true).is_err());
+        assert_eq!(1, exports.purge(parent, 0).unwrap());
+        assert!(!dir.exists());
+        assert!(exports.stream("1", dir, "db", 0, true).unwrap().is_none());
+        fs::remove_dir_all(parent).unwrap();
+    }
+}
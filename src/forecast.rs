@@ -0,0 +1,219 @@
+// Disk usage history and forecast per db.
+//
+// Samples of disk usage, the sequence number (grows with every write) and the number of swept expired
+// records are taken periodically and kept in memory, so the history starts over on restart. Growth is the
+// least squares slope of disk usage over the sampled window, which already nets expirations against writes.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+
+use serde::Serialize;
+
+// 30 days of hourly samples
+pub const MAX_SAMPLES: usize = 24 * 30;
+const MS_PER_DAY: f64 = 24.0 * 60.0 * 60.0 * 1000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub at: u128,
+    pub bytes: u64,
+    pub seq: u64,
+    // swept expired records since startup
+    pub expired: u64,
+}
+
+#[derive(Default)]
+struct Usage {
+    samples: VecDeque<Sample>,
+    expired: u64,
+}
+
+#[derive(Default)]
+pub struct UsageHistory {
+    dbs: Mutex<HashMap<String, Usage>>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Forecast {
+    samples: usize,
+    window_ms: u128,
+    current_bytes: u64,
+    writes_per_day: f64,
+    expirations_per_day: f64,
+    growth_bytes_per_day: f64,
+    days: Vec<DayForecast>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DayForecast {
+    day: u32,
+    bytes: u64,
+}
+
+impl UsageHistory {
+    pub fn record_expired(&self, db_name: &str, count: usize) {
+        self.lock().entry(db_name.into()).or_default().expired += count as u64;
+    }
+
+    pub fn expired(&self, db_name: &str) -> u64 {
+        self.lock().get(db_name).map_or(0, |u| u.expired)
+    }
+
+    pub fn push(&self, db_name: &str, sample: Sample) {
+        let mut dbs = self.lock();
+        let samples = &mut dbs.entry(db_name.into()).or_default().samples;
+        if samples.len() == MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    pub fn samples(&self, db_name: &str) -> Vec<Sample> {
+        self.lock()
+            .get(db_name)
+            .map_or_else(Vec::new, |u| u.samples.iter().copied().collect())
+    }
+
+    pub fn remove_db(&self, db_name: &str) {
+        self.lock().remove(db_name);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Usage>> {
+        self.dbs.lock().expect("Can't acquire usage history lock")
+    }
+}
+
+// samples are ordered by time, the last one is the current state
+pub fn forecast(samples: &[Sample], days: u32) -> Forecast {
+    let (first, last) = match (samples.first(), samples.last()) {
+        (Some(first), Some(last)) => (*first, *last),
+        _ => {
+            return Forecast {
+                samples: 0,
+                window_ms: 0,
+                current_bytes: 0,
+                writes_per_day: 0.0,
+                expirations_per_day: 0.0,
+                growth_bytes_per_day: 0.0,
+                days: vec![],
+            }
+        }
+    };
+
+    let window_days = (last.at - first.at) as f64 / MS_PER_DAY;
+    let per_day = |delta: u64| {
+        if window_days > 0.0 {
+            delta as f64 / window_days
+        } else {
+            0.0
+        }
+    };
+    let growth = slope(samples, first.at);
+    let days = (1..=days)
+        .map(|day| DayForecast {
+            day,
+            bytes: (last.bytes as f64 + growth * f64::from(day)).max(0.0) as u64,
+        })
+        .collect();
+
+    Forecast {
+        samples: samples.len(),
+        window_ms: last.at - first.at,
+        current_bytes: last.bytes,
+        writes_per_day: per_day(last.seq.saturating_sub(first.seq)),
+        expirations_per_day: per_day(last.expired.saturating_sub(first.expired)),
+        growth_bytes_per_day: growth,
+        days,
+    }
+}
+
+// bytes per day
+fn slope(samples: &[Sample], start: u128) -> f64 {
+    let n = samples.len() as f64;
+    let points: Vec<(f64, f64)> = samples
+        .iter()
+        .map(|s| ((s.at - start) as f64 / MS_PER_DAY, s.bytes as f64))
+        .collect();
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let (cov, var) = points.iter().fold((0.0, 0.0), |(cov, var), (x, y)| {
+        (
+            cov + (x - mean_x) * (y - mean_y),
+            var + (x - mean_x) * (x - mean_x),
+        )
+    });
+
+    if var > 0.0 {
+        cov / var
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(day: u128, bytes: u64, seq: u64, expired: u64) -> Sample {
+        Sample {
+            at: day * MS_PER_DAY as u128,
+            bytes,
+            seq,
+            expired,
+        }
+    }
+
+    #[test]
+    fn should_project_linear_growth() {
+        let samples = vec![
+            sample(0, 1000, 0, 0),
+            sample(1, 2000, 100, 10),
+            sample(2, 3000, 200, 20),
+        ];
+        let forecast = forecast(&samples, 2);
+
+        assert_eq!(3, forecast.samples);
+        assert_eq!(3000, forecast.current_bytes);
+        assert!((forecast.growth_bytes_per_day - 1000.0).abs() < 1e-6);
+        assert!((forecast.writes_per_day - 100.0).abs() < 1e-6);
+        assert!((forecast.expirations_per_day - 10.0).abs() < 1e-6);
+        assert_eq!(
+            vec![
+                DayForecast {
+                    day: 1,
+                    bytes: 4000
+                },
+                DayForecast {
+                    day: 2,
+                    bytes: 5000
+                }
+            ],
+            forecast.days
+        );
+    }
+
+    #[test]
+    fn should_not_project_below_zero() {
+        let samples = vec![sample(0, 3000, 0, 0), sample(1, 1000, 0, 50)];
+        let forecast = forecast(&samples, 3);
+        assert_eq!(0, forecast.days[2].bytes);
+    }
+
+    #[test]
+    fn should_keep_bounded_history() {
+        let history = UsageHistory::default();
+        for i in 0..MAX_SAMPLES + 5 {
+            history.push("db", sample(i as u128, i as u64, 0, 0));
+        }
+        let samples = history.samples("db");
+        assert_eq!(MAX_SAMPLES, samples.len());
+        assert_eq!(5, samples[0].bytes);
+
+        history.record_expired("db", 3);
+        assert_eq!(3, history.expired("db"));
+        history.remove_db("db");
+        assert!(history.samples("db").is_empty());
+    }
+}
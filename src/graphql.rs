@@ -0,0 +1,329 @@
+// GraphQL endpoint (async-graphql) for dashboards and frontends, enabled with graphql = true in the service config.
+//
+// POST /graphql runs queries over the same DbManager as the REST API - databases, record lookups and prefix scans -
+// and mutations to open and close databases and to put and delete records. Keys are strings, values are returned
+// base64 encoded and as text when they're valid UTF-8. Like the REST data endpoints it isn't authenticated, so the
+// depth and complexity of a query are limited and all scans of a request share a budget of entries.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use actix_web::web::Bytes;
+use async_graphql::{
+    Context, EmptySubscription, Object, Request, Response, Result, Schema, SimpleObject,
+};
+
+use crate::config::RocksOverrides;
+use crate::conversion::{current_ms, decode_cursor, encode_cursor};
+use crate::db::{Data, DbManager, Representation, WriteCondition};
+use crate::key_encoding::display_key;
+
+const NO_TTL: u128 = 0;
+const MAX_SCAN_LIMIT: i32 = 1000;
+const MAX_DEPTH: usize = 8;
+const MAX_COMPLEXITY: usize = 200;
+const MAX_SCANNED: usize = 10 * MAX_SCAN_LIMIT as usize;
+pub const MAX_REQUEST_BYTES: usize = 64 * 1024;
+
+pub type RockySchema = Schema<Query, Mutation, EmptySubscription>;
+
+pub struct Query;
+
+pub struct Mutation;
+
+#[derive(SimpleObject)]
+pub struct Database {
+    name: String,
+    path: String,
+    opened_at: u64,
+    ephemeral: bool,
+}
+
+#[derive(SimpleObject)]
+pub struct Record {
+    key: String,
+    // base64 encoded
+    value: String,
+    // None when the value isn't UTF-8
+    text: Option<String>,
+    content_type: Option<String>,
+    version: u64,
+    // ms until the record expires, None when it doesn't
+    ttl_remaining: Option<u64>,
+}
+
+#[derive(SimpleObject)]
+pub struct Entry {
+    key: String,
+    value: String,
+    text: Option<String>,
+}
+
+#[derive(SimpleObject)]
+pub struct ScanPage {
+    entries: Vec<Entry>,
+    // continues the scan, None on the last page
+    cursor: Option<String>,
+}
+
+#[derive(SimpleObject)]
+pub struct Written {
+    version: u64,
+    seq: u64,
+}
+
+// entries the scans of a request can still return, aliased scans take from the same budget
+struct ScanBudget(AtomicUsize);
+
+impl ScanBudget {
+    fn take(&self, limit: usize) -> Result<()> {
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(limit)
+            })
+            .map(|_| ())
+            .map_err(|_| {
+                format!(
+                    "Scans of a request return at most {} entries together",
+                    MAX_SCANNED
+                )
+                .into()
+            })
+    }
+}
+
+pub fn schema(db_man: Arc<DbManager>) -> RockySchema {
+    Schema::build(Query, Mutation, EmptySubscription)
+        .data(db_man)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+// every request gets its own scan budget
+pub async fn execute(schema: &RockySchema, request: Request) -> Response {
+    schema
+        .execute(request.data(ScanBudget(AtomicUsize::new(MAX_SCANNED))))
+        .await
+}
+
+fn db_man<'a>(ctx: &Context<'a>) -> &'a DbManager {
+    ctx.data_unchecked::<Arc<DbManager>>()
+}
+
+fn text(value: &[u8]) -> Option<String> {
+    std::str::from_utf8(value).ok().map(String::from)
+}
+
+impl Record {
+    fn new(key: &str, data: Data) -> anyhow::Result<Self> {
+        let content_type = data.content_type().map(String::from);
+        let version = data.version();
+        let ttl_remaining = data.remaining_ttl()?.map(|ttl| ttl as u64);
+        let value = data.into_value();
+        Ok(Record {
+            key: key.into(),
+            text: text(&value),
+            value: base64::encode(value),
+            content_type,
+            version,
+            ttl_remaining,
+        })
+    }
+}
+
+#[Object]
+impl Query {
+    // open databases by name
+    async fn databases(&self, ctx: &Context<'_>) -> Vec<Database> {
+        db_man(ctx)
+            .list()
+            .into_iter()
+            .map(|info| Database {
+                name: info.name,
+                path: info.path,
+                opened_at: info.opened_at as u64,
+                ephemeral: info.ephemeral,
+            })
+            .collect()
+    }
+
+    // None when the record is missing or expired
+    async fn record(&self, ctx: &Context<'_>, db: String, key: String) -> Result<Option<Record>> {
+        match db_man(ctx).read(&db, key.as_bytes()).await? {
+            Some(data) => Ok(Some(Record::new(&key, data)?)),
+            None => Ok(None),
+        }
+    }
+
+    // records under the prefix in key order, a page at a time
+    async fn scan(
+        &self,
+        ctx: &Context<'_>,
+        db: String,
+        #[graphql(default)] prefix: String,
+        cursor: Option<String>,
+        #[graphql(default = 100)] limit: i32,
+    ) -> Result<ScanPage> {
+        if limit < 1 || limit > MAX_SCAN_LIMIT {
+            return Err(format!("limit has to be between 1 and {}", MAX_SCAN_LIMIT).into());
+        }
+        ctx.data::<ScanBudget>()?.take(limit as usize)?;
+        let cursor = cursor.as_deref().map(decode_cursor).transpose()?;
+        let (entries, next) =
+            db_man(ctx).entries(&db, prefix.as_bytes(), cursor, limit as usize)?;
+        Ok(ScanPage {
+            entries: entries
+                .into_iter()
+                .map(|(key, value)| Entry {
+                    key: display_key(&key).into(),
+                    text: text(&value),
+                    value: base64::encode(value),
+                })
+                .collect(),
+            cursor: next.as_deref().map(encode_cursor),
+        })
+    }
+}
+
+#[Object]
+impl Mutation {
+    async fn open_db(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        #[graphql(default)] ephemeral: bool,
+    ) -> Result<bool> {
+        db_man(ctx)
+            .open(name, ephemeral, RocksOverrides::default())
+            .await?;
+        Ok(true)
+    }
+
+    async fn close_db(&self, ctx: &Context<'_>, name: String) -> Result<bool> {
+        db_man(ctx).close(name).await?;
+        Ok(true)
+    }
+
+    // value is text unless base64 is set, ttl is in ms from now
+    async fn put(
+        &self,
+        ctx: &Context<'_>,
+        db: String,
+        key: String,
+        value: String,
+        #[graphql(default)] base64: bool,
+        ttl: Option<u64>,
+    ) -> Result<Written> {
+        let value = if base64 {
+            base64::decode(&value)?
+        } else {
+            value.into_bytes()
+        };
+        let ttl = match ttl {
+            Some(ttl) if ttl > 0 => current_ms()? + ttl as u128,
+            _ => NO_TTL,
+        };
+        let stored = db_man(ctx)
+            .store(
+                &db,
+                key.as_bytes(),
+                Bytes::from(value),
+                ttl,
+                NO_TTL,
+                Representation::default(),
+                WriteCondition::Always,
+            )
+            .await?;
+        Ok(Written {
+            version: stored.version,
+            seq: stored.seq,
+        })
+    }
+
+    // sequence number of the delete
+    async fn delete(&self, ctx: &Context<'_>, db: String, key: String) -> Result<u64> {
+        Ok(db_man(ctx).remove(&db, key.as_bytes()).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use crate::config::DbConfig;
+
+    use super::*;
+
+    fn run(schema: &RockySchema, query: &str) -> serde_json::Value {
+        let res = block_on(execute(schema, query.into()));
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        serde_json::to_value(&res.data).unwrap()
+    }
+
+    #[test]
+    fn should_query_and_mutate_records() {
+        let schema = schema(Arc::new(
+            DbManager::new(DbConfig::new_per_test_defaults()).unwrap(),
+        ));
+        run(&schema, r#"mutation { openDb(name: "test_db") }"#);
+        let written = run(
+            &schema,
+            r#"mutation { put(db: "test_db", key: "user:1", value: "payload") { version } }"#,
+        );
+        assert_eq!(1, written["put"]["version"]);
+        run(
+            &schema,
+            r#"mutation { put(db: "test_db", key: "user:2", value: "/w==", base64: true, ttl: 60000) { version } }"#,
+        );
+
+        let read = run(
+            &schema,
+            r#"{ databases { name } record(db: "test_db", key: "user:1") { value text ttlRemaining } }"#,
+        );
+        assert_eq!("test_db", read["databases"][0]["name"]);
+        assert_eq!(base64::encode("payload"), read["record"]["value"]);
+        assert_eq!("payload", read["record"]["text"]);
+        assert!(read["record"]["ttlRemaining"].is_null());
+
+        let page = run(
+            &schema,
+            r#"{ scan(db: "test_db", prefix: "user:", limit: 1) { entries { key text } cursor } }"#,
+        );
+        assert_eq!("user:1", page["scan"]["entries"][0]["key"]);
+        let cursor = page["scan"]["cursor"].as_str().unwrap().to_string();
+        let page = run(
+            &schema,
+            &format!(
+                r#"{{ scan(db: "test_db", prefix: "user:", cursor: "{}") {{ entries {{ key text }} cursor }} }}"#,
+                cursor
+            ),
+        );
+        assert_eq!("user:2", page["scan"]["entries"][0]["key"]);
+        assert!(page["scan"]["entries"][0]["text"].is_null());
+        assert!(page["scan"]["cursor"].is_null());
+
+        run(
+            &schema,
+            r#"mutation { delete(db: "test_db", key: "user:1") }"#,
+        );
+        let read = run(
+            &schema,
+            r#"{ record(db: "test_db", key: "user:1") { value } }"#,
+        );
+        assert!(read["record"].is_null());
+
+        let res = block_on(execute(
+            &schema,
+            r#"{ record(db: "missing", key: "k") { value } }"#.into(),
+        ));
+        assert_eq!(1, res.errors.len());
+
+        let scans = (0..11)
+            .map(|i| format!(r#"s{}: scan(db: "test_db", limit: 1000) {{ cursor }}"#, i))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let res = block_on(execute(&schema, format!("{{ {} }}", scans).into()));
+        assert_eq!(1, res.errors.len());
+    }
+}
@@ -0,0 +1,328 @@
+// gRPC API (proto/rocky.proto) next to the HTTP one, sharing its DbManager.
+//
+// Enabled with grpc_port in the service config, it listens on the same ip as the HTTP server. The server runs on
+// its own thread with its own actix system, like webhook deliveries. Scans are streamed page by page, so a large
+// db isn't held in memory, and bulk puts are applied as they arrive from the client stream.
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::thread;
+
+use actix_web::rt::System;
+use actix_web::web::Bytes;
+use anyhow::anyhow;
+use futures::{stream, Stream, StreamExt};
+use tonic::transport::Server;
+use tonic::{Code, Request, Response, Status, Streaming};
+
+use crate::config::RocksOverrides;
+use crate::conversion::current_ms;
+use crate::db::{DbManager, Representation, WriteCondition};
+use crate::errors::ErrorCtx;
+
+use proto::rocky_server::{Rocky, RockyServer};
+use proto::{
+    BulkPutResponse, CloseRequest, CloseResponse, DeleteRequest, DeleteResponse, GetRequest,
+    GetResponse, OpenRequest, OpenResponse, PutRequest, PutResponse, Record, ScanRequest,
+};
+
+pub mod proto {
+    tonic::include_proto!("rocky");
+}
+
+const NO_TTL: u128 = 0;
+const SCAN_PAGE: usize = 1000;
+
+type RecordStream = Pin<Box<dyn Stream<Item = Result<Record, Status>> + Send + Sync>>;
+
+pub struct RockyService {
+    db_man: Arc<DbManager>,
+}
+
+pub fn listen(address: &str, db_man: Arc<DbManager>) -> anyhow::Result<()> {
+    let addr: SocketAddr = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("Can't resolve grpc address {}", address))?;
+    thread::Builder::new()
+        .name("grpc-server".into())
+        .spawn(move || {
+            let mut runner = System::new("grpc");
+            info!("gRPC listening on {}", addr);
+            let served = runner.block_on(
+                Server::builder()
+                    .add_service(RockyServer::new(RockyService { db_man }))
+                    .serve(addr),
+            );
+            if let Err(e) = served {
+                error!("gRPC server stopped, e = {}", e);
+            }
+        })?;
+    Ok(())
+}
+
+// same mapping as the HTTP status codes of the errors
+fn to_status(e: anyhow::Error) -> Status {
+    let code = match e.downcast_ref::<ErrorCtx>() {
+        Some(ErrorCtx::Validation(_)) => Code::InvalidArgument,
+        Some(ErrorCtx::PreconditionFailed(_)) => Code::FailedPrecondition,
+        Some(ErrorCtx::Unauthorized(_)) => Code::Unauthenticated,
+        Some(ErrorCtx::Forbidden(_)) => Code::PermissionDenied,
+        Some(ErrorCtx::Unavailable(_)) => Code::Unavailable,
+        Some(ErrorCtx::NotFound(_)) | Some(ErrorCtx::Gone(_)) => Code::NotFound,
+        Some(ErrorCtx::Conflict(_)) => Code::AlreadyExists,
+        Some(ErrorCtx::TooManyRequests(_))
+        | Some(ErrorCtx::InsufficientStorage(_))
+        | Some(ErrorCtx::PayloadTooLarge(_)) => Code::ResourceExhausted,
+        None => Code::Internal,
+    };
+    Status::new(code, e.to_string())
+}
+
+impl RockyService {
+    async fn store(&self, put: PutRequest) -> anyhow::Result<PutResponse> {
+        let ttl = match put.ttl {
+            0 => NO_TTL,
+            ttl => current_ms()? + ttl as u128,
+        };
+        let stored = self
+            .db_man
+            .store(
+                &put.db,
+                &put.key,
+                Bytes::from(put.value),
+                ttl,
+                NO_TTL,
+                Representation::default(),
+                WriteCondition::Always,
+            )
+            .await?;
+        Ok(PutResponse {
+            version: stored.version,
+            seq: stored.seq,
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl Rocky for RockyService {
+    async fn open(&self, request: Request<OpenRequest>) -> Result<Response<OpenResponse>, Status> {
+        let open = request.into_inner();
+        self.db_man
+            .open(open.db, open.ephemeral, RocksOverrides::default())
+            .await
+            .map_err(to_status)?;
+        Ok(Response::new(OpenResponse {}))
+    }
+
+    async fn close(
+        &self,
+        request: Request<CloseRequest>,
+    ) -> Result<Response<CloseResponse>, Status> {
+        self.db_man
+            .close(request.into_inner().db)
+            .await
+            .map_err(to_status)?;
+        Ok(Response::new(CloseResponse {}))
+    }
+
+    async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
+        let stored = self.store(request.into_inner()).await.map_err(to_status)?;
+        Ok(Response::new(stored))
+    }
+
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let get = request.into_inner();
+        let data = self
+            .db_man
+            .read(&get.db, &get.key)
+            .await
+            .map_err(to_status)?;
+        let res = match data {
+            Some(data) => GetResponse {
+                found: true,
+                version: data.version(),
+                ttl_remaining: data.remaining_ttl().map_err(to_status)?.unwrap_or(0) as u64,
+                value: data.into_value(),
+            },
+            None => GetResponse::default(),
+        };
+        Ok(Response::new(res))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let delete = request.into_inner();
+        let seq = self
+            .db_man
+            .remove(&delete.db, &delete.key)
+            .await
+            .map_err(to_status)?;
+        Ok(Response::new(DeleteResponse { seq }))
+    }
+
+    type ScanStream = RecordStream;
+
+    async fn scan(&self, request: Request<ScanRequest>) -> Result<Response<RecordStream>, Status> {
+        let scan = request.into_inner();
+        if !self.db_man.contains(&scan.db) {
+            return Err(Status::invalid_argument(format!(
+                "Db {} - doesn't exist",
+                scan.db
+            )));
+        }
+        let limit = match scan.limit {
+            0 => usize::MAX,
+            limit => limit as usize,
+        };
+
+        // None once the last page is sent, a page read after the db is closed ends the stream with an error
+        let db_man = self.db_man.clone();
+        let pages = stream::unfold(Some(None), move |cursor| {
+            let page = cursor
+                .map(|cursor| db_man.entries(&scan.db, &scan.prefix, cursor, SCAN_PAGE.min(limit)));
+            async move {
+                match page? {
+                    Ok((entries, next)) => Some((Ok(entries), next.map(Some))),
+                    Err(e) => Some((Err(to_status(e)), None)),
+                }
+            }
+        });
+        let records = pages
+            .flat_map(|page| {
+                let records: Vec<Result<Record, Status>> = match page {
+                    Ok(entries) => entries
+                        .into_iter()
+                        .map(|(key, value)| Ok(Record { key, value }))
+                        .collect(),
+                    Err(status) => vec![Err(status)],
+                };
+                stream::iter(records)
+            })
+            .take(limit);
+        Ok(Response::new(Box::pin(records)))
+    }
+
+    async fn bulk_put(
+        &self,
+        request: Request<Streaming<PutRequest>>,
+    ) -> Result<Response<BulkPutResponse>, Status> {
+        let mut puts = request.into_inner();
+        let mut res = BulkPutResponse::default();
+        while let Some(put) = puts.message().await? {
+            let stored = self.store(put).await.map_err(to_status)?;
+            res.count += 1;
+            res.seq = stored.seq;
+        }
+        Ok(Response::new(res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use crate::config::DbConfig;
+    use crate::db::MAX_VALUE_BYTES;
+
+    use super::*;
+
+    fn service() -> RockyService {
+        RockyService {
+            db_man: Arc::new(DbManager::new(DbConfig::new_per_test_defaults()).unwrap()),
+        }
+    }
+
+    fn put(key: &str, value: &str) -> PutRequest {
+        PutRequest {
+            db: "test_db".into(),
+            key: key.into(),
+            value: value.into(),
+            ttl: 0,
+        }
+    }
+
+    #[test]
+    fn should_put_get_and_delete() {
+        let service = service();
+        block_on(service.open(Request::new(OpenRequest {
+            db: "test_db".into(),
+            ephemeral: false,
+        })))
+        .unwrap();
+
+        let stored = block_on(Rocky::put(&service, Request::new(put("k", "v"))))
+            .unwrap()
+            .into_inner();
+        assert_eq!(1, stored.version);
+
+        let get = || {
+            block_on(service.get(Request::new(GetRequest {
+                db: "test_db".into(),
+                key: b"k".to_vec(),
+            })))
+            .unwrap()
+            .into_inner()
+        };
+        let found = get();
+        assert!(found.found);
+        assert_eq!(b"v".to_vec(), found.value);
+        assert_eq!(0, found.ttl_remaining);
+
+        block_on(service.delete(Request::new(DeleteRequest {
+            db: "test_db".into(),
+            key: b"k".to_vec(),
+        })))
+        .unwrap();
+        assert!(!get().found);
+
+        let missing = block_on(service.get(Request::new(GetRequest {
+            db: "missing".into(),
+            key: b"k".to_vec(),
+        })))
+        .unwrap_err();
+        assert_eq!(Code::InvalidArgument, missing.code());
+
+        let value = "v".repeat(MAX_VALUE_BYTES + 1);
+        let too_large = block_on(Rocky::put(&service, Request::new(put("k", &value)))).unwrap_err();
+        assert_eq!(Code::InvalidArgument, too_large.code());
+    }
+
+    #[test]
+    fn should_stream_scanned_records() {
+        let service = service();
+        block_on(service.open(Request::new(OpenRequest {
+            db: "test_db".into(),
+            ephemeral: false,
+        })))
+        .unwrap();
+        for i in 0..SCAN_PAGE + 10 {
+            block_on(Rocky::put(
+                &service,
+                Request::new(put(&format!("a:{:05}", i), "v")),
+            ))
+            .unwrap();
+        }
+        block_on(Rocky::put(&service, Request::new(put("b:1", "v")))).unwrap();
+
+        let scan = |prefix: &str, limit: u64| {
+            let records = block_on(service.scan(Request::new(ScanRequest {
+                db: "test_db".into(),
+                prefix: prefix.into(),
+                limit,
+            })))
+            .unwrap()
+            .into_inner();
+            block_on(records.map(Result::unwrap).collect::<Vec<_>>())
+        };
+        let all = scan("a:", 0);
+        assert_eq!(SCAN_PAGE + 10, all.len());
+        assert_eq!(b"a:00000".to_vec(), all[0].key);
+        assert_eq!(b"a:01009".to_vec(), all[SCAN_PAGE + 9].key);
+        assert_eq!(3, scan("a:", 3).len());
+        assert_eq!(SCAN_PAGE + 11, scan("", 0).len());
+    }
+}
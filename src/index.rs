@@ -0,0 +1,144 @@
+// Secondary indexes over JSON values.
+//
+// A db with json = true accepts only JSON documents and indexes the fields listed in its indexes setting, e.g.
+// "email" or "address.city". Index entries are kept in the indexes column family as {field}\0{value}\0{key} and
+// written in the same batch as the record. Strings are indexed as they are, numbers and booleans by their JSON text
+// and every element of an array on its own, other values aren't indexed. GET /{db}/_query?{field}={value} looks up
+// keys by a field value, a key is returned only if its record still holds the value when it's read.
+
+use anyhow::anyhow;
+use serde_json::Value;
+
+use crate::errors::ErrorCtx;
+
+pub const INDEX_CF: &str = "indexes";
+const SEPARATOR: u8 = 0;
+
+pub fn check_json(value: &[u8]) -> anyhow::Result<()> {
+    serde_json::from_slice::<Value>(value)
+        .map(|_| ())
+        .map_err(|e| anyhow!(ErrorCtx::Validation(format!("Value isn't JSON - {}", e))))
+}
+
+// indexed values of the field, a dot separates nested fields
+pub fn field_values(field: &str, json: &Value) -> Vec<String> {
+    let pointer = format!("/{}", field.replace('.', "/"));
+    match json.pointer(&pointer) {
+        Some(Value::Array(items)) => items.iter().filter_map(scalar).collect(),
+        Some(value) => scalar(value).into_iter().collect(),
+        None => vec![],
+    }
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+// index entries of a value, none if it isn't JSON
+pub fn entries(fields: &[String], key: &[u8], value: &[u8]) -> Vec<Vec<u8>> {
+    let json: Value = match serde_json::from_slice(value) {
+        Ok(json) => json,
+        Err(_) => return vec![],
+    };
+    fields
+        .iter()
+        .flat_map(|field| {
+            field_values(field, &json)
+                .into_iter()
+                .map(move |v| entry_key(field, &v, key))
+        })
+        .collect()
+}
+
+pub fn holds(value: &[u8], field: &str, wanted: &str) -> bool {
+    serde_json::from_slice(value).map_or(false, |json| {
+        field_values(field, &json).iter().any(|v| v == wanted)
+    })
+}
+
+// entries of all keys holding the value, the key follows
+pub fn lookup_prefix(field: &str, value: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(field.len() + value.len() + 2);
+    prefix.extend_from_slice(field.as_bytes());
+    prefix.push(SEPARATOR);
+    prefix.extend_from_slice(value.as_bytes());
+    prefix.push(SEPARATOR);
+    prefix
+}
+
+// keys an entry may be for - fields hold no separator, but values and keys can, so every suffix following one after
+// the field is a candidate and the key of the entry is always among them
+pub fn entry_keys(entry: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let field_end = entry
+        .iter()
+        .position(|b| *b == SEPARATOR)
+        .unwrap_or(entry.len());
+    entry
+        .iter()
+        .enumerate()
+        .skip(field_end + 1)
+        .filter(|(_, b)| **b == SEPARATOR)
+        .map(move |(i, _)| &entry[i + 1..])
+}
+
+fn entry_key(field: &str, value: &str, key: &[u8]) -> Vec<u8> {
+    let mut entry = lookup_prefix(field, value);
+    entry.extend_from_slice(key);
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_index_scalars_and_arrays() {
+        let value =
+            br#"{"email":"a@b.c","age":42,"address":{"city":"Split"},"tags":["x","y"],"meta":{}}"#;
+        let fields: Vec<String> = ["email", "age", "address.city", "tags", "meta", "missing"]
+            .iter()
+            .map(|f| f.to_string())
+            .collect();
+
+        let indexed = entries(&fields, b"k1", value);
+        assert_eq!(
+            vec![
+                entry_key("email", "a@b.c", b"k1"),
+                entry_key("age", "42", b"k1"),
+                entry_key("address.city", "Split", b"k1"),
+                entry_key("tags", "x", b"k1"),
+                entry_key("tags", "y", b"k1"),
+            ],
+            indexed
+        );
+        assert!(indexed[0].starts_with(&lookup_prefix("email", "a@b.c")));
+
+        assert!(holds(value, "address.city", "Split"));
+        assert!(!holds(value, "address.city", "Zagreb"));
+        assert!(entries(&fields, b"k1", b"not json").is_empty());
+    }
+
+    #[test]
+    fn should_find_key_of_entry() {
+        let entry = entry_key("email", "a@b.c", b"user:1");
+        assert_eq!(
+            vec![b"user:1".as_ref()],
+            entry_keys(&entry).collect::<Vec<_>>()
+        );
+
+        let entry = entry_key("name", "a\0b", b"k\0ey");
+        let keys: Vec<&[u8]> = entry_keys(&entry).collect();
+        assert_eq!(vec![b"b\0k\0ey".as_ref(), b"k\0ey", b"ey"], keys);
+    }
+
+    #[test]
+    fn should_reject_invalid_json() {
+        assert!(check_json(br#"{"a":1}"#).is_ok());
+        assert!(check_json(b"{").is_err());
+    }
+}
@@ -7,8 +7,12 @@ use actix_web::http::StatusCode;
 use actix_web::rt as actix_rt;
 use actix_web::{test, web, App};
 
-use crate::config::{DbConfig, RocksDbConfig};
-use crate::conversion::bytes_to_str;
+use crate::auth::{AdminToken, MetricsAuth};
+use crate::compression::{Codec, Compression};
+use crate::config::{DbConfig, DbSettings, KeyPolicy, RocksDbConfig};
+use crate::conversion::{bytes_to_str, FromBytes, IntoBytes};
+use crate::db::Data;
+use crate::wire::{self, WireRecord};
 
 use super::*;
 
@@ -30,6 +34,8 @@ impl DbConfig {
     pub fn new_per_test_defaults() -> Self {
         let mut cfg = RocksDbConfig::default();
         cfg.path = format!("{}/{}", cfg.path, safe_test_name());
+        cfg.backup_path = format!("{}/backup", cfg.path);
+        cfg.checkpoint_path = format!("{}/checkpoint", cfg.path);
 
         DbConfig(cfg)
     }
@@ -210,25 +216,5021 @@ async fn should_expire_record() -> anyhow::Result<()> {
 }
 
 #[actix_rt::test]
-async fn should_check_service_status() -> anyhow::Result<()> {
+async fn should_return_remaining_ttl() -> anyhow::Result<()> {
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("Will expire after 1 minute")
+        .header("ttl", "60000")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let remaining: u128 = res
+        .headers()
+        .get("ttl-remaining")
+        .expect("ttl-remaining header expected")
+        .to_str()?
+        .parse()?;
+    assert!(remaining > 0 && remaining <= 60000);
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_advance_time_only_with_test_clock() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(AdminToken::new(Some("secret".into()))))
+            .configure(|cfg| test_routes(cfg, false)),
+    )
+    .await;
+    let req = test::TestRequest::post()
+        .uri("/_test/advance_time?ms=0")
+        .header("authorization", "Bearer secret")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(AdminToken::new(Some("secret".into()))))
+            .configure(|cfg| test_routes(cfg, true)),
+    )
+    .await;
+    let req = test::TestRequest::post()
+        .uri("/_test/advance_time?ms=0")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+    // the clock is process wide, tests running alongside must not see it move
+    let req = test::TestRequest::post()
+        .uri("/_test/advance_time?ms=0")
+        .header("authorization", "Bearer secret")
+        .to_request();
+    let offset: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert!(offset["offsetMs"].as_u64().is_some());
+
+    let req = test::TestRequest::post()
+        .uri("/_test/advance_time")
+        .header("authorization", "Bearer secret")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_serve_versioned_and_deprecated_routes() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .configure(versioned_routes),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/v1/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/v1/test_db/record_1")
+        .set_payload("payload")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert!(res.headers().get("deprecation").is_none());
+
+    // the unversioned path answers the same but points to its successor
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("true", res.headers().get("deprecation").unwrap());
+    assert_eq!(
+        "</v1/test_db/record_1>; rel=\"successor-version\"",
+        res.headers().get("link").unwrap()
+    );
+    assert_eq!("payload", test::read_body(res).await);
+
+    let req = test::TestRequest::get().uri("/v1/_dbs").to_request();
+    let dbs: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!("test_db", dbs[0]["name"]);
+
+    let req = test::TestRequest::post().uri("/v2").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri("/v1/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_list_open_dbs() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(list_dbs)
+            .service(open)
+            .service(close),
+    )
+    .await;
+
+    for db in &["test_db_2", "test_db_1"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}", db))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::post().uri("/_reserved").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::get().uri("/_dbs").to_request();
+    let dbs: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!("test_db_1", dbs[0]["name"]);
+    assert_eq!("test_db_2", dbs[1]["name"]);
+    assert!(dbs[0]["path"].as_str().unwrap().ends_with("test_db_1"));
+    assert!(dbs[0]["openedAt"].as_u64().is_some());
+
+    for db in &["test_db_1", "test_db_2"] {
+        let req = test::TestRequest::delete()
+            .uri(&format!("/{}", db))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_delete_closed_db_in_background() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(deletions)
+            .service(open)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let mut deleted = false;
+    for _ in 0..50 {
+        let req = test::TestRequest::get().uri("/_deletions").to_request();
+        let pending: serde_json::Value = test::read_response_json(&mut app, req).await;
+        if pending.as_array().map_or(false, Vec::is_empty) {
+            deleted = true;
+            break;
+        }
+        assert_eq!("test_db", pending[0]["name"]);
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(deleted, "Db files should have been deleted");
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_keep_ephemeral_db_in_memory() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let cfg = DbConfig::new_per_test_defaults();
+    let db_path = cfg.db_path("test_db");
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(list_dbs)
+            .service(open)
+            .service(close)
+            .service(checkpoint)
+            .service(store)
+            .service(read),
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/test_db?ephemeral=true")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert!(!Path::new(&db_path).exists());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/k1")
+        .set_payload("v1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get().uri("/test_db/k1").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("v1", response_as_str(res)?);
+
+    let req = test::TestRequest::get().uri("/_dbs").to_request();
+    let dbs: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(true, dbs[0]["ephemeral"]);
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_checkpoint")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    // nothing survives a close
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let req = test::TestRequest::post()
+        .uri("/test_db?ephemeral=true")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let req = test::TestRequest::get().uri("/test_db/k1").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NO_CONTENT, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_round_trip_binary_keys() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(keys)
+            .service(scan)
+            .service(store)
+            .service(read)
+            .service(remove)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    // [0, 255, '/', 1]
+    let req = test::TestRequest::post()
+        .uri("/test_db/AP8vAQ")
+        .header("key-encoding", "base64")
+        .set_payload("binary")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/AP8vAQ==")
+        .header("key-encoding", "base64")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("binary", response_as_str(res)?);
+
+    // the same path as a UTF-8 key is another record
+    let req = test::TestRequest::get().uri("/test_db/AP8vAQ").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_keys")
+        .header("key-encoding", "base64")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("base64", res.headers().get("key-encoding").unwrap());
+    let page: serde_json::Value = serde_json::from_str(&response_as_str(res)?)?;
+    assert_eq!(serde_json::json!(["AP8vAQ"]), page["keys"]);
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_scan")
+        .header("key-encoding", "base64")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!("AP8vAQ", page["records"][0]["key"]);
+
+    let req = test::TestRequest::get().uri("/test_db/_keys").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete()
+        .uri("/test_db/AP8vAQ")
+        .header("key-encoding", "base64")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get().uri("/test_db/_keys").to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!([]), page["keys"]);
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_enforce_strict_keys_with_header_route() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            key_policy: KeyPolicy::Strict,
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(keys)
+            .service(store_key)
+            .service(read_key)
+            .service(head_key)
+            .service(remove_key)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for (key, status) in &[
+        ("user-1.v2", StatusCode::OK),
+        ("user%3A1", StatusCode::BAD_REQUEST),
+        ("user%201", StatusCode::BAD_REQUEST),
+    ] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload("value")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(*status, res.status(), "{}", key);
+    }
+
+    // the base64 encoded path key is binary-safe, strict or not
+    let req = test::TestRequest::get()
+        .uri("/test_db/dXNlci0xLnYy")
+        .header("key-encoding", "base64")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    // [0, 255, '/', 1] and "user:1"
+    for key in &["AP8vAQ", "dXNlcjox"] {
+        let req = test::TestRequest::post()
+            .uri("/test_db/_key")
+            .header("key", *key)
+            .set_payload(*key)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+
+        let req = test::TestRequest::get()
+            .uri("/test_db/_key")
+            .header("key", *key)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(*key, response_as_str(res)?);
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_keys")
+        .header("key-encoding", "base64")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(
+        serde_json::json!(["AP8vAQ", "dXNlci0xLnYy", "dXNlcjox"]),
+        page["keys"]
+    );
+
+    let req = test::TestRequest::delete()
+        .uri("/test_db/_key")
+        .header("key", "AP8vAQ")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::default()
+        .method(http::Method::HEAD)
+        .uri("/test_db/_key")
+        .header("key", "AP8vAQ")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+    let req = test::TestRequest::get().uri("/test_db/_key").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_add_db_response_headers() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            default_value: Some("{}".into()),
+            response_headers: vec![
+                ("Cache-Control".into(), "public, max-age=60".into()),
+                ("X-Tenant".into(), "acme".into()),
+            ]
+            .into_iter()
+            .collect(),
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(read)
+            .service(head_record)
+            .service(close),
+    )
+    .await;
+
+    for db in &["test_db", "other_db"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}", db))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/key_1", db))
+            .set_payload("value")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::get().uri("/test_db/key_1").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(
+        "public, max-age=60",
+        res.headers().get("cache-control").unwrap()
+    );
+    assert_eq!("acme", res.headers().get("x-tenant").unwrap());
+    let etag = res.headers().get("etag").unwrap().clone();
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/key_1")
+        .header("If-None-Match", etag)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NOT_MODIFIED, res.status());
+    assert_eq!("acme", res.headers().get("x-tenant").unwrap());
+
+    let req = test::TestRequest::default()
+        .method(http::Method::HEAD)
+        .uri("/test_db/key_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("acme", res.headers().get("x-tenant").unwrap());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/missing")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("acme", res.headers().get("x-tenant").unwrap());
+
+    let req = test::TestRequest::get().uri("/other_db/key_1").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert!(res.headers().get("x-tenant").is_none());
+
+    for db in &["test_db", "other_db"] {
+        let req = test::TestRequest::delete()
+            .uri(&format!("/{}", db))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_assemble_multipart_upload() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            multipart_uploads: true,
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(begin_upload)
+            .service(upload_status)
+            .service(upload_part)
+            .service(complete_upload)
+            .service(abort_upload)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/big_1/_mpu")
+        .header("Content-Type", "text/plain")
+        .to_request();
+    let upload: serde_json::Value = test::read_response_json(&mut app, req).await;
+    let upload_id = upload["uploadId"].as_str().expect("Upload id expected");
+    let uri = format!("/test_db/big_1/_mpu/{}", upload_id);
+
+    // out of order, part 1 retried
+    for (number, part) in &[(2, "second "), (1, "lost"), (1, "first "), (3, "third")] {
+        let req = test::TestRequest::put()
+            .uri(&format!("{}/parts/{}", uri, number))
+            .set_payload(*part)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let status: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(
+        serde_json::json!([1, 2, 3]),
+        serde_json::json!(status["parts"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["number"].clone())
+            .collect::<Vec<_>>())
+    );
+
+    let req = test::TestRequest::get().uri("/test_db/big_1").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+    let req = test::TestRequest::post()
+        .uri(&format!("{}/complete", uri))
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get().uri("/test_db/big_1").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("text/plain", res.headers().get("content-type").unwrap());
+    assert_eq!("first second third", response_as_str(res)?);
+
+    // completing drops the upload
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+    // a gap fails completion, an aborted upload is gone
+    let req = test::TestRequest::post()
+        .uri("/test_db/big_2/_mpu")
+        .to_request();
+    let upload: serde_json::Value = test::read_response_json(&mut app, req).await;
+    let uri = format!(
+        "/test_db/big_2/_mpu/{}",
+        upload["uploadId"].as_str().unwrap()
+    );
+    let req = test::TestRequest::put()
+        .uri(&format!("{}/parts/2", uri))
+        .set_payload("second")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri(&format!("{}/complete", uri))
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri(&uri).to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_list_keys_with_cursor() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(keys)
+            .service(store)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for key in &["record_1", "record_2", "record_3"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload("payload")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_keys?limit=2")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!(["record_1", "record_2"]), page["keys"]);
+
+    let cursor = page["cursor"].as_str().expect("Cursor expected");
+    let req = test::TestRequest::get()
+        .uri(&format!("/test_db/_keys?limit=2&cursor={}", cursor))
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!(["record_3"]), page["keys"]);
+    assert!(page["cursor"].is_null());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_page_through_listing_snapshot() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(keys)
+            .service(scan)
+            .service(store)
+            .service(remove)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for key in &["record_1", "record_2", "record_3"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload("payload")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let mut cursors = vec![];
+    for listing in &["_keys", "_scan"] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/test_db/{}?limit=1", listing))
+            .to_request();
+        let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+        cursors.push(page["cursor"].as_str().expect("Cursor expected").to_owned());
+    }
+
+    // changes after the listings started aren't seen by them
+    let req = test::TestRequest::delete()
+        .uri("/test_db/record_2")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_4")
+        .set_payload("payload")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/test_db/_keys?cursor={}", cursors[0]))
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!(["record_2", "record_3"]), page["keys"]);
+    assert!(page["cursor"].is_null());
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/test_db/_scan?cursor={}", cursors[1]))
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!("record_2", page["records"][0]["key"]);
+    assert_eq!(2, page["records"].as_array().unwrap().len());
+
+    // a new listing sees the changes
+    let req = test::TestRequest::get().uri("/test_db/_keys").to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(
+        serde_json::json!(["record_1", "record_3", "record_4"]),
+        page["keys"]
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_keys?cursor=999.cmVjb3JkXzI")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::GONE, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_list_in_descending_order() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            key_delimiter: ":".into(),
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(keys)
+            .service(scan)
+            .service(store)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for key in &[
+        "teams:1",
+        "users:42:orders:1",
+        "users:42:orders:2",
+        "users:43:name",
+        "users:count",
+        "zones:1",
+    ] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload(key.to_string())
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_keys?order=desc&limit=4")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(
+        serde_json::json!([
+            "zones:1",
+            "users:count",
+            "users:43:name",
+            "users:42:orders:2"
+        ]),
+        page["keys"]
+    );
+
+    let cursor = page["cursor"].as_str().expect("Cursor expected");
+    let req = test::TestRequest::get()
+        .uri(&format!("/test_db/_keys?order=desc&cursor={}", cursor))
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(
+        serde_json::json!(["users:42:orders:1", "teams:1"]),
+        page["keys"]
+    );
+    assert!(page["cursor"].is_null());
+
+    // the prefix bounds the listing from both sides
+    let req = test::TestRequest::get()
+        .uri("/test_db/_keys?prefix=users:&namespaces=true&order=desc")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!(["users:count"]), page["keys"]);
+    assert_eq!(
+        serde_json::json!(["users:43:", "users:42:"]),
+        page["namespaces"]
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_scan?order=desc&limit=1")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!("zones:1", page["records"][0]["key"]);
+
+    let cursor = page["cursor"].as_str().expect("Cursor expected");
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/test_db/_scan?order=desc&limit=1&cursor={}",
+            cursor
+        ))
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!("users:count", page["records"][0]["key"]);
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_keys?order=sideways")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_list_and_delete_namespaces() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            key_delimiter: ":".into(),
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(keys)
+            .service(remove_prefix)
+            .service(store)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for key in &[
+        "teams:1",
+        "users:42:orders:1",
+        "users:42:orders:2",
+        "users:43:name",
+        "users:count",
+    ] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload("payload")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_keys?prefix=users:")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(
+        serde_json::json!([
+            "users:42:orders:1",
+            "users:42:orders:2",
+            "users:43:name",
+            "users:count"
+        ]),
+        page["keys"]
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_keys?prefix=users:&namespaces=true&limit=2")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(
+        serde_json::json!(["users:42:", "users:43:"]),
+        page["namespaces"]
+    );
+    assert!(page["keys"].as_array().unwrap().is_empty());
+
+    let cursor = page["cursor"].as_str().expect("Cursor expected");
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/test_db/_keys?prefix=users:&namespaces=true&limit=2&cursor={}",
+            cursor
+        ))
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!(["users:count"]), page["keys"]);
+    assert!(page["cursor"].is_null());
+
+    let req = test::TestRequest::delete()
+        .uri("/test_db/_prefix/users:42:")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get().uri("/test_db/_keys").to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(
+        serde_json::json!(["teams:1", "users:43:name", "users:count"]),
+        page["keys"]
+    );
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_query_json_indexes() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            json: true,
+            indexes: vec!["email".into(), "address.city".into()],
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(query)
+            .service(store)
+            .service(remove)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let users = [
+        (
+            "user_1",
+            r#"{"email":"a@rocky.io","address":{"city":"Split"}}"#,
+        ),
+        (
+            "user_2",
+            r#"{"email":"b@rocky.io","address":{"city":"Zagreb"}}"#,
+        ),
+        (
+            "user_3",
+            r#"{"email":"c@rocky.io","address":{"city":"Split"}}"#,
+        ),
+    ];
+    for (key, user) in &users {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload(*user)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/user_4")
+        .set_payload("not json")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_query?address.city=Split")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!(["user_1", "user_3"]), page["keys"]);
+
+    // moved and deleted records drop out
+    let req = test::TestRequest::post()
+        .uri("/test_db/user_1")
+        .set_payload(r#"{"email":"a@rocky.io","address":{"city":"Zagreb"}}"#)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let req = test::TestRequest::delete()
+        .uri("/test_db/user_2")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_query?address.city=Split")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!(["user_3"]), page["keys"]);
+    let req = test::TestRequest::get()
+        .uri("/test_db/_query?address.city=Zagreb")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!(["user_1"]), page["keys"]);
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_query?name=x")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_scan_records() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(scan)
+            .service(store)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for key in &["record_1", "record_2"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload("payload")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_scan?limit=1")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(
+        serde_json::json!([{"key": "record_1", "value": base64::encode("payload")}]),
+        page["records"]
+    );
+
+    let cursor = page["cursor"].as_str().expect("Cursor expected");
+    let req = test::TestRequest::get()
+        .uri(&format!("/test_db/_scan?cursor={}", cursor))
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!("record_2", page["records"][0]["key"]);
+    assert!(page["cursor"].is_null());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_scan?fields=keys")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(
+        serde_json::json!([{"key": "record_1"}, {"key": "record_2"}]),
+        page["records"]
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_scan?fields=values&limit=1")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(
+        serde_json::json!([{"value": base64::encode("payload")}]),
+        page["records"]
+    );
+    assert!(page["cursor"].is_string());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_filter_scanned_records() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(scan)
+            .service(store)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for (key, value) in &[
+        ("user_1", r#"{"name":"rocky","address":{"city":"Split"}}"#),
+        ("user_2", r#"{"name":"adrian","address":{"city":"Zagreb"}}"#),
+        ("user_3", r#"{"name":"apollo","address":{"city":"Split"}}"#),
+        ("note_1", "plain text"),
+    ] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload(*value)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_scan?field=address.city&equals=Split")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    let keys: Vec<_> = page["records"]
+        .as_array()
+        .expect("Records expected")
+        .iter()
+        .map(|r| r["key"].as_str().unwrap_or_default())
+        .collect();
+    assert_eq!(vec!["user_1", "user_3"], keys);
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_scan?value_prefix=plain")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(
+        serde_json::json!([{"key": "note_1", "value": base64::encode("plain text")}]),
+        page["records"]
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_scan?field=address.city")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_write_and_read_binary_batches() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(batch)
+            .service(multi_get)
+            .service(store)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_3")
+        .set_payload("to be deleted")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let records = vec![
+        WireRecord::new("record_1".into(), Some(b"val_1".to_vec())),
+        WireRecord::new("record_2".into(), Some(b"val_2".to_vec())),
+        WireRecord::new("record_3".into(), None),
+    ];
+    let req = test::TestRequest::post()
+        .uri("/test_db/_batch")
+        .set_payload(wire::encode(&records))
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let keys: Vec<WireRecord> = vec!["record_1", "record_3", "record_2"]
+        .into_iter()
+        .map(|k| WireRecord::new(k.into(), None))
+        .collect();
+    let req = test::TestRequest::post()
+        .uri("/test_db/_mget")
+        .set_payload(wire::encode(&keys))
+        .to_request();
+    let body = test::read_response(&mut app, req).await;
+    assert_eq!(
+        vec![
+            WireRecord::new("record_1".into(), Some(b"val_1".to_vec())),
+            WireRecord::new("record_3".into(), None),
+            WireRecord::new("record_2".into(), Some(b"val_2".to_vec())),
+        ],
+        wire::decode(&body)?
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_batch")
+        .set_payload(vec![1, 0, 0, 0, 1])
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_write_multipart_batch() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(multipart_batch)
+            .service(batch)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let form = "--XyZ\r\n\
+        Content-Disposition: form-data; name=\"record_1\"\r\n\r\n\
+        val_1\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"record_2\"; filename=\"v.txt\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        val_2\r\n\
+        --XyZ--\r\n";
+    let req = test::TestRequest::post()
+        .uri("/test_db/_batch")
+        .header(
+            http::header::CONTENT_TYPE,
+            "multipart/form-data; boundary=XyZ",
+        )
+        .set_payload(form)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for (key, value) in &[("record_1", "val_1"), ("record_2", "val_2")] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/test_db/{}", key))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(*value, response_as_str(res)?);
+    }
+
+    let form = "--XyZ\r\n\
+        Content-Disposition: form-data\r\n\r\n\
+        val\r\n\
+        --XyZ--\r\n";
+    let req = test::TestRequest::post()
+        .uri("/test_db/_batch")
+        .header(
+            http::header::CONTENT_TYPE,
+            "multipart/form-data; boundary=XyZ",
+        )
+        .set_payload(form)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_apply_txn_atomically() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(txn)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_3")
+        .set_payload("to be deleted")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let ops = serde_json::json!({"ops": [
+        {"op": "put", "key": "record_1", "value": base64::encode("val_1")},
+        {"op": "put", "key": "record_2", "value": base64::encode("val_2"), "ttl": 60000, "expectedVersion": 0},
+        {"op": "put", "key": "record_1", "value": base64::encode("val_1_2")},
+        {"op": "delete", "key": "record_3", "expectedVersion": 1}
+    ]});
+    let req = test::TestRequest::post()
+        .uri("/test_db/_txn")
+        .set_json(&ops)
+        .to_request();
+    let res: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!([1, 1, 2, null]), res["versions"]);
+    assert!(res["seq"].as_u64().is_some());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("val_1_2", response_as_str(res)?);
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_3")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+    // the failed condition of the last op aborts the put before it
+    let ops = serde_json::json!({"ops": [
+        {"op": "put", "key": "record_4", "value": base64::encode("val_4")},
+        {"op": "delete", "key": "record_2", "expectedVersion": 7}
+    ]});
+    let req = test::TestRequest::post()
+        .uri("/test_db/_txn")
+        .set_json(&ops)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::PRECONDITION_FAILED, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_4")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+    for body in &[
+        r#"{"ops": []}"#,
+        r#"{"ops": [{"op": "merge", "key": "a"}]}"#,
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/test_db/_txn")
+            .set_payload(*body)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_commit_interactive_txn() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            interactive_txns: true,
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(begin_txn)
+            .service(commit_txn)
+            .service(rollback_txn)
+            .service(txn_read)
+            .service(txn_store)
+            .service(txn_remove)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    for db in &["test_db", "test_db_2"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}", db))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/test_db_2/_txn/begin")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/counter")
+        .set_payload("1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_txn/begin")
+        .to_request();
+    let res: serde_json::Value = test::read_response_json(&mut app, req).await;
+    let token = res["token"].as_str().unwrap().to_string();
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/test_db/_txn/{}/counter", token))
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("1", response_as_str(res)?);
+
+    for (method, key) in &[
+        (http::Method::POST, "counter"),
+        (http::Method::DELETE, "to_delete"),
+    ] {
+        let req = test::TestRequest::default()
+            .method(method.clone())
+            .uri(&format!("/test_db/_txn/{}/{}", token, key))
+            .set_payload("2")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    // own writes are visible, others only after commit
+    let req = test::TestRequest::get()
+        .uri(&format!("/test_db/_txn/{}/counter", token))
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("2", response_as_str(res)?);
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/counter")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("1", response_as_str(res)?);
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/test_db/_txn/{}/_commit", token))
+        .to_request();
+    let res: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!([null, 2, null]), res["versions"]);
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/counter")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("2", response_as_str(res)?);
+
+    // a concurrent write to a record read by the transaction fails the commit
+    let req = test::TestRequest::post()
+        .uri("/test_db/_txn/begin")
+        .to_request();
+    let res: serde_json::Value = test::read_response_json(&mut app, req).await;
+    let token = res["token"].as_str().unwrap().to_string();
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/test_db/_txn/{}/counter", token))
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/counter")
+        .set_payload("concurrent")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/test_db/_txn/{}/counter", token))
+        .set_payload("3")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/test_db/_txn/{}/_commit", token))
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::PRECONDITION_FAILED, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/counter")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("concurrent", response_as_str(res)?);
+
+    // ended transactions are gone
+    for uri in &[
+        format!("/test_db/_txn/{}/_commit", token),
+        format!("/test_db/_txn/{}/_rollback", token),
+    ] {
+        let req = test::TestRequest::post().uri(uri).to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    for db in &["test_db", "test_db_2"] {
+        let req = test::TestRequest::delete()
+            .uri(&format!("/{}", db))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_open_db_with_rocksdb_overrides() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(exists)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/test_db")
+        .set_payload(r#"{"compaction_style": "sideways"}"#)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db")
+        .set_payload(r#"{"max_open_files": 10}"#)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::get().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db")
+        .set_payload(
+            r#"{"write_buffer_size": 1048576, "compaction_style": "universal", "compression": "none"}"#,
+        )
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("payload")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("payload", test::read_body(res).await);
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_delete_listed_keys() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(keys)
+            .service(multi_delete)
+            .service(store)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for key in &["a", "b", "c"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload("payload")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_mdelete")
+        .set_payload(r#"["a", "missing", "c", "a"]"#)
+        .to_request();
+    let removed: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(
+        serde_json::json!([
+            {"key": "a", "status": "deleted"},
+            {"key": "missing", "status": "not_found"},
+            {"key": "c", "status": "deleted"},
+            {"key": "a", "status": "deleted"}
+        ]),
+        removed["results"]
+    );
+    assert!(removed["seq"].as_u64().unwrap() > 0);
+
+    let req = test::TestRequest::get().uri("/test_db/_keys").to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!(["b"]), page["keys"]);
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_mdelete")
+        .set_payload(r#"{"keys": ["b"]}"#)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_read_listed_keys_as_json() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(json_multi_get)
+            .service(multi_get)
+            .service(store)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/a")
+        .set_payload("payload")
+        .header("content-type", "text/plain")
+        .header("ttl", "60000")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/b")
+        .set_payload("other")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_mget")
+        .header("content-type", "application/json")
+        .set_payload(r#"["a", "b", "missing"]"#)
+        .to_request();
+    let records: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(true, records["a"]["found"]);
+    assert_eq!(base64::encode("payload"), records["a"]["value"]);
+    assert_eq!("text/plain", records["a"]["contentType"]);
+    let ttl = records["a"]["ttl"].as_u64().unwrap();
+    assert!(ttl > 0 && ttl <= 60000);
+    assert_eq!(
+        serde_json::json!({"found": true, "value": base64::encode("other")}),
+        records["b"]
+    );
+    assert_eq!(serde_json::json!({"found": false}), records["missing"]);
+
+    // the binary batch is still answered without the JSON content type
+    let req = test::TestRequest::post()
+        .uri("/test_db/_mget")
+        .set_payload(wire::encode(&[WireRecord::new("b".into(), None)]))
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let body = test::read_body(res).await;
+    assert_eq!(
+        vec![WireRecord::new("b".into(), Some(b"other".to_vec()))],
+        wire::decode(&body)?
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_mget")
+        .header("content-type", "application/json")
+        .set_payload(r#"{"keys": ["a"]}"#)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_remove_key_range() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(keys)
+            .service(remove_range)
+            .service(store)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for key in &["a", "b", "c", "d"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload("payload")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::delete()
+        .uri("/test_db/_range?start=b&end=d")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(
+        StatusCode::OK,
+        res.status(),
+        "Received payload:: {:?}",
+        response_as_str(res)
+    );
+
+    let req = test::TestRequest::get().uri("/test_db/_keys").to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!(["a", "d"]), page["keys"]);
+
+    let req = test::TestRequest::delete()
+        .uri("/test_db/_range?start=d&end=a")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_cache_listing_until_db_advances() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            scan_cache_ms: 60_000,
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(keys)
+            .service(store)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .header(TTL_HEADER, "50")
+        .set_payload("value")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get().uri("/test_db/_keys").to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!(["record_1"]), page["keys"]);
+
+    // expiring doesn't advance the db, the cached page is served
+    actix_rt::time::delay_for(Duration::from_millis(100)).await;
+    let req = test::TestRequest::get().uri("/test_db/_keys").to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!(["record_1"]), page["keys"]);
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_2")
+        .set_payload("value")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get().uri("/test_db/_keys").to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!(["record_2"]), page["keys"]);
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_count_keys() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(count)
+            .service(store)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for key in &["record_1", "record_2", "record_3"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload("value")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+    // already expired
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_4")
+        .header(TTL_HEADER, "1")
+        .set_payload("value")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    actix_rt::time::delay_for(Duration::from_millis(10)).await;
+
+    let req = test::TestRequest::get().uri("/test_db/_count").to_request();
+    let count: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!({"count": 3, "approximate": false}), count);
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_count?approx=true")
+        .to_request();
+    let count: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!(true), count["approximate"]);
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_expose_db_internals() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(internals)
+            .service(db_stats)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_internals")
+        .to_request();
+    let internals: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert!(internals["levels"].is_array());
+    assert_eq!(serde_json::json!(false), internals["writeStopped"]);
+
+    // nothing flushed yet
+    let req = test::TestRequest::get().uri("/test_db/_stats").to_request();
+    let stats: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(0, stats["physicalBytes"]);
+    assert_eq!(serde_json::json!([]), stats["levels"]);
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_compress_values_at_rest() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            compression: Some(Codec::Gzip),
+            compression_min_bytes: 16,
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(append)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let value = "text heavy value ".repeat(100);
+    for (key, value) in &[("record_1", value.as_str()), ("record_2", "small")] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload(value.to_string())
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::patch()
+        .uri("/test_db/record_1")
+        .set_payload("!")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let expected = format!("{}!", value);
+    for (key, value) in &[("record_1", expected.as_str()), ("record_2", "small")] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/test_db/{}", key))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(*value, response_as_str(res)?);
+    }
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_negotiate_value_encoding() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let value = "text heavy value ".repeat(100);
+    let gzipped = Compression::new(Codec::Gzip, 0).compress(value.as_bytes())?;
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .header(http::header::CONTENT_ENCODING, "gzip")
+        .header(http::header::CONTENT_TYPE, "text/plain")
+        .set_payload(gzipped.clone())
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    // sent as stored to a reader accepting gzip, decompressed for others
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .header(http::header::ACCEPT_ENCODING, "zstd, gzip")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(
+        "gzip",
+        res.headers().get(http::header::CONTENT_ENCODING).unwrap()
+    );
+    assert_eq!(
+        "text/plain",
+        res.headers().get(http::header::CONTENT_TYPE).unwrap()
+    );
+    assert_eq!(gzipped, test::read_body(res).await.to_vec());
+
+    for accept in &["zstd", "gzip;q=0", "identity"] {
+        let req = test::TestRequest::get()
+            .uri("/test_db/record_1")
+            .header(http::header::ACCEPT_ENCODING, *accept)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+        assert!(res.headers().get(http::header::CONTENT_ENCODING).is_none());
+        assert_eq!(value, response_as_str(res)?);
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_2")
+        .header(http::header::CONTENT_ENCODING, "zstd")
+        .set_payload("not zstd")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    // a few KB expanding past the value limit
+    let bomb = Compression::new(Codec::Zstd, 0).compress(&vec![0; 16 * 1024 * 1024])?;
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_2")
+        .header(http::header::CONTENT_ENCODING, "zstd")
+        .set_payload(bomb)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_reject_writes_over_quota() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            max_keys: 2,
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(db_quota)
+            .service(store)
+            .service(read)
+            .service(remove)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for key in &["record_1", "record_2"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload("v")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_3")
+        .set_payload("v")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::INSUFFICIENT_STORAGE, res.status());
+
+    // reads and deletes still go through
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let req = test::TestRequest::delete()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get().uri("/test_db/_quota").to_request();
+    let quota: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(2, quota["maxKeys"]);
+    assert_eq!(0, quota["maxBytes"]);
+    assert!(quota["bytes"].as_u64().unwrap() > 0);
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_return_stored_content_type() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(read)
+            .service(head_record)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .set_payload(r#"{"name": "rocky"}"#)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_2")
+        .set_payload("raw")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for (method, key, expected) in &[
+        (http::Method::GET, "record_1", "application/json"),
+        (http::Method::HEAD, "record_1", "application/json"),
+        (http::Method::GET, "record_2", "application/octet-stream"),
+    ] {
+        let req = test::TestRequest::default()
+            .method(method.clone())
+            .uri(&format!("/test_db/{}", key))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            *expected,
+            res.headers().get(http::header::CONTENT_TYPE).unwrap()
+        );
+    }
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_store_with_if_match() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("v1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("\"1\"", res.headers().get("etag").unwrap());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("v2")
+        .header("if-match", "\"1\"")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("\"2\"", res.headers().get("etag").unwrap());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("stale")
+        .header("if-match", "\"1\"")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(
+        StatusCode::PRECONDITION_FAILED,
+        res.status(),
+        "Received payload:: {:?}",
+        response_as_str(res)
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_2")
+        .set_payload("new")
+        .header("if-match", "*")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::PRECONDITION_FAILED, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_store_if_absent() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/lock_1")
+        .set_payload("owner_1")
+        .header("if-none-match", "*")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/lock_1")
+        .set_payload("owner_2")
+        .header("if-none-match", "*")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::CONFLICT, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/lock_1?mode=nx")
+        .set_payload("owner_2")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::CONFLICT, res.status());
+
+    let req = test::TestRequest::get().uri("/test_db/lock_1").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("owner_1", response_as_str(res)?);
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/lock_2?mode=nx")
+        .set_payload("owner_2")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for req in vec![
+        test::TestRequest::post()
+            .uri("/test_db/lock_3?mode=nx")
+            .header("if-none-match", "*"),
+        test::TestRequest::post()
+            .uri("/test_db/lock_3")
+            .header("if-none-match", "\"1\""),
+        test::TestRequest::post().uri("/test_db/lock_3?mode=sometimes"),
+    ] {
+        let res = test::call_service(&mut app, req.set_payload("owner_3").to_request()).await;
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_store_if_present() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1?mode=xx")
+        .set_payload("v1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("v1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1?mode=xx")
+        .set_payload("v2")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(2, header_as_u64(&res, "version"));
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("v2", response_as_str(res)?);
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_store_with_expected_version() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(read)
+            .service(head_record)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    // 0 creates only if the record doesn't exist
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("v1")
+        .header("expected-version", "0")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(1, header_as_u64(&res, "version"));
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(1, header_as_u64(&res, "version"));
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("v2")
+        .header("expected-version", "1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(2, header_as_u64(&res, "version"));
+
+    let req = test::TestRequest::default()
+        .method(http::Method::HEAD)
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(2, header_as_u64(&res, "version"));
+
+    for (expected, status) in &[
+        ("1", StatusCode::PRECONDITION_FAILED),
+        ("0", StatusCode::PRECONDITION_FAILED),
+        ("latest", StatusCode::BAD_REQUEST),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/test_db/record_1")
+            .set_payload("stale")
+            .header("expected-version", *expected)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(*status, res.status());
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("both")
+        .header("expected-version", "2")
+        .header("if-match", "\"2\"")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("v2", response_as_str(res)?);
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_read_with_if_none_match() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("v1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .header("if-none-match", "\"1\"")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NOT_MODIFIED, res.status());
+    assert_eq!("\"1\"", res.headers().get("etag").unwrap());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("v2")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .header("if-none-match", "\"1\"")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("\"2\"", res.headers().get("etag").unwrap());
+    assert_eq!("v2", response_as_str(res)?);
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_queue_outbound_webhooks() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            // nothing listens there, the delivery stays queued
+            expiration_webhook: Some("http://127.0.0.1:9/expired".into()),
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .app_data(web::Data::new(AdminToken::new(Some("secret".into()))))
+            .service(open)
+            .service(list_outbound)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("payload")
+        .header("ttl", "1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    thread::sleep(Duration::from_millis(5));
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+    let req = test::TestRequest::get().uri("/_outbound").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/_outbound")
+        .header("authorization", "Bearer secret")
+        .to_request();
+    let pending: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!("webhook", pending[0]["kind"]);
+    assert_eq!("test_db", pending[0]["db"]);
+    assert_eq!("http://127.0.0.1:9/expired", pending[0]["target"]);
+    assert!(pending[0].get("body").is_none());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_cancel_running_operations() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = web::Data::new(DbManager::new(DbConfig::new_per_test_defaults())?);
+    let mut app = test::init_service(
+        App::new()
+            .app_data(db_manager.clone())
+            .app_data(web::Data::new(AdminToken::new(Some("secret".into()))))
+            .service(list_operations)
+            .service(cancel_operation),
+    )
+    .await;
+
+    let export = db_manager.start_operation(OperationKind::Export, "test_db");
+
+    let req = test::TestRequest::get()
+        .uri("/_admin/operations")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/_admin/operations")
+        .header("authorization", "Bearer secret")
+        .to_request();
+    let running: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!("export", running[0]["kind"]);
+    assert_eq!("test_db", running[0]["db"]);
+    assert_eq!(false, running[0]["cancelled"]);
+    let id = running[0]["id"].as_u64().unwrap();
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/_admin/operations/{}", id))
+        .header("authorization", "Bearer secret")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::ACCEPTED, res.status());
+    assert!(export.is_cancelled());
+
+    drop(export);
+    let req = test::TestRequest::delete()
+        .uri(&format!("/_admin/operations/{}", id))
+        .header("authorization", "Bearer secret")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NOT_FOUND, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_guard_debug_endpoints() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .app_data(web::Data::new(AdminToken::new(Some("secret".into()))))
+            .service(open)
+            .service(live_files)
+            .service(manifest)
+            .service(approximate_size)
+            .service(property)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_debug/live_files")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_debug/live_files")
+        .header("authorization", "Bearer secret")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(
+        StatusCode::OK,
+        res.status(),
+        "Received payload:: {:?}",
+        response_as_str(res)
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_debug/manifest")
+        .header("authorization", "Bearer secret")
+        .to_request();
+    let manifest: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert!(manifest["current"]
+        .as_str()
+        .expect("Manifest name expected")
+        .starts_with("MANIFEST"));
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_debug/approximate_size?start=a&end=z")
+        .header("authorization", "Bearer secret")
+        .to_request();
+    let size: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!(0), size["size"]);
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_property/rocksdb.estimate-num-keys")
+        .header("authorization", "Bearer secret")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("0", response_as_str(res)?);
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_property/rocksdb.unknown")
+        .header("authorization", "Bearer secret")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_disable_debug_endpoints_without_token() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(live_files),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_debug/live_files")
+        .header("authorization", "Bearer secret")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::FORBIDDEN, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_serve_metrics_in_negotiated_format() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let registry = prometheus::Registry::new();
+    metrics::register(&registry)?;
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(registry))
+            .app_data(web::Data::new(db_manager))
+            .app_data(web::Data::new(AdminToken::new(Some("secret".into()))))
+            .app_data(web::Data::new(MetricsAuth::new(true)))
+            .service(prometheus_metrics)
+            .service(json_metrics),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/rocky/prometheus/metrics")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/rocky/prometheus/metrics")
+        .header("authorization", "Bearer secret")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert!(response_as_str(res)?.contains("# TYPE rocky_process_rss_bytes gauge"));
+
+    let req = test::TestRequest::get()
+        .uri("/rocky/prometheus/metrics")
+        .header("authorization", "Bearer secret")
+        .header("accept", "application/openmetrics-text; version=1.0.0")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert!(res
+        .headers()
+        .get("content-type")
+        .unwrap()
+        .to_str()?
+        .starts_with("application/openmetrics-text"));
+    assert!(response_as_str(res)?.ends_with("# EOF\n"));
+
+    let req = test::TestRequest::get()
+        .uri("/_metrics.json")
+        .header("authorization", "Bearer secret")
+        .to_request();
+    let families: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert!(families
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|f| f["name"] == "rocky_process_rss_bytes"));
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_export_storage_metrics() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let registry = prometheus::Registry::new();
+    metrics::register(&registry)?;
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .wrap_fn(timed)
+            .app_data(web::Data::new(registry))
+            .app_data(web::Data::new(db_manager))
+            .app_data(web::Data::new(AdminToken::new(None)))
+            .app_data(web::Data::new(MetricsAuth::new(false)))
+            .service(metrics_endpoint)
+            .service(open)
+            .service(store)
+            .service(read)
+            .service(remove),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/k")
+        .set_payload("v")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let req = test::TestRequest::get().uri("/test_db/k").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let req = test::TestRequest::delete().uri("/test_db/k").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get().uri("/metrics").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let body = response_as_str(res)?;
+    assert!(body.contains("# TYPE rocky_open_dbs gauge"));
+    for op in &["read", "write", "delete"] {
+        assert!(body.contains(&format!("rocky_key_operations_total{{op=\"{}\"}}", op)));
+    }
+    assert!(body.contains("rocky_expired_reads_total"));
+    assert!(body.contains(
+        "rocky_http_request_duration_seconds_count{endpoint=\"/{db_name}/{key}\",method=\"GET\",status=\"200\"}"
+    ));
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_create_and_list_backups() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(create_backup)
+            .service(list_backups)
+            .service(store)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_backup")
+        .to_request();
+    let backups: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!([]), backups);
+
+    for val in &["v1", "v2"] {
+        let req = test::TestRequest::post()
+            .uri("/test_db/record_1")
+            .set_payload(*val)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+
+        let req = test::TestRequest::post()
+            .uri("/test_db/_backup")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_backup")
+        .to_request();
+    let backups: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(2, backups.as_array().unwrap().len());
+    assert_eq!(1, backups[0]["backupId"]);
+    assert_eq!(2, backups[1]["backupId"]);
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_erase_matching_keys() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .app_data(web::Data::new(AdminToken::new(Some("secret".into()))))
+            .app_data(web::Data::new(SigningKey::new(Some("signing".into()))))
+            .service(erase)
+            .service(erasure)
+            .service(open)
+            .service(create_backup)
+            .service(list_backups)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for key in &["user:1:name", "user:1:email", "user:2:name"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload("personal")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+    let req = test::TestRequest::post()
+        .uri("/test_db/_backup")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let body = r#"{"patterns": ["^user:1:"], "dbs": ["test_db"]}"#;
+    let req = test::TestRequest::post()
+        .uri("/_erasure")
+        .set_payload(body)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/_erasure")
+        .header("authorization", "Bearer secret")
+        .set_payload(body)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::ACCEPTED, res.status());
+    let report: serde_json::Value = serde_json::from_str(&response_as_str(res)?)?;
+    let id = report["id"].as_str().unwrap().to_string();
+
+    let mut report = report;
+    for _ in 0..50 {
+        if report["status"] != "running" {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+        let req = test::TestRequest::get()
+            .uri(&format!("/_erasure/{}", id))
+            .header("authorization", "Bearer secret")
+            .to_request();
+        report = test::read_response_json(&mut app, req).await;
+    }
+    assert_eq!("completed", report["status"]);
+    assert_eq!(2, report["dbs"][0]["records"]);
+    assert_eq!(serde_json::json!(true), report["dbs"][0]["verified"]);
+    assert_eq!(serde_json::json!([1]), report["dbs"][0]["backups"]);
+    assert_eq!(serde_json::json!([]), report["dbs"][0]["checkpoints"]);
+    assert_eq!(64, report["signature"].as_str().unwrap().len());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/user:1:name")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NOT_FOUND, res.status());
+    let req = test::TestRequest::get()
+        .uri("/test_db/user:2:name")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_backup")
+        .to_request();
+    let backups: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!([id]), backups[0]["erasures"]);
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_read_from_snapshots() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .app_data(web::Data::new(AdminToken::new(Some("secret".into()))))
+            .service(snapshot_read)
+            .service(unmount_snapshot)
+            .service(open)
+            .service(checkpoint)
+            .service(create_backup)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let mut created_at = serde_json::Value::Null;
+    for val in &["v1", "v2", "v3"] {
+        let req = test::TestRequest::post()
+            .uri("/test_db/record_1")
+            .set_payload(*val)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+
+        if *val == "v1" {
+            let req = test::TestRequest::post()
+                .uri("/test_db/_checkpoint")
+                .to_request();
+            let checkpoint: serde_json::Value = test::read_response_json(&mut app, req).await;
+            created_at = checkpoint["createdAt"].clone();
+        } else if *val == "v2" {
+            let req = test::TestRequest::post()
+                .uri("/test_db/_backup")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(StatusCode::OK, res.status());
+        }
+    }
+
+    let snapshots = [
+        (format!("/_snapshots/{}/test_db/record_1", created_at), "v1"),
+        ("/_snapshots/backup-1/test_db/record_1".to_string(), "v2"),
+        ("/test_db/record_1".to_string(), "v3"),
+    ];
+    for (uri, val) in snapshots.iter() {
+        let req = test::TestRequest::get()
+            .uri(uri)
+            .header("authorization", "Bearer secret")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(*val, response_as_str(res)?);
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/_snapshots/backup-1/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+    for (uri, status) in &[
+        (
+            "/_snapshots/backup-1/test_db/record_2",
+            StatusCode::NO_CONTENT,
+        ),
+        (
+            "/_snapshots/backup-9/test_db/record_1",
+            StatusCode::NOT_FOUND,
+        ),
+        (
+            "/_snapshots/export-1/test_db/record_1",
+            StatusCode::BAD_REQUEST,
+        ),
+    ] {
+        let req = test::TestRequest::get()
+            .uri(uri)
+            .header("authorization", "Bearer secret")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(*status, res.status(), "{}", uri);
+    }
+
+    for status in &[StatusCode::OK, StatusCode::NOT_FOUND] {
+        let req = test::TestRequest::delete()
+            .uri("/_snapshots/backup-1/test_db")
+            .header("authorization", "Bearer secret")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(*status, res.status());
+    }
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_restore_from_backup() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(create_backup)
+            .service(restore)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_restore")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    for val in &["v1", "v2"] {
+        let req = test::TestRequest::post()
+            .uri("/test_db/record_1")
+            .set_payload(*val)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+
+        let req = test::TestRequest::post()
+            .uri("/test_db/_backup")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("v3")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_restore?backup_id=1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("v1", response_as_str(res)?);
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_restore")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("v2", response_as_str(res)?);
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_restore?backup_id=7")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_create_checkpoint() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(checkpoint)
+            .service(store)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("v1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_checkpoint")
+        .to_request();
+    let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+    let path = body["path"].as_str().expect("Checkpoint path expected");
+
+    // a checkpoint is a regular db
+    let copy = rocksdb::DB::open_default(path)?;
+    let data: Data = copy
+        .get("record_1")?
+        .expect("Record expected")
+        .as_struct()?;
+    assert_eq!(b"v1".to_vec(), data.into_value());
+    drop(copy);
+    fs::remove_dir_all(path)?;
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_export_db_as_tar() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(export)
+            .service(store)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("v1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_export")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(
+        TAR_CONTENT_TYPE,
+        res.headers().get(http::header::CONTENT_TYPE).unwrap()
+    );
+    let etag = res.headers().get(http::header::ETAG).unwrap().clone();
+    let archive = test::read_body(res).await;
+    assert_eq!(0, archive.len() % 512);
+    assert_eq!(b"test_db/", &archive[..8]);
+
+    // the rest of the same archive, even after more writes
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_2")
+        .set_payload("v2")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let req = test::TestRequest::get()
+        .uri("/test_db/_export")
+        .header(http::header::RANGE, "bytes=1000-")
+        .header(http::header::IF_RANGE, etag.clone())
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::PARTIAL_CONTENT, res.status());
+    assert_eq!(
+        format!("bytes 1000-{}/{}", archive.len() - 1, archive.len()),
+        *res.headers().get(http::header::CONTENT_RANGE).unwrap()
+    );
+    assert_eq!(&archive[1000..], &test::read_body(res).await[..]);
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_export")
+        .header(http::header::RANGE, "bytes=1000-")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    // an export that's gone starts over
+    let req = test::TestRequest::get()
+        .uri("/test_db/_export")
+        .header(http::header::RANGE, "bytes=1000-")
+        .header(http::header::IF_RANGE, "\"export-1\"")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_ne!(&etag, res.headers().get(http::header::ETAG).unwrap());
+
+    let req = test::TestRequest::get()
+        .uri("/missing_db/_export")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_import_sst_files() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let cfg = DbConfig::new_per_test_defaults();
+    let dir = format!("{}/sst", cfg.path());
+    fs::create_dir_all(&dir)?;
+    for (i, key) in ["record_1", "record_2"].iter().enumerate() {
+        let mut writer = rocksdb::SstFileWriter::create(&rocksdb::Options::default());
+        writer.open(format!("{}/{}.sst", dir, i))?;
+        writer.put(key, Data::new(NO_TTL, key.as_bytes().to_vec()).as_bytes()?)?;
+        writer.finish()?;
+    }
+    let single = fs::read(format!("{}/0.sst", dir))?;
+    fs::remove_file(format!("{}/0.sst", dir))?;
+    let archive: Vec<u8> = crate::archive::TarStream::new(&dir, "test_db")?
+        .flat_map(|chunk| chunk.unwrap().to_vec())
+        .collect();
+
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(import)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_import")
+        .set_payload(single)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_import")
+        .header(http::header::CONTENT_TYPE, TAR_CONTENT_TYPE)
+        .set_payload(archive)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for key in &["record_1", "record_2"] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/test_db/{}", key))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(*key, response_as_str(res)?);
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_import")
+        .set_payload("not an sst file")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_expose_sequence_numbers() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(seq)
+            .service(store)
+            .service(remove)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("v1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let first = header_as_u64(&res, SEQ_HEADER);
+
+    let req = test::TestRequest::delete()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let second = header_as_u64(&res, SEQ_HEADER);
+    assert!(second > first);
+
+    let req = test::TestRequest::get().uri("/test_db/_seq").to_request();
+    let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(second, body["seq"]);
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_forecast_storage_usage() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(forecast)
+            .service(store)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("v1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_forecast?days=3")
+        .to_request();
+    let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert!(body["samples"].as_u64().unwrap() >= 1);
+    assert!(body["currentBytes"].as_u64().unwrap() > 0);
+    assert_eq!(3, body["days"].as_array().unwrap().len());
+    assert_eq!(3, body["days"][2]["day"]);
+
+    let req = test::TestRequest::get()
+        .uri("/missing_db/_forecast")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_append_to_record() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(append)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/log_1")
+        .set_payload("line 1;")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for line in &["line 2;", "line 3;"] {
+        let req = test::TestRequest::patch()
+            .uri("/test_db/log_1")
+            .set_payload(*line)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(
+            StatusCode::OK,
+            res.status(),
+            "Received payload:: {:?}",
+            response_as_str(res)
+        );
+    }
+
+    let req = test::TestRequest::get().uri("/test_db/log_1").to_request();
+    let res = test::call_service(&mut app, req).await;
+    let content = response_as_str(res).expect("Can't read response");
+    assert_eq!("line 1;line 2;line 3;", content);
+
+    let req = test::TestRequest::patch()
+        .uri("/test_db/log_2")
+        .set_payload("created by append")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get().uri("/test_db/log_2").to_request();
+    let res = test::call_service(&mut app, req).await;
+    let content = response_as_str(res).expect("Can't read response");
+    assert_eq!("created by append", content);
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_patch_json_documents() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            json: true,
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(append)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/user_1")
+        .set_payload(r#"{"name":"rocky","tags":["a"],"phone":"123"}"#)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::patch()
+        .uri("/test_db/user_1")
+        .header(http::header::CONTENT_TYPE, "application/json-patch+json")
+        .set_payload(
+            r#"[{"op":"test","path":"/name","value":"rocky"},{"op":"add","path":"/tags/-","value":"b"}]"#,
+        )
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(2, header_as_u64(&res, "version"));
+
+    let req = test::TestRequest::patch()
+        .uri("/test_db/user_1")
+        .header(http::header::CONTENT_TYPE, "application/merge-patch+json")
+        .header(http::header::IF_MATCH, "\"2\"")
+        .set_payload(r#"{"name":"balboa","phone":null}"#)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get().uri("/test_db/user_1").to_request();
+    let doc: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(
+        serde_json::json!({"name": "balboa", "tags": ["a", "b"]}),
+        doc
+    );
+
+    // failed test, malformed patch and a stale version change nothing
+    let req = test::TestRequest::patch()
+        .uri("/test_db/user_1")
+        .header(http::header::CONTENT_TYPE, "application/json-patch+json")
+        .set_payload(
+            r#"[{"op":"remove","path":"/tags"},{"op":"test","path":"/name","value":"rocky"}]"#,
+        )
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::CONFLICT, res.status());
+    let req = test::TestRequest::patch()
+        .uri("/test_db/user_1")
+        .header(http::header::CONTENT_TYPE, "application/json-patch+json")
+        .set_payload("{")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    let req = test::TestRequest::patch()
+        .uri("/test_db/user_1")
+        .header(http::header::CONTENT_TYPE, "application/merge-patch+json")
+        .header(http::header::IF_MATCH, "\"2\"")
+        .set_payload(r#"{"tags":null}"#)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::PRECONDITION_FAILED, res.status());
+
+    let req = test::TestRequest::get().uri("/test_db/user_1").to_request();
+    let doc: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!(["a", "b"]), doc["tags"]);
+
+    // a merge patch creates a missing document, appends would break it
+    let req = test::TestRequest::patch()
+        .uri("/test_db/user_2")
+        .header(http::header::CONTENT_TYPE, "application/merge-patch+json")
+        .set_payload(r#"{"name":"adrian"}"#)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(1, header_as_u64(&res, "version"));
+    let req = test::TestRequest::patch()
+        .uri("/test_db/user_2")
+        .set_payload("tail")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_check_record_existence_with_head() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(head_record)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("12 bytes val")
+        .header("ttl", "60000")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::default()
+        .method(http::Method::HEAD)
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("12", res.headers().get("value-size").unwrap());
+    assert!(res.headers().contains_key("expires-at"));
+
+    let req = test::TestRequest::default()
+        .method(http::Method::HEAD)
+        .uri("/test_db/record_2")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_long_poll_for_change() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = web::Data::new(DbManager::new(DbConfig::new_per_test_defaults())?);
+    let mut app = test::init_service(
+        App::new()
+            .app_data(db_manager.clone())
+            .service(open)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("v1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1?wait=100ms&if_version=1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NOT_MODIFIED, res.status());
+
+    let writer = db_manager.clone();
+    actix_rt::spawn(async move {
+        actix_rt::time::delay_for(Duration::from_millis(100)).await;
+        writer
+            .store(
+                "test_db",
+                b"record_1",
+                Bytes::from("v2"),
+                NO_TTL,
+                NO_TTL,
+                Representation::default(),
+                WriteCondition::Always,
+            )
+            .await
+            .expect("Store failed");
+    });
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1?wait=10s&if_version=1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("\"2\"", res.headers().get("etag").unwrap());
+    assert_eq!("v2", response_as_str(res)?);
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1?wait=10s&if_version=1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_stream_changes_of_watched_prefix() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(watch)
+            .service(store)
+            .service(remove)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_watch?prefix=user:")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_watch?prefix=user:")
+        .to_request();
+    let mut res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(
+        "text/event-stream",
+        res.headers().get("content-type").unwrap()
+    );
+    let mut events = res.take_body();
+
+    for key in &["order:1", "user:1"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload("val")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+    let req = test::TestRequest::delete()
+        .uri("/test_db/user:1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let event = events.next().await.expect("Put event expected").unwrap();
+    let event = bytes_to_str(&event)?;
+    assert!(event.starts_with("event: put\ndata: "));
+    assert!(event.contains(r#""key":"user:1""#));
+    let event = events.next().await.expect("Delete event expected").unwrap();
+    assert!(bytes_to_str(&event)?.starts_with("event: delete\n"));
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_touch_record() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(touch)
+            .service(read)
+            .service(head_record)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("val")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1/_touch")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1/_touch")
+        .header("ttl", "60000")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::default()
+        .method(http::Method::HEAD)
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert!(res.headers().contains_key("expires-at"));
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("val", response_as_str(res)?);
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_2/_touch")
+        .header("ttl", "60000")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_undelete_within_window() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            undelete_window_ms: 60_000,
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(remove)
+            .service(undelete)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    for db in &["test_db", "test_db_2"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}", db))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/record_1", db))
+            .set_payload("precious")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/{}/record_1", db))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/record_1", db))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+    }
+
+    // deleted immediately without a window
+    let req = test::TestRequest::post()
+        .uri("/test_db_2/record_1/_undelete")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1/_undelete")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(1, header_as_u64(&res, "version"));
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("precious", response_as_str(res)?);
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1/_undelete")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+    // a record written after the delete isn't overwritten
+    let req = test::TestRequest::delete()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .set_payload("new")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1/_undelete")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::PRECONDITION_FAILED, res.status());
+
+    for db in &["test_db", "test_db_2"] {
+        let req = test::TestRequest::delete()
+            .uri(&format!("/{}", db))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_rename_record() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(rename)
+            .service(read)
+            .service(head_record)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for (key, val) in &[("record_1", "v1"), ("record_1", "v2"), ("record_3", "v3")] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload(*val)
+            .header("ttl", "60000")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1/_rename?to=record_2")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!(2, header_as_u64(&res, "version"));
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+    let req = test::TestRequest::default()
+        .method(http::Method::HEAD)
+        .uri("/test_db/record_2")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert!(res.headers().contains_key("expires-at"));
+    assert_eq!(2, header_as_u64(&res, "version"));
+
+    for (uri, status) in &[
+        (
+            "/test_db/record_2/_rename?to=record_3",
+            StatusCode::PRECONDITION_FAILED,
+        ),
+        (
+            "/test_db/record_1/_rename?to=record_4",
+            StatusCode::NOT_FOUND,
+        ),
+        (
+            "/test_db/record_2/_rename?to=record_2",
+            StatusCode::BAD_REQUEST,
+        ),
+    ] {
+        let req = test::TestRequest::post().uri(uri).to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(*status, res.status());
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_2")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("v2", response_as_str(res)?);
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_append_touch_and_rename_binary_keys() -> anyhow::Result<()> {
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(append)
+            .service(touch)
+            .service(rename)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    // [0, 255, '/', 1]
+    let req = test::TestRequest::post()
+        .uri("/test_db/AP8vAQ")
+        .header("key-encoding", "base64")
+        .set_payload("v1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::patch()
+        .uri("/test_db/AP8vAQ")
+        .header("key-encoding", "base64")
+        .set_payload("v2")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/AP8vAQ/_touch")
+        .header("key-encoding", "base64")
+        .header("ttl", "60000")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    // to [0, 255, '0', 1]
+    let req = test::TestRequest::post()
+        .uri("/test_db/AP8vAQ/_rename?to=AP8wAQ")
+        .header("key-encoding", "base64")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/AP8wAQ")
+        .header("key-encoding", "base64")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert!(res.headers().contains_key("ttl-remaining"));
+    assert_eq!("v1v2", response_as_str(res)?);
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/AP8vAQ")
+        .header("key-encoding", "base64")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_apply_replicated_changes_by_last_writer() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(replicate)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_2")
+        .set_payload("local")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    // record_2 was written locally just now, the replicated write is older
+    let req = test::TestRequest::post()
+        .uri("/test_db/_replicate")
+        .set_payload(
+            r#"[
+                {"key": "record_1", "value": "djE=", "writtenAt": 1000},
+                {"key": "record_2", "value": "djI=", "writtenAt": 1000},
+                {"key": "record_1", "value": "djA=", "writtenAt": 500}
+            ]"#,
+        )
+        .to_request();
+    let res: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!({"applied": 1, "skipped": 2}), res);
+
+    // nothing to delete
+    let req = test::TestRequest::post()
+        .uri("/test_db/_replicate")
+        .set_payload(r#"[{"key": "record_3", "writtenAt": 1000}]"#)
+        .to_request();
+    let res: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(serde_json::json!({"applied": 0, "skipped": 1}), res);
+
+    for (key, val) in &[("record_1", "v1"), ("record_2", "local")] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/test_db/{}", key))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(*val, response_as_str(res)?);
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_replicate")
+        .set_payload(r#"[{"key": "record_1", "writtenAt": 2000}]"#)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_replicate")
+        .set_payload("not json")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_return_configured_default_for_missing_key() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            default_value: Some("{}".into()),
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/missing")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("configured", res.headers().get("default-value").unwrap());
+    assert_eq!("{}", response_as_str(res)?);
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/present")
+        .set_payload("value")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/present")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert!(!res.headers().contains_key("default-value"));
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_serve_stale_records_within_window() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            stale_window_ms: 60_000,
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = web::Data::new(DbManager::new(cfg)?);
+    let mut app = test::init_service(
+        App::new()
+            .app_data(db_manager.clone())
+            .service(open)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    for db in &["test_db", "fresh_db"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}", db))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/record_1", db))
+            .header("ttl", "50")
+            .set_payload("v1")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+    let mut refreshes = db_manager.events.subscribe(Filter::db("test_db"));
+    thread::sleep(Duration::from_millis(100));
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("true", res.headers().get(STALE_HEADER).unwrap());
+    assert_eq!("v1", response_as_str(res)?);
+
+    let event = refreshes.next().await.expect("Refresh event expected");
+    assert_eq!(EventKind::Refresh, event.kind);
+
+    let req = test::TestRequest::get()
+        .uri("/fresh_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+    for db in &["test_db", "fresh_db"] {
+        let req = test::TestRequest::delete()
+            .uri(&format!("/{}", db))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_serve_soft_expired_records_as_stale() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(store)
+            .service(read)
+            .service(head_record)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/record_1")
+        .header(SOFT_TTL_HEADER, "50")
+        .header(TTL_HEADER, "60000")
+        .set_payload("v1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert!(!res.headers().contains_key(STALE_HEADER));
+
+    let req = test::TestRequest::default()
+        .method(http::Method::HEAD)
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert!(res.headers().contains_key(SOFT_EXPIRES_AT_HEADER));
+
+    thread::sleep(Duration::from_millis(100));
+    let req = test::TestRequest::get()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("true", res.headers().get(STALE_HEADER).unwrap());
+    assert_eq!("v1", response_as_str(res)?);
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_track_access_stats() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            access_stats: true,
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(hot_keys)
+            .service(store)
+            .service(read)
+            .service(head_record)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for key in &["record_1", "record_2"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload("val")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    for _ in 0..2 {
+        let req = test::TestRequest::get()
+            .uri("/test_db/record_2")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    let req = test::TestRequest::default()
+        .method(http::Method::HEAD)
+        .uri("/test_db/record_2")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("2", res.headers().get("reads").unwrap());
+    assert_eq!("1", res.headers().get("writes").unwrap());
+    assert!(res.headers().contains_key("last-access"));
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_hotkeys?limit=1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    let content = response_as_str(res)?;
+    assert!(content.contains("record_2"));
+    assert!(!content.contains("record_1"));
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_read_change_log_since() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            change_log: true,
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(changes)
+            .service(store)
+            .service(remove)
+            .service(close),
+    )
+    .await;
+
+    for db in &["test_db", "test_db_2"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}", db))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    // not enabled
+    let req = test::TestRequest::get()
+        .uri("/test_db_2/_changes")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    for (key, value) in &[("record_1", "v1"), ("record_2", "v2")] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/test_db/{}", key))
+            .set_payload(*value)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+    let req = test::TestRequest::delete()
+        .uri("/test_db/record_1")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_changes?limit=2")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(2, page["next"]);
+    assert_eq!(1, page["first"]);
+    assert_eq!(
+        serde_json::json!(["put", "put"]),
+        serde_json::json!([page["changes"][0]["kind"], page["changes"][1]["kind"]])
+    );
+    assert_eq!(
+        serde_json::json!(base64::encode("v2")),
+        page["changes"][1]["value"]
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_changes?since=2")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(3, page["next"]);
+    assert_eq!("delete", page["changes"][0]["kind"]);
+    assert_eq!("record_1", page["changes"][0]["key"]);
+    assert!(page["changes"][0].get("value").is_none());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_changes?since=3")
+        .to_request();
+    let page: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(3, page["next"]);
+    assert_eq!(serde_json::json!([]), page["changes"]);
+
+    for db in &["test_db", "test_db_2"] {
+        let req = test::TestRequest::delete()
+            .uri(&format!("/{}", db))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_upgrade_watch_to_websocket() -> anyhow::Result<()> {
     std::env::set_var("RUST_BACKTRACE", "full");
 
     let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
     let mut app = test::init_service(
         App::new()
             .app_data(web::Data::new(db_manager))
-            .service(health),
+            .service(open)
+            .service(ws_watch)
+            .service(close),
+    )
+    .await;
+
+    let upgrade = || {
+        test::TestRequest::get()
+            .uri("/ws/test_db")
+            .header(http::header::UPGRADE, "websocket")
+            .header(http::header::CONNECTION, "upgrade")
+            .header(http::header::SEC_WEBSOCKET_VERSION, "13")
+            .header(http::header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+    };
+    let res = test::call_service(&mut app, upgrade().to_request()).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    // not a websocket handshake
+    let req = test::TestRequest::get().uri("/ws/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let res = test::call_service(&mut app, upgrade().to_request()).await;
+    assert_eq!(StatusCode::SWITCHING_PROTOCOLS, res.status());
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_merge_crdt_counters_and_sets() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let mut cfg = DbConfig::new_per_test_defaults();
+    cfg.0.databases.insert(
+        "test_db".into(),
+        DbSettings {
+            crdts: true,
+            ..DbSettings::default()
+        },
+    );
+    let db_manager = DbManager::new(cfg)?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(open)
+            .service(update_crdt)
+            .service(crdt_value)
+            .service(crdt_state)
+            .service(merge_crdt)
+            .service(close),
     )
     .await;
 
-    let req = test::TestRequest::get().uri("/health").to_request();
+    for db in &["test_db", "test_db_2"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}", db))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    // not enabled
+    let req = test::TestRequest::post()
+        .uri("/test_db_2/_crdt/visits")
+        .set_payload(r#"{"type": "gcounter", "by": 1}"#)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_crdt/visits")
+        .to_request();
     let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+    for by in &[2, 3] {
+        let req = test::TestRequest::post()
+            .uri("/test_db/_crdt/visits")
+            .set_payload(format!(r#"{{"type": "gcounter", "by": {}}}"#, by))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    // merging the same remote state twice counts it once
+    for _ in 0..2 {
+        let req = test::TestRequest::post()
+            .uri("/test_db/_crdt/visits/_merge")
+            .set_payload(r#"{"type": "gcounter", "counts": {"rocky": 100, "dc2": 4}}"#)
+            .to_request();
+        let value: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(serde_json::json!({"type": "gcounter", "value": 9}), value);
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_crdt/visits/_state")
+        .to_request();
+    let state: serde_json::Value = test::read_response_json(&mut app, req).await;
     assert_eq!(
-        StatusCode::OK,
-        res.status(),
-        "Received payload:: {:?}",
-        response_as_str(res)
+        serde_json::json!({"type": "gcounter", "counts": {"dc2": 4, "rocky": 5}}),
+        state
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_crdt/visits")
+        .set_payload(r#"{"type": "orset", "add": ["a"]}"#)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::CONFLICT, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_crdt/tags")
+        .set_payload(r#"{"type": "orset", "add": ["a", "b"]}"#)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/test_db/_crdt/tags")
+        .set_payload(r#"{"type": "orset", "remove": ["a"]}"#)
+        .to_request();
+    let value: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(
+        serde_json::json!({"type": "orset", "elements": ["b"]}),
+        value
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/_crdt/tags")
+        .to_request();
+    let value: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(
+        serde_json::json!({"type": "orset", "elements": ["b"]}),
+        value
     );
+
+    for db in &["test_db", "test_db_2"] {
+        let req = test::TestRequest::delete()
+            .uri(&format!("/{}", db))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_check_service_status() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .service(health_live)
+            .service(health_ready)
+            .service(health)
+            .service(open),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    for uri in &["/health", "/health/live", "/health/ready"] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(
+            StatusCode::OK,
+            res.status(),
+            "Received payload:: {:?}",
+            response_as_str(res)
+        );
+    }
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn should_serve_admin_ui_and_compact() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db_manager))
+            .app_data(web::Data::new(AdminToken::new(Some("secret".into()))))
+            .service(admin_ui)
+            .service(compact)
+            .service(open),
+    )
+    .await;
+
+    let req = test::TestRequest::get().uri("/admin").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    assert!(res.headers()["content-type"]
+        .to_str()?
+        .starts_with("text/html"));
+    assert!(response_as_str(res)?.contains("Rocky admin"));
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/_admin/dbs/test_db/compact")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+    let req = test::TestRequest::post()
+        .uri("/_admin/dbs/test_db/compact")
+        .header("authorization", "Bearer secret")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::ACCEPTED, res.status());
+    let operation: serde_json::Value = serde_json::from_str(&response_as_str(res)?)?;
+    assert_eq!("compaction", operation["kind"]);
+    assert_eq!("test_db", operation["db"]);
+
+    let req = test::TestRequest::post()
+        .uri("/_admin/dbs/missing/compact")
+        .header("authorization", "Bearer secret")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
     Ok(())
 }
 
@@ -256,9 +5258,85 @@ async fn should_handle_404() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[actix_rt::test]
+async fn should_negotiate_metadata_format() -> anyhow::Result<()> {
+    std::env::set_var("RUST_BACKTRACE", "full");
+
+    let db_manager = DbManager::new(DbConfig::new_per_test_defaults())?;
+    let mut app = test::init_service(
+        App::new()
+            .wrap(ErrorHandlers::new().handler(http::StatusCode::NOT_FOUND, not_found))
+            .wrap_fn(negotiated)
+            .app_data(web::Data::new(db_manager))
+            .service(list_dbs)
+            .service(open)
+            .service(store)
+            .service(read)
+            .service(close),
+    )
+    .await;
+
+    let req = test::TestRequest::post().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/_dbs")
+        .header("accept", "application/msgpack")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("application/msgpack", res.headers()["content-type"]);
+    let dbs: serde_json::Value = rmp_serde::from_slice(&test::read_body(res).await)?;
+    assert_eq!("test_db", dbs[0]["name"]);
+
+    let req = test::TestRequest::post()
+        .uri("/_reserved")
+        .header("accept", "application/cbor")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    assert_eq!("application/cbor", res.headers()["content-type"]);
+    let err: serde_json::Value = serde_cbor::from_slice(&test::read_body(res).await)?;
+    assert!(err["msg"].as_str().is_some());
+
+    // records keep their stored content type
+    let req = test::TestRequest::post()
+        .uri("/test_db/a")
+        .header("content-type", "application/json")
+        .set_payload(r#"{"a": 1}"#)
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+
+    let req = test::TestRequest::get()
+        .uri("/test_db/a")
+        .header("accept", "application/msgpack")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("application/json", res.headers()["content-type"]);
+    assert_eq!(r#"{"a": 1}"#, response_as_str(res)?);
+
+    let req = test::TestRequest::get().uri("/_dbs").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!("application/json", res.headers()["content-type"]);
+
+    let req = test::TestRequest::delete().uri("/test_db").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, res.status());
+    Ok(())
+}
+
 fn response_as_str(res: ServiceResponse<Body>) -> anyhow::Result<String> {
     match res.response().body().as_ref() {
         Some(Body::Bytes(bytes)) => bytes_to_str(bytes),
         _ => Ok("empty".to_string()),
     }
 }
+
+fn header_as_u64(res: &ServiceResponse<Body>, name: &str) -> u64 {
+    res.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .expect("Numeric header expected")
+}
@@ -0,0 +1,299 @@
+use std::fs;
+use std::path::Path;
+
+use rocksdb::LiveFile;
+use serde::Serialize;
+
+pub const LEVEL_STATS: &str = "rocksdb.levelstats";
+pub const CF_STATS: &str = "rocksdb.cfstats";
+pub const PENDING_COMPACTION_BYTES: &str = "rocksdb.estimate-pending-compaction-bytes";
+pub const IS_WRITE_STOPPED: &str = "rocksdb.is-write-stopped";
+pub const DELAYED_WRITE_RATE: &str = "rocksdb.actual-delayed-write-rate";
+pub const RUNNING_COMPACTIONS: &str = "rocksdb.num-running-compactions";
+pub const RUNNING_FLUSHES: &str = "rocksdb.num-running-flushes";
+pub const BACKGROUND_ERRORS: &str = "rocksdb.background-errors";
+pub const ESTIMATE_NUM_KEYS: &str = "rocksdb.estimate-num-keys";
+pub const ESTIMATE_LIVE_DATA_SIZE: &str = "rocksdb.estimate-live-data-size";
+pub const CUR_SIZE_ALL_MEM_TABLES: &str = "rocksdb.cur-size-all-mem-tables";
+// suffixed with the level
+pub const NUM_FILES_AT_LEVEL: &str = "rocksdb.num-files-at-level";
+pub const TABLE_PROPERTIES_AT_LEVEL: &str = "rocksdb.aggregated-table-properties-at-level";
+// rocksdb default
+pub const NUM_LEVELS: u32 = 7;
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Internals {
+    pub levels: Vec<LevelSummary>,
+    pub cf_stats: Vec<String>,
+    pub pending_compaction_bytes: u64,
+    pub write_stopped: bool,
+    pub delayed_write_rate: u64,
+    pub running_compactions: u64,
+    pub running_flushes: u64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LevelSummary {
+    level: u32,
+    files: u64,
+    size_mb: f64,
+}
+
+// logical is the raw size of keys and values, physical the size of the blocks written - memtables aren't included
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CompressionStats {
+    logical_bytes: u64,
+    physical_bytes: u64,
+    ratio: f64,
+    levels: Vec<LevelCompression>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LevelCompression {
+    level: u32,
+    files: u64,
+    entries: u64,
+    logical_bytes: u64,
+    physical_bytes: u64,
+    ratio: f64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LiveFileInfo {
+    name: String,
+    level: i32,
+    size: usize,
+    start_key: Option<String>,
+    end_key: Option<String>,
+    num_entries: u64,
+    num_deletions: u64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestInfo {
+    current: String,
+    size: u64,
+    files: Vec<LiveFileInfo>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ApproximateSize {
+    start: String,
+    end: String,
+    files: usize,
+    size: usize,
+}
+
+impl From<&LiveFile> for LiveFileInfo {
+    fn from(f: &LiveFile) -> Self {
+        LiveFileInfo {
+            name: f.name.clone(),
+            level: f.level,
+            size: f.size,
+            start_key: f.start_key.as_deref().map(lossy),
+            end_key: f.end_key.as_deref().map(lossy),
+            num_entries: f.num_entries,
+            num_deletions: f.num_deletions,
+        }
+    }
+}
+
+pub fn live_files(files: &[LiveFile]) -> Vec<LiveFileInfo> {
+    files.iter().map(LiveFileInfo::from).collect()
+}
+
+// CURRENT file holds the name of the active manifest
+pub fn manifest<P>(db_path: P, files: &[LiveFile]) -> anyhow::Result<ManifestInfo>
+where
+    P: AsRef<Path>,
+{
+    let current = fs::read_to_string(db_path.as_ref().join("CURRENT"))?
+        .trim()
+        .to_string();
+    let size = fs::metadata(db_path.as_ref().join(&current))?.len();
+
+    Ok(ManifestInfo {
+        current,
+        size,
+        files: live_files(files),
+    })
+}
+
+// sums sizes of sst files overlapping [start, end) - precision is one file
+pub fn approximate_size(files: &[LiveFile], start: &str, end: &str) -> ApproximateSize {
+    let overlapping: Vec<&LiveFile> = files
+        .iter()
+        .filter(|f| overlaps(f, start.as_bytes(), end.as_bytes()))
+        .collect();
+
+    ApproximateSize {
+        start: start.into(),
+        end: end.into(),
+        files: overlapping.len(),
+        size: overlapping.iter().map(|f| f.size).sum(),
+    }
+}
+
+fn overlaps(f: &LiveFile, start: &[u8], end: &[u8]) -> bool {
+    let starts_before_end = f.start_key.as_deref().map_or(true, |k| k < end);
+    let ends_after_start = f.end_key.as_deref().map_or(true, |k| k >= start);
+    starts_before_end && ends_after_start
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+// levelstats output is a fixed width table prefixed with a two line header
+pub fn parse_level_stats(stats: &str) -> Vec<LevelSummary> {
+    stats
+        .lines()
+        .skip(2)
+        .filter_map(|line| {
+            let mut cols = line.split_whitespace();
+            Some(LevelSummary {
+                level: cols.next()?.parse().ok()?,
+                files: cols.next()?.parse().ok()?,
+                size_mb: cols.next()?.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+pub fn parse_lines(stats: &str) -> Vec<String> {
+    stats
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+pub fn parse_int(val: &str) -> u64 {
+    val.trim().parse().unwrap_or_default()
+}
+
+impl CompressionStats {
+    pub fn new(levels: Vec<LevelCompression>) -> Self {
+        let logical_bytes = levels.iter().map(|l| l.logical_bytes).sum();
+        let physical_bytes = levels.iter().map(|l| l.physical_bytes).sum();
+        CompressionStats {
+            logical_bytes,
+            physical_bytes,
+            ratio: ratio(logical_bytes, physical_bytes),
+            levels,
+        }
+    }
+}
+
+impl LevelCompression {
+    // table properties are "name=value" pairs separated by "; "
+    pub fn parse(level: u32, files: u64, properties: &str) -> Self {
+        let mut entries = 0;
+        let mut logical_bytes = 0;
+        let mut physical_bytes = 0;
+        for (name, value) in properties
+            .split(';')
+            .filter_map(|p| p.split_once('='))
+            .map(|(name, value)| (name.trim(), parse_int(value)))
+        {
+            match name {
+                "# entries" => entries = value,
+                "raw key size" | "raw value size" => logical_bytes += value,
+                // the index block name carries its format, e.g. "index block size (user-key? 0, delta-value? 0)"
+                name if name == "data block size"
+                    || name == "filter block size"
+                    || name.starts_with("index block size") =>
+                {
+                    physical_bytes += value
+                }
+                _ => {}
+            }
+        }
+
+        LevelCompression {
+            level,
+            files,
+            entries,
+            logical_bytes,
+            physical_bytes,
+            ratio: ratio(logical_bytes, physical_bytes),
+        }
+    }
+}
+
+fn ratio(logical: u64, physical: u64) -> f64 {
+    if physical > 0 {
+        logical as f64 / physical as f64
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_level_stats() {
+        let stats = "Level Files Size(MB)\n\
+                     --------------------\n  \
+                       0        2        1\n  \
+                       1        4       12\n";
+
+        assert_eq!(
+            vec![
+                LevelSummary {
+                    level: 0,
+                    files: 2,
+                    size_mb: 1.0
+                },
+                LevelSummary {
+                    level: 1,
+                    files: 4,
+                    size_mb: 12.0
+                }
+            ],
+            parse_level_stats(stats)
+        );
+    }
+
+    #[test]
+    fn should_sum_compression_by_level() {
+        let props = "# data blocks=4; # entries=100; # deletions=0; raw key size=2000; \
+                     raw average key size=20.000000; raw value size=8000; raw average value size=80.000000; \
+                     data block size=3500; index block size (user-key? 0, delta-value? 0)=300; \
+                     filter block size=200; (estimated) table size=4000; filter policy name=N/A";
+        let level = LevelCompression::parse(1, 2, props);
+        assert_eq!(
+            LevelCompression {
+                level: 1,
+                files: 2,
+                entries: 100,
+                logical_bytes: 10_000,
+                physical_bytes: 4000,
+                ratio: 2.5
+            },
+            level
+        );
+
+        let stats = CompressionStats::new(vec![level, LevelCompression::parse(2, 0, "")]);
+        assert_eq!(10_000, stats.logical_bytes);
+        assert_eq!(4000, stats.physical_bytes);
+        assert!((stats.ratio - 2.5).abs() < 1e-9);
+        assert_eq!(0.0, stats.levels[1].ratio);
+    }
+
+    #[test]
+    fn should_parse_int_property() {
+        assert_eq!(42, parse_int("42\n"));
+        assert_eq!(0, parse_int("not a number"));
+    }
+}
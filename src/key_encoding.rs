@@ -0,0 +1,275 @@
+// Binary-safe keys.
+//
+// Keys in the URL path are UTF-8 by default, which rules out arbitrary bytes. With a `key-encoding: base64` request
+// header the key of a single record read, write or delete is decoded from url safe base64 and keys in _keys and
+// _scan pages are returned encoded the same way, so any byte sequence round trips. Listing a key that isn't valid
+// UTF-8 without the header fails.
+//
+// The /{db}/_key routes take the key base64 encoded in the `key` header instead of the path, nothing about it is left
+// to URL escaping. A db with key_policy = "strict" accepts only UTF-8 path keys made of unreserved URL characters,
+// anything else has to go through the header.
+//
+// JSON documents carrying keys - events, webhooks, the change log and replicated changes - hold them as text when
+// they're UTF-8. When any key of a document isn't, all of its keys are url safe base64 encoded and the document's
+// keyEncoding is "base64".
+
+use std::borrow::Cow;
+
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpRequest};
+use anyhow::anyhow;
+use futures::future::{ready, Ready};
+
+use crate::errors::{ErrWrapper, ErrorCtx};
+
+pub const KEY_ENCODING_HEADER: &str = "key-encoding";
+pub const KEY_HEADER: &str = "key";
+const BASE64: &str = "base64";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyEncoding {
+    Utf8,
+    Base64,
+}
+
+impl KeyEncoding {
+    fn from_headers(req: &HttpRequest) -> anyhow::Result<Self> {
+        match req.headers().get(KEY_ENCODING_HEADER) {
+            None => Ok(KeyEncoding::Utf8),
+            Some(h) if h.as_bytes().eq_ignore_ascii_case(BASE64.as_bytes()) => {
+                Ok(KeyEncoding::Base64)
+            }
+            Some(h) => Err(anyhow!(ErrorCtx::Validation(format!(
+                "Unsupported {} {:?} - only {} is supported",
+                KEY_ENCODING_HEADER, h, BASE64
+            )))),
+        }
+    }
+
+    // of a JSON document holding the keys
+    pub fn of_document<'a>(keys: impl IntoIterator<Item = &'a [u8]>) -> Self {
+        if keys.into_iter().all(|key| std::str::from_utf8(key).is_ok()) {
+            KeyEncoding::Utf8
+        } else {
+            KeyEncoding::Base64
+        }
+    }
+
+    // keyEncoding of a received JSON document, UTF-8 when it's absent
+    pub fn from_document(encoding: Option<&str>) -> anyhow::Result<Self> {
+        match encoding {
+            None => Ok(KeyEncoding::Utf8),
+            Some(encoding) if encoding.eq_ignore_ascii_case(BASE64) => Ok(KeyEncoding::Base64),
+            Some(encoding) => Err(anyhow!(ErrorCtx::Validation(format!(
+                "Unsupported keyEncoding {} - only {} is supported",
+                encoding, BASE64
+            )))),
+        }
+    }
+
+    pub fn decode(self, key: &str) -> anyhow::Result<Vec<u8>> {
+        match self {
+            KeyEncoding::Utf8 => Ok(key.as_bytes().to_vec()),
+            KeyEncoding::Base64 => {
+                base64::decode_config(key.trim_end_matches('='), base64::URL_SAFE_NO_PAD).map_err(
+                    |_| {
+                        anyhow!(ErrorCtx::Validation(format!(
+                            "Key {} isn't url safe base64",
+                            key
+                        )))
+                    },
+                )
+            }
+        }
+    }
+
+    pub fn encode(self, key: &[u8]) -> anyhow::Result<String> {
+        match self {
+            KeyEncoding::Utf8 => String::from_utf8(key.to_vec()).map_err(|_| {
+                anyhow!(ErrorCtx::Validation(format!(
+                    "Key {} isn't valid UTF-8, list it with {}: {}",
+                    display_key(key),
+                    KEY_ENCODING_HEADER,
+                    BASE64
+                )))
+            }),
+            KeyEncoding::Base64 => Ok(base64::encode_config(key, base64::URL_SAFE_NO_PAD)),
+        }
+    }
+
+    // for the response header, None for the default
+    pub fn header_value(self) -> Option<&'static str> {
+        match self {
+            KeyEncoding::Utf8 => None,
+            KeyEncoding::Base64 => Some(BASE64),
+        }
+    }
+}
+
+impl FromRequest for KeyEncoding {
+    type Error = ErrWrapper;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(KeyEncoding::from_headers(req).map_err(ErrWrapper::from))
+    }
+}
+
+// url safe or standard base64, padding optional
+pub fn header_key(req: &HttpRequest) -> anyhow::Result<Vec<u8>> {
+    let key = req.headers().get(KEY_HEADER).ok_or_else(|| {
+        anyhow!(ErrorCtx::Validation(format!(
+            "Missing {} header",
+            KEY_HEADER
+        )))
+    })?;
+    let encoded = key
+        .to_str()
+        .unwrap_or_default()
+        .trim()
+        .trim_end_matches('=');
+    base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)
+        .or_else(|_| base64::decode_config(encoded, base64::STANDARD_NO_PAD))
+        .ok()
+        .filter(|key| !key.is_empty())
+        .ok_or_else(|| {
+            anyhow!(ErrorCtx::Validation(format!(
+                "{} header {:?} isn't a base64 encoded key",
+                KEY_HEADER, key
+            )))
+        })
+}
+
+// unreserved URL characters, they reach rocky the same whatever proxies and clients escape
+pub fn check_strict_key(key: &str) -> anyhow::Result<()> {
+    let unreserved = |b: u8| b.is_ascii_alphanumeric() || b"-._~".contains(&b);
+    if !key.is_empty() && key.bytes().all(unreserved) {
+        Ok(())
+    } else {
+        Err(anyhow!(ErrorCtx::Validation(format!(
+            "Key {} has characters besides A-Z a-z 0-9 - . _ ~, send it base64 encoded in the {} header to /{{db_name}}/_key",
+            key, KEY_HEADER
+        ))))
+    }
+}
+
+// a single path segment of an upstream url, dots are escaped too so a key can't be . or ..
+pub fn path_segment(key: &[u8]) -> String {
+    key.iter()
+        .map(|&b| {
+            if b.is_ascii_alphanumeric() || b"-_~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect()
+}
+
+// events, logs and error messages identify keys as text, invalid bytes are replaced
+pub fn display_key(key: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn should_round_trip_binary_keys() {
+        let key = vec![0, 159, 146, 150, b'/', 255];
+        let encoded = KeyEncoding::Base64.encode(&key).unwrap();
+        assert!(!encoded.contains('/'));
+        assert_eq!(key, KeyEncoding::Base64.decode(&encoded).unwrap());
+        assert_eq!(
+            key,
+            KeyEncoding::Base64
+                .decode(&format!("{}==", encoded))
+                .unwrap()
+        );
+
+        assert!(KeyEncoding::Utf8.encode(&key).is_err());
+        assert!(KeyEncoding::Base64.decode("not base64!").is_err());
+        assert_eq!(b"key".to_vec(), KeyEncoding::Utf8.decode("key").unwrap());
+    }
+
+    #[test]
+    fn should_escape_path_segments() {
+        assert_eq!("user_1-a~", path_segment(b"user_1-a~"));
+        assert_eq!("a%3Fb%3Dc%23d", path_segment(b"a?b=c#d"));
+        assert_eq!("%2E%2E%2Fadmin", path_segment(b"../admin"));
+        assert_eq!("%C5%BE%00%FF", path_segment(&[0xc5, 0xbe, 0, 255]));
+    }
+
+    #[test]
+    fn should_encode_document_keys() {
+        let binary: &[u8] = &[0, 255];
+        assert_eq!(
+            KeyEncoding::Utf8,
+            KeyEncoding::of_document(vec![&b"a"[..], b"b"])
+        );
+        assert_eq!(
+            KeyEncoding::Base64,
+            KeyEncoding::of_document(vec![&b"a"[..], binary])
+        );
+        assert_eq!(KeyEncoding::Utf8, KeyEncoding::from_document(None).unwrap());
+        assert_eq!(
+            KeyEncoding::Base64,
+            KeyEncoding::from_document(Some("base64")).unwrap()
+        );
+        assert!(KeyEncoding::from_document(Some("hex")).is_err());
+    }
+
+    #[test]
+    fn should_read_encoding_header() {
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(KeyEncoding::Utf8, KeyEncoding::from_headers(&req).unwrap());
+
+        let req = TestRequest::default()
+            .header(KEY_ENCODING_HEADER, "Base64")
+            .to_http_request();
+        assert_eq!(
+            KeyEncoding::Base64,
+            KeyEncoding::from_headers(&req).unwrap()
+        );
+
+        let req = TestRequest::default()
+            .header(KEY_ENCODING_HEADER, "hex")
+            .to_http_request();
+        assert!(KeyEncoding::from_headers(&req).is_err());
+    }
+
+    #[test]
+    fn should_read_key_header() {
+        for encoded in &["AP8vAQ", "AP8vAQ==", "AP8/AQ"] {
+            let req = TestRequest::default()
+                .header(KEY_HEADER, *encoded)
+                .to_http_request();
+            let expected = if encoded.contains('/') {
+                vec![0, 255, 63, 1]
+            } else {
+                vec![0, 255, 47, 1]
+            };
+            assert_eq!(expected, header_key(&req).unwrap(), "{}", encoded);
+        }
+
+        assert!(header_key(&TestRequest::default().to_http_request()).is_err());
+        for invalid in &["", "not base64!"] {
+            let req = TestRequest::default()
+                .header(KEY_HEADER, *invalid)
+                .to_http_request();
+            assert!(header_key(&req).is_err());
+        }
+    }
+
+    #[test]
+    fn should_check_strict_keys() {
+        assert!(check_strict_key("user-1.profile_v2~").is_ok());
+        for key in &["", "a b", "a/b", "a%2Fb", "a:b", "ključ"] {
+            assert!(check_strict_key(key).is_err(), "{}", key);
+        }
+    }
+}
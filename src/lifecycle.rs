@@ -0,0 +1,101 @@
+// Per db name serialization of lifecycle transitions.
+//
+// Open, close and restore check the state of a db and then change it, two of them racing on the same name could
+// both pass the checks - two opens of the same db, or an open slipping in between a close and the scheduled
+// deletion of its files. A transition holds the name until it's done, transitions of other dbs go on in parallel.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use futures::channel::oneshot;
+
+// transitions in progress by db name, with the ones waiting for them
+type Busy = HashMap<String, Vec<oneshot::Sender<()>>>;
+
+#[derive(Default)]
+pub struct Lifecycle {
+    busy: Arc<Mutex<Busy>>,
+}
+
+// releases the name when dropped
+pub struct Transition {
+    busy: Arc<Mutex<Busy>>,
+    db_name: String,
+}
+
+impl Lifecycle {
+    // waits while another transition of the db is in progress, without blocking the worker meanwhile
+    pub async fn begin(&self, db_name: &str) -> Transition {
+        loop {
+            let released = {
+                let mut busy = lock(&self.busy);
+                match busy.get_mut(db_name) {
+                    Some(waiting) => {
+                        let (tx, rx) = oneshot::channel();
+                        waiting.push(tx);
+                        rx
+                    }
+                    None => {
+                        busy.insert(db_name.to_string(), vec![]);
+                        return Transition {
+                            busy: self.busy.clone(),
+                            db_name: db_name.to_string(),
+                        };
+                    }
+                }
+            };
+            // every waiter is woken, the name goes to whichever one asks first
+            let _ = released.await;
+        }
+    }
+}
+
+impl Drop for Transition {
+    fn drop(&mut self) {
+        let waiting = lock(&self.busy).remove(&self.db_name);
+        for tx in waiting.into_iter().flatten() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+fn lock(busy: &Mutex<Busy>) -> MutexGuard<'_, Busy> {
+    busy.lock().expect("Can't acquire lifecycle lock")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn should_serialize_transitions_per_name() {
+        let lifecycle = Arc::new(Lifecycle::default());
+        let in_progress = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let lifecycle = lifecycle.clone();
+                let in_progress = in_progress.clone();
+                thread::spawn(move || {
+                    let _transition = block_on(lifecycle.begin("db"));
+                    assert_eq!(0, in_progress.fetch_add(1, Ordering::SeqCst));
+                    thread::sleep(Duration::from_millis(10));
+                    in_progress.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // other names aren't held up
+        let _db = block_on(lifecycle.begin("db"));
+        let _other = block_on(lifecycle.begin("other"));
+    }
+}
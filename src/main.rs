@@ -1,34 +1,162 @@
 #[macro_use]
 extern crate log;
 
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use actix_web::body::{Body, ResponseBody};
+use actix_multipart::Multipart;
+use actix_web::body::{Body, ResponseBody, SizedStream};
+use actix_web::client::Client;
+use actix_web::dev::{Decompress, HttpResponseBuilder, Service, ServiceRequest, ServiceResponse};
+use actix_web::error::{BlockingError, PayloadError};
 use actix_web::http::header::ContentType;
 use actix_web::middleware::errhandlers::{ErrorHandlerResponse, ErrorHandlers};
+use actix_web::rt::time::timeout;
 use actix_web::web::Bytes;
-use actix_web::{delete, dev, get, http, post, HttpRequest, HttpResponse, ResponseError};
+use actix_web::{
+    delete, dev, get, head, http, patch, post, put, Error, HttpMessage, HttpRequest, HttpResponse,
+    ResponseError,
+};
 use actix_web::{web, App, HttpServer};
+use actix_web_actors::ws;
 use actix_web_prom::PrometheusMetrics;
 use anyhow::anyhow;
+use futures::{future, FutureExt, Stream, StreamExt};
 use log::LevelFilter;
-use serde::Deserialize;
+use prometheus::proto::MetricFamily;
+use prometheus::{Encoder, Registry, TextEncoder};
+use serde::{Deserialize, Serialize};
 use simplelog::{ConfigBuilder, TermLogger, TerminalMode, ThreadLogMode, WriteLogger};
 use structopt::StructOpt;
+use tracing::Instrument;
 
-use crate::config::{load_db_config, load_service_config};
-use crate::conversion::{convert, current_ms};
-use crate::db::DbManager;
+use crate::archive::unpack;
+use crate::auth::{AdminToken, Authorized, MetricsAuth, MetricsAuthorized};
+use crate::cli::Command;
+use crate::compression::{Accepted, Codec};
+use crate::config::{load_db_config, load_service_config, DbSettings, KeyPolicy, RocksOverrides};
+use crate::conversion::{
+    advance_clock, convert, current_ms, decode_cursor, etag_matches, parse_duration, parse_etag,
+    parse_version, to_etag,
+};
+use crate::crdt::{CrdtOp, CrdtState};
+use crate::db::{DbManager, Order, Representation, WriteCondition, MAX_VALUE_BYTES};
+use crate::erasure::{ErasureRequest, SigningKey};
 use crate::errors::{ApiError, ErrWrapper, ErrorCtx};
+use crate::events::{EventKind, Filter};
+use crate::export::{export_etag, parse_export_etag, parse_range};
+use crate::filter::{Fields, ScanFilter};
+use crate::graphql::RockySchema;
+use crate::key_encoding::{
+    check_strict_key, header_key, path_segment, KeyEncoding, KEY_ENCODING_HEADER,
+};
+use crate::maintenance::Schedule;
+use crate::negotiation::Format;
+use crate::operations::OperationKind;
+use crate::patch::PatchFormat;
+use crate::replication::Change;
+use crate::scan_snapshot::ScanCursor;
+use crate::txn::{TxnAction, TxnRequest, TxnToken, TxnWrite, TXN_IDLE_TIMEOUT};
+use crate::upload::MAX_PART_BYTES;
+use crate::versioning::{API_PREFIX, DEPRECATED};
+use crate::wire::WireRecord;
+use crate::ws::WsSession;
 
 mod errors;
 
+mod access_log;
+mod archive;
+mod auth;
+mod backup;
+mod changelog;
+mod cli;
+mod compression;
 mod config;
 mod conversion;
+mod crdt;
 mod db;
+mod deletion;
+mod erasure;
+mod events;
+mod export;
+mod filter;
+mod forecast;
+mod graphql;
+mod grpc;
+mod index;
+mod internals;
+mod key_encoding;
+mod lifecycle;
+mod maintenance;
+mod memory;
+mod metrics;
+mod namespace;
+mod negotiation;
+mod operations;
+mod outbound;
+mod patch;
+mod preflight;
+mod quota;
+mod replication;
+mod resp;
+mod rock;
+mod scan_cache;
+mod scan_snapshot;
+mod snapshot;
+mod stats;
+mod telemetry;
+mod tls;
+mod txn;
+mod upload;
+mod versioning;
+mod webhook;
+mod wire;
+mod ws;
 
 const NO_TTL: u128 = 0;
 const TTL_HEADER: &str = "ttl";
+const SOFT_TTL_HEADER: &str = "soft-ttl";
+const VALUE_SIZE_HEADER: &str = "value-size";
+const EXPIRES_AT_HEADER: &str = "expires-at";
+const SOFT_EXPIRES_AT_HEADER: &str = "soft-expires-at";
+const TTL_REMAINING_HEADER: &str = "ttl-remaining";
+const READS_HEADER: &str = "reads";
+const WRITES_HEADER: &str = "writes";
+const LAST_ACCESS_HEADER: &str = "last-access";
+const SEQ_HEADER: &str = "seq";
+const VERSION_HEADER: &str = "version";
+const EXPECTED_VERSION_HEADER: &str = "expected-version";
+const STALE_HEADER: &str = "x-stale";
+const TAR_CONTENT_TYPE: &str = "application/x-tar";
+const OPENMETRICS_TYPE: &str = "application/openmetrics-text";
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+const DEFAULT_VALUE_HEADER: &str = "default-value";
+const CONFIGURED_DEFAULT: &str = "configured";
+const UPSTREAM_DEFAULT: &str = "upstream";
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+const UPSTREAM_MAX_BYTES: usize = 16 * 1024 * 1024;
+const MAX_WAIT: Duration = Duration::from_secs(60);
+// comment line sent on an idle watch so proxies don't time out the connection
+const WATCH_KEEPALIVE: Duration = Duration::from_secs(15);
+const EVENT_STREAM_TYPE: &str = "text/event-stream";
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+const MAX_DELETE_KEYS: usize = 10_000;
+const MAX_READ_KEYS: usize = 10_000;
+const DEFAULT_FORECAST_DAYS: u32 = 30;
+const MAX_FORECAST_DAYS: u32 = 365;
+// a shipped batch holds up to 1000 records of any size, more than the default payload limit
+const MAX_REPLICATION_BYTES: usize = 256 * 1024 * 1024;
+const MAX_MULTIPART_BYTES: usize = 64 * 1024 * 1024;
+// largest import body staged on disk
+const MAX_IMPORT_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+const MULTIPART_TYPE: &str = "multipart/form-data";
+const JSON_TYPE: &str = "application/json";
 
 type Response<T> = Result<T, ErrWrapper>;
 
@@ -45,6 +173,19 @@ pub struct PathCfg {
         default_value = "./config"
     )]
     config_path: String,
+    #[structopt(
+        long,
+        help = "Rewrites records stored in older formats in the current one and exits without \
+    starting the server"
+    )]
+    migrate_and_exit: bool,
+    #[structopt(
+        long,
+        help = "Validates service and database config and exits, with a non zero code if invalid"
+    )]
+    check_config: bool,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
 }
 
 #[derive(Deserialize)]
@@ -53,16 +194,318 @@ struct PathVal {
     key: String,
 }
 
+#[derive(Deserialize)]
+struct PageQuery {
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+impl PageQuery {
+    fn cursor(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        self.cursor
+            .as_deref()
+            .filter(|c| !c.is_empty())
+            .map(decode_cursor)
+            .transpose()
+    }
+
+    // listings continue from the snapshot they started at
+    fn scan_cursor(&self) -> anyhow::Result<Option<ScanCursor>> {
+        self.cursor
+            .as_deref()
+            .filter(|c| !c.is_empty())
+            .map(ScanCursor::parse)
+            .transpose()
+    }
+
+    fn limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .min(MAX_PAGE_SIZE)
+            .max(1)
+    }
+}
+
+#[derive(Deserialize)]
+struct KeysQuery {
+    cursor: Option<String>,
+    limit: Option<usize>,
+    #[serde(default)]
+    prefix: String,
+    #[serde(default)]
+    namespaces: bool,
+    #[serde(default)]
+    order: Order,
+}
+
+impl KeysQuery {
+    fn page(&self) -> PageQuery {
+        PageQuery {
+            cursor: self.cursor.clone(),
+            limit: self.limit,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ScanQuery {
+    cursor: Option<String>,
+    limit: Option<usize>,
+    value_prefix: Option<String>,
+    field: Option<String>,
+    equals: Option<String>,
+    #[serde(default)]
+    order: Order,
+    #[serde(default)]
+    fields: Fields,
+}
+
+impl ScanQuery {
+    fn page(&self) -> PageQuery {
+        PageQuery {
+            cursor: self.cursor.clone(),
+            limit: self.limit,
+        }
+    }
+
+    fn filter(&self) -> anyhow::Result<ScanFilter> {
+        ScanFilter::new(
+            self.value_prefix.clone(),
+            self.field.clone(),
+            self.equals.clone(),
+        )
+        .map(|filter| filter.with_fields(self.fields))
+    }
+}
+
+#[derive(Deserialize)]
+struct CountQuery {
+    cursor: Option<String>,
+    #[serde(default)]
+    approx: bool,
+}
+
+#[derive(Deserialize)]
+struct OpenQuery {
+    #[serde(default)]
+    ephemeral: bool,
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    #[serde(default)]
+    since: u64,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct WatchQuery {
+    prefix: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct WaitQuery {
+    wait: Option<String>,
+    if_version: Option<u64>,
+}
+
+impl WaitQuery {
+    fn wait(&self) -> anyhow::Result<Option<Duration>> {
+        self.wait
+            .as_deref()
+            .map(|w| parse_duration(w).map(|d| d.min(MAX_WAIT)))
+            .transpose()
+    }
+}
+
+#[derive(Deserialize)]
+struct HotKeysQuery {
+    limit: Option<usize>,
+    #[serde(default)]
+    cold: bool,
+}
+
+#[derive(Serialize)]
+struct SeqInfo {
+    seq: u64,
+}
+
+#[derive(Serialize)]
+struct RemovedKeys {
+    results: Vec<RemovedKey>,
+    seq: u64,
+}
+
+#[derive(Serialize)]
+struct RemovedKey {
+    key: String,
+    status: RemoveStatus,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RemoveStatus {
+    Deleted,
+    NotFound,
+}
+
+// value is base64 encoded, ttl is the remaining one in ms
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReadRecord {
+    found: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AdvanceQuery {
+    ms: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ClockOffset {
+    offset_ms: u64,
+}
+
+#[derive(Deserialize)]
+struct ForecastQuery {
+    days: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct RestoreQuery {
+    backup_id: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum WriteMode {
+    // write only if the key doesn't exist
+    Nx,
+    // write only if the key exists
+    Xx,
+}
+
+impl WriteMode {
+    fn condition(self) -> WriteCondition {
+        match self {
+            WriteMode::Nx => WriteCondition::IfAbsent,
+            WriteMode::Xx => WriteCondition::IfPresent,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct StoreQuery {
+    mode: Option<WriteMode>,
+}
+
+#[derive(Deserialize)]
+struct UploadPath {
+    db_name: String,
+    key: String,
+    upload_id: String,
+}
+
+#[derive(Deserialize)]
+struct PartPath {
+    db_name: String,
+    key: String,
+    upload_id: String,
+    number: u32,
+}
+
+#[derive(Deserialize)]
+struct TxnPath {
+    db_name: String,
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct TxnKeyPath {
+    db_name: String,
+    token: String,
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct RenameQuery {
+    to: String,
+}
+
+#[derive(Deserialize)]
+struct RangeQuery {
+    start: String,
+    end: String,
+}
+
 trait Expiration {
     fn calc_expire(&self) -> anyhow::Result<u128>;
+
+    fn calc_soft_expire(&self) -> anyhow::Result<u128>;
 }
 
 impl Expiration for HttpRequest {
     fn calc_expire(&self) -> anyhow::Result<u128> {
-        self.headers()
-            .get(TTL_HEADER)
-            .map(|h| Ok(current_ms()? + convert(h)?))
-            .unwrap_or(Ok(NO_TTL))
+        expire_from(self, TTL_HEADER)
+    }
+
+    fn calc_soft_expire(&self) -> anyhow::Result<u128> {
+        expire_from(self, SOFT_TTL_HEADER)
+    }
+}
+
+fn expire_from(req: &HttpRequest, header: &str) -> anyhow::Result<u128> {
+    req.headers()
+        .get(header)
+        .map(|h| Ok(current_ms()? + convert(h)?))
+        .unwrap_or(Ok(NO_TTL))
+}
+
+trait Conditional {
+    fn write_condition(&self, mode: Option<WriteMode>) -> anyhow::Result<WriteCondition>;
+}
+
+impl Conditional for HttpRequest {
+    // at most one of If-Match, If-None-Match, expected-version and mode can be used
+    fn write_condition(&self, mode: Option<WriteMode>) -> anyhow::Result<WriteCondition> {
+        let mut conditions = vec![];
+        if let Some(h) = self.headers().get(http::header::IF_MATCH) {
+            conditions.push(if h.as_bytes() == b"*" {
+                WriteCondition::IfExists
+            } else {
+                WriteCondition::IfVersion(parse_etag(h)?)
+            });
+        }
+        if let Some(h) = self.headers().get(http::header::IF_NONE_MATCH) {
+            if h.as_bytes() != b"*" {
+                return Err(anyhow!(ErrorCtx::Validation(
+                    "Only If-None-Match: * is supported on writes".into()
+                )));
+            }
+            conditions.push(WriteCondition::IfAbsent);
+        }
+        if let Some(h) = self.headers().get(EXPECTED_VERSION_HEADER) {
+            conditions.push(WriteCondition::IfVersion(parse_version(h)?));
+        }
+        if let Some(mode) = mode {
+            conditions.push(mode.condition());
+        }
+
+        match conditions.as_slice() {
+            [] => Ok(WriteCondition::Always),
+            [condition] => Ok(*condition),
+            _ => Err(anyhow!(ErrorCtx::Validation(format!(
+                "Use a single write condition - If-Match, If-None-Match, {} header or mode",
+                EXPECTED_VERSION_HEADER
+            )))),
+        }
     }
 }
 
@@ -72,6 +515,28 @@ impl ResponseError for ErrWrapper {
         if let Some(ctx) = ctx {
             match ctx {
                 ErrorCtx::Validation(s) => HttpResponse::BadRequest().json(ApiError::Msg(s.into())),
+                ErrorCtx::PreconditionFailed(s) => {
+                    HttpResponse::PreconditionFailed().json(ApiError::Msg(s.into()))
+                }
+                ErrorCtx::Unauthorized(s) => {
+                    HttpResponse::Unauthorized().json(ApiError::Msg(s.into()))
+                }
+                ErrorCtx::Forbidden(s) => HttpResponse::Forbidden().json(ApiError::Msg(s.into())),
+                ErrorCtx::Unavailable(s) => {
+                    HttpResponse::ServiceUnavailable().json(ApiError::Msg(s.into()))
+                }
+                ErrorCtx::NotFound(s) => HttpResponse::NotFound().json(ApiError::Msg(s.into())),
+                ErrorCtx::Conflict(s) => HttpResponse::Conflict().json(ApiError::Msg(s.into())),
+                ErrorCtx::TooManyRequests(s) => {
+                    HttpResponse::TooManyRequests().json(ApiError::Msg(s.into()))
+                }
+                ErrorCtx::InsufficientStorage(s) => {
+                    HttpResponse::InsufficientStorage().json(ApiError::Msg(s.into()))
+                }
+                ErrorCtx::Gone(s) => HttpResponse::Gone().json(ApiError::Msg(s.into())),
+                ErrorCtx::PayloadTooLarge(s) => {
+                    HttpResponse::PayloadTooLarge().json(ApiError::Msg(s.into()))
+                }
             }
         } else {
             HttpResponse::InternalServerError().json(ApiError::Msg(self.to_string()))
@@ -100,9 +565,325 @@ fn not_found<B>(mut res: dev::ServiceResponse<B>) -> actix_web::Result<ErrorHand
     Ok(ErrorHandlerResponse::Response(r))
 }
 
+// JSON responses in the format the reader accepts, wraps not found responses of the error handlers too
+fn negotiated<S>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<Body>, actix_web::Error>>
+where
+    S: Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse<Body>,
+        Error = actix_web::Error,
+    >,
+{
+    let format = negotiation::requested_format(&req);
+    srv.call(req)
+        .map(move |res| res.map(|res| negotiation::negotiate(format, res)))
+}
+
+// request span, the handler and the db operations it calls run inside it
+fn traced<S>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<Body>, actix_web::Error>>
+where
+    S: Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse<Body>,
+        Error = actix_web::Error,
+    >,
+{
+    let span = telemetry::request_span(&req);
+    let record = span.clone();
+    srv.call(req).instrument(span).map(move |res| {
+        if let Ok(res) = &res {
+            telemetry::record_response(&record, res);
+        }
+        res
+    })
+}
+
+// latency by route pattern, paths carry db names and keys
+fn timed<S>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<Body>, actix_web::Error>>
+where
+    S: Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse<Body>,
+        Error = actix_web::Error,
+    >,
+{
+    let started = Instant::now();
+    srv.call(req).map(move |res| {
+        if let Ok(res) = &res {
+            let endpoint = res
+                .request()
+                .match_pattern()
+                .unwrap_or_else(|| "unmatched".into());
+            metrics::HTTP_REQUEST_DURATION
+                .with_label_values(&[
+                    &endpoint,
+                    res.request().method().as_str(),
+                    res.status().as_str(),
+                ])
+                .observe(started.elapsed().as_secs_f64());
+        }
+        res
+    })
+}
+
+#[get("/rocky/prometheus/metrics")]
+async fn prometheus_metrics(
+    _auth: MetricsAuthorized,
+    req: HttpRequest,
+    registry: web::Data<Registry>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    render_metrics(&req, gather(&registry, &db_man))
+}
+
+// same as /rocky/prometheus/metrics, shadows a db named metrics
+#[get("/metrics")]
+async fn metrics_endpoint(
+    _auth: MetricsAuthorized,
+    req: HttpRequest,
+    registry: web::Data<Registry>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    render_metrics(&req, gather(&registry, &db_man))
+}
+
+// gauges of the db manager are read on scrape
+fn gather(registry: &Registry, db_man: &DbManager) -> Vec<MetricFamily> {
+    metrics::OPEN_DBS.set(db_man.open_count() as i64);
+    registry.gather()
+}
+
+fn render_metrics(req: &HttpRequest, families: Vec<MetricFamily>) -> Response<HttpResponse> {
+    let accepts_openmetrics = req
+        .headers()
+        .get(http::header::ACCEPT)
+        .and_then(|h| h.to_str().ok())
+        .map_or(false, |h| h.contains(OPENMETRICS_TYPE));
+
+    if accepts_openmetrics {
+        Ok(HttpResponse::Ok()
+            .content_type(OPENMETRICS_CONTENT_TYPE)
+            .body(metrics::render_openmetrics(&families)))
+    } else {
+        let encoder = TextEncoder::new();
+        let mut body = vec![];
+        encoder
+            .encode(&families, &mut body)
+            .map_err(anyhow::Error::from)?;
+        Ok(HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(body))
+    }
+}
+
+#[get("/_metrics.json")]
+async fn json_metrics(
+    format: Format,
+    _auth: MetricsAuthorized,
+    registry: web::Data<Registry>,
+    db_man: web::Data<DbManager>,
+) -> HttpResponse {
+    format.respond(
+        &mut HttpResponse::Ok(),
+        &metrics::render_json(&gather(&registry, &db_man)),
+    )
+}
+
+#[get("/_dbs")]
+async fn list_dbs(format: Format, db_man: web::Data<DbManager>) -> HttpResponse {
+    format.respond(&mut HttpResponse::Ok(), &db_man.list())
+}
+
+// test_clock only - every record gets that much closer to expiring
+#[post("/_test/advance_time")]
+async fn advance_time(
+    format: Format,
+    _auth: Authorized,
+    query: web::Query<AdvanceQuery>,
+) -> HttpResponse {
+    let offset_ms = advance_clock(query.ms);
+    warn!(
+        "Clock advanced by {} ms, total offset = {} ms",
+        query.ms, offset_ms
+    );
+    format.respond(&mut HttpResponse::Ok(), &ClockOffset { offset_ms })
+}
+
+// test helpers, registered only with test_clock
+fn test_routes(cfg: &mut web::ServiceConfig, test_clock: bool) {
+    if test_clock {
+        cfg.service(advance_time);
+    }
+}
+
+// the body is read up to a limit before it's parsed, a single request is served - no batches or uploads
+#[post("/graphql")]
+async fn graphql_query(
+    format: Format,
+    schema: web::Data<RockySchema>,
+    body: web::Payload,
+) -> Response<HttpResponse> {
+    let body = read_body(body, graphql::MAX_REQUEST_BYTES).await?;
+    let req = serde_json::from_slice(&body).map_err(|e| {
+        anyhow!(ErrorCtx::Validation(format!(
+            "Invalid GraphQL request - {}",
+            e
+        )))
+    })?;
+    Ok(format.respond(
+        &mut HttpResponse::Ok(),
+        &graphql::execute(&schema, req).await,
+    ))
+}
+
+// registered before the db routes, it shadows a db named graphql
+fn graphql_routes(cfg: &mut web::ServiceConfig, schema: Option<RockySchema>) {
+    if let Some(schema) = schema {
+        cfg.data(schema).service(graphql_query);
+    }
+}
+
+#[post("/_erasure")]
+async fn erase(
+    format: Format,
+    _auth: Authorized,
+    body: Bytes,
+    signing_key: web::Data<SigningKey>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let request = ErasureRequest::from_json(&body)?;
+    let report = db_man.erase(request, signing_key.get()?)?;
+    Ok(format.respond(&mut HttpResponse::Accepted(), &report))
+}
+
+#[get("/_erasure/{id}")]
+async fn erasure(
+    format: Format,
+    _auth: Authorized,
+    id: web::Path<String>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    Ok(format.respond(&mut HttpResponse::Ok(), &db_man.erasure(&id)?))
+}
+
+#[get("/_snapshots/{id}/{db_name}/{key}")]
+async fn snapshot_read(
+    _auth: Authorized,
+    path: web::Path<(String, String, String)>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let (id, db_name, key) = path.into_inner();
+    Ok(match db_man.snapshot_read(&id, &db_name, &key).await? {
+        Some(data) => {
+            let mut res = HttpResponse::Ok();
+            match data.content_type() {
+                Some(content_type) => res.content_type(content_type),
+                None => res.set(ContentType::octet_stream()),
+            };
+            res.header(VERSION_HEADER, data.version());
+            res.body(data.into_value())
+        }
+        None => HttpResponse::NoContent().finish(),
+    })
+}
+
+#[delete("/_snapshots/{id}/{db_name}")]
+async fn unmount_snapshot(
+    _auth: Authorized,
+    path: web::Path<(String, String)>,
+    db_man: web::Data<DbManager>,
+) -> HttpResponse {
+    let (id, db_name) = path.into_inner();
+    if db_man.unmount_snapshot(&id, &db_name) {
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+#[get("/_admin/operations")]
+async fn list_operations(
+    format: Format,
+    _auth: Authorized,
+    db_man: web::Data<DbManager>,
+) -> HttpResponse {
+    format.respond(&mut HttpResponse::Ok(), &db_man.operations())
+}
+
+#[delete("/_admin/operations/{id}")]
+async fn cancel_operation(
+    _auth: Authorized,
+    id: web::Path<u64>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    db_man.cancel_operation(*id)?;
+    Ok(HttpResponse::Accepted().finish())
+}
+
+// answered once the compaction is started, it runs in the background as a listed operation
+#[post("/_admin/dbs/{db_name}/compact")]
+async fn compact(
+    format: Format,
+    _auth: Authorized,
+    db_name: web::Path<String>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let operation = db_man.compact(db_name.as_str())?;
+    Ok(format.respond(&mut HttpResponse::Accepted(), &operation))
+}
+
+// the page calls the /v1 endpoints, admin ones with the token entered in it
+#[get("/admin")]
+async fn admin_ui() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(include_str!("../admin/index.html"))
+}
+
+#[get("/_outbound")]
+async fn list_outbound(
+    format: Format,
+    _auth: Authorized,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    Ok(format.respond(&mut HttpResponse::Ok(), &db_man.outbound()?))
+}
+
+#[get("/_deletions")]
+async fn deletions(format: Format, db_man: web::Data<DbManager>) -> HttpResponse {
+    format.respond(&mut HttpResponse::Ok(), &db_man.deletions())
+}
+
+// an optional JSON body overrides rocksdb options for this db only
 #[post("/{db_name}")]
-async fn open(db_name: web::Path<String>, db_man: web::Data<DbManager>) -> Response<HttpResponse> {
-    db_man.open(db_name.into_inner()).await?;
+async fn open(
+    db_name: web::Path<String>,
+    query: web::Query<OpenQuery>,
+    body: Bytes,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let overrides = if body.is_empty() {
+        RocksOverrides::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|e| {
+            anyhow!(ErrorCtx::Validation(format!(
+                "Malformed rocksdb overrides - {}",
+                e
+            )))
+        })?
+    };
+    db_man
+        .open(db_name.into_inner(), query.ephemeral, overrides)
+        .await?;
     Ok(HttpResponse::Ok().finish())
 }
 
@@ -122,95 +903,1746 @@ async fn close(db_name: web::Path<String>, db_man: web::Data<DbManager>) -> Resp
     Ok(HttpResponse::Ok().finish())
 }
 
-#[post("/{db_name}/{key}")]
-async fn store(
-    p_val: web::Path<PathVal>,
-    body: Bytes,
-    req: HttpRequest,
+#[get("/{db_name}/_keys")]
+async fn keys(
+    format: Format,
+    db_name: web::Path<String>,
+    query: web::Query<KeysQuery>,
+    encoding: KeyEncoding,
     db_man: web::Data<DbManager>,
 ) -> Response<HttpResponse> {
-    db_man
-        .store(
-            p_val.db_name.as_str(),
-            p_val.key.as_str(),
-            body,
-            req.calc_expire()?,
+    let page_query = query.page();
+    let page = db_man
+        .keys(
+            db_name.as_str(),
+            page_query.scan_cursor()?,
+            page_query.limit(),
+            encoding,
+            encoding.decode(&query.prefix)?,
+            query.namespaces,
+            query.order,
         )
         .await?;
-    Ok(HttpResponse::Ok().finish())
+    Ok(format.respond(&mut with_key_encoding(HttpResponse::Ok(), encoding), &page))
 }
 
-#[get("/{db_name}/{key}")]
-async fn read(p_val: web::Path<PathVal>, db_man: web::Data<DbManager>) -> Response<HttpResponse> {
-    let res = db_man
-        .read(p_val.db_name.as_str(), p_val.key.as_str())
+#[get("/{db_name}/_query")]
+async fn query(
+    format: Format,
+    db_name: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let (field, value, page_query) = field_query(query.into_inner())?;
+    let page = db_man
+        .query(
+            db_name.as_str(),
+            &field,
+            &value,
+            page_query.cursor()?,
+            page_query.limit(),
+            encoding,
+        )
         .await?;
+    Ok(format.respond(&mut with_key_encoding(HttpResponse::Ok(), encoding), &page))
+}
 
-    Ok(if let Some(bytes) = res {
-        HttpResponse::Ok()
-            .set(ContentType::octet_stream())
-            .body(bytes)
-    } else {
-        HttpResponse::NoContent().finish()
-    })
+// the one parameter besides cursor and limit is the field and the value looked up
+fn field_query(mut params: HashMap<String, String>) -> anyhow::Result<(String, String, PageQuery)> {
+    let page_query = PageQuery {
+        cursor: params.remove("cursor"),
+        limit: params
+            .remove("limit")
+            .map(|l| l.parse())
+            .transpose()
+            .map_err(|_| anyhow!(ErrorCtx::Validation("limit isn't a number".into())))?,
+    };
+    let mut params = params.into_iter();
+    match (params.next(), params.next()) {
+        (Some((field, value)), None) => Ok((field, value, page_query)),
+        _ => Err(anyhow!(ErrorCtx::Validation(
+            "Query takes exactly one field=value besides cursor and limit".into()
+        ))),
+    }
 }
 
-#[delete("/{db_name}/{key}")]
-async fn remove(p_val: web::Path<PathVal>, db_man: web::Data<DbManager>) -> Response<HttpResponse> {
-    db_man
-        .remove(p_val.db_name.as_str(), p_val.key.as_str())
+#[get("/{db_name}/_count")]
+async fn count(
+    format: Format,
+    db_name: web::Path<String>,
+    query: web::Query<CountQuery>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let cursor = query
+        .cursor
+        .as_deref()
+        .filter(|c| !c.is_empty())
+        .map(decode_cursor)
+        .transpose()?;
+    let count = db_man.count(db_name.as_str(), cursor, query.approx).await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &count))
+}
+
+#[get("/{db_name}/_scan")]
+async fn scan(
+    format: Format,
+    db_name: web::Path<String>,
+    query: web::Query<ScanQuery>,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let page_query = query.page();
+    let page = db_man
+        .scan(
+            db_name.as_str(),
+            page_query.scan_cursor()?,
+            page_query.limit(),
+            encoding,
+            query.filter()?,
+            query.order,
+        )
         .await?;
-    Ok(HttpResponse::Ok().finish())
+    Ok(format.respond(&mut with_key_encoding(HttpResponse::Ok(), encoding), &page))
 }
 
-#[get("/health")]
-async fn health() -> HttpResponse {
-    HttpResponse::Ok().finish()
+// keys in the page are encoded as requested, the header tells clients how to read them
+fn with_key_encoding(mut res: HttpResponseBuilder, encoding: KeyEncoding) -> HttpResponseBuilder {
+    if let Some(value) = encoding.header_value() {
+        res.header(KEY_ENCODING_HEADER, value);
+    }
+    res
 }
 
-// main thread will panic! if config can't be initialized
+// a strict db takes only path keys that no client or proxy escapes differently
+fn path_key(
+    db_man: &DbManager,
+    db_name: &str,
+    key: &str,
+    encoding: KeyEncoding,
+) -> anyhow::Result<Vec<u8>> {
+    if encoding == KeyEncoding::Utf8 {
+        check_path_key(db_man, db_name, key)?;
+    }
+    encoding.decode(key)
+}
+
+fn check_path_key(db_man: &DbManager, db_name: &str, key: &str) -> anyhow::Result<()> {
+    match db_man.db_cfg.key_policy(db_name) {
+        KeyPolicy::Strict => check_strict_key(key),
+        KeyPolicy::Lenient => Ok(()),
+    }
+}
+
+#[post("/{db_name}/_batch")]
+async fn batch(
+    db_name: web::Path<String>,
+    body: Bytes,
+    req: HttpRequest,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let records = wire::decode(&body)?;
+    let seq = db_man
+        .write_batch(db_name.as_str(), records, req.calc_expire()?)
+        .await?;
+    Ok(HttpResponse::Ok().header(SEQ_HEADER, seq).finish())
+}
+
+// same batch from a form, each part name is a key and its body the value
+#[post("/{db_name}/_batch", guard = "is_multipart")]
+async fn multipart_batch(
+    db_name: web::Path<String>,
+    parts: Multipart,
+    req: HttpRequest,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let records = read_parts(parts, MAX_MULTIPART_BYTES).await?;
+    let seq = db_man
+        .write_batch(db_name.as_str(), records, req.calc_expire()?)
+        .await?;
+    Ok(HttpResponse::Ok().header(SEQ_HEADER, seq).finish())
+}
+
+fn is_multipart(head: &dev::RequestHead) -> bool {
+    head.headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .map_or(false, |ct| ct.starts_with(MULTIPART_TYPE))
+}
+
+async fn read_parts(mut parts: Multipart, limit: usize) -> anyhow::Result<Vec<WireRecord>> {
+    let malformed = |e| {
+        anyhow!(ErrorCtx::Validation(format!(
+            "Malformed multipart body - {}",
+            e
+        )))
+    };
+    let mut records = vec![];
+    let mut total = 0;
+    while let Some(part) = parts.next().await {
+        let mut part = part.map_err(malformed)?;
+        let key = part
+            .content_disposition()
+            .and_then(|cd| cd.get_name().map(String::from))
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| anyhow!(ErrorCtx::Validation("Every part needs a name".into())))?;
+
+        let mut value = vec![];
+        while let Some(chunk) = part.next().await {
+            let chunk = chunk.map_err(malformed)?;
+            total += chunk.len();
+            if total > limit {
+                return Err(anyhow!(ErrorCtx::Validation(format!(
+                    "Body exceeds {} bytes",
+                    limit
+                ))));
+            }
+            value.extend_from_slice(&chunk);
+        }
+        records.push(WireRecord::new(key, Some(value)));
+    }
+    Ok(records)
+}
+
+#[post("/{db_name}/_txn")]
+async fn txn(
+    format: Format,
+    db_name: web::Path<String>,
+    body: Bytes,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let writes = TxnRequest::from_json(&body)?.into_writes()?;
+    let res = db_man.txn(db_name.as_str(), writes).await?;
+    Ok(format.respond(HttpResponse::Ok().header(SEQ_HEADER, res.seq), &res))
+}
+
+// changes shipped by a deployment replicating the db here
+#[post("/{db_name}/_replicate")]
+async fn replicate(
+    format: Format,
+    db_name: web::Path<String>,
+    body: web::Payload,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let changes = Change::from_json(&read_body(body, MAX_REPLICATION_BYTES).await?)?;
+    let res = db_man.replicate(db_name.as_str(), changes).await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &res))
+}
+
+async fn read_body<S>(mut body: S, limit: usize) -> anyhow::Result<Vec<u8>>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    let mut bytes = vec![];
+    while let Some(chunk) = body.next().await {
+        bytes.extend_from_slice(&chunk?);
+        if bytes.len() > limit {
+            return Err(anyhow!(ErrorCtx::Validation(format!(
+                "Body exceeds {} bytes",
+                limit
+            ))));
+        }
+    }
+    Ok(bytes)
+}
+
+#[post("/{db_name}/_txn/begin")]
+async fn begin_txn(
+    format: Format,
+    db_name: web::Path<String>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let token = db_man.begin_txn(db_name.as_str()).await?;
+    Ok(format.respond(
+        &mut HttpResponse::Ok(),
+        &TxnToken {
+            token,
+            idle_timeout_ms: TXN_IDLE_TIMEOUT.as_millis(),
+        },
+    ))
+}
+
+#[post("/{db_name}/_txn/{token}/_commit")]
+async fn commit_txn(
+    format: Format,
+    p_val: web::Path<TxnPath>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let res = db_man
+        .commit_txn(p_val.db_name.as_str(), p_val.token.as_str())
+        .await?;
+    Ok(format.respond(HttpResponse::Ok().header(SEQ_HEADER, res.seq), &res))
+}
+
+#[post("/{db_name}/_txn/{token}/_rollback")]
+async fn rollback_txn(
+    p_val: web::Path<TxnPath>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    db_man
+        .rollback_txn(p_val.db_name.as_str(), p_val.token.as_str())
+        .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[get("/{db_name}/_txn/{token}/{key}")]
+async fn txn_read(
+    p_val: web::Path<TxnKeyPath>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let value = db_man
+        .txn_read(
+            p_val.db_name.as_str(),
+            p_val.token.as_str(),
+            p_val.key.as_str(),
+        )
+        .await?;
+    Ok(match value {
+        Some(value) => HttpResponse::Ok()
+            .set(ContentType::octet_stream())
+            .body(value),
+        None => HttpResponse::NoContent().finish(),
+    })
+}
+
+#[post("/{db_name}/_txn/{token}/{key}")]
+async fn txn_store(
+    p_val: web::Path<TxnKeyPath>,
+    body: Bytes,
+    req: HttpRequest,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let write = TxnWrite {
+        key: p_val.key.clone(),
+        action: TxnAction::Put(body.to_vec()),
+        ttl: req.calc_expire()?,
+        expected_version: None,
+    };
+    db_man
+        .txn_write(p_val.db_name.as_str(), p_val.token.as_str(), write)
+        .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[delete("/{db_name}/_txn/{token}/{key}")]
+async fn txn_remove(
+    p_val: web::Path<TxnKeyPath>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let write = TxnWrite {
+        key: p_val.key.clone(),
+        action: TxnAction::Delete,
+        ttl: NO_TTL,
+        expected_version: None,
+    };
+    db_man
+        .txn_write(p_val.db_name.as_str(), p_val.token.as_str(), write)
+        .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/{db_name}/_mget")]
+async fn multi_get(
+    db_name: web::Path<String>,
+    body: Bytes,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let keys = wire::decode(&body)?.into_iter().map(|r| r.key).collect();
+    let records = db_man.multi_get(db_name.as_str(), keys).await?;
+    Ok(HttpResponse::Ok()
+        .set(ContentType::octet_stream())
+        .body(wire::encode(&records)))
+}
+
+// same read from a JSON array of keys, answered with a JSON object of records by key
+#[post("/{db_name}/_mget", guard = "is_json")]
+async fn json_multi_get(
+    format: Format,
+    db_name: web::Path<String>,
+    body: Bytes,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let keys: Vec<String> = serde_json::from_slice(&body).map_err(|e| {
+        anyhow!(ErrorCtx::Validation(format!(
+            "Expected a JSON array of keys - {}",
+            e
+        )))
+    })?;
+    if keys.len() > MAX_READ_KEYS {
+        return Err(anyhow!(ErrorCtx::Validation(format!(
+            "At most {} keys can be read at once",
+            MAX_READ_KEYS
+        )))
+        .into());
+    }
+    let decoded = keys
+        .iter()
+        .map(|key| encoding.decode(key))
+        .collect::<anyhow::Result<_>>()?;
+    let records = db_man.read_many(db_name.as_str(), decoded).await?;
+    let mut results = BTreeMap::new();
+    for (key, data) in keys.into_iter().zip(records) {
+        let record = match data {
+            Some(data) => ReadRecord {
+                found: true,
+                ttl: data.remaining_ttl()?,
+                content_type: data.content_type().map(String::from),
+                value: Some(base64::encode(data.into_value())),
+            },
+            None => ReadRecord {
+                found: false,
+                value: None,
+                ttl: None,
+                content_type: None,
+            },
+        };
+        results.insert(key, record);
+    }
+    Ok(format.respond(&mut HttpResponse::Ok(), &results))
+}
+
+fn is_json(head: &dev::RequestHead) -> bool {
+    head.headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .map_or(false, |ct| ct.starts_with(JSON_TYPE))
+}
+
+// a JSON array of keys deleted by a single write
+#[post("/{db_name}/_mdelete")]
+async fn multi_delete(
+    format: Format,
+    db_name: web::Path<String>,
+    body: Bytes,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let keys: Vec<String> = serde_json::from_slice(&body).map_err(|e| {
+        anyhow!(ErrorCtx::Validation(format!(
+            "Expected a JSON array of keys - {}",
+            e
+        )))
+    })?;
+    if keys.len() > MAX_DELETE_KEYS {
+        return Err(anyhow!(ErrorCtx::Validation(format!(
+            "At most {} keys can be deleted at once",
+            MAX_DELETE_KEYS
+        )))
+        .into());
+    }
+    let decoded = keys
+        .iter()
+        .map(|key| encoding.decode(key))
+        .collect::<anyhow::Result<_>>()?;
+    let (found, seq) = db_man.remove_many(db_name.as_str(), decoded).await?;
+    let results = keys
+        .into_iter()
+        .zip(found)
+        .map(|(key, found)| RemovedKey {
+            key,
+            status: if found {
+                RemoveStatus::Deleted
+            } else {
+                RemoveStatus::NotFound
+            },
+        })
+        .collect();
+    Ok(format.respond(
+        HttpResponse::Ok().header(SEQ_HEADER, seq),
+        &RemovedKeys { results, seq },
+    ))
+}
+
+#[post("/{db_name}/{key}")]
+async fn store(
+    p_val: web::Path<PathVal>,
+    query: web::Query<StoreQuery>,
+    body: web::Payload,
+    req: HttpRequest,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let key = path_key(&db_man, &p_val.db_name, &p_val.key, encoding)?;
+    store_record(&p_val.db_name, key, &query, body, &req, &db_man).await
+}
+
+#[post("/{db_name}/_key")]
+async fn store_key(
+    db_name: web::Path<String>,
+    query: web::Query<StoreQuery>,
+    body: web::Payload,
+    req: HttpRequest,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let key = header_key(&req)?;
+    store_record(&db_name, key, &query, body, &req, &db_man).await
+}
+
+async fn store_record(
+    db_name: &str,
+    key: Vec<u8>,
+    query: &StoreQuery,
+    body: web::Payload,
+    req: &HttpRequest,
+    db_man: &DbManager,
+) -> Response<HttpResponse> {
+    let (value, repr) = read_value(body, req).await?;
+    let stored = db_man
+        .store(
+            db_name,
+            &key,
+            value,
+            req.calc_expire()?,
+            req.calc_soft_expire()?,
+            repr,
+            req.write_condition(query.mode)?,
+        )
+        .await?;
+    Ok(HttpResponse::Ok()
+        .header(http::header::ETAG, to_etag(stored.version))
+        .header(VERSION_HEADER, stored.version)
+        .header(SEQ_HEADER, stored.seq)
+        .finish())
+}
+
+// gzip and zstd bodies are stored compressed as they're sent, other content codings are decoded
+async fn read_value(
+    body: web::Payload,
+    req: &HttpRequest,
+) -> anyhow::Result<(Bytes, Representation)> {
+    let encoding = req
+        .headers()
+        .get(http::header::CONTENT_ENCODING)
+        .and_then(|h| h.to_str().ok())
+        .and_then(Codec::from_content_coding);
+    let value = match encoding {
+        Some(_) => read_body(body, MAX_VALUE_BYTES).await?,
+        None => {
+            read_body(
+                Decompress::from_headers(body, req.headers()),
+                MAX_VALUE_BYTES,
+            )
+            .await?
+        }
+    };
+    Ok((
+        Bytes::from(value),
+        Representation {
+            content_type: content_type(req),
+            encoding,
+        },
+    ))
+}
+
+// kept as sent, values stored without one are read back as application/octet-stream
+fn content_type(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .filter(|ct| !ct.is_empty())
+        .map(String::from)
+}
+
+// JSON Patch and merge patch bodies are applied to the stored document, anything else is appended to the value
+#[patch("/{db_name}/{key}")]
+async fn append(
+    p_val: web::Path<PathVal>,
+    body: Bytes,
+    req: HttpRequest,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let key = path_key(&db_man, &p_val.db_name, &p_val.key, encoding)?;
+    if let Some(format) = content_type(&req)
+        .as_deref()
+        .and_then(PatchFormat::from_content_type)
+    {
+        let stored = db_man
+            .patch(
+                p_val.db_name.as_str(),
+                &key,
+                format,
+                body,
+                req.write_condition(None)?,
+            )
+            .await?;
+        return Ok(HttpResponse::Ok()
+            .header(http::header::ETAG, to_etag(stored.version))
+            .header(VERSION_HEADER, stored.version)
+            .header(SEQ_HEADER, stored.seq)
+            .finish());
+    }
+
+    let seq = db_man.append(p_val.db_name.as_str(), &key, body).await?;
+    Ok(HttpResponse::Ok().header(SEQ_HEADER, seq).finish())
+}
+
+#[post("/{db_name}/{key}/_mpu")]
+async fn begin_upload(
+    format: Format,
+    p_val: web::Path<PathVal>,
+    req: HttpRequest,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let upload = db_man
+        .begin_upload(
+            p_val.db_name.as_str(),
+            &path_key(&db_man, &p_val.db_name, &p_val.key, encoding)?,
+            content_type(&req),
+        )
+        .await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &upload))
+}
+
+#[get("/{db_name}/{key}/_mpu/{upload_id}")]
+async fn upload_status(
+    format: Format,
+    path: web::Path<UploadPath>,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let upload = db_man
+        .upload(
+            path.db_name.as_str(),
+            &path_key(&db_man, &path.db_name, &path.key, encoding)?,
+            &path.upload_id,
+        )
+        .await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &upload))
+}
+
+// parts are streamed, the default payload limit is far below a part
+#[put("/{db_name}/{key}/_mpu/{upload_id}/parts/{number}")]
+async fn upload_part(
+    format: Format,
+    path: web::Path<PartPath>,
+    body: web::Payload,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let part = read_body(body, MAX_PART_BYTES).await?;
+    let part = db_man
+        .upload_part(
+            path.db_name.as_str(),
+            &path_key(&db_man, &path.db_name, &path.key, encoding)?,
+            &path.upload_id,
+            path.number,
+            Bytes::from(part),
+        )
+        .await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &part))
+}
+
+#[post("/{db_name}/{key}/_mpu/{upload_id}/complete")]
+async fn complete_upload(
+    path: web::Path<UploadPath>,
+    query: web::Query<StoreQuery>,
+    req: HttpRequest,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let stored = db_man
+        .complete_upload(
+            path.db_name.as_str(),
+            &path_key(&db_man, &path.db_name, &path.key, encoding)?,
+            &path.upload_id,
+            req.calc_expire()?,
+            req.calc_soft_expire()?,
+            req.write_condition(query.mode)?,
+        )
+        .await?;
+    Ok(HttpResponse::Ok()
+        .header(http::header::ETAG, to_etag(stored.version))
+        .header(VERSION_HEADER, stored.version)
+        .header(SEQ_HEADER, stored.seq)
+        .finish())
+}
+
+#[delete("/{db_name}/{key}/_mpu/{upload_id}")]
+async fn abort_upload(
+    path: web::Path<UploadPath>,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    db_man
+        .abort_upload(
+            path.db_name.as_str(),
+            &path_key(&db_man, &path.db_name, &path.key, encoding)?,
+            &path.upload_id,
+        )
+        .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/{db_name}/{key}/_touch")]
+async fn touch(
+    p_val: web::Path<PathVal>,
+    req: HttpRequest,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    if !req.headers().contains_key(TTL_HEADER) {
+        return Err(anyhow!(ErrorCtx::Validation(format!(
+            "Missing {} header",
+            TTL_HEADER
+        )))
+        .into());
+    }
+
+    let key = path_key(&db_man, &p_val.db_name, &p_val.key, encoding)?;
+    let seq = db_man
+        .touch(p_val.db_name.as_str(), &key, req.calc_expire()?)
+        .await?;
+    match seq {
+        Some(seq) => Ok(HttpResponse::Ok().header(SEQ_HEADER, seq).finish()),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+#[post("/{db_name}/{key}/_rename")]
+async fn rename(
+    p_val: web::Path<PathVal>,
+    query: web::Query<RenameQuery>,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    // the new key is sent in the same encoding
+    let from = path_key(&db_man, &p_val.db_name, &p_val.key, encoding)?;
+    let to = path_key(&db_man, &p_val.db_name, &query.to, encoding)?;
+    let stored = db_man.rename(p_val.db_name.as_str(), &from, &to).await?;
+    match stored {
+        Some(stored) => Ok(HttpResponse::Ok()
+            .header(http::header::ETAG, to_etag(stored.version))
+            .header(VERSION_HEADER, stored.version)
+            .header(SEQ_HEADER, stored.seq)
+            .finish()),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+#[post("/{db_name}/{key}/_undelete")]
+async fn undelete(
+    p_val: web::Path<PathVal>,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let key = path_key(&db_man, &p_val.db_name, &p_val.key, encoding)?;
+    let stored = db_man.undelete(p_val.db_name.as_str(), &key).await?;
+    match stored {
+        Some(stored) => Ok(HttpResponse::Ok()
+            .header(http::header::ETAG, to_etag(stored.version))
+            .header(VERSION_HEADER, stored.version)
+            .header(SEQ_HEADER, stored.seq)
+            .finish()),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+// server-sent events for changes of matching keys, the stream ends when the client falls too far behind
+#[get("/{db_name}/_watch")]
+async fn watch(
+    db_name: web::Path<String>,
+    query: web::Query<WatchQuery>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let changes = db_man
+        .watch(db_name.as_str(), query.prefix.as_deref())
+        .await?;
+    let events = futures::stream::unfold(changes, |mut changes| async move {
+        loop {
+            match timeout(WATCH_KEEPALIVE, changes.next()).await {
+                // refreshes are requests to reload the record, not changes
+                Ok(Some(event)) if event.kind == EventKind::Refresh => {}
+                Ok(Some(event)) => match event.to_sse() {
+                    Ok(sse) => return Some((Ok::<_, actix_web::Error>(Bytes::from(sse)), changes)),
+                    Err(e) => error!("Failed to serialize event = {:?}, e = {}", event, e),
+                },
+                Ok(None) => return None,
+                Err(_) => return Some((Ok(Bytes::from_static(b": keepalive\n\n")), changes)),
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type(EVENT_STREAM_TYPE)
+        .header(http::header::CACHE_CONTROL, "no-cache")
+        .streaming(events))
+}
+
+// entries logged after since, next is the since of the following request
+#[get("/{db_name}/_changes")]
+async fn changes(
+    format: Format,
+    db_name: web::Path<String>,
+    query: web::Query<ChangesQuery>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .min(MAX_PAGE_SIZE)
+        .max(1);
+    let page = db_man
+        .change_log(db_name.as_str(), query.since, limit)
+        .await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &page))
+}
+
+// subscriptions are sent over the socket, see ws.rs
+#[get("/ws/{db_name}")]
+async fn ws_watch(
+    db_name: web::Path<String>,
+    req: HttpRequest,
+    body: web::Payload,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let changes = db_man.watch(db_name.as_str(), None).await?;
+    Ok(
+        ws::start(WsSession::new(db_name.as_str(), changes), &req, body)
+            .map_err(|e| anyhow!(ErrorCtx::Validation(e.to_string())))?,
+    )
+}
+
+#[get("/{db_name}/{key}")]
+async fn read(
+    p_val: web::Path<PathVal>,
+    query: web::Query<WaitQuery>,
+    req: HttpRequest,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let key = path_key(&db_man, &p_val.db_name, &p_val.key, encoding)?;
+    read_record(&p_val.db_name, key, &query, &req, &db_man).await
+}
+
+#[get("/{db_name}/_key")]
+async fn read_key(
+    db_name: web::Path<String>,
+    query: web::Query<WaitQuery>,
+    req: HttpRequest,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    read_record(&db_name, header_key(&req)?, &query, &req, &db_man).await
+}
+
+async fn read_record(
+    db_name: &str,
+    key: Vec<u8>,
+    query: &WaitQuery,
+    req: &HttpRequest,
+    db_man: &DbManager,
+) -> Response<HttpResponse> {
+    let accepted = req
+        .headers()
+        .get(http::header::ACCEPT_ENCODING)
+        .and_then(|h| h.to_str().ok())
+        .map(Accepted::from_header)
+        .unwrap_or_default();
+    let res = match query.wait()? {
+        Some(wait) => {
+            // subscribe before reading so a change between the read and the wait isn't missed
+            // refreshes are requests to reload the record, not changes
+            let mut changes = db_man
+                .events
+                .subscribe(Filter::key(db_name, &key))
+                .filter(|e| future::ready(e.kind != EventKind::Refresh));
+            let current = db_man.read_stale(db_name, &key, &accepted).await?;
+            let version = current.as_ref().map_or(0, |(data, _)| data.version());
+            if version != query.if_version.unwrap_or(version) {
+                current
+            } else if timeout(wait, changes.next()).await.is_ok() {
+                db_man.read_stale(db_name, &key, &accepted).await?
+            } else {
+                return Ok(HttpResponse::NotModified().finish());
+            }
+        }
+        None => db_man.read_stale(db_name, &key, &accepted).await?,
+    };
+
+    let if_none_match = req.headers().get(http::header::IF_NONE_MATCH);
+    Ok(if let Some((data, stale)) = res {
+        if if_none_match.map_or(false, |h| etag_matches(h, data.version())) {
+            return Ok(
+                with_db_headers(&mut HttpResponse::NotModified(), db_man, db_name)
+                    .header(http::header::ETAG, to_etag(data.version()))
+                    .finish(),
+            );
+        }
+
+        let mut res = HttpResponse::Ok();
+        with_db_headers(&mut res, db_man, db_name);
+        match data.content_type() {
+            Some(content_type) => res.content_type(content_type),
+            None => res.set(ContentType::octet_stream()),
+        };
+        res.header(http::header::ETAG, to_etag(data.version()));
+        res.header(VERSION_HEADER, data.version());
+        if data.codec().is_some() {
+            res.header(http::header::VARY, "Accept-Encoding");
+        }
+        if let Some(coding) = data.content_encoding() {
+            res.header(http::header::CONTENT_ENCODING, coding);
+        }
+        if stale {
+            res.header(STALE_HEADER, "true");
+        }
+        if let Some(remaining) = data.remaining_ttl()? {
+            res.header(TTL_REMAINING_HEADER, remaining.to_string());
+        }
+        res.body(data.into_value())
+    } else {
+        let settings = db_man.db_cfg.settings(db_name);
+        match default_value(&settings, &key).await {
+            Some((value, source)) => with_db_headers(&mut HttpResponse::Ok(), db_man, db_name)
+                .set(ContentType::octet_stream())
+                .header(DEFAULT_VALUE_HEADER, source)
+                .body(value),
+            None => HttpResponse::NoContent().finish(),
+        }
+    })
+}
+
+// configured per db, tune caching of gateways in front of rocky
+fn with_db_headers<'a>(
+    res: &'a mut HttpResponseBuilder,
+    db_man: &DbManager,
+    db_name: &str,
+) -> &'a mut HttpResponseBuilder {
+    for (name, value) in db_man
+        .db_cfg
+        .response_headers(db_name)
+        .into_iter()
+        .flatten()
+    {
+        res.header(name.as_str(), value.as_str());
+    }
+    res
+}
+
+// upstream fallback is tried first, an unreachable upstream falls back to the configured default
+async fn default_value(settings: &DbSettings, key: &[u8]) -> Option<(Bytes, &'static str)> {
+    if let Some(url) = &settings.fallback_url {
+        let url = url.replace("{key}", &path_segment(key));
+        match fetch_upstream(&url).await {
+            Ok(Some(value)) => return Some((value, UPSTREAM_DEFAULT)),
+            Ok(None) => {}
+            Err(e) => warn!("Upstream fallback {} failed, e = {}", url, e),
+        }
+    }
+
+    settings
+        .default_value
+        .as_ref()
+        .map(|v| (Bytes::from(v.clone()), CONFIGURED_DEFAULT))
+}
+
+async fn fetch_upstream(url: &str) -> anyhow::Result<Option<Bytes>> {
+    let mut res = Client::default()
+        .get(url)
+        .timeout(UPSTREAM_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| anyhow!("{}", e))?;
+
+    if res.status() == http::StatusCode::OK {
+        let body = res
+            .body()
+            .limit(UPSTREAM_MAX_BYTES)
+            .await
+            .map_err(|e| anyhow!("{}", e))?;
+        Ok(Some(body))
+    } else {
+        Ok(None)
+    }
+}
+
+#[head("/{db_name}/{key}")]
+async fn head_record(
+    p_val: web::Path<PathVal>,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let key = path_key(&db_man, &p_val.db_name, &p_val.key, encoding)?;
+    head(&p_val.db_name, key, &db_man).await
+}
+
+#[head("/{db_name}/_key")]
+async fn head_key(
+    db_name: web::Path<String>,
+    req: HttpRequest,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    head(&db_name, header_key(&req)?, &db_man).await
+}
+
+async fn head(db_name: &str, key: Vec<u8>, db_man: &DbManager) -> Response<HttpResponse> {
+    let res = db_man.read(db_name, &key).await?;
+
+    Ok(if let Some(data) = res {
+        let mut res = HttpResponse::Ok();
+        with_db_headers(&mut res, db_man, db_name);
+        if let Some(content_type) = data.content_type() {
+            res.content_type(content_type);
+        }
+        res.header(VALUE_SIZE_HEADER, data.size());
+        res.header(http::header::ETAG, to_etag(data.version()));
+        res.header(VERSION_HEADER, data.version());
+        if let Some(remaining) = data.remaining_ttl()? {
+            res.header(EXPIRES_AT_HEADER, data.ttl().to_string());
+            res.header(TTL_REMAINING_HEADER, remaining.to_string());
+        }
+        if data.soft_ttl() != NO_TTL {
+            res.header(SOFT_EXPIRES_AT_HEADER, data.soft_ttl().to_string());
+        }
+        let stats = db_man.access_stats(db_name, &key).await?;
+        if let Some(stats) = stats {
+            res.header(READS_HEADER, stats.reads.to_string());
+            res.header(WRITES_HEADER, stats.writes.to_string());
+            res.header(LAST_ACCESS_HEADER, stats.last_access.to_string());
+        }
+        res.finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    })
+}
+
+#[delete("/{db_name}/{key}")]
+async fn remove(
+    p_val: web::Path<PathVal>,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let seq = db_man
+        .remove(
+            p_val.db_name.as_str(),
+            &path_key(&db_man, &p_val.db_name, &p_val.key, encoding)?,
+        )
+        .await?;
+    Ok(HttpResponse::Ok().header(SEQ_HEADER, seq).finish())
+}
+
+#[delete("/{db_name}/_key")]
+async fn remove_key(
+    db_name: web::Path<String>,
+    req: HttpRequest,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let seq = db_man.remove(&db_name, &header_key(&req)?).await?;
+    Ok(HttpResponse::Ok().header(SEQ_HEADER, seq).finish())
+}
+
+#[get("/{db_name}/_hotkeys")]
+async fn hot_keys(
+    format: Format,
+    db_name: web::Path<String>,
+    query: web::Query<HotKeysQuery>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let stats = db_man.hot_keys(db_name.as_str(), limit, query.cold).await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &stats))
+}
+
+#[post("/{db_name}/_crdt/{key}")]
+async fn update_crdt(
+    format: Format,
+    p_val: web::Path<PathVal>,
+    body: Bytes,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let key = path_key(&db_man, &p_val.db_name, &p_val.key, encoding)?;
+    let op = CrdtOp::from_json(&body)?;
+    let value = db_man.update_crdt(p_val.db_name.as_str(), &key, op).await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &value))
+}
+
+#[get("/{db_name}/_crdt/{key}")]
+async fn crdt_value(
+    format: Format,
+    p_val: web::Path<PathVal>,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let key = path_key(&db_man, &p_val.db_name, &p_val.key, encoding)?;
+    match db_man.crdt(p_val.db_name.as_str(), &key).await? {
+        Some(crdt) => Ok(format.respond(&mut HttpResponse::Ok(), &crdt.value())),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+#[get("/{db_name}/_crdt/{key}/_state")]
+async fn crdt_state(
+    format: Format,
+    p_val: web::Path<PathVal>,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let key = path_key(&db_man, &p_val.db_name, &p_val.key, encoding)?;
+    match db_man.crdt(p_val.db_name.as_str(), &key).await? {
+        Some(crdt) => Ok(format.respond(&mut HttpResponse::Ok(), &crdt.state())),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+// state of the key on another replica
+#[post("/{db_name}/_crdt/{key}/_merge")]
+async fn merge_crdt(
+    format: Format,
+    p_val: web::Path<PathVal>,
+    body: Bytes,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let key = path_key(&db_man, &p_val.db_name, &p_val.key, encoding)?;
+    let state = CrdtState::from_json(&body)?;
+    let value = db_man
+        .merge_crdt(p_val.db_name.as_str(), &key, state)
+        .await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &value))
+}
+
+#[delete("/{db_name}/_range")]
+async fn remove_range(
+    db_name: web::Path<String>,
+    query: web::Query<RangeQuery>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let seq = db_man
+        .remove_range(db_name.as_str(), &query.start, &query.end)
+        .await?;
+    Ok(HttpResponse::Ok().header(SEQ_HEADER, seq).finish())
+}
+
+#[delete("/{db_name}/_prefix/{prefix:.+}")]
+async fn remove_prefix(
+    path: web::Path<(String, String)>,
+    encoding: KeyEncoding,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let (db_name, prefix) = path.into_inner();
+    let seq = db_man
+        .remove_prefix(&db_name, &encoding.decode(&prefix)?)
+        .await?;
+    Ok(HttpResponse::Ok().header(SEQ_HEADER, seq).finish())
+}
+
+#[get("/{db_name}/_seq")]
+async fn seq(
+    format: Format,
+    db_name: web::Path<String>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let seq = db_man.seq(db_name.as_str()).await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &SeqInfo { seq }))
+}
+
+#[get("/{db_name}/_forecast")]
+async fn forecast(
+    format: Format,
+    db_name: web::Path<String>,
+    query: web::Query<ForecastQuery>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let days = query
+        .days
+        .unwrap_or(DEFAULT_FORECAST_DAYS)
+        .min(MAX_FORECAST_DAYS);
+    let forecast = db_man.forecast(db_name.as_str(), days).await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &forecast))
+}
+
+#[get("/{db_name}/_stats")]
+async fn db_stats(
+    format: Format,
+    db_name: web::Path<String>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let stats = db_man.compression_stats(db_name.as_str()).await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &stats))
+}
+
+#[get("/{db_name}/_quota")]
+async fn db_quota(
+    format: Format,
+    db_name: web::Path<String>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let quota = db_man.quota(db_name.as_str()).await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &quota))
+}
+
+#[get("/{db_name}/_internals")]
+async fn internals(
+    format: Format,
+    db_name: web::Path<String>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let internals = db_man.internals(db_name.as_str()).await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &internals))
+}
+
+#[get("/{db_name}/_debug/live_files")]
+async fn live_files(
+    format: Format,
+    _auth: Authorized,
+    db_name: web::Path<String>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let files = db_man.live_files(db_name.as_str()).await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &files))
+}
+
+#[get("/{db_name}/_property/{name}")]
+async fn property(
+    _auth: Authorized,
+    path: web::Path<(String, String)>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let (db_name, name) = path.into_inner();
+    match db_man.property(&db_name, &name).await? {
+        Some(value) => Ok(HttpResponse::Ok().set(ContentType::plaintext()).body(value)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+#[post("/{db_name}/_backup")]
+async fn create_backup(
+    format: Format,
+    db_name: web::Path<String>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let backups = db_man.backup(db_name.as_str()).await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &backups))
+}
+
+#[get("/{db_name}/_backup")]
+async fn list_backups(
+    format: Format,
+    db_name: web::Path<String>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let backups = db_man.backups(db_name.as_str()).await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &backups))
+}
+
+#[post("/{db_name}/_checkpoint")]
+async fn checkpoint(
+    format: Format,
+    db_name: web::Path<String>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let checkpoint = db_man.checkpoint(db_name.as_str()).await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &checkpoint))
+}
+
+// Range: bytes={offset}- with If-Range: {etag} resumes a download, a resume of an export that's gone gets all of a new one
+#[get("/{db_name}/_export")]
+async fn export(
+    db_name: web::Path<String>,
+    req: HttpRequest,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let resume = export_resume(&req)?;
+    let export = db_man.export(db_name.as_str(), resume).await?;
+
+    let mut res = if export.offset > 0 {
+        let mut res = HttpResponse::PartialContent();
+        res.header(
+            http::header::CONTENT_RANGE,
+            format!(
+                "bytes {}-{}/{}",
+                export.offset,
+                export.size - 1,
+                export.size
+            ),
+        );
+        res
+    } else {
+        HttpResponse::Ok()
+    };
+    let len = export.size - export.offset;
+    let operation = db_man.start_operation(OperationKind::Export, &db_name);
+    res.content_type(TAR_CONTENT_TYPE)
+        .header(http::header::ETAG, export_etag(&export.id))
+        .header(http::header::ACCEPT_RANGES, "bytes")
+        .header(
+            http::header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.tar\"", db_name.as_str()),
+        );
+    // files are read on the blocking pool, a read error ends the stream
+    let reads = futures::stream::unfold(Some(export.stream()), |stream| async move {
+        let mut stream = stream?;
+        match web::block(move || Ok::<_, io::Error>((stream.next(), stream))).await {
+            Ok((Some(Ok(chunk)), stream)) => Some((Ok(chunk), Some(stream))),
+            Ok((Some(Err(e)), _)) => Some((Err(e), None)),
+            Ok((None, _)) => None,
+            Err(e) => Some((
+                Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+                None,
+            )),
+        }
+    });
+    // a cancelled export cuts the connection, the client can resume it
+    let chunks = reads.map(move |chunk| {
+        if operation.is_cancelled() {
+            Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Export was cancelled",
+            ))
+        } else {
+            chunk
+        }
+        .map_err(actix_web::Error::from)
+    });
+    Ok(res.body(SizedStream::new(len, Box::pin(chunks))))
+}
+
+// a Range needs the If-Range of the export it continues, otherwise it's unknown what the offset is into
+fn export_resume(req: &HttpRequest) -> anyhow::Result<Option<(&str, u64)>> {
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .map(|v| v.to_str().unwrap_or_default())
+    };
+    let range = match header(http::header::RANGE) {
+        Some(range) => range,
+        None => return Ok(None),
+    };
+    let offset = parse_range(range).ok_or_else(|| {
+        anyhow!(ErrorCtx::Validation(format!(
+            "Unsupported range {}, exports resume from bytes={{offset}}-",
+            range
+        )))
+    })?;
+    match header(http::header::IF_RANGE).and_then(parse_export_etag) {
+        Some(id) => Ok(Some((id, offset))),
+        None => Err(anyhow!(ErrorCtx::Validation(
+            "Resuming an export needs If-Range with the ETag of the export".into()
+        ))),
+    }
+}
+
+// the body is a single SST file or a tar archive of them (application/x-tar), staged on disk first
+#[post("/{db_name}/_import")]
+async fn import(
+    db_name: web::Path<String>,
+    req: HttpRequest,
+    body: web::Payload,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let staging = db_man.import_staging(db_name.as_str())?;
+    let upload = staging.path().join(".upload");
+    stage_upload(body, upload.clone(), MAX_IMPORT_BYTES).await?;
+
+    let files = if req.content_type() == TAR_CONTENT_TYPE {
+        let dir = staging.path().to_path_buf();
+        on_blocking_pool(move || unpack(&upload, &dir))
+            .await?
+            .into_iter()
+            .filter(|f| f.extension().map_or(false, |ext| ext == "sst"))
+            .collect()
+    } else {
+        vec![upload]
+    };
+    let seq = db_man.import(db_name.as_str(), files).await?;
+    Ok(HttpResponse::Ok().header(SEQ_HEADER, seq).finish())
+}
+
+// chunks are written on the blocking pool, a body over the limit is refused
+async fn stage_upload(mut body: web::Payload, path: PathBuf, limit: u64) -> anyhow::Result<()> {
+    let mut file = on_blocking_pool(move || Ok(File::create(path)?)).await?;
+    let mut staged = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        staged += chunk.len() as u64;
+        if staged > limit {
+            return Err(anyhow!(ErrorCtx::PayloadTooLarge(format!(
+                "Import exceeds {} bytes",
+                limit
+            ))));
+        }
+        file = on_blocking_pool(move || {
+            file.write_all(&chunk)?;
+            Ok(file)
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+async fn on_blocking_pool<F, T>(f: F) -> anyhow::Result<T>
+where
+    F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    match web::block(f).await {
+        Ok(value) => Ok(value),
+        Err(BlockingError::Error(e)) => Err(e),
+        Err(BlockingError::Canceled) => Err(anyhow!("Blocking task was canceled")),
+    }
+}
+
+// restores the latest backup when backup_id is omitted - it waits for requests in flight and copies files, so it
+// runs on the blocking pool
+#[post("/{db_name}/_restore")]
+async fn restore(
+    db_name: web::Path<String>,
+    query: web::Query<RestoreQuery>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let backup_id = query.backup_id;
+    let restored = web::block(move || db_man.restore(db_name.as_str(), backup_id)).await;
+    match restored {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(BlockingError::Error(e)) => Err(e.into()),
+        Err(BlockingError::Canceled) => Err(anyhow!("Restore was canceled").into()),
+    }
+}
+
+#[get("/{db_name}/_debug/manifest")]
+async fn manifest(
+    format: Format,
+    _auth: Authorized,
+    db_name: web::Path<String>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let manifest = db_man.manifest(db_name.as_str()).await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &manifest))
+}
+
+#[get("/{db_name}/_debug/approximate_size")]
+async fn approximate_size(
+    format: Format,
+    _auth: Authorized,
+    db_name: web::Path<String>,
+    query: web::Query<RangeQuery>,
+    db_man: web::Data<DbManager>,
+) -> Response<HttpResponse> {
+    let size = db_man
+        .approximate_size(db_name.as_str(), &query.start, &query.end)
+        .await?;
+    Ok(format.respond(&mut HttpResponse::Ok(), &size))
+}
+
+// liveness, kept for probes configured before the split
+#[get("/health")]
+async fn health() -> HttpResponse {
+    HttpResponse::Ok().finish()
+}
+
+#[get("/health/live")]
+async fn health_live() -> HttpResponse {
+    HttpResponse::Ok().finish()
+}
+
+#[get("/health/ready")]
+async fn health_ready(db_man: web::Data<DbManager>) -> Response<HttpResponse> {
+    let problems = db_man.readiness();
+    if problems.is_empty() {
+        Ok(HttpResponse::Ok().finish())
+    } else {
+        Err(anyhow!(ErrorCtx::Unavailable(format!(
+            "Not ready - {}",
+            problems.join(", ")
+        ))))
+    }
+}
+
+// config files aren't created with defaults here, a missing file is reported instead
+fn check_config(cfg_path: &str) -> anyhow::Result<()> {
+    let mut problems = vec![];
+    for file in &["service_config.toml", "db_config.toml"] {
+        if !Path::new(&format!("{}/{}", cfg_path, file)).exists() {
+            problems.push(format!("Missing {} under {}", file, cfg_path));
+        }
+    }
+    if problems.is_empty() {
+        match load_service_config(cfg_path) {
+            Ok(cfg) => problems.extend(cfg.validate()),
+            Err(e) => problems.push(format!("Invalid service_config.toml - {}", e)),
+        }
+        match load_db_config(cfg_path) {
+            Ok(cfg) => problems.extend(cfg.validate()),
+            Err(e) => problems.push(format!("Invalid db_config.toml - {}", e)),
+        }
+    }
+
+    if problems.is_empty() {
+        eprintln!("Config under {} is valid", cfg_path);
+    }
+    report_problems(&problems)
+}
+
+// printed as they are, the logger may not be initialized yet
+fn report_problems(problems: &[String]) -> anyhow::Result<()> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+    for p in problems {
+        eprintln!("{}", p);
+    }
+    Err(anyhow!("Found {} config problem(s)", problems.len()))
+}
+
+fn migrate(db_manager: &DbManager) -> anyhow::Result<()> {
+    for (db_name, migrated) in db_manager.migrate()? {
+        info!("Migrated {} records of db = {}", migrated, &db_name);
+        eprintln!("{}\t{}", db_name, migrated);
+    }
+    Ok(())
+}
+
+// main thread will panic! if config can't be initialized
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
     std::env::set_var("RUST_LOG", "actix_web=error");
     std::env::set_var("RUST_BACKTRACE", "1");
 
-    let path_cfg = PathCfg::from_args();
+    let mut path_cfg = PathCfg::from_args();
+    if let Some(cmd) = path_cfg.cmd.take() {
+        let db_cfg = load_db_config(&path_cfg.config_path).expect("Can't load db config");
+        return cli::run(cmd, db_cfg).await;
+    }
+    if path_cfg.check_config {
+        return check_config(&path_cfg.config_path);
+    }
+
     let service_cfg =
         load_service_config(&path_cfg.config_path).expect("Can't load service config");
+    let db_cfg = load_db_config(&path_cfg.config_path).expect("Can't load service config");
+    // the checks of --check-config, before any db is opened - a bad setting would fail every request it applies to
+    let mut problems = service_cfg.validate();
+    problems.extend(db_cfg.validate());
+    report_problems(&problems)?;
+    // before the logger, it can't start on an unwritable log path either
+    let mut preflight = service_cfg.preflight();
+    preflight.paths.extend(db_cfg.data_paths());
+    preflight
+        .paths
+        .push(("config", path_cfg.config_path.clone()));
+    if !service_cfg.dev_mode() {
+        preflight.paths.push(("log", path_cfg.log_path.clone()));
+    }
+    let notes = preflight.run()?;
+
     init_logger(&path_cfg.log_path, service_cfg.dev_mode());
     info!("Running with path configuration = {:#?}", path_cfg);
     info!("Loaded service configuration = {:#?}", &service_cfg);
-
-    let db_cfg = load_db_config(&path_cfg.config_path).expect("Can't load service config");
     info!("Loaded db configuration = {:#?}", &db_cfg);
+    notes.iter().for_each(|note| info!("{}", note));
+    let _tracing = service_cfg
+        .otlp_endpoint()
+        .map(telemetry::init)
+        .transpose()?;
 
     let db_manager = DbManager::new(db_cfg)?;
+    if path_cfg.migrate_and_exit {
+        return migrate(&db_manager);
+    }
+    let schedule = Schedule::parse(service_cfg.maintenance_windows())?;
+    info!("Maintenance schedule = {:?}", &schedule);
+    db_manager.set_maintenance(schedule);
     let db_manager = web::Data::new(db_manager);
+    if let Some(address) = service_cfg.redis_address() {
+        resp::listen(
+            &address,
+            db_manager.clone().into_inner(),
+            service_cfg.redis_limits(),
+        )?;
+    }
+    if let Some(address) = service_cfg.grpc_address() {
+        grpc::listen(&address, db_manager.clone().into_inner())?;
+    }
+    let graphql_schema = if service_cfg.graphql() {
+        Some(graphql::schema(db_manager.clone().into_inner()))
+    } else {
+        None
+    };
+    let admin_token = web::Data::new(AdminToken::new(service_cfg.admin_token()));
+    let signing_key = web::Data::new(SigningKey::new(service_cfg.erasure_signing_key()));
 
-    let prometheus = init_prometheus();
-    HttpServer::new(move || {
+    let prometheus = init_prometheus()?;
+    // the handlers extract web::Data<Registry>, it has to be the registry type of actix-web-prom
+    let registry: web::Data<Registry> = web::Data::new(prometheus.registry.clone());
+    let metrics_auth = web::Data::new(MetricsAuth::new(service_cfg.metrics_auth()));
+    let test_clock = service_cfg.test_clock();
+    // next to the application log, on stdout with it in dev mode
+    let access_log = match service_cfg.access_log()? {
+        Some(log) if !service_cfg.dev_mode() => Some(log.to_file(&path_cfg.log_path)?),
+        log => log,
+    }
+    .map(Arc::new);
+    let tls = service_cfg.tls()?;
+    let server = HttpServer::new(move || {
+        let access_log = access_log.clone();
         App::new()
             .wrap(ErrorHandlers::new().handler(http::StatusCode::NOT_FOUND, not_found))
+            .wrap_fn(negotiated)
+            .wrap_fn(timed)
+            .wrap_fn(traced)
             .wrap(prometheus.clone())
+            .wrap_fn(move |req, srv| {
+                let access_log = access_log.clone();
+                let entry = access_log.as_ref().map(|log| log.start(&req));
+                srv.call(req).map(move |res| {
+                    if let (Some(log), Some(entry), Ok(res)) = (&access_log, entry, &res) {
+                        log.finish(entry, res);
+                    }
+                    res
+                })
+            })
             .app_data(db_manager.clone())
-            .service(open)
-            .service(close)
-            .service(exists)
-            .service(store)
-            .service(read)
-            .service(remove)
-            .service(health)
+            .app_data(admin_token.clone())
+            .app_data(signing_key.clone())
+            .app_data(registry.clone())
+            .app_data(metrics_auth.clone())
+            .service(prometheus_metrics)
+            .service(metrics_endpoint)
+            .service(json_metrics)
+            .configure(|cfg| test_routes(cfg, test_clock))
+            .service(admin_ui)
+            .configure(|cfg| graphql_routes(cfg, graphql_schema.clone()))
+            .configure(versioned_routes)
+    });
+    let server = server
+        .keep_alive(service_cfg.keep_alive())
+        .client_timeout(service_cfg.client_timeout())
+        .max_connections(service_cfg.max_connections())
+        .backlog(service_cfg.backlog());
+    // the backlog is applied when binding
+    let server = match tls {
+        Some(tls) => {
+            info!("Serving HTTPS on {}", service_cfg.bind_address());
+            server.bind_rustls(service_cfg.bind_address(), tls)?
+        }
+        None => server.bind(service_cfg.bind_address())?,
+    };
+    server
+        .workers(service_cfg.workers())
+        .shutdown_timeout(60)
+        .run()
+        .await
+        .map_err(|e| anyhow!("Startup failed {}", e))
+}
+
+// every api route, under /v1 and unversioned for clients that haven't moved over yet
+fn versioned_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope(API_PREFIX)
+            .wrap_fn(with_deprecation)
+            .configure(api_routes),
+    )
+    .service(
+        web::scope("")
+            .wrap_fn(with_deprecation)
+            .configure(api_routes),
+    );
+}
+
+fn api_routes(cfg: &mut web::ServiceConfig) {
+    // before the record routes they'd match as db health
+    cfg.service(health_live)
+        .service(health_ready)
+        .service(list_dbs)
+        .service(deletions)
+        .service(list_outbound)
+        .service(list_operations)
+        .service(cancel_operation)
+        .service(compact)
+        .service(erase)
+        .service(erasure)
+        .service(snapshot_read)
+        .service(unmount_snapshot)
+        .service(open)
+        .service(close)
+        .service(exists)
+        .service(keys)
+        .service(query)
+        .service(count)
+        .service(scan)
+        .service(multipart_batch)
+        .service(batch)
+        .service(txn)
+        .service(replicate)
+        .service(begin_txn)
+        .service(commit_txn)
+        .service(rollback_txn)
+        .service(txn_read)
+        .service(txn_store)
+        .service(txn_remove)
+        .service(json_multi_get)
+        .service(multi_get)
+        .service(multi_delete)
+        .service(hot_keys)
+        .service(update_crdt)
+        .service(crdt_value)
+        .service(crdt_state)
+        .service(merge_crdt)
+        .service(internals)
+        .service(db_stats)
+        .service(db_quota)
+        .service(live_files)
+        .service(manifest)
+        .service(property)
+        .service(create_backup)
+        .service(list_backups)
+        .service(restore)
+        .service(checkpoint)
+        .service(export)
+        .service(import)
+        .service(seq)
+        .service(forecast)
+        .service(watch)
+        .service(ws_watch)
+        .service(changes)
+        .service(approximate_size)
+        .service(remove_range)
+        .service(remove_prefix)
+        .service(store_key)
+        .service(read_key)
+        .service(head_key)
+        .service(remove_key)
+        .service(store)
+        .service(append)
+        .service(begin_upload)
+        .service(upload_status)
+        .service(upload_part)
+        .service(complete_upload)
+        .service(abort_upload)
+        .service(touch)
+        .service(rename)
+        .service(undelete)
+        .service(read)
+        .service(head_record)
+        .service(remove)
+        .service(health);
+}
+
+fn with_deprecation<S>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+{
+    let deprecation =
+        versioning::deprecation_headers(req.path(), req.match_pattern().as_deref(), DEPRECATED);
+    srv.call(req).map(move |res| {
+        res.map(|mut res| {
+            versioning::apply(res.headers_mut(), deprecation);
+            res
+        })
     })
-    .bind(service_cfg.bind_address())?
-    .workers(service_cfg.workers())
-    .shutdown_timeout(60)
-    .run()
-    .await
-    .map_err(|e| anyhow!("Startup failed {}", e))
 }
 
-fn init_prometheus() -> PrometheusMetrics {
-    PrometheusMetrics::new("api", Some("/rocky/prometheus/metrics"), None)
+// metrics are served by our own handlers so they can be guarded and negotiated
+fn init_prometheus() -> anyhow::Result<PrometheusMetrics> {
+    let prometheus = PrometheusMetrics::new("api", None, None);
+    metrics::register(&prometheus.registry)?;
+    Ok(prometheus)
 }
 
 fn init_logger(log_path: &str, dev_mode: bool) {
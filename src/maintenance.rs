@@ -0,0 +1,120 @@
+use anyhow::anyhow;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+const MS_PER_MINUTE: u128 = 60 * 1000;
+
+// daily UTC window in minutes since midnight, end is exclusive and may wrap past midnight
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Window {
+    start: u32,
+    end: u32,
+}
+
+// windows during which heavy background jobs may run, no windows means always
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Schedule {
+    windows: Vec<Window>,
+}
+
+impl Window {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            minute >= self.start && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl Schedule {
+    // windows are formatted as "HH:MM-HH:MM" in UTC, e.g. "22:30-04:00"
+    pub fn parse(windows: &[String]) -> anyhow::Result<Self> {
+        let windows = windows
+            .iter()
+            .map(|w| parse_window(w.as_str()))
+            .collect::<anyhow::Result<Vec<Window>>>()?;
+        Ok(Schedule { windows })
+    }
+
+    pub fn is_open(&self, now_ms: u128) -> bool {
+        let minute = minute_of_day(now_ms);
+        self.windows.is_empty() || self.windows.iter().any(|w| w.contains(minute))
+    }
+}
+
+fn parse_window(window: &str) -> anyhow::Result<Window> {
+    let invalid = || {
+        anyhow!(
+            "Invalid maintenance window {} - expected HH:MM-HH:MM",
+            window
+        )
+    };
+    let mut parts = window.trim().splitn(2, '-');
+    // 24:00 is accepted as the end of the day
+    let start = parts.next().ok_or_else(invalid).and_then(parse_minute)? % MINUTES_PER_DAY;
+    let end = parts.next().ok_or_else(invalid).and_then(parse_minute)?;
+    if start == end {
+        return Err(invalid());
+    }
+
+    Ok(Window { start, end })
+}
+
+fn parse_minute(time: &str) -> anyhow::Result<u32> {
+    let invalid = || anyhow!("Invalid time of day {}", time);
+    let mut parts = time.trim().splitn(2, ':');
+    let hours: u32 = parts
+        .next()
+        .and_then(|h| h.parse().ok())
+        .ok_or_else(invalid)?;
+    let minutes: u32 = parts
+        .next()
+        .and_then(|m| m.parse().ok())
+        .ok_or_else(invalid)?;
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+        return Err(invalid());
+    }
+
+    Ok(hours * 60 + minutes)
+}
+
+fn minute_of_day(now_ms: u128) -> u32 {
+    ((now_ms / MS_PER_MINUTE) % u128::from(MINUTES_PER_DAY)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hours: u128, minutes: u128) -> u128 {
+        // some day at midnight UTC
+        let midnight = 18_000 * 24 * 60 * MS_PER_MINUTE;
+        midnight + (hours * 60 + minutes) * MS_PER_MINUTE
+    }
+
+    #[test]
+    fn should_always_be_open_without_windows() {
+        let schedule = Schedule::parse(&[]).unwrap();
+        assert!(schedule.is_open(at(12, 0)));
+    }
+
+    #[test]
+    fn should_open_within_windows() {
+        let schedule = Schedule::parse(&["01:00-05:00".into(), "22:30-00:30".into()]).unwrap();
+        assert!(schedule.is_open(at(1, 0)));
+        assert!(schedule.is_open(at(4, 59)));
+        assert!(!schedule.is_open(at(5, 0)));
+        assert!(schedule.is_open(at(23, 0)));
+        assert!(schedule.is_open(at(0, 15)));
+        assert!(!schedule.is_open(at(12, 0)));
+    }
+
+    #[test]
+    fn should_reject_invalid_windows() {
+        assert!(Schedule::parse(&["01:00".into()]).is_err());
+        assert!(Schedule::parse(&["25:00-01:00".into()]).is_err());
+        assert!(Schedule::parse(&["01:00-01:00".into()]).is_err());
+        assert!(Schedule::parse(&["1am-5am".into()]).is_err());
+        assert!(Schedule::parse(&["00:00-24:00".into()]).is_ok());
+    }
+}
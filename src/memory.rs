@@ -0,0 +1,59 @@
+use std::fs;
+
+use anyhow::anyhow;
+
+const PROC_STATUS: &str = "/proc/self/status";
+const SOFT_LIMIT_RATIO: f64 = 0.9;
+const RECOVERED_RATIO: f64 = 0.7;
+
+#[derive(Debug, PartialEq)]
+pub enum Pressure {
+    High,
+    Normal,
+    Recovered,
+}
+
+// between recovered and soft limit ratio nothing changes to avoid flapping
+pub fn pressure(rss: u64, limit: u64) -> Pressure {
+    let used = rss as f64 / limit as f64;
+    if used >= SOFT_LIMIT_RATIO {
+        Pressure::High
+    } else if used <= RECOVERED_RATIO {
+        Pressure::Recovered
+    } else {
+        Pressure::Normal
+    }
+}
+
+pub fn rss_bytes() -> anyhow::Result<u64> {
+    parse_vm_rss(&fs::read_to_string(PROC_STATUS)?)
+}
+
+fn parse_vm_rss(status: &str) -> anyhow::Result<u64> {
+    status
+        .lines()
+        .find(|line| line.starts_with("VmRSS:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+        .ok_or_else(|| anyhow!("Can't read VmRSS from {}", PROC_STATUS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_vm_rss() {
+        let status = "Name:\trocky\nVmPeak:\t  200 kB\nVmRSS:\t  128 kB\nThreads:\t8\n";
+        assert_eq!(128 * 1024, parse_vm_rss(status).unwrap());
+        assert!(parse_vm_rss("Name:\trocky\n").is_err());
+    }
+
+    #[test]
+    fn should_detect_pressure() {
+        assert_eq!(Pressure::High, pressure(95, 100));
+        assert_eq!(Pressure::Normal, pressure(80, 100));
+        assert_eq!(Pressure::Recovered, pressure(50, 100));
+    }
+}
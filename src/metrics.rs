@@ -0,0 +1,309 @@
+use lazy_static::lazy_static;
+use prometheus::proto::{MetricFamily, MetricType};
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+};
+use serde_json::{json, Map, Value};
+
+lazy_static! {
+    pub static ref PROCESS_RSS: IntGauge = IntGauge::new(
+        "rocky_process_rss_bytes",
+        "Resident set size of the process"
+    )
+    .expect("Can't create metric");
+    pub static ref MEMORY_PRESSURE_ACTIONS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "rocky_memory_pressure_actions_total",
+            "Actions taken when process memory nears the configured limit"
+        ),
+        &["action"]
+    )
+    .expect("Can't create metric");
+    pub static ref SWEPT_KEYS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "rocky_swept_keys_total",
+            "Keys removed by the sweeper because they expired, were evicted or their tombstone was purged"
+        ),
+        &["reason"]
+    )
+    .expect("Can't create metric");
+    pub static ref WEBHOOK_DELIVERIES: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "rocky_webhook_deliveries_total",
+            "Expiration webhook notifications by outcome"
+        ),
+        &["result"]
+    )
+    .expect("Can't create metric");
+    pub static ref REPLICATION_LAG: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "rocky_replication_lag_ms",
+            "Age of the oldest change in the last batch shipped to the remote deployment"
+        ),
+        &["db"]
+    )
+    .expect("Can't create metric");
+    pub static ref SCAN_CACHE_LOOKUPS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "rocky_scan_cache_lookups_total",
+            "Lookups of cached _keys, _scan and _count results by outcome"
+        ),
+        &["result"]
+    )
+    .expect("Can't create metric");
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "rocky_http_request_duration_seconds",
+            "Latency of HTTP requests by route pattern, method and status"
+        ),
+        &["endpoint", "method", "status"]
+    )
+    .expect("Can't create metric");
+    pub static ref KEY_OPERATIONS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "rocky_key_operations_total",
+            "Record reads, writes and deletes across all protocols"
+        ),
+        &["op"]
+    )
+    .expect("Can't create metric");
+    pub static ref EXPIRED_READS: IntCounter = IntCounter::new(
+        "rocky_expired_reads_total",
+        "Reads that found an expired record and removed it"
+    )
+    .expect("Can't create metric");
+    pub static ref READ_DECOMPRESSIONS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "rocky_read_decompressions_total",
+            "Values decompressed for reads, inline on the worker or on the blocking pool"
+        ),
+        &["pool"]
+    )
+    .expect("Can't create metric");
+    pub static ref OPEN_DBS: IntGauge =
+        IntGauge::new("rocky_open_dbs", "Open databases").expect("Can't create metric");
+}
+
+pub fn register(registry: &Registry) -> anyhow::Result<()> {
+    registry.register(Box::new(PROCESS_RSS.clone()))?;
+    registry.register(Box::new(MEMORY_PRESSURE_ACTIONS.clone()))?;
+    registry.register(Box::new(SWEPT_KEYS.clone()))?;
+    registry.register(Box::new(WEBHOOK_DELIVERIES.clone()))?;
+    registry.register(Box::new(REPLICATION_LAG.clone()))?;
+    registry.register(Box::new(SCAN_CACHE_LOOKUPS.clone()))?;
+    registry.register(Box::new(HTTP_REQUEST_DURATION.clone()))?;
+    registry.register(Box::new(KEY_OPERATIONS.clone()))?;
+    registry.register(Box::new(EXPIRED_READS.clone()))?;
+    registry.register(Box::new(READ_DECOMPRESSIONS.clone()))?;
+    registry.register(Box::new(OPEN_DBS.clone()))?;
+    Ok(())
+}
+
+// OpenMetrics 1.0 text format - counter families drop the _total suffix which moves to the sample
+pub fn render_openmetrics(families: &[MetricFamily]) -> String {
+    let mut out = String::new();
+    for family in families {
+        let name = match family.get_field_type() {
+            MetricType::COUNTER => family.get_name().trim_end_matches("_total"),
+            _ => family.get_name(),
+        };
+        out.push_str(&format!("# TYPE {} {}\n", name, type_name(family)));
+        out.push_str(&format!("# HELP {} {}\n", name, escape(family.get_help())));
+        for metric in family.get_metric() {
+            let labels: Vec<(String, String)> = metric
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                .collect();
+            match family.get_field_type() {
+                MetricType::COUNTER => sample(
+                    &mut out,
+                    name,
+                    "_total",
+                    &labels,
+                    metric.get_counter().get_value(),
+                ),
+                MetricType::GAUGE => {
+                    sample(&mut out, name, "", &labels, metric.get_gauge().get_value())
+                }
+                MetricType::HISTOGRAM => {
+                    let h = metric.get_histogram();
+                    for bucket in h.get_bucket() {
+                        let mut labels = labels.clone();
+                        labels.push(("le".into(), bound(bucket.get_upper_bound())));
+                        sample(
+                            &mut out,
+                            name,
+                            "_bucket",
+                            &labels,
+                            bucket.get_cumulative_count() as f64,
+                        );
+                    }
+                    let mut inf = labels.clone();
+                    inf.push(("le".into(), "+Inf".into()));
+                    sample(&mut out, name, "_bucket", &inf, h.get_sample_count() as f64);
+                    sample(
+                        &mut out,
+                        name,
+                        "_count",
+                        &labels,
+                        h.get_sample_count() as f64,
+                    );
+                    sample(&mut out, name, "_sum", &labels, h.get_sample_sum());
+                }
+                MetricType::SUMMARY => {
+                    let s = metric.get_summary();
+                    for q in s.get_quantile() {
+                        let mut labels = labels.clone();
+                        labels.push(("quantile".into(), bound(q.get_quantile())));
+                        sample(&mut out, name, "", &labels, q.get_value());
+                    }
+                    sample(
+                        &mut out,
+                        name,
+                        "_count",
+                        &labels,
+                        s.get_sample_count() as f64,
+                    );
+                    sample(&mut out, name, "_sum", &labels, s.get_sample_sum());
+                }
+                MetricType::UNTYPED => sample(
+                    &mut out,
+                    name,
+                    "",
+                    &labels,
+                    metric.get_untyped().get_value(),
+                ),
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+pub fn render_json(families: &[MetricFamily]) -> Value {
+    let families: Vec<Value> = families
+        .iter()
+        .map(|family| {
+            let metrics: Vec<Value> = family
+                .get_metric()
+                .iter()
+                .map(|metric| {
+                    let labels: Map<String, Value> = metric
+                        .get_label()
+                        .iter()
+                        .map(|l| (l.get_name().to_string(), json!(l.get_value())))
+                        .collect();
+                    let mut m = json!({ "labels": labels });
+                    match family.get_field_type() {
+                        MetricType::COUNTER => m["value"] = json!(metric.get_counter().get_value()),
+                        MetricType::GAUGE => m["value"] = json!(metric.get_gauge().get_value()),
+                        MetricType::UNTYPED => m["value"] = json!(metric.get_untyped().get_value()),
+                        MetricType::HISTOGRAM => {
+                            let h = metric.get_histogram();
+                            m["count"] = json!(h.get_sample_count());
+                            m["sum"] = json!(h.get_sample_sum());
+                            m["buckets"] = h
+                                .get_bucket()
+                                .iter()
+                                .map(|b| json!({"le": b.get_upper_bound(), "count": b.get_cumulative_count()}))
+                                .collect();
+                        }
+                        MetricType::SUMMARY => {
+                            let s = metric.get_summary();
+                            m["count"] = json!(s.get_sample_count());
+                            m["sum"] = json!(s.get_sample_sum());
+                            m["quantiles"] = s
+                                .get_quantile()
+                                .iter()
+                                .map(|q| json!({"quantile": q.get_quantile(), "value": q.get_value()}))
+                                .collect();
+                        }
+                    }
+                    m
+                })
+                .collect();
+            json!({
+                "name": family.get_name(),
+                "help": family.get_help(),
+                "type": type_name(family),
+                "metrics": metrics,
+            })
+        })
+        .collect();
+    Value::Array(families)
+}
+
+fn type_name(family: &MetricFamily) -> &'static str {
+    match family.get_field_type() {
+        MetricType::COUNTER => "counter",
+        MetricType::GAUGE => "gauge",
+        MetricType::HISTOGRAM => "histogram",
+        MetricType::SUMMARY => "summary",
+        MetricType::UNTYPED => "unknown",
+    }
+}
+
+fn sample(out: &mut String, name: &str, suffix: &str, labels: &[(String, String)], value: f64) {
+    out.push_str(name);
+    out.push_str(suffix);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+            .collect();
+        out.push_str(&format!("{{{}}}", labels.join(",")));
+    }
+    out.push_str(&format!(" {}\n", bound(value)));
+}
+
+fn bound(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".into()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".into()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape(val: &str) -> String {
+    val.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{IntCounter, Registry};
+
+    use super::*;
+
+    fn registry() -> Registry {
+        let registry = Registry::new();
+        let counter = IntCounter::new("rocky_test_total", "Test \"counter\"").unwrap();
+        counter.inc_by(3);
+        registry.register(Box::new(counter)).unwrap();
+        registry
+    }
+
+    #[test]
+    fn should_render_openmetrics() {
+        let out = render_openmetrics(&registry().gather());
+        assert_eq!(
+            "# TYPE rocky_test counter\n\
+             # HELP rocky_test Test \\\"counter\\\"\n\
+             rocky_test_total 3\n\
+             # EOF\n",
+            out
+        );
+    }
+
+    #[test]
+    fn should_render_json() {
+        let out = render_json(&registry().gather());
+        assert_eq!("rocky_test_total", out[0]["name"]);
+        assert_eq!("counter", out[0]["type"]);
+        assert_eq!(json!(3.0), out[0]["metrics"][0]["value"]);
+    }
+}
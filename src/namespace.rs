@@ -0,0 +1,73 @@
+// Hierarchical key namespaces.
+//
+// Keys like users/42/orders/7 are grouped by a delimiter, / unless the db sets key_delimiter. _keys?prefix= lists
+// only keys under the prefix and with namespaces=true keys one level deeper are rolled up into the namespaces they
+// belong to, e.g. prefix=users/ lists users/42/ once however many keys it holds. DELETE /{db}/_prefix/{prefix}
+// deletes everything under a prefix as one range delete.
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Listing {
+    prefix: Vec<u8>,
+    // keys are rolled up into namespaces only with a delimiter
+    delimiter: Option<Vec<u8>>,
+}
+
+impl Listing {
+    pub fn new(prefix: Vec<u8>, delimiter: Option<Vec<u8>>) -> Self {
+        Listing {
+            prefix,
+            delimiter: delimiter.filter(|d| !d.is_empty()),
+        }
+    }
+
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    // the child namespace of the prefix the key belongs to, ending with the delimiter - None for keys directly under it
+    pub fn namespace<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        let delimiter = self.delimiter.as_deref()?;
+        let rest = key.strip_prefix(self.prefix.as_slice())?;
+        rest.windows(delimiter.len())
+            .position(|w| w == delimiter)
+            .map(|pos| &key[..self.prefix.len() + pos + delimiter.len()])
+    }
+}
+
+// smallest key greater than all keys starting with the prefix, None when there's no such key (all bytes are 0xff)
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|b| *b != u8::MAX)?;
+    let mut end = prefix[..=last].to_vec();
+    end[last] += 1;
+    Some(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_find_prefix_end() {
+        assert_eq!(Some(b"users0".to_vec()), prefix_end(b"users/"));
+        assert_eq!(Some(vec![1]), prefix_end(&[0, 255]));
+        assert_eq!(None, prefix_end(&[255, 255]));
+        assert_eq!(None, prefix_end(b""));
+    }
+
+    #[test]
+    fn should_roll_up_namespaces() {
+        let listing = Listing::new(b"users/".to_vec(), Some(b"/".to_vec()));
+        assert_eq!(
+            Some(&b"users/42/"[..]),
+            listing.namespace(b"users/42/orders/7")
+        );
+        assert_eq!(None, listing.namespace(b"users/42"));
+        assert_eq!(None, listing.namespace(b"teams/1/users"));
+
+        let listing = Listing::new(vec![], Some(b"::".to_vec()));
+        assert_eq!(Some(&b"a::"[..]), listing.namespace(b"a::b::c"));
+
+        let listing = Listing::new(b"users/".to_vec(), None);
+        assert_eq!(None, listing.namespace(b"users/42/orders/7"));
+    }
+}
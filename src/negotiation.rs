@@ -0,0 +1,206 @@
+// Content negotiation of JSON responses - errors, stats, listings and the other metadata endpoints.
+//
+// A reader listing application/msgpack or application/cbor in Accept gets metadata in that format instead of JSON.
+// Handlers take the negotiated Format and serialize their documents straight into it, JSON left by the rest - error
+// and not found responses - is converted by the middleware. Streamed responses and records - those carry an ETag,
+// whatever their stored content type - are sent as they are. The format with the highest q wins, the first listed
+// one on ties, JSON otherwise. Metadata responses vary by Accept in every format.
+
+use actix_web::body::{Body, ResponseBody};
+use actix_web::dev::{HttpResponseBuilder, Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE, ETAG, VARY};
+use actix_web::{FromRequest, HttpRequest, HttpResponse};
+use futures::future::{ok, Ready};
+use serde::Serialize;
+
+pub const MSGPACK_TYPE: &str = "application/msgpack";
+pub const CBOR_TYPE: &str = "application/cbor";
+const JSON_TYPE: &str = "application/json";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+impl Format {
+    pub fn from_accept(accept: &str) -> Self {
+        let mut best = (Format::Json, 0.0);
+        for item in accept.split(',') {
+            let mut params = item.split(';');
+            let format = match params.next().unwrap_or_default().trim() {
+                MSGPACK_TYPE | "application/x-msgpack" => Format::MsgPack,
+                CBOR_TYPE => Format::Cbor,
+                JSON_TYPE => Format::Json,
+                _ => continue,
+            };
+            let q = params
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+                .unwrap_or(0.0);
+            if q > best.1 {
+                best = (format, q);
+            }
+        }
+        best.0
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => JSON_TYPE,
+            Format::MsgPack => MSGPACK_TYPE,
+            Format::Cbor => CBOR_TYPE,
+        }
+    }
+
+    pub fn encode(self, json: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let doc: serde_json::Value = serde_json::from_slice(json)?;
+        self.serialize(&doc)
+    }
+
+    // binary formats go through a JSON value, so 128 bit timestamps stay integers as they are in JSON
+    pub fn serialize<T: Serialize>(self, doc: &T) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Format::Json => serde_json::to_vec(doc)?,
+            Format::MsgPack => rmp_serde::to_vec_named(&serde_json::to_value(doc)?)?,
+            Format::Cbor => serde_cbor::to_vec(&serde_json::to_value(doc)?)?,
+        })
+    }
+
+    // a document that can't be serialized is answered with 500
+    pub fn respond<T: Serialize>(self, res: &mut HttpResponseBuilder, doc: &T) -> HttpResponse {
+        match self.serialize(doc) {
+            Ok(body) => res
+                .content_type(self.content_type())
+                .header(VARY, "accept")
+                .body(body),
+            Err(e) => {
+                error!(
+                    "Can't serialize response as {}, e = {}",
+                    self.content_type(),
+                    e
+                );
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+
+    fn of(headers: &HeaderMap) -> Self {
+        headers
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .map_or(Format::Json, Format::from_accept)
+    }
+}
+
+impl FromRequest for Format {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ok(Format::of(req.headers()))
+    }
+}
+
+pub fn requested_format(req: &ServiceRequest) -> Format {
+    Format::of(req.headers())
+}
+
+fn is_metadata(res: &ServiceResponse<Body>) -> bool {
+    !res.headers().contains_key(ETAG)
+        && res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map_or(false, |content_type| content_type.starts_with(JSON_TYPE))
+}
+
+// a body that can't be converted is sent as JSON
+pub fn negotiate(format: Format, mut res: ServiceResponse<Body>) -> ServiceResponse<Body> {
+    if !is_metadata(&res) {
+        return res;
+    }
+    if format == Format::Json {
+        res.headers_mut()
+            .insert(VARY, HeaderValue::from_static("accept"));
+        return res;
+    }
+    let json = match res.take_body() {
+        ResponseBody::Body(Body::Bytes(json)) | ResponseBody::Other(Body::Bytes(json)) => json,
+        body => return res.map_body(|_, _| body),
+    };
+    let encoded = match format.encode(&json) {
+        Ok(encoded) => encoded,
+        Err(e) => {
+            warn!(
+                "Can't encode response as {}, e = {}",
+                format.content_type(),
+                e
+            );
+            return res.map_body(|_, _| ResponseBody::Other(Body::Bytes(json)));
+        }
+    };
+    let headers = res.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+    headers.insert(VARY, HeaderValue::from_static("accept"));
+    res.map_body(|_, _| ResponseBody::Other(Body::from(encoded)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_pick_accepted_format() {
+        assert_eq!(Format::Json, Format::from_accept("*/*"));
+        assert_eq!(Format::MsgPack, Format::from_accept("application/msgpack"));
+        assert_eq!(
+            Format::Cbor,
+            Format::from_accept("application/json;q=0.5, application/cbor")
+        );
+        assert_eq!(
+            Format::MsgPack,
+            Format::from_accept("application/x-msgpack, application/cbor")
+        );
+        assert_eq!(Format::Json, Format::from_accept("application/cbor;q=0"));
+    }
+
+    #[test]
+    fn should_encode_json_documents() {
+        let json = br#"{"name":"db","count":3}"#;
+        let doc: serde_json::Value =
+            rmp_serde::from_slice(&Format::MsgPack.encode(json).unwrap()).unwrap();
+        assert_eq!(serde_json::json!({"name": "db", "count": 3}), doc);
+        let doc: serde_json::Value =
+            serde_cbor::from_slice(&Format::Cbor.encode(json).unwrap()).unwrap();
+        assert_eq!(serde_json::json!({"name": "db", "count": 3}), doc);
+        assert!(Format::Cbor.encode(b"not json").is_err());
+    }
+
+    #[test]
+    fn should_serialize_documents_directly() {
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Doc {
+            created_at: u128,
+        }
+        let doc = Doc {
+            created_at: 1_600_000_000_000,
+        };
+        let decoded: serde_json::Value =
+            rmp_serde::from_slice(&Format::MsgPack.serialize(&doc).unwrap()).unwrap();
+        assert_eq!(
+            serde_json::json!({"createdAt": 1_600_000_000_000u64}),
+            decoded
+        );
+        assert_eq!(
+            br#"{"createdAt":1600000000000}"#.to_vec(),
+            Format::Json.serialize(&doc).unwrap()
+        );
+    }
+}
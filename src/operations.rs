@@ -0,0 +1,179 @@
+// Registry of long-running work that can be cancelled - key listings, scans, counts, export streams, compactions and
+// migrations.
+//
+// Work registers itself when it starts and is removed when it ends, GET /_admin/operations lists what is in flight.
+// DELETE /_admin/operations/{id} cancels an operation cooperatively - it's flagged and stops the next time it checks,
+// listings, scans and counts between records (answered with 503), exports between chunks (the connection is cut),
+// compactions between column families and migrations between batches.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::anyhow;
+use serde::Serialize;
+
+use crate::conversion::current_ms;
+use crate::errors::ErrorCtx;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OperationKind {
+    Keys,
+    Scan,
+    Count,
+    Export,
+    Compaction,
+    Migration,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OperationInfo {
+    pub id: u64,
+    pub kind: OperationKind,
+    pub db: String,
+    pub started_at: u128,
+    pub cancelled: bool,
+}
+
+#[derive(Default)]
+pub struct Operations {
+    next_id: AtomicU64,
+    running: Mutex<HashMap<u64, (OperationInfo, Arc<AtomicBool>)>>,
+}
+
+// unregisters the operation once dropped
+pub struct Operation {
+    cancel: Cancel,
+    operations: Arc<Operations>,
+}
+
+// checked by the work an operation does, e.g. a scan through its limits
+#[derive(Debug, Clone)]
+pub struct Cancel {
+    id: u64,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Operations {
+    pub fn start(self: &Arc<Self>, kind: OperationKind, db: &str) -> Operation {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let info = OperationInfo {
+            id,
+            kind,
+            db: db.into(),
+            started_at: current_ms().unwrap_or_default(),
+            cancelled: false,
+        };
+        self.lock().insert(id, (info, cancelled.clone()));
+        Operation {
+            cancel: Cancel { id, cancelled },
+            operations: self.clone(),
+        }
+    }
+
+    // oldest first
+    pub fn list(&self) -> Vec<OperationInfo> {
+        let mut all: Vec<OperationInfo> = self
+            .lock()
+            .values()
+            .map(|(info, cancelled)| OperationInfo {
+                cancelled: cancelled.load(Ordering::Relaxed),
+                ..info.clone()
+            })
+            .collect();
+        all.sort_by_key(|info| info.id);
+        all
+    }
+
+    // false when there's no such operation, it may have just ended
+    pub fn cancel(&self, id: u64) -> bool {
+        match self.lock().get(&id) {
+            Some((info, cancelled)) => {
+                info!(
+                    "Cancelling {:?} of db = {}, id = {}",
+                    info.kind, info.db, id
+                );
+                cancelled.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, (OperationInfo, Arc<AtomicBool>)>> {
+        self.running.lock().expect("Can't acquire operations lock")
+    }
+}
+
+impl Operation {
+    // registered until dropped
+    pub fn info(&self) -> OperationInfo {
+        self.operations.lock()[&self.cancel.id].0.clone()
+    }
+
+    pub fn cancel(&self) -> Cancel {
+        self.cancel.clone()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+}
+
+impl Cancel {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.is_cancelled() {
+            Err(anyhow!(ErrorCtx::Unavailable(format!(
+                "Operation {} was cancelled",
+                self.id
+            ))))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        self.operations.lock().remove(&self.cancel.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_cancel_running_operations() {
+        let operations = Arc::new(Operations::default());
+        let scan = operations.start(OperationKind::Scan, "db_1");
+        let export = operations.start(OperationKind::Export, "db_2");
+        assert_eq!(
+            vec![(1, OperationKind::Scan), (2, OperationKind::Export)],
+            operations
+                .list()
+                .iter()
+                .map(|info| (info.id, info.kind))
+                .collect::<Vec<_>>()
+        );
+
+        assert!(operations.cancel(2));
+        assert!(export.is_cancelled());
+        assert!(export.cancel().check().is_err());
+        assert!(scan.cancel().check().is_ok());
+        assert!(operations.list()[1].cancelled);
+
+        drop(export);
+        assert!(!operations.cancel(2));
+        assert_eq!(1, operations.list().len());
+        drop(scan);
+        assert!(operations.list().is_empty());
+    }
+}
@@ -0,0 +1,538 @@
+// Durable queue of outbound deliveries - expiration webhooks and replication batches.
+//
+// A delivery is written to the outbound column family of the root db before it's attempted and removed once its
+// target accepted it, so a downstream outage or a restart doesn't lose it. Deliveries are scheduled under the time
+// of their next attempt with the body kept apart, so only headers of due deliveries are read and a body only when
+// it's sent. Failed attempts are retried with exponential backoff, after MAX_ATTEMPTS a delivery is dead-lettered -
+// kept for inspection but not retried, the oldest dead letters make room for new ones. GET /_outbound lists pending
+// and dead deliveries along with attempts and the last error. Webhooks are delivered from the queue and replication
+// batches by their shipper, leftovers of a previous run included. Once MAX_PENDING deliveries are scheduled
+// further ones are dropped.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use rocksdb::{ColumnFamily, Direction, IteratorMode, WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+
+use crate::conversion::current_ms;
+use crate::rock::Rock;
+
+pub const OUTBOUND_CF: &str = "outbound";
+pub const MAX_PENDING: usize = 100_000;
+pub const MAX_ATTEMPTS: u32 = 20;
+const MAX_DEAD_LETTERS: usize = 10_000;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+// {SCHEDULED}{next_attempt_at}{id} and {DEAD}{id} hold headers, {BODY}{id} the body
+const SCHEDULED: u8 = b's';
+const DEAD: u8 = b'x';
+const BODY: u8 = b'b';
+// keys of the layout before schedules, the id alone
+const QUEUED_KEY_LEN: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Webhook,
+    Replication,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Delivery {
+    pub id: u64,
+    pub kind: Kind,
+    pub db: String,
+    pub target: String,
+    // JSON posted to the target, stored apart from the header and read only to send it
+    #[serde(skip)]
+    pub body: String,
+    pub bytes: usize,
+    pub attempts: u32,
+    pub created_at: u128,
+    pub next_attempt_at: u128,
+    pub last_error: Option<String>,
+}
+
+// layout before schedules, kept under the id with the body inline
+#[derive(Deserialize)]
+struct QueuedDelivery {
+    id: u64,
+    kind: Kind,
+    db: String,
+    target: String,
+    body: String,
+    attempts: u32,
+    created_at: u128,
+    next_attempt_at: u128,
+    last_error: Option<String>,
+}
+
+// listed without the body
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingDelivery {
+    pub id: u64,
+    pub kind: Kind,
+    pub db: String,
+    pub target: String,
+    pub bytes: usize,
+    pub attempts: u32,
+    pub created_at: u128,
+    pub next_attempt_at: u128,
+    pub last_error: Option<String>,
+    // attempts ran out, it isn't retried anymore
+    pub dead: bool,
+}
+
+pub struct Outbox {
+    rock: Arc<Rock>,
+    next_id: AtomicU64,
+    pending: AtomicUsize,
+    dead: AtomicUsize,
+}
+
+impl Outbox {
+    // deliveries queued in the layout before schedules are rescheduled
+    pub fn open(rock: Arc<Rock>) -> anyhow::Result<Self> {
+        let outbox = Outbox {
+            rock,
+            next_id: AtomicU64::new(0),
+            pending: AtomicUsize::new(0),
+            dead: AtomicUsize::new(0),
+        };
+        outbox.reschedule_queued()?;
+
+        let rock = outbox.rock.read();
+        let cf = outbound_cf(&rock)?;
+        let mut last_id = 0;
+        for (prefix, count) in &[(SCHEDULED, &outbox.pending), (DEAD, &outbox.dead)] {
+            for (k, _) in prefixed(&rock, cf, *prefix) {
+                last_id = last_id.max(entry_id(&k));
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        outbox.next_id.store(last_id, Ordering::Relaxed);
+        drop(rock);
+        Ok(outbox)
+    }
+
+    pub fn push(
+        &self,
+        kind: Kind,
+        db: &str,
+        target: &str,
+        body: String,
+    ) -> anyhow::Result<Delivery> {
+        if self.pending.load(Ordering::Relaxed) >= MAX_PENDING {
+            return Err(anyhow!(
+                "Outbound queue is full, {} deliveries pending",
+                MAX_PENDING
+            ));
+        }
+        let now = current_ms()?;
+        let delivery = Delivery {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            kind,
+            db: db.into(),
+            target: target.into(),
+            bytes: body.len(),
+            body,
+            attempts: 0,
+            created_at: now,
+            next_attempt_at: now,
+            last_error: None,
+        };
+        let rock = self.rock.read();
+        let cf = outbound_cf(&rock)?;
+        let mut batch = WriteBatch::default();
+        batch.put_cf(cf, body_key(delivery.id), delivery.body.as_bytes());
+        batch.put_cf(cf, scheduled_key(&delivery), bincode::serialize(&delivery)?);
+        rock.write(batch)?;
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        Ok(delivery)
+    }
+
+    // the failed attempt is recorded and the next one scheduled after the backoff, once MAX_ATTEMPTS failed the
+    // delivery is dead-lettered instead - returns whether it was. A delivery done meanwhile is left alone.
+    pub fn retry(
+        &self,
+        delivery: &mut Delivery,
+        error: String,
+        after: Duration,
+    ) -> anyhow::Result<bool> {
+        let rock = self.rock.read();
+        let cf = outbound_cf(&rock)?;
+        let scheduled = scheduled_key(delivery);
+        if rock.get_cf(cf, &scheduled)?.is_none() {
+            return Ok(false);
+        }
+
+        delivery.attempts += 1;
+        delivery.last_error = Some(error);
+        let dead = delivery.attempts >= MAX_ATTEMPTS;
+        let mut batch = WriteBatch::default();
+        batch.delete_cf(cf, scheduled);
+        if dead {
+            batch.put_cf(cf, dead_key(delivery.id), bincode::serialize(delivery)?);
+        } else {
+            delivery.next_attempt_at = current_ms()? + after.as_millis();
+            batch.put_cf(cf, scheduled_key(delivery), bincode::serialize(delivery)?);
+        }
+        rock.write(batch)?;
+
+        if dead {
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+            if self.dead.fetch_add(1, Ordering::Relaxed) >= MAX_DEAD_LETTERS {
+                self.drop_oldest_dead(&rock, cf)?;
+            }
+        }
+        Ok(dead)
+    }
+
+    pub fn done(&self, delivery: &Delivery) -> anyhow::Result<()> {
+        let rock = self.rock.read();
+        let cf = outbound_cf(&rock)?;
+        let scheduled = scheduled_key(delivery);
+        if rock.get_cf(cf, &scheduled)?.is_some() {
+            let mut batch = WriteBatch::default();
+            batch.delete_cf(cf, scheduled);
+            batch.delete_cf(cf, body_key(delivery.id));
+            rock.write(batch)?;
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    // of the kind with their bodies, due ones only when due is set - in the order they're due, reading stops at
+    // the first delivery that isn't
+    pub fn pending(&self, kind: Kind, due: bool, limit: usize) -> anyhow::Result<Vec<Delivery>> {
+        let now = current_ms()?;
+        let rock = self.rock.read();
+        let cf = outbound_cf(&rock)?;
+        let mut deliveries = vec![];
+        for (_, v) in prefixed(&rock, cf, SCHEDULED) {
+            if deliveries.len() == limit {
+                break;
+            }
+            let mut delivery: Delivery = bincode::deserialize(&v)?;
+            if due && delivery.next_attempt_at > now {
+                break;
+            }
+            if delivery.kind == kind {
+                delivery.body = body(&rock, cf, delivery.id)?;
+                deliveries.push(delivery);
+            }
+        }
+        Ok(deliveries)
+    }
+
+    // scheduled deliveries of the kind to the target, without their bodies
+    pub fn scheduled(&self, kind: Kind, target: &str) -> anyhow::Result<Vec<Delivery>> {
+        let rock = self.rock.read();
+        let cf = outbound_cf(&rock)?;
+        let mut deliveries = vec![];
+        for (_, v) in prefixed(&rock, cf, SCHEDULED) {
+            let delivery: Delivery = bincode::deserialize(&v)?;
+            if delivery.kind == kind && delivery.target == target {
+                deliveries.push(delivery);
+            }
+        }
+        Ok(deliveries)
+    }
+
+    pub fn body(&self, delivery: &mut Delivery) -> anyhow::Result<()> {
+        let rock = self.rock.read();
+        delivery.body = body(&rock, outbound_cf(&rock)?, delivery.id)?;
+        Ok(())
+    }
+
+    // scheduled deliveries in the order they're due, then dead letters oldest first
+    pub fn list(&self) -> anyhow::Result<Vec<PendingDelivery>> {
+        let rock = self.rock.read();
+        let cf = outbound_cf(&rock)?;
+        let mut listed = vec![];
+        for (prefix, dead) in &[(SCHEDULED, false), (DEAD, true)] {
+            for (_, v) in prefixed(&rock, cf, *prefix) {
+                let delivery: Delivery = bincode::deserialize(&v)?;
+                listed.push(PendingDelivery::new(delivery, *dead));
+            }
+        }
+        Ok(listed)
+    }
+
+    fn drop_oldest_dead(&self, rock: &DB, cf: &ColumnFamily) -> anyhow::Result<()> {
+        if let Some((k, _)) = prefixed(rock, cf, DEAD).next() {
+            let id = entry_id(&k);
+            let mut batch = WriteBatch::default();
+            batch.delete_cf(cf, dead_key(id));
+            batch.delete_cf(cf, body_key(id));
+            rock.write(batch)?;
+            self.dead.fetch_sub(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn reschedule_queued(&self) -> anyhow::Result<()> {
+        let rock = self.rock.read();
+        let cf = outbound_cf(&rock)?;
+        let mut batch = WriteBatch::default();
+        for (k, v) in rock.iterator_cf(cf, IteratorMode::Start) {
+            if k.len() != QUEUED_KEY_LEN {
+                continue;
+            }
+            let queued: QueuedDelivery = bincode::deserialize(&v)?;
+            let delivery = Delivery::from(queued);
+            batch.put_cf(cf, body_key(delivery.id), delivery.body.as_bytes());
+            batch.put_cf(cf, scheduled_key(&delivery), bincode::serialize(&delivery)?);
+            batch.delete_cf(cf, &k);
+        }
+        if batch.len() > 0 {
+            rock.write(batch)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<QueuedDelivery> for Delivery {
+    fn from(queued: QueuedDelivery) -> Self {
+        Delivery {
+            id: queued.id,
+            kind: queued.kind,
+            db: queued.db,
+            target: queued.target,
+            bytes: queued.body.len(),
+            body: queued.body,
+            attempts: queued.attempts,
+            created_at: queued.created_at,
+            next_attempt_at: queued.next_attempt_at,
+            last_error: queued.last_error,
+        }
+    }
+}
+
+impl PendingDelivery {
+    fn new(delivery: Delivery, dead: bool) -> Self {
+        PendingDelivery {
+            id: delivery.id,
+            kind: delivery.kind,
+            db: delivery.db,
+            target: delivery.target,
+            bytes: delivery.bytes,
+            attempts: delivery.attempts,
+            created_at: delivery.created_at,
+            next_attempt_at: delivery.next_attempt_at,
+            last_error: delivery.last_error,
+            dead,
+        }
+    }
+}
+
+// 0.5s, 1s, 2s ... up to 5 minutes
+pub fn backoff(attempts: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1)).min(1024);
+    (INITIAL_BACKOFF * factor).min(MAX_BACKOFF)
+}
+
+fn outbound_cf(rock: &DB) -> anyhow::Result<&ColumnFamily> {
+    rock.cf_handle(OUTBOUND_CF)
+        .ok_or_else(|| anyhow!("Missing {} column family", OUTBOUND_CF))
+}
+
+fn prefixed<'a>(
+    rock: &'a DB,
+    cf: &ColumnFamily,
+    prefix: u8,
+) -> impl Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a {
+    rock.iterator_cf(cf, IteratorMode::From(&[prefix], Direction::Forward))
+        .take_while(move |(k, _)| k.first() == Some(&prefix))
+}
+
+fn body(rock: &DB, cf: &ColumnFamily, id: u64) -> anyhow::Result<String> {
+    Ok(rock
+        .get_cf(cf, body_key(id))?
+        .map(|b| String::from_utf8_lossy(&b).into_owned())
+        .unwrap_or_default())
+}
+
+// the next attempt sorts first, the id keeps deliveries due at the same time apart
+fn scheduled_key(delivery: &Delivery) -> Vec<u8> {
+    let mut key = vec![SCHEDULED];
+    key.extend_from_slice(&delivery.next_attempt_at.to_be_bytes());
+    key.extend_from_slice(&delivery.id.to_be_bytes());
+    key
+}
+
+fn dead_key(id: u64) -> Vec<u8> {
+    let mut key = vec![DEAD];
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+fn body_key(id: u64) -> Vec<u8> {
+    let mut key = vec![BODY];
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+// every key ends with the id
+fn entry_id(key: &[u8]) -> u64 {
+    let mut id = [0; 8];
+    id.copy_from_slice(&key[key.len() - 8..]);
+    u64::from_be_bytes(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use rocksdb::{ColumnFamilyDescriptor, Options, DB};
+
+    use super::*;
+
+    fn options() -> Options {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        opts
+    }
+
+    fn open(path: &str) -> Arc<Rock> {
+        let cfs = vec![ColumnFamilyDescriptor::new(OUTBOUND_CF, Options::default())];
+        Arc::new(Rock::new(
+            DB::open_cf_descriptors(&options(), path, cfs).unwrap(),
+        ))
+    }
+
+    #[test]
+    fn should_back_off_exponentially() {
+        assert_eq!(Duration::from_millis(500), backoff(1));
+        assert_eq!(Duration::from_secs(4), backoff(4));
+        assert_eq!(MAX_BACKOFF, backoff(30));
+    }
+
+    #[test]
+    fn should_keep_deliveries_until_done() {
+        let path = "./db/should_keep_deliveries_until_done";
+        let outbox = Outbox::open(open(path)).unwrap();
+        let mut webhook = outbox
+            .push(Kind::Webhook, "db", "http://hook", "{}".into())
+            .unwrap();
+        let replication = outbox
+            .push(Kind::Replication, "db", "http://remote", "[]".into())
+            .unwrap();
+        assert_eq!(1, outbox.pending(Kind::Webhook, true, 10).unwrap().len());
+
+        assert!(!outbox
+            .retry(&mut webhook, "refused".into(), Duration::from_secs(60))
+            .unwrap());
+        assert!(outbox.pending(Kind::Webhook, true, 10).unwrap().is_empty());
+        let pending = outbox.pending(Kind::Webhook, false, 10).unwrap();
+        assert_eq!(1, pending[0].attempts);
+        assert_eq!(Some("refused".into()), pending[0].last_error);
+        assert_eq!("{}", pending[0].body);
+        drop(outbox);
+
+        // a restart keeps webhooks and replication batches
+        let outbox = Outbox::open(open(path)).unwrap();
+        assert_eq!(2, outbox.list().unwrap().len());
+        let mut leftovers = outbox
+            .scheduled(Kind::Replication, "http://remote")
+            .unwrap();
+        assert_eq!(vec![replication.id], vec![leftovers[0].id]);
+        assert!(leftovers[0].body.is_empty());
+        outbox.body(&mut leftovers[0]).unwrap();
+        assert_eq!(replication, leftovers[0]);
+        let next = outbox
+            .push(Kind::Webhook, "db", "http://hook", "{}".into())
+            .unwrap();
+        assert_eq!(3, next.id);
+
+        outbox.done(&webhook).unwrap();
+        outbox.done(&replication).unwrap();
+        outbox.done(&next).unwrap();
+        assert!(outbox.list().unwrap().is_empty());
+        drop(outbox);
+        DB::destroy(&options(), path).unwrap();
+    }
+
+    #[test]
+    fn should_dead_letter_after_max_attempts() {
+        let path = "./db/should_dead_letter_after_max_attempts";
+        let outbox = Outbox::open(open(path)).unwrap();
+        let mut delivery = outbox
+            .push(Kind::Webhook, "db", "http://hook", "{}".into())
+            .unwrap();
+        for _ in 1..MAX_ATTEMPTS {
+            assert!(!outbox
+                .retry(&mut delivery, "refused".into(), Duration::from_millis(0))
+                .unwrap());
+        }
+        assert!(outbox
+            .retry(&mut delivery, "refused".into(), Duration::from_millis(0))
+            .unwrap());
+
+        assert!(outbox.pending(Kind::Webhook, false, 10).unwrap().is_empty());
+        let listed = outbox.list().unwrap();
+        assert_eq!(1, listed.len());
+        assert!(listed[0].dead);
+        assert_eq!(MAX_ATTEMPTS, listed[0].attempts);
+        drop(outbox);
+        DB::destroy(&options(), path).unwrap();
+    }
+
+    #[test]
+    fn should_reschedule_queued_deliveries() {
+        #[derive(Serialize)]
+        struct Queued {
+            id: u64,
+            kind: Kind,
+            db: String,
+            target: String,
+            body: String,
+            attempts: u32,
+            created_at: u128,
+            next_attempt_at: u128,
+            last_error: Option<String>,
+        }
+
+        let path = "./db/should_reschedule_queued_deliveries";
+        let rock = open(path);
+        let queued = Queued {
+            id: 7,
+            kind: Kind::Webhook,
+            db: "db".into(),
+            target: "http://hook".into(),
+            body: "{}".into(),
+            attempts: 2,
+            created_at: 1,
+            next_attempt_at: 2,
+            last_error: None,
+        };
+        {
+            let db = rock.read();
+            let cf = outbound_cf(&db).unwrap();
+            db.put_cf(cf, 7u64.to_be_bytes(), bincode::serialize(&queued).unwrap())
+                .unwrap();
+        }
+
+        let outbox = Outbox::open(rock).unwrap();
+        let pending = outbox.pending(Kind::Webhook, true, 10).unwrap();
+        assert_eq!(1, pending.len());
+        assert_eq!(
+            (7, 2, 2),
+            (pending[0].id, pending[0].attempts, pending[0].bytes)
+        );
+        assert_eq!("{}", pending[0].body);
+        assert_eq!(
+            8,
+            outbox
+                .push(Kind::Webhook, "db", "t", "{}".into())
+                .unwrap()
+                .id
+        );
+        drop(outbox);
+        DB::destroy(&options(), path).unwrap();
+    }
+}
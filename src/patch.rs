@@ -0,0 +1,305 @@
+// Partial updates of JSON documents.
+//
+// PATCH /{db}/{key} with Content-Type application/json-patch+json applies a RFC 6902 JSON Patch, with
+// application/merge-patch+json a RFC 7386 merge patch to the stored document. All operations of a JSON Patch are
+// applied or none is, a missing document is patched as null. A malformed patch is a validation error, a patch that
+// can't be applied to the document (a failed test, a missing path) is a conflict.
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::errors::ErrorCtx;
+
+pub const JSON_PATCH_CONTENT_TYPE: &str = "application/json-patch+json";
+pub const MERGE_PATCH_CONTENT_TYPE: &str = "application/merge-patch+json";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PatchFormat {
+    JsonPatch,
+    MergePatch,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Operation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+impl PatchFormat {
+    // parameters like charset are ignored
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        if essence.eq_ignore_ascii_case(JSON_PATCH_CONTENT_TYPE) {
+            Some(PatchFormat::JsonPatch)
+        } else if essence.eq_ignore_ascii_case(MERGE_PATCH_CONTENT_TYPE) {
+            Some(PatchFormat::MergePatch)
+        } else {
+            None
+        }
+    }
+}
+
+// the patched document
+pub fn apply(format: PatchFormat, doc: Option<&[u8]>, patch: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut doc = match doc {
+        Some(doc) => serde_json::from_slice(doc).map_err(|e| {
+            anyhow!(ErrorCtx::Conflict(format!(
+                "Stored value isn't JSON - {}",
+                e
+            )))
+        })?,
+        None => Value::Null,
+    };
+    match format {
+        PatchFormat::JsonPatch => {
+            let ops: Vec<Operation> = serde_json::from_slice(patch).map_err(|e| malformed(&e))?;
+            for op in ops {
+                apply_op(&mut doc, op)?;
+            }
+        }
+        PatchFormat::MergePatch => {
+            let patch: Value = serde_json::from_slice(patch).map_err(|e| malformed(&e))?;
+            merge(&mut doc, patch);
+        }
+    }
+    Ok(serde_json::to_vec(&doc)?)
+}
+
+fn apply_op(doc: &mut Value, op: Operation) -> anyhow::Result<()> {
+    match op {
+        Operation::Add { path, value } => add(doc, &path, value),
+        Operation::Remove { path } => remove(doc, &path).map(|_| ()),
+        Operation::Replace { path, value } => {
+            *get_mut(doc, &path)? = value;
+            Ok(())
+        }
+        Operation::Move { from, path } => {
+            if path.starts_with(&format!("{}/", from)) {
+                return Err(anyhow!(ErrorCtx::Validation(format!(
+                    "Can't move {} into its own child {}",
+                    from, path
+                ))));
+            }
+            let value = remove(doc, &from)?;
+            add(doc, &path, value)
+        }
+        Operation::Copy { from, path } => {
+            let value = get_mut(doc, &from)?.clone();
+            add(doc, &path, value)
+        }
+        Operation::Test { path, value } => {
+            if *get_mut(doc, &path)? == value {
+                Ok(())
+            } else {
+                Err(anyhow!(ErrorCtx::Conflict(format!(
+                    "Test of {} failed",
+                    path
+                ))))
+            }
+        }
+    }
+}
+
+// RFC 7386 - null members are removed, objects are merged, anything else replaces the target
+fn merge(target: &mut Value, patch: Value) {
+    match patch {
+        Value::Object(members) => {
+            if !target.is_object() {
+                *target = Value::Object(Map::new());
+            }
+            if let Value::Object(target) = target {
+                for (name, value) in members {
+                    if value.is_null() {
+                        target.remove(&name);
+                    } else {
+                        merge(target.entry(name).or_insert(Value::Null), value);
+                    }
+                }
+            }
+        }
+        patch => *target = patch,
+    }
+}
+
+fn add(doc: &mut Value, path: &str, value: Value) -> anyhow::Result<()> {
+    let (parent, last) = match split(path)? {
+        Some(split) => split,
+        None => {
+            *doc = value;
+            return Ok(());
+        }
+    };
+    match get_mut(doc, parent)? {
+        Value::Object(members) => {
+            members.insert(last, value);
+            Ok(())
+        }
+        Value::Array(items) => {
+            let index = if last == "-" {
+                items.len()
+            } else {
+                index(&last, items.len() + 1, path)?
+            };
+            items.insert(index, value);
+            Ok(())
+        }
+        _ => Err(missing(path)),
+    }
+}
+
+fn remove(doc: &mut Value, path: &str) -> anyhow::Result<Value> {
+    let (parent, last) = split(path)?.ok_or_else(|| {
+        anyhow!(ErrorCtx::Validation(
+            "Can't remove the whole document".into()
+        ))
+    })?;
+    match get_mut(doc, parent)? {
+        Value::Object(members) => members.remove(&last).ok_or_else(|| missing(path)),
+        Value::Array(items) => {
+            let index = index(&last, items.len(), path)?;
+            Ok(items.remove(index))
+        }
+        _ => Err(missing(path)),
+    }
+}
+
+fn get_mut<'a>(doc: &'a mut Value, path: &str) -> anyhow::Result<&'a mut Value> {
+    check_pointer(path)?;
+    doc.pointer_mut(path).ok_or_else(|| missing(path))
+}
+
+// the parent pointer and the unescaped last token, None for the whole document
+fn split(path: &str) -> anyhow::Result<Option<(&str, String)>> {
+    check_pointer(path)?;
+    Ok(path.rfind('/').map(|pos| {
+        (
+            &path[..pos],
+            path[pos + 1..].replace("~1", "/").replace("~0", "~"),
+        )
+    }))
+}
+
+fn check_pointer(path: &str) -> anyhow::Result<()> {
+    if path.is_empty() || path.starts_with('/') {
+        Ok(())
+    } else {
+        Err(anyhow!(ErrorCtx::Validation(format!(
+            "Path {} isn't a JSON pointer",
+            path
+        ))))
+    }
+}
+
+// array indexes are digits without leading zeros below the bound
+fn index(token: &str, bound: usize, path: &str) -> anyhow::Result<usize> {
+    let valid = !token.is_empty()
+        && token.bytes().all(|b| b.is_ascii_digit())
+        && (token == "0" || !token.starts_with('0'));
+    match token.parse() {
+        Ok(index) if valid && index < bound => Ok(index),
+        _ => Err(missing(path)),
+    }
+}
+
+fn missing(path: &str) -> anyhow::Error {
+    anyhow!(ErrorCtx::Conflict(format!("Path {} doesn't exist", path)))
+}
+
+fn malformed(e: &serde_json::Error) -> anyhow::Error {
+    anyhow!(ErrorCtx::Validation(format!("Malformed patch - {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn json_patch(doc: Value, patch: Value) -> anyhow::Result<Value> {
+        let doc = serde_json::to_vec(&doc).unwrap();
+        let patch = serde_json::to_vec(&patch).unwrap();
+        let patched = apply(PatchFormat::JsonPatch, Some(&doc), &patch)?;
+        Ok(serde_json::from_slice(&patched).unwrap())
+    }
+
+    #[test]
+    fn should_apply_json_patch() {
+        let doc = json!({"name": "rocky", "tags": ["a", "b"], "a/b": {"c": 1}});
+        let patched = json_patch(
+            doc.clone(),
+            json!([
+                {"op": "test", "path": "/name", "value": "rocky"},
+                {"op": "replace", "path": "/name", "value": "balboa"},
+                {"op": "add", "path": "/tags/1", "value": "x"},
+                {"op": "add", "path": "/tags/-", "value": "z"},
+                {"op": "remove", "path": "/tags/0"},
+                {"op": "copy", "from": "/a~1b/c", "path": "/count"},
+                {"op": "move", "from": "/a~1b", "path": "/moved"},
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            json!({"name": "balboa", "tags": ["x", "b", "z"], "count": 1, "moved": {"c": 1}}),
+            patched
+        );
+
+        assert_eq!(
+            json!([1]),
+            json_patch(
+                doc.clone(),
+                json!([{"op": "add", "path": "", "value": [1]}])
+            )
+            .unwrap()
+        );
+        for patch in &[
+            json!([{"op": "test", "path": "/name", "value": "x"}]),
+            json!([{"op": "remove", "path": "/missing"}]),
+            json!([{"op": "replace", "path": "/tags/5", "value": 1}]),
+            json!([{"op": "add", "path": "/tags/01", "value": 1}]),
+            json!([{"op": "move", "from": "/a~1b", "path": "/a~1b/c/d"}]),
+            json!([{"op": "add", "path": "name", "value": 1}]),
+            json!([{"op": "unknown", "path": "/name"}]),
+            json!({"op": "remove", "path": "/name"}),
+        ] {
+            assert!(json_patch(doc.clone(), patch.clone()).is_err(), "{}", patch);
+        }
+    }
+
+    #[test]
+    fn should_apply_merge_patch() {
+        let doc = br#"{"a":"b","c":{"d":"e","f":"g"}}"#;
+        let patched = apply(
+            PatchFormat::MergePatch,
+            Some(doc),
+            br#"{"a":"z","c":{"f":null},"h":[1]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            json!({"a": "z", "c": {"d": "e"}, "h": [1]}),
+            serde_json::from_slice::<Value>(&patched).unwrap()
+        );
+
+        let created = apply(PatchFormat::MergePatch, None, br#"{"a":{"b":null,"c":1}}"#).unwrap();
+        assert_eq!(br#"{"a":{"c":1}}"#.to_vec(), created);
+        assert!(apply(PatchFormat::MergePatch, Some(b"not json"), b"{}").is_err());
+    }
+
+    #[test]
+    fn should_detect_patch_content_types() {
+        assert_eq!(
+            Some(PatchFormat::JsonPatch),
+            PatchFormat::from_content_type("application/json-patch+json")
+        );
+        assert_eq!(
+            Some(PatchFormat::MergePatch),
+            PatchFormat::from_content_type("Application/Merge-Patch+JSON; charset=utf-8")
+        );
+        assert_eq!(None, PatchFormat::from_content_type("application/json"));
+    }
+}
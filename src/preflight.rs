@@ -0,0 +1,206 @@
+// Checks run at startup before anything is opened.
+//
+// A low open files limit, a full disk or a read-only data path otherwise surface as RocksDb IO errors under load,
+// long after startup. Every path Rocky writes to is created if missing and probed with a preallocated file, the
+// available disk space is checked against min_free_disk_mb and the soft open files limit is raised up to
+// min_fd_limit when raise_fd_limit is set. All problems are reported together and the process exits.
+
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::mem::MaybeUninit;
+use std::path::Path;
+
+use anyhow::anyhow;
+
+const PROBE_FILE: &str = ".rocky-preflight";
+const PROBE_BYTES: usize = 1024 * 1024;
+
+pub struct Preflight {
+    pub min_fd_limit: u64,
+    pub raise_fd_limit: bool,
+    pub min_free_bytes: u64,
+    // name of the path in messages and the path
+    pub paths: Vec<(&'static str, String)>,
+}
+
+impl Preflight {
+    // notes on adjustments made, an error listing every problem found otherwise
+    pub fn run(&self) -> anyhow::Result<Vec<String>> {
+        let mut notes = vec![];
+        let mut problems = vec![];
+
+        match check_fd_limit(self.min_fd_limit, self.raise_fd_limit) {
+            Ok(Some(note)) => notes.push(note),
+            Ok(None) => {}
+            Err(problem) => problems.push(problem),
+        }
+        for (name, path) in &self.paths {
+            if let Err(problem) = check_writable(name, path) {
+                problems.push(problem);
+                continue;
+            }
+            if let Err(problem) = check_free_space(name, path, self.min_free_bytes) {
+                problems.push(problem);
+            }
+        }
+
+        if problems.is_empty() {
+            return Ok(notes);
+        }
+        Err(anyhow!("Startup checks failed:\n{}", problems.join("\n")))
+    }
+}
+
+fn check_fd_limit(min: u64, raise: bool) -> Result<Option<String>, String> {
+    if min == 0 {
+        return Ok(None);
+    }
+    let (soft, hard) = fd_limit().map_err(|e| format!("Can't read open files limit - {}", e))?;
+    if soft >= min {
+        return Ok(None);
+    }
+
+    let fix = format!(
+        "raise it with ulimit -n {} or LimitNOFILE={} in the systemd unit, or lower min_fd_limit",
+        min, min
+    );
+    if !raise {
+        return Err(format!(
+            "Open files limit {} is below min_fd_limit {} - {}",
+            soft, min, fix
+        ));
+    }
+    let target = min.min(hard);
+    set_fd_limit(target, hard)
+        .map_err(|e| format!("Can't raise open files limit to {} - {}", target, e))?;
+    if target < min {
+        return Err(format!(
+            "Open files limit can be raised only to the hard limit {}, below min_fd_limit {} - {}",
+            hard, min, fix
+        ));
+    }
+    Ok(Some(format!(
+        "Raised open files limit from {} to {}",
+        soft, target
+    )))
+}
+
+fn fd_limit() -> io::Result<(u64, u64)> {
+    let mut limit = MaybeUninit::<libc::rlimit>::uninit();
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, limit.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let limit = unsafe { limit.assume_init() };
+    Ok((limit.rlim_cur as u64, limit.rlim_max as u64))
+}
+
+fn set_fd_limit(soft: u64, hard: u64) -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: soft as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    };
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// the probe is allocated and synced so a full disk or a quota fails here too, not only missing permissions
+fn check_writable(name: &str, path: &str) -> Result<(), String> {
+    let probe = Path::new(path).join(PROBE_FILE);
+    let written = fs::create_dir_all(path).and_then(|_| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&probe)?;
+        file.write_all(&vec![0; PROBE_BYTES])?;
+        file.sync_all()
+    });
+    let _ = fs::remove_file(&probe);
+    written.map_err(|e| {
+        format!(
+            "Can't write to {} path {} - {}, check it exists and is writable by the rocky user",
+            name, path, e
+        )
+    })
+}
+
+fn check_free_space(name: &str, path: &str, min: u64) -> Result<(), String> {
+    if min == 0 {
+        return Ok(());
+    }
+    let free = free_bytes(path)
+        .map_err(|e| format!("Can't read free space of {} path {} - {}", name, path, e))?;
+    if free < min {
+        return Err(format!(
+            "Only {} MB free on {} path {}, below min_free_disk_mb {} - free up or grow the volume",
+            free / 1024 / 1024,
+            name,
+            path,
+            min / 1024 / 1024
+        ));
+    }
+    Ok(())
+}
+
+// available to unprivileged users, the reserved blocks don't count
+fn free_bytes(path: &str) -> io::Result<u64> {
+    let c_path = CString::new(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_pass_on_writable_paths() {
+        let dir = "./db/should_pass_on_writable_paths";
+        let preflight = Preflight {
+            min_fd_limit: 0,
+            raise_fd_limit: false,
+            min_free_bytes: 1,
+            paths: vec![("data", format!("{}/data", dir))],
+        };
+        assert!(preflight.run().unwrap().is_empty());
+        assert!(Path::new(dir).join("data").exists());
+        assert!(!Path::new(dir).join("data").join(PROBE_FILE).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_report_all_problems() {
+        let dir = "./db/should_report_all_problems";
+        fs::create_dir_all(dir).unwrap();
+        let file = format!("{}/file", dir);
+        fs::write(&file, b"not a dir").unwrap();
+
+        let preflight = Preflight {
+            min_fd_limit: 0,
+            raise_fd_limit: false,
+            min_free_bytes: u64::MAX,
+            paths: vec![("data", format!("{}/data", file)), ("log", dir.into())],
+        };
+        let err = preflight.run().unwrap_err().to_string();
+        assert!(err.contains("Can't write to data path"), "{}", err);
+        assert!(err.contains("min_free_disk_mb"), "{}", err);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_check_fd_limit() {
+        let (soft, _) = fd_limit().unwrap();
+        assert_eq!(Ok(None), check_fd_limit(0, false));
+        assert_eq!(Ok(None), check_fd_limit(soft, false));
+        if soft < u64::MAX {
+            assert!(check_fd_limit(soft + 1, false).is_err());
+        }
+    }
+}
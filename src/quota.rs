@@ -0,0 +1,104 @@
+// Per db quotas on the number of keys and stored bytes.
+//
+// Usage is approximate - keys are the RocksDb estimate and bytes are the live data in sst files plus memtables,
+// both lag behind deletes until compaction. A db at its quota rejects writes with 507 Insufficient Storage
+// (or 429 Too Many Requests with quota_status = 429) while reads and deletes go on so it can be cleaned up.
+// Replicated changes are never rejected so replicas don't diverge.
+
+use anyhow::anyhow;
+use serde::Serialize;
+
+use crate::errors::ErrorCtx;
+
+pub const INSUFFICIENT_STORAGE: u16 = 507;
+pub const TOO_MANY_REQUESTS: u16 = 429;
+
+// 0 is unlimited
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Quota {
+    pub max_keys: u64,
+    pub max_bytes: u64,
+    #[serde(skip)]
+    pub status: u16,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaUsage {
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaInfo {
+    #[serde(flatten)]
+    pub quota: Quota,
+    #[serde(flatten)]
+    pub usage: QuotaUsage,
+}
+
+impl Quota {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_keys == 0 && self.max_bytes == 0
+    }
+
+    pub fn check(&self, db_name: &str, usage: QuotaUsage) -> anyhow::Result<()> {
+        let exceeded = if self.max_keys > 0 && usage.keys >= self.max_keys {
+            format!("{} keys of max {}", usage.keys, self.max_keys)
+        } else if self.max_bytes > 0 && usage.bytes >= self.max_bytes {
+            format!("{} bytes of max {}", usage.bytes, self.max_bytes)
+        } else {
+            return Ok(());
+        };
+
+        let msg = format!("Db {} is over its quota - {}", db_name, exceeded);
+        if self.status == TOO_MANY_REQUESTS {
+            Err(anyhow!(ErrorCtx::TooManyRequests(msg)))
+        } else {
+            Err(anyhow!(ErrorCtx::InsufficientStorage(msg)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(max_keys: u64, max_bytes: u64, status: u16) -> Quota {
+        Quota {
+            max_keys,
+            max_bytes,
+            status,
+        }
+    }
+
+    #[test]
+    fn should_reject_at_quota() {
+        let usage = QuotaUsage {
+            keys: 10,
+            bytes: 1000,
+        };
+        assert!(quota(0, 0, INSUFFICIENT_STORAGE).is_unlimited());
+        assert!(quota(0, 0, INSUFFICIENT_STORAGE).check("db", usage).is_ok());
+        assert!(quota(11, 1001, INSUFFICIENT_STORAGE)
+            .check("db", usage)
+            .is_ok());
+
+        let err = quota(10, 0, INSUFFICIENT_STORAGE)
+            .check("db", usage)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ErrorCtx>(),
+            Some(ErrorCtx::InsufficientStorage(_))
+        ));
+        let err = quota(0, 1000, TOO_MANY_REQUESTS)
+            .check("db", usage)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ErrorCtx>(),
+            Some(ErrorCtx::TooManyRequests(_))
+        ));
+    }
+}
@@ -0,0 +1,610 @@
+// Asynchronous replication of a db to another Rocky deployment, e.g. a passive copy in a second datacenter.
+//
+// Changes of a db with replicate_to are shipped in batches to POST {replicate_to}/{db}/_replicate of the
+// remote deployment. A change carries the record as it is now along with the time it was written, the remote
+// resolves conflicts with its own copy by its replication_conflict policy. The whole db is resent when
+// shipping starts and whenever the shipper falls too far behind the writes - a delete missed meanwhile is
+// not repeated. Batches go through the outbound queue, so the ones a restart interrupts are shipped first by
+// the next run.
+//
+// The shipper acknowledges the sequence numbers the remote accepted, writes to a db with write_concern =
+// "replicated" wait for that acknowledgment up to replication_ack_timeout_ms. A completed resend acknowledges
+// everything written before it started.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use actix_web::client::Client;
+use actix_web::rt::time::delay_for;
+use anyhow::anyhow;
+use futures::channel::oneshot;
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConflictPolicy;
+use crate::conversion::current_ms;
+use crate::errors::ErrorCtx;
+use crate::key_encoding::{display_key, KeyEncoding};
+use crate::metrics::REPLICATION_LAG;
+use crate::outbound::{Delivery, Kind, Outbox};
+use crate::NO_TTL;
+
+// events buffered for a shipper before it falls behind and resends the db
+pub const REPLICATION_BUFFER: usize = 65_536;
+pub const MAX_BATCH: usize = 1000;
+// how often an idle shipper checks whether its db was closed
+pub const STOP_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const SHIP_TIMEOUT: Duration = Duration::from_secs(30);
+// how often writes waiting for an acknowledgment are checked for their timeout
+const ACK_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(into = "ChangeJson", try_from = "ChangeJson")]
+pub struct Change {
+    pub key: Vec<u8>,
+    // base64 encoded, absent for deletes
+    pub value: Option<String>,
+    // exclusive end of a range delete starting at key
+    pub end: Option<Vec<u8>>,
+    pub ttl: u128,
+    pub soft_ttl: u128,
+    pub written_at: u128,
+    pub content_type: Option<String>,
+}
+
+// a change as it's shipped, keys that aren't UTF-8 are base64 encoded with keyEncoding set
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChangeJson {
+    key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_encoding: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    end: Option<String>,
+    #[serde(default)]
+    ttl: u128,
+    #[serde(default)]
+    soft_ttl: u128,
+    written_at: u128,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+}
+
+// decoded change
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replicated {
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+    pub end: Option<Vec<u8>>,
+    pub ttl: u128,
+    pub soft_ttl: u128,
+    pub written_at: u128,
+    pub content_type: Option<String>,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplicationResult {
+    pub applied: usize,
+    // changes losing to the local copy
+    pub skipped: usize,
+}
+
+// running shippers by db
+#[derive(Default)]
+pub struct Replicas {
+    shippers: Mutex<HashMap<String, Arc<Shipper>>>,
+}
+
+pub struct Shipper {
+    running: AtomicBool,
+    acks: Mutex<Acks>,
+}
+
+// highest sequence number the remote accepted and the writes waiting for theirs, a dropped sender fails the wait
+#[derive(Default)]
+struct Acks {
+    seq: u64,
+    waiting: Vec<(u64, Instant, oneshot::Sender<()>)>,
+}
+
+impl Change {
+    pub fn put(key: &[u8], value: &[u8], ttl: u128, soft_ttl: u128, written_at: u128) -> Self {
+        Change {
+            key: key.to_vec(),
+            value: Some(base64::encode(value)),
+            end: None,
+            ttl,
+            soft_ttl,
+            written_at,
+            content_type: None,
+        }
+    }
+
+    pub fn with_content_type(mut self, content_type: Option<&str>) -> Self {
+        self.content_type = content_type.map(String::from);
+        self
+    }
+
+    pub fn delete(key: &[u8], deleted_at: u128) -> Self {
+        Change {
+            key: key.to_vec(),
+            value: None,
+            end: None,
+            ttl: NO_TTL,
+            soft_ttl: NO_TTL,
+            written_at: deleted_at,
+            content_type: None,
+        }
+    }
+
+    pub fn delete_range(start: &[u8], end: &[u8], deleted_at: u128) -> Self {
+        Change {
+            end: Some(end.to_vec()),
+            ..Change::delete(start, deleted_at)
+        }
+    }
+
+    pub fn from_json(body: &[u8]) -> anyhow::Result<Vec<Self>> {
+        serde_json::from_slice(body).map_err(|e| {
+            anyhow!(ErrorCtx::Validation(format!(
+                "Malformed replicated changes - {}",
+                e
+            )))
+        })
+    }
+
+    pub fn decode(self) -> anyhow::Result<Replicated> {
+        let value = match self.value {
+            Some(value) => Some(base64::decode(&value).map_err(|_| {
+                anyhow!(ErrorCtx::Validation(format!(
+                    "Value of key {} is not valid base64",
+                    display_key(&self.key)
+                )))
+            })?),
+            None => None,
+        };
+        Ok(Replicated {
+            key: self.key,
+            value,
+            end: self.end,
+            ttl: self.ttl,
+            soft_ttl: self.soft_ttl,
+            written_at: self.written_at,
+            content_type: self.content_type,
+        })
+    }
+}
+
+impl From<Change> for ChangeJson {
+    fn from(change: Change) -> Self {
+        let encoding = KeyEncoding::of_document(
+            std::iter::once(change.key.as_slice()).chain(change.end.as_deref()),
+        );
+        // every key is valid in the encoding of the document
+        let encode = |key: &[u8]| encoding.encode(key).unwrap_or_default();
+        ChangeJson {
+            key: encode(&change.key),
+            key_encoding: encoding.header_value().map(String::from),
+            value: change.value,
+            end: change.end.as_deref().map(encode),
+            ttl: change.ttl,
+            soft_ttl: change.soft_ttl,
+            written_at: change.written_at,
+            content_type: change.content_type,
+        }
+    }
+}
+
+impl TryFrom<ChangeJson> for Change {
+    type Error = anyhow::Error;
+
+    fn try_from(json: ChangeJson) -> anyhow::Result<Self> {
+        let encoding = KeyEncoding::from_document(json.key_encoding.as_deref())?;
+        Ok(Change {
+            key: encoding.decode(&json.key)?,
+            value: json.value,
+            end: json.end.map(|end| encoding.decode(&end)).transpose()?,
+            ttl: json.ttl,
+            soft_ttl: json.soft_ttl,
+            written_at: json.written_at,
+            content_type: json.content_type,
+        })
+    }
+}
+
+impl Replicated {
+    // whether the change replaces the local record (written_at, value) - with last writer wins a tie goes to
+    // the greater value so both sides settle on the same record, and a change echoed back is skipped
+    pub fn wins(&self, policy: ConflictPolicy, local: Option<(u128, &[u8])>) -> bool {
+        match (policy, local) {
+            // nothing to delete
+            (_, None) => self.value.is_some(),
+            (ConflictPolicy::Overwrite, Some(_)) => true,
+            (ConflictPolicy::Lww, Some((written_at, value))) => {
+                self.written_at > written_at
+                    || (self.written_at == written_at
+                        && self.value.as_deref().map_or(false, |v| v > value))
+            }
+        }
+    }
+}
+
+impl Replicas {
+    // a running shipper of the db is stopped, writes waiting for it fail - the new one acknowledges nothing
+    // before its resend
+    pub fn start(&self, db_name: &str) -> Arc<Shipper> {
+        let shipper = Arc::new(Shipper {
+            running: AtomicBool::new(true),
+            acks: Mutex::new(Acks::default()),
+        });
+        if let Some(previous) = self
+            .shippers
+            .lock()
+            .expect("Can't acquire replicas lock")
+            .insert(db_name.into(), shipper.clone())
+        {
+            previous.stop();
+        }
+        shipper
+    }
+
+    pub fn stop(&self, db_name: &str) {
+        if let Some(shipper) = self
+            .shippers
+            .lock()
+            .expect("Can't acquire replicas lock")
+            .remove(db_name)
+        {
+            shipper.stop();
+        }
+    }
+
+    // false when the remote didn't accept the write in time or the db isn't replicated
+    pub async fn acknowledged(&self, db_name: &str, seq: u64, wait: Duration) -> bool {
+        let shipper = self
+            .shippers
+            .lock()
+            .expect("Can't acquire replicas lock")
+            .get(db_name)
+            .cloned();
+        let acked = match shipper {
+            Some(shipper) => {
+                let mut acks = shipper.acks();
+                if acks.seq >= seq {
+                    return true;
+                }
+                let (tx, rx) = oneshot::channel();
+                acks.waiting.push((seq, Instant::now() + wait, tx));
+                rx
+            }
+            None => return false,
+        };
+        acked.await.is_ok()
+    }
+}
+
+impl Shipper {
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    // writes up to seq are on the remote
+    pub fn acknowledge(&self, seq: u64) {
+        let mut acks = self.acks();
+        acks.seq = acks.seq.max(seq);
+        let acked = acks.seq;
+        for (_, _, tx) in drain(&mut acks.waiting, |(seq, _, _)| *seq <= acked) {
+            let _ = tx.send(());
+        }
+    }
+
+    // fails overdue waits, it's run alongside the shipping until that ends
+    pub async fn expire_acks(&self) {
+        loop {
+            delay_for(ACK_CHECK_INTERVAL).await;
+            let now = Instant::now();
+            drain(&mut self.acks().waiting, |(_, deadline, _)| {
+                *deadline <= now
+            });
+        }
+    }
+
+    fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+        self.acks().waiting.clear();
+    }
+
+    fn acks(&self) -> MutexGuard<'_, Acks> {
+        self.acks
+            .lock()
+            .expect("Can't acquire replication acks lock")
+    }
+}
+
+fn drain<T>(items: &mut Vec<T>, matches: impl Fn(&T) -> bool) -> Vec<T> {
+    let (drained, kept) = items.drain(..).partition(|item| matches(item));
+    *items = kept;
+    drained
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shipped {
+    Delivered,
+    // attempts ran out, the remote misses the batch until the db is resent
+    DeadLettered,
+    Stopped,
+}
+
+pub fn replication_target(url: &str, db_name: &str) -> String {
+    format!("{}/{}/_replicate", url.trim_end_matches('/'), db_name)
+}
+
+// the batch is queued before it's sent, so one a restart interrupts is shipped by the next run - lag is recorded
+// from the oldest write of live changes, resent records are as old as they are
+pub async fn ship(
+    client: &Client,
+    outbox: &Outbox,
+    url: &str,
+    db_name: &str,
+    changes: &[Change],
+    live: bool,
+    shipper: &Shipper,
+) -> Shipped {
+    let target = replication_target(url, db_name);
+    let oldest = changes.iter().map(|c| c.written_at).min().filter(|_| live);
+    let body = match serde_json::to_string(changes) {
+        Ok(body) => body,
+        Err(e) => {
+            error!(
+                "Failed to serialize replication batch of db = {}, e = {}",
+                db_name, e
+            );
+            return Shipped::DeadLettered;
+        }
+    };
+    let queued = match outbox.push(Kind::Replication, db_name, &target, body.clone()) {
+        Ok(delivery) => Some(delivery),
+        Err(e) => {
+            warn!(
+                "Failed to queue replication batch of db = {}, e = {}",
+                db_name, e
+            );
+            None
+        }
+    };
+    deliver(
+        client, outbox, db_name, &target, body, queued, oldest, shipper,
+    )
+    .await
+}
+
+// a batch left queued by a previous run
+pub async fn reship(
+    client: &Client,
+    outbox: &Outbox,
+    mut delivery: Delivery,
+    shipper: &Shipper,
+) -> Shipped {
+    if let Err(e) = outbox.body(&mut delivery) {
+        error!(
+            "Failed to read replication batch {}, e = {}",
+            delivery.id, e
+        );
+        return Shipped::DeadLettered;
+    }
+    let body = std::mem::take(&mut delivery.body);
+    let (db_name, target) = (delivery.db.clone(), delivery.target.clone());
+    deliver(
+        client,
+        outbox,
+        &db_name,
+        &target,
+        body,
+        Some(delivery),
+        None,
+        shipper,
+    )
+    .await
+}
+
+// retries until the remote accepts the batch, its attempts run out or the shipper is stopped - a stopped shipper
+// leaves the batch queued
+#[allow(clippy::too_many_arguments)]
+async fn deliver(
+    client: &Client,
+    outbox: &Outbox,
+    db_name: &str,
+    target: &str,
+    body: String,
+    mut queued: Option<Delivery>,
+    oldest: Option<u128>,
+    shipper: &Shipper,
+) -> Shipped {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let error = match client
+            .post(target)
+            .timeout(SHIP_TIMEOUT)
+            .content_type("application/json")
+            .send_body(body.clone())
+            .await
+        {
+            Ok(res) if res.status().is_success() => {
+                if let Some(oldest) = oldest {
+                    record_lag(db_name, oldest);
+                }
+                if let Some(delivery) = &queued {
+                    if let Err(e) = outbox.done(delivery) {
+                        warn!(
+                            "Failed to remove replication batch {} from the outbound queue, e = {}",
+                            delivery.id, e
+                        );
+                    }
+                }
+                return Shipped::Delivered;
+            }
+            Ok(res) => format!("answered with {}", res.status()),
+            Err(e) => e.to_string(),
+        };
+        warn!(
+            "Replication of db = {} to {} failed, e = {}",
+            db_name, target, &error
+        );
+
+        if let Some(delivery) = queued.as_mut() {
+            match outbox.retry(delivery, error, backoff) {
+                Ok(true) => {
+                    error!(
+                        "Replication batch {} of db = {} dead-lettered after {} attempts",
+                        delivery.id, db_name, delivery.attempts
+                    );
+                    return Shipped::DeadLettered;
+                }
+                Ok(false) => {}
+                Err(e) => warn!(
+                    "Failed to update replication batch of db = {}, e = {}",
+                    db_name, e
+                ),
+            }
+        }
+
+        // the lag keeps growing while the remote is unreachable
+        if let Some(oldest) = oldest {
+            record_lag(db_name, oldest);
+        }
+        delay_for(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        if !shipper.is_running() {
+            return Shipped::Stopped;
+        }
+    }
+}
+
+fn record_lag(db_name: &str, written_at: u128) {
+    let lag = current_ms().unwrap_or_default().saturating_sub(written_at);
+    REPLICATION_LAG
+        .with_label_values(&[db_name])
+        .set(lag as i64);
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    fn put(value: &[u8], written_at: u128) -> Replicated {
+        Change::put(b"k", value, NO_TTL, NO_TTL, written_at)
+            .decode()
+            .unwrap()
+    }
+
+    #[test]
+    fn should_decode_changes() {
+        let changes = Change::from_json(
+            br#"[
+                {"key": "a", "value": "djE=", "ttl": 10, "softTtl": 5, "writtenAt": 1, "contentType": "text/plain"},
+                {"key": "b", "writtenAt": 2},
+                {"key": "c", "end": "d", "writtenAt": 3}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Change::put(b"a", b"v1", 10, 5, 1).with_content_type(Some("text/plain")),
+            changes[0]
+        );
+        assert_eq!(Change::delete(b"b", 2), changes[1]);
+        assert_eq!(Change::delete_range(b"c", b"d", 3), changes[2]);
+
+        assert!(Change::from_json(b"{}").is_err());
+        let invalid = Change {
+            value: Some("not base64!".into()),
+            ..Change::delete(b"a", 1)
+        };
+        assert!(invalid.decode().is_err());
+    }
+
+    #[test]
+    fn should_ship_binary_keys() {
+        let changes = vec![
+            Change::delete_range(&[0, 255], b"z", 1),
+            Change::delete(b"k", 2),
+        ];
+        let json = serde_json::to_string(&changes).unwrap();
+        assert_eq!(
+            r#"[{"key":"AP8","keyEncoding":"base64","end":"eg","ttl":0,"softTtl":0,"writtenAt":1},{"key":"k","ttl":0,"softTtl":0,"writtenAt":2}]"#,
+            json
+        );
+        assert_eq!(changes, Change::from_json(json.as_bytes()).unwrap());
+        assert!(Change::from_json(br#"[{"key":"k","keyEncoding":"hex","writtenAt":1}]"#).is_err());
+    }
+
+    #[test]
+    fn should_resolve_conflicts_by_last_writer() {
+        let policy = ConflictPolicy::Lww;
+        assert!(put(b"b", 2).wins(policy, Some((1, b"a"))));
+        assert!(!put(b"b", 1).wins(policy, Some((2, b"a"))));
+        // ties go to the greater value, an echo of the local record is skipped
+        assert!(put(b"b", 1).wins(policy, Some((1, b"a"))));
+        assert!(!put(b"a", 1).wins(policy, Some((1, b"b"))));
+        assert!(!put(b"a", 1).wins(policy, Some((1, b"a"))));
+
+        let delete = Change::delete(b"k", 2).decode().unwrap();
+        assert!(delete.wins(policy, Some((1, b"a"))));
+        assert!(!delete.wins(policy, Some((3, b"a"))));
+        assert!(!delete.wins(policy, None));
+        assert!(put(b"a", 1).wins(policy, None));
+    }
+
+    #[test]
+    fn should_overwrite_regardless_of_time() {
+        let policy = ConflictPolicy::Overwrite;
+        assert!(put(b"a", 1).wins(policy, Some((2, b"b"))));
+        assert!(Change::delete(b"k", 1)
+            .decode()
+            .unwrap()
+            .wins(policy, Some((2, b"b"))));
+    }
+
+    #[test]
+    fn should_stop_previous_shipper() {
+        let replicas = Replicas::default();
+        let first = replicas.start("db");
+        let second = replicas.start("db");
+        assert!(!first.is_running());
+        assert!(second.is_running());
+
+        replicas.stop("db");
+        assert!(!second.is_running());
+    }
+
+    #[test]
+    fn should_wait_for_acknowledgment() {
+        block_on(async {
+            let replicas = Replicas::default();
+            let wait = Duration::from_secs(60);
+            assert!(!replicas.acknowledged("db", 1, wait).await);
+
+            let shipper = replicas.start("db");
+            shipper.acknowledge(3);
+            assert!(replicas.acknowledged("db", 2, wait).await);
+
+            let waiting = replicas.acknowledged("db", 5, wait);
+            let stopped = replicas.acknowledged("db", 9, wait);
+            futures::pin_mut!(waiting, stopped);
+            assert!(futures::poll!(waiting.as_mut()).is_pending());
+            assert!(futures::poll!(stopped.as_mut()).is_pending());
+            shipper.acknowledge(6);
+            assert!(waiting.await);
+            replicas.stop("db");
+            assert!(!stopped.await);
+        });
+    }
+}
@@ -0,0 +1,563 @@
+// Redis protocol (RESP) listener, so Redis clients can read and write records without HTTP.
+//
+// Enabled with redis_port in the service config, it listens on the same ip as the HTTP server. Every connection
+// is served by its own thread, commands are answered in order. Connections over redis_max_connections are refused
+// and idle ones are closed after redis_idle_timeout_secs. A connection starts on db "0" - the db Redis
+// clients select by default - and SELECT switches to any db by name, it has to be opened over HTTP first.
+// Supported are PING, ECHO, SELECT, GET, SET (EX, PX, NX, XX), DEL, EXISTS, EXPIRE, PEXPIRE, TTL, PTTL, COMMAND
+// and QUIT, anything else is answered with an error. Inline commands (as typed into telnet) are accepted too.
+
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use actix_web::web::Bytes;
+use anyhow::anyhow;
+use futures::executor::block_on;
+
+use crate::conversion::current_ms;
+use crate::db::{DbManager, Representation, WriteCondition};
+use crate::errors::ErrorCtx;
+
+// same as values stored over HTTP
+const MAX_BULK_BYTES: usize = 256 * 1024;
+const MAX_ARGS: usize = 10_000;
+const MAX_LINE_BYTES: usize = 64 * 1024;
+const DEFAULT_DB: &str = "0";
+const NO_TTL: u128 = 0;
+const COMMANDS: [&str; 13] = [
+    "PING", "ECHO", "QUIT", "COMMAND", "SELECT", "GET", "SET", "DEL", "EXISTS", "EXPIRE",
+    "PEXPIRE", "TTL", "PTTL",
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_connections: usize,
+    // None keeps idle connections open
+    pub idle_timeout: Option<Duration>,
+}
+
+// state of a single connection
+pub struct Session {
+    db_man: Arc<DbManager>,
+    db_name: String,
+    closed: bool,
+}
+
+pub fn listen(address: &str, db_man: Arc<DbManager>, limits: Limits) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    info!("Redis protocol listening on {}", address);
+    thread::Builder::new()
+        .name("resp-listener".into())
+        .spawn(move || serve(listener, db_man, limits))?;
+    Ok(())
+}
+
+// releases the connection slot when the connection thread ends
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn serve(listener: TcpListener, db_man: Arc<DbManager>, limits: Limits) {
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept redis connection, e = {}", e);
+                continue;
+            }
+        };
+        if connections.fetch_add(1, Ordering::SeqCst) >= limits.max_connections {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            let _ = Reply::error("max number of clients reached").write_to(&mut stream);
+            continue;
+        }
+        let slot = Slot(connections.clone());
+        if let Err(e) = stream
+            .set_read_timeout(limits.idle_timeout)
+            .and_then(|_| stream.set_write_timeout(limits.idle_timeout))
+        {
+            warn!("Failed to set redis connection timeout, e = {}", e);
+            continue;
+        }
+        let db_man = db_man.clone();
+        let spawned = thread::Builder::new()
+            .name("resp-connection".into())
+            .spawn(move || {
+                let _slot = slot;
+                if let Err(e) = handle(stream, db_man) {
+                    debug!("Redis connection closed, e = {}", e);
+                }
+            });
+        if let Err(e) = spawned {
+            error!("Failed to spawn redis connection thread, e = {}", e);
+        }
+    }
+}
+
+fn handle(stream: TcpStream, db_man: Arc<DbManager>) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut session = Session::new(db_man);
+    while !session.closed {
+        let reply = match read_command(&mut reader) {
+            Ok(Some(args)) => session.execute(args),
+            Ok(None) => return Ok(()),
+            Err(e) if is_timeout(&e) => return Ok(()),
+            // the rest of the stream can't be framed anymore
+            Err(e) => {
+                session.closed = true;
+                Reply::Error(format!("ERR Protocol error: {}", e))
+            }
+        };
+        reply.write_to(&mut writer)?;
+        // replies of pipelined commands are sent together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// None once the client has closed the connection, blank lines and empty arrays are skipped
+fn read_command(reader: &mut impl BufRead) -> anyhow::Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if line.first() != Some(&b'*') {
+            let args: Vec<Vec<u8>> = line
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+            if !args.is_empty() {
+                return Ok(Some(args));
+            }
+            continue;
+        }
+        match parse_len(&line[1..], MAX_ARGS)? {
+            0 => continue,
+            count => return read_args(reader, count).map(Some),
+        }
+    }
+}
+
+fn read_args(reader: &mut impl BufRead, count: usize) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or_else(|| anyhow!("unexpected end of stream"))?;
+        if header.first() != Some(&b'$') {
+            return Err(anyhow!(
+                "expected '$', got '{}'",
+                String::from_utf8_lossy(&header)
+            ));
+        }
+        let len = parse_len(&header[1..], MAX_BULK_BYTES)?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(anyhow!("bulk string isn't terminated by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+// without the line ending
+fn read_line(reader: &mut impl BufRead) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut line = vec![];
+    let read = reader
+        .by_ref()
+        .take(MAX_LINE_BYTES as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(anyhow!("line is too long or unterminated"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(bytes: &[u8], max: usize) -> anyhow::Result<usize> {
+    let len = std::str::from_utf8(bytes)?
+        .parse::<usize>()
+        .map_err(|_| anyhow!("invalid length '{}'", String::from_utf8_lossy(bytes)))?;
+    if len > max {
+        return Err(anyhow!("length {} is over {}", len, max));
+    }
+    Ok(len)
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Reply::Status("OK")
+    }
+
+    fn error(msg: &str) -> Self {
+        Reply::Error(format!("ERR {}", msg))
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> std::io::Result<()> {
+        match self {
+            Reply::Status(s) => write!(out, "+{}\r\n", s),
+            // line breaks would end the error early
+            Reply::Error(e) => write!(out, "-{}\r\n", e.replace(&['\r', '\n'][..], " ")),
+            Reply::Integer(i) => write!(out, ":{}\r\n", i),
+            Reply::Bulk(Some(bytes)) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Bulk(None) => out.write_all(b"$-1\r\n"),
+            Reply::Array(replies) => {
+                write!(out, "*{}\r\n", replies.len())?;
+                replies.iter().try_for_each(|reply| reply.write_to(out))
+            }
+        }
+    }
+}
+
+impl From<anyhow::Error> for Reply {
+    fn from(e: anyhow::Error) -> Self {
+        Reply::error(&e.to_string())
+    }
+}
+
+impl Session {
+    pub fn new(db_man: Arc<DbManager>) -> Self {
+        Session {
+            db_man,
+            db_name: DEFAULT_DB.into(),
+            closed: false,
+        }
+    }
+
+    pub fn execute(&mut self, args: Vec<Vec<u8>>) -> Reply {
+        let (name, args) = match args.split_first() {
+            Some((name, args)) => (String::from_utf8_lossy(name).to_string(), args),
+            None => return Reply::error("empty command"),
+        };
+        let result = match (name.to_uppercase().as_str(), args.len()) {
+            ("PING", 0) => Ok(Reply::Status("PONG")),
+            ("PING", 1) | ("ECHO", 1) => Ok(Reply::Bulk(Some(args[0].clone()))),
+            ("QUIT", _) => {
+                self.closed = true;
+                Ok(Reply::ok())
+            }
+            ("COMMAND", _) => Ok(Reply::Array(vec![])),
+            ("SELECT", 1) => self.select(&args[0]),
+            ("GET", 1) => self.get(&args[0]),
+            ("SET", n) if n >= 2 => self.set(args),
+            ("DEL", n) if n >= 1 => self.del(args),
+            ("EXISTS", n) if n >= 1 => self.exists(args),
+            ("EXPIRE", 2) => self.expire(args, 1000),
+            ("PEXPIRE", 2) => self.expire(args, 1),
+            ("TTL", 1) => self.ttl(&args[0], 1000),
+            ("PTTL", 1) => self.ttl(&args[0], 1),
+            (command, _) if COMMANDS.contains(&command) => Err(anyhow!(
+                "wrong number of arguments for '{}' command",
+                name.to_lowercase()
+            )),
+            _ => Err(anyhow!("unknown command '{}'", name)),
+        };
+        result.unwrap_or_else(Reply::from)
+    }
+
+    fn select(&mut self, db_name: &[u8]) -> anyhow::Result<Reply> {
+        let db_name = utf8(db_name)?;
+        if !self.db_man.contains(&db_name) {
+            return Err(anyhow!(ErrorCtx::Validation(format!(
+                "Db {} - doesn't exist",
+                db_name
+            ))));
+        }
+        self.db_name = db_name;
+        Ok(Reply::ok())
+    }
+
+    fn get(&self, key: &[u8]) -> anyhow::Result<Reply> {
+        let data = block_on(self.db_man.read(&self.db_name, key))?;
+        Ok(Reply::Bulk(data.map(|data| data.into_value())))
+    }
+
+    // SET key value [EX seconds | PX milliseconds] [NX | XX]
+    fn set(&self, args: &[Vec<u8>]) -> anyhow::Result<Reply> {
+        let mut ttl = NO_TTL;
+        let mut condition = WriteCondition::Always;
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            match String::from_utf8_lossy(option).to_uppercase().as_str() {
+                unit @ "EX" | unit @ "PX" if ttl == NO_TTL => {
+                    let value = options.next().ok_or_else(syntax_error)?;
+                    let ms = positive(value)? * if unit == "EX" { 1000 } else { 1 };
+                    ttl = current_ms()? + ms;
+                }
+                "NX" if condition == WriteCondition::Always => condition = WriteCondition::IfAbsent,
+                "XX" if condition == WriteCondition::Always => {
+                    condition = WriteCondition::IfPresent
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+
+        let stored = block_on(self.db_man.store(
+            &self.db_name,
+            &args[0],
+            Bytes::from(args[1].clone()),
+            ttl,
+            NO_TTL,
+            Representation::default(),
+            condition,
+        ));
+        match stored {
+            Ok(_) => Ok(Reply::ok()),
+            // a failed NX or XX condition is a nil reply, not an error
+            Err(e) if condition != WriteCondition::Always && is_condition_error(&e) => {
+                Ok(Reply::Bulk(None))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // a key given twice is counted once
+    fn del(&self, keys: &[Vec<u8>]) -> anyhow::Result<Reply> {
+        let mut seen = HashSet::with_capacity(keys.len());
+        let keys: Vec<Vec<u8>> = keys.iter().filter(|k| seen.insert(*k)).cloned().collect();
+        let (found, _) = block_on(self.db_man.remove_many(&self.db_name, keys))?;
+        Ok(Reply::Integer(
+            found.into_iter().filter(|f| *f).count() as i64
+        ))
+    }
+
+    // a key given twice is counted twice
+    fn exists(&self, keys: &[Vec<u8>]) -> anyhow::Result<Reply> {
+        let records = block_on(self.db_man.read_many(&self.db_name, keys.to_vec()))?;
+        Ok(Reply::Integer(
+            records.iter().filter(|r| r.is_some()).count() as i64,
+        ))
+    }
+
+    // a timeout that isn't positive deletes the key, like in Redis
+    fn expire(&self, args: &[Vec<u8>], unit_ms: u128) -> anyhow::Result<Reply> {
+        let timeout = integer(&args[1])?;
+        if timeout <= 0 {
+            return self.del(&args[..1]);
+        }
+        let ttl = current_ms()? + timeout as u128 * unit_ms;
+        let touched = block_on(self.db_man.touch(&self.db_name, &args[0], ttl))?;
+        Ok(Reply::Integer(if touched.is_some() { 1 } else { 0 }))
+    }
+
+    // -2 for a missing key and -1 for a key without a ttl
+    fn ttl(&self, key: &[u8], unit_ms: u128) -> anyhow::Result<Reply> {
+        let data = block_on(self.db_man.read(&self.db_name, key))?;
+        let remaining = match data {
+            Some(data) => match data.remaining_ttl()? {
+                Some(ms) => ((ms + unit_ms / 2) / unit_ms) as i64,
+                None => -1,
+            },
+            None => -2,
+        };
+        Ok(Reply::Integer(remaining))
+    }
+}
+
+fn is_timeout(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<std::io::Error>().map(std::io::Error::kind),
+        Some(ErrorKind::WouldBlock) | Some(ErrorKind::TimedOut)
+    )
+}
+
+fn is_condition_error(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<ErrorCtx>(),
+        Some(ErrorCtx::NotFound(_)) | Some(ErrorCtx::Conflict(_))
+    )
+}
+
+fn syntax_error() -> anyhow::Error {
+    anyhow!("syntax error")
+}
+
+fn utf8(bytes: &[u8]) -> anyhow::Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| {
+        anyhow!(ErrorCtx::Validation(
+            "Key or db name isn't valid UTF-8".into()
+        ))
+    })
+}
+
+fn integer(bytes: &[u8]) -> anyhow::Result<i64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| anyhow!("value is not an integer or out of range"))
+}
+
+fn positive(bytes: &[u8]) -> anyhow::Result<u128> {
+    match integer(bytes)? {
+        i if i > 0 => Ok(i as u128),
+        _ => Err(anyhow!("invalid expire time in 'set' command")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::config::DbConfig;
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<Vec<u8>> {
+        args.iter().map(|a| a.as_bytes().to_vec()).collect()
+    }
+
+    fn encoded(reply: Reply) -> String {
+        let mut out = vec![];
+        reply.write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn should_read_commands() {
+        let mut input = Cursor::new(
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\nv\r\nv\r\nPING  hi\r\n\r\n*0\r\nQUIT\n".to_vec(),
+        );
+        assert_eq!(
+            Some(vec![b"SET".to_vec(), b"k".to_vec(), b"v\r\nv".to_vec()]),
+            read_command(&mut input).unwrap()
+        );
+        assert_eq!(
+            Some(args(&["PING", "hi"])),
+            read_command(&mut input).unwrap()
+        );
+        assert_eq!(Some(args(&["QUIT"])), read_command(&mut input).unwrap());
+        assert_eq!(None, read_command(&mut input).unwrap());
+
+        // skipping blank lines doesn't grow the stack
+        let mut input = Cursor::new(b"\r\n".repeat(1_000_000));
+        assert_eq!(None, read_command(&mut input).unwrap());
+
+        let mut input = Cursor::new(b"*1\r\n$3\r\nGETX\r\n".to_vec());
+        assert!(read_command(&mut input).is_err());
+        let mut input = Cursor::new(b"*1\r\n$999999999\r\n".to_vec());
+        assert!(read_command(&mut input).is_err());
+    }
+
+    #[test]
+    fn should_encode_replies() {
+        assert_eq!("+OK\r\n", encoded(Reply::ok()));
+        assert_eq!("-ERR a b\r\n", encoded(Reply::error("a\nb")));
+        assert_eq!(":-2\r\n", encoded(Reply::Integer(-2)));
+        assert_eq!("$-1\r\n", encoded(Reply::Bulk(None)));
+        assert_eq!(
+            "*2\r\n$1\r\nv\r\n$0\r\n\r\n",
+            encoded(Reply::Array(vec![
+                Reply::Bulk(Some(b"v".to_vec())),
+                Reply::Bulk(Some(vec![]))
+            ]))
+        );
+    }
+
+    #[test]
+    fn should_execute_commands() {
+        let db_man = Arc::new(DbManager::new(DbConfig::new_per_test_defaults()).unwrap());
+        block_on(db_man.open("0".into(), false, Default::default())).unwrap();
+        block_on(db_man.open("other".into(), false, Default::default())).unwrap();
+        let mut session = Session::new(db_man);
+
+        assert_eq!(Reply::Status("PONG"), session.execute(args(&["ping"])));
+        assert_eq!(Reply::Bulk(None), session.execute(args(&["GET", "k"])));
+        assert_eq!(Reply::ok(), session.execute(args(&["SET", "k", "v"])));
+        assert_eq!(
+            Reply::Bulk(None),
+            session.execute(args(&["SET", "k", "v", "NX"]))
+        );
+        assert_eq!(
+            Reply::Bulk(None),
+            session.execute(args(&["SET", "x", "v", "XX"]))
+        );
+        assert_eq!(
+            Reply::Bulk(Some(b"v".to_vec())),
+            session.execute(args(&["GET", "k"]))
+        );
+        assert_eq!(Reply::Integer(-1), session.execute(args(&["TTL", "k"])));
+        assert_eq!(
+            Reply::Integer(1),
+            session.execute(args(&["EXPIRE", "k", "100"]))
+        );
+        assert_eq!(Reply::Integer(100), session.execute(args(&["TTL", "k"])));
+        assert_eq!(
+            Reply::Integer(0),
+            session.execute(args(&["EXPIRE", "x", "100"]))
+        );
+        assert_eq!(
+            Reply::ok(),
+            session.execute(args(&["SET", "t", "v", "PX", "5000"]))
+        );
+        assert_eq!(Reply::Integer(5), session.execute(args(&["TTL", "t"])));
+        assert_eq!(Reply::Integer(-2), session.execute(args(&["PTTL", "x"])));
+        assert_eq!(
+            Reply::Integer(3),
+            session.execute(args(&["EXISTS", "k", "t", "k", "x"]))
+        );
+        assert_eq!(
+            Reply::Integer(2),
+            session.execute(args(&["DEL", "k", "t", "k", "x"]))
+        );
+
+        assert_eq!(Reply::ok(), session.execute(args(&["SELECT", "other"])));
+        assert_eq!(Reply::ok(), session.execute(args(&["SET", "k", "other"])));
+        assert_eq!(Reply::ok(), session.execute(args(&["SELECT", "0"])));
+        assert_eq!(Reply::Bulk(None), session.execute(args(&["GET", "k"])));
+        assert!(matches!(
+            session.execute(args(&["SELECT", "missing"])),
+            Reply::Error(_)
+        ));
+
+        assert!(matches!(
+            session.execute(args(&["SET", "k", "v", "EX", "0"])),
+            Reply::Error(_)
+        ));
+        assert_eq!(
+            Reply::error("wrong number of arguments for 'get' command"),
+            session.execute(args(&["GET"]))
+        );
+        assert_eq!(
+            Reply::error("unknown command 'hset'"),
+            session.execute(args(&["hset", "h", "f", "v"]))
+        );
+        assert_eq!(Reply::error("empty command"), session.execute(vec![]));
+        assert_eq!(Reply::ok(), session.execute(args(&["QUIT"])));
+        assert!(session.closed);
+    }
+}
@@ -0,0 +1,69 @@
+// RocksDB handle of a db together with the lock its writers take.
+//
+// RocksDB is thread safe on its own, the lock only orders read-modify-write sequences against each other. It guards
+// no data, so the db isn't behind it - snapshots can borrow the db for as long as they keep the handle referenced,
+// without holding the lock and blocking writers meanwhile.
+
+use std::ops::Deref;
+
+use crossbeam::sync::{ShardedLock, ShardedLockReadGuard, ShardedLockWriteGuard};
+use rocksdb::DB;
+
+pub struct Rock {
+    lock: ShardedLock<()>,
+    db: DB,
+}
+
+pub struct ReadGuard<'a> {
+    _guard: ShardedLockReadGuard<'a, ()>,
+    db: &'a DB,
+}
+
+pub struct WriteGuard<'a> {
+    _guard: ShardedLockWriteGuard<'a, ()>,
+    db: &'a DB,
+}
+
+impl Rock {
+    pub fn new(db: DB) -> Self {
+        Rock {
+            lock: ShardedLock::new(()),
+            db,
+        }
+    }
+
+    pub fn read(&self) -> ReadGuard<'_> {
+        ReadGuard {
+            _guard: self.lock.read().expect("Can't acquire read lock"),
+            db: &self.db,
+        }
+    }
+
+    pub fn write(&self) -> WriteGuard<'_> {
+        WriteGuard {
+            _guard: self.lock.write().expect("Can't acquire write lock"),
+            db: &self.db,
+        }
+    }
+
+    // without the lock, for snapshots
+    pub fn db(&self) -> &DB {
+        &self.db
+    }
+}
+
+impl<'a> Deref for ReadGuard<'a> {
+    type Target = DB;
+
+    fn deref(&self) -> &DB {
+        self.db
+    }
+}
+
+impl<'a> Deref for WriteGuard<'a> {
+    type Target = DB;
+
+    fn deref(&self) -> &DB {
+        self.db
+    }
+}
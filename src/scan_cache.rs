@@ -0,0 +1,130 @@
+// Short lived cache of listing results (_keys, _scan and exact _count) for dashboards repeating the same query.
+//
+// Results are cached by db and normalized parameters along with the db sequence number they were computed at,
+// a lookup only hits while the db hasn't advanced since and the entry is younger than the db's scan_cache_ms.
+// Records expiring don't advance the sequence, so a cached result can hold them for up to scan_cache_ms.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::metrics::SCAN_CACHE_LOOKUPS;
+
+pub const MAX_ENTRIES: usize = 1024;
+
+struct Entry {
+    seq: u64,
+    at: Instant,
+    value: Arc<dyn Any + Send + Sync>,
+}
+
+#[derive(Default)]
+pub struct ScanCache {
+    entries: Mutex<HashMap<(String, String), Entry>>,
+}
+
+impl ScanCache {
+    pub fn get<T>(&self, db_name: &str, query: &str, seq: u64, ttl: Duration) -> Option<T>
+    where
+        T: Clone + 'static,
+    {
+        let mut entries = self.lock();
+        let key = (db_name.to_string(), query.to_string());
+        let hit = match entries.get(&key) {
+            Some(e) if e.seq == seq && e.at.elapsed() < ttl => e.value.downcast_ref::<T>().cloned(),
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        };
+
+        let result = if hit.is_some() { "hit" } else { "miss" };
+        SCAN_CACHE_LOOKUPS.with_label_values(&[result]).inc();
+        hit
+    }
+
+    // the oldest entry makes room once the cache is full
+    pub fn put<T>(&self, db_name: &str, query: String, seq: u64, value: T)
+    where
+        T: Send + Sync + 'static,
+    {
+        let mut entries = self.lock();
+        let key = (db_name.to_string(), query);
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(&key) {
+            if let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, e)| e.at)
+                .map(|(k, _)| k.clone())
+            {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            key,
+            Entry {
+                seq,
+                at: Instant::now(),
+                value: Arc::new(value),
+            },
+        );
+    }
+
+    pub fn remove_db(&self, db_name: &str) {
+        self.lock().retain(|(db, _), _| db != db_name);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<(String, String), Entry>> {
+        self.entries.lock().expect("Can't acquire scan cache lock")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn should_hit_until_db_advances() {
+        let cache = ScanCache::default();
+        cache.put("db", "keys::100".into(), 7, vec!["k1".to_string()]);
+
+        assert_eq!(
+            Some(vec!["k1".to_string()]),
+            cache.get::<Vec<String>>("db", "keys::100", 7, TTL)
+        );
+        assert_eq!(None, cache.get::<Vec<String>>("db", "keys::10", 7, TTL));
+        assert_eq!(None, cache.get::<Vec<String>>("other", "keys::100", 7, TTL));
+        assert_eq!(None, cache.get::<Vec<String>>("db", "keys::100", 8, TTL));
+        // dropped once stale
+        assert_eq!(None, cache.get::<Vec<String>>("db", "keys::100", 7, TTL));
+    }
+
+    #[test]
+    fn should_expire_entries() {
+        let cache = ScanCache::default();
+        cache.put("db", "count:".into(), 1, 42u64);
+        assert_eq!(
+            None,
+            cache.get::<u64>("db", "count:", 1, Duration::from_millis(0))
+        );
+    }
+
+    #[test]
+    fn should_evict_oldest_when_full() {
+        let cache = ScanCache::default();
+        cache.put("db", "0".into(), 1, 0usize);
+        std::thread::sleep(Duration::from_millis(1));
+        for i in 1..=MAX_ENTRIES {
+            cache.put("db", i.to_string(), 1, i);
+        }
+        assert_eq!(MAX_ENTRIES, cache.lock().len());
+        assert_eq!(None, cache.get::<usize>("db", "0", 1, TTL));
+        assert_eq!(Some(1), cache.get::<usize>("db", "1", 1, TTL));
+
+        cache.remove_db("db");
+        assert!(cache.lock().is_empty());
+    }
+}
@@ -0,0 +1,227 @@
+// Snapshot consistent pagination of _keys and _scan.
+//
+// A listing reads its first page from a RocksDB snapshot. When there are more pages the snapshot is pinned and its id
+// travels in the cursor ({id}.{key}), so following pages read the same snapshot - records written or deleted meanwhile
+// don't appear or disappear mid listing. Expiration is still evaluated when a page is read. A pinned snapshot holds
+// back compaction of what it sees, it's released once nobody continued from it for scan_snapshot_ms and a cursor of a
+// released snapshot is answered with 410 Gone - the listing has to start over. Plain key cursors pin a new snapshot.
+// Closing, deleting or restoring the db releases all of its snapshots at once, they keep the db handle referenced.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use rocksdb::{DBIterator, DBRawIterator, IteratorMode, Snapshot};
+use self_cell::self_cell;
+
+use crate::conversion::{decode_cursor, encode_cursor};
+use crate::errors::ErrorCtx;
+use crate::rock::Rock;
+
+// per db, the least recently continued one is released to make room
+pub const MAX_PINNED: usize = 256;
+const SEPARATOR: char = '.';
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanCursor {
+    pub snapshot: Option<u64>,
+    pub key: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct Pins {
+    next_id: AtomicU64,
+    pinned: Mutex<HashMap<u64, (Arc<Pinned>, Instant)>>,
+    // set once the db is closed, listings in flight don't pin anymore
+    released: AtomicBool,
+}
+
+self_cell!(
+    // the snapshot owns a reference to the db handle it was taken from, it's released before the handle
+    pub struct Pinned {
+        owner: Arc<Rock>,
+
+        #[covariant]
+        dependent: Snapshot,
+    }
+);
+
+impl ScanCursor {
+    // base64 keys never hold the separator
+    pub fn parse(cursor: &str) -> anyhow::Result<Self> {
+        match cursor.split_once(SEPARATOR) {
+            Some((id, key)) => Ok(ScanCursor {
+                snapshot: Some(id.parse().map_err(|_| {
+                    anyhow!(ErrorCtx::Validation(format!("Invalid cursor {}", cursor)))
+                })?),
+                key: decode_cursor(key)?,
+            }),
+            None => Ok(ScanCursor {
+                snapshot: None,
+                key: decode_cursor(cursor)?,
+            }),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        match self.snapshot {
+            Some(id) => pinned_cursor(id, encode_cursor(&self.key)),
+            None => encode_cursor(&self.key),
+        }
+    }
+}
+
+pub fn pinned_cursor(id: u64, cursor: String) -> String {
+    format!("{}{}{}", id, SEPARATOR, cursor)
+}
+
+impl Pinned {
+    fn take(rock: &Arc<Rock>) -> Self {
+        Pinned::new(rock.clone(), |rock| rock.db().snapshot())
+    }
+
+    pub fn iterator(&self, mode: IteratorMode) -> DBIterator<'_> {
+        self.borrow_dependent().iterator(mode)
+    }
+
+    pub fn raw_iterator(&self) -> DBRawIterator<'_> {
+        self.borrow_dependent().raw_iterator()
+    }
+}
+
+impl Pins {
+    // the pinned snapshot of a cursor, a new one to start from otherwise
+    pub fn snapshot(&self, rock: &Arc<Rock>, id: Option<u64>) -> anyhow::Result<Arc<Pinned>> {
+        let id = match id {
+            Some(id) => id,
+            None => return Ok(Arc::new(Pinned::take(rock))),
+        };
+        match self.lock().get_mut(&id) {
+            Some((pinned, used_at)) => {
+                *used_at = Instant::now();
+                Ok(pinned.clone())
+            }
+            None => Err(anyhow!(ErrorCtx::Gone(format!(
+                "Snapshot {} of the listing was released, start over",
+                id
+            )))),
+        }
+    }
+
+    // pins the snapshot for following pages unless it already is, returns its id
+    pub fn keep(&self, pinned: &Arc<Pinned>, id: Option<u64>) -> u64 {
+        if let Some(id) = id {
+            return id;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut all = self.lock();
+        // the following page of a closed db is answered with 410 Gone
+        if self.released.load(Ordering::SeqCst) {
+            return id;
+        }
+        if all.len() >= MAX_PINNED {
+            if let Some(oldest) = all
+                .iter()
+                .min_by_key(|(_, (_, used_at))| *used_at)
+                .map(|(id, _)| *id)
+            {
+                all.remove(&oldest);
+            }
+        }
+        all.insert(id, (pinned.clone(), Instant::now()));
+        id
+    }
+
+    // releases every snapshot for good, the db handle is referenced by them until then
+    pub fn release_all(&self) {
+        let mut all = self.lock();
+        self.released.store(true, Ordering::SeqCst);
+        all.clear();
+    }
+
+    // releases snapshots nobody continued from for idle, returns how many were released
+    pub fn purge(&self, idle: Duration) -> usize {
+        let mut all = self.lock();
+        let before = all.len();
+        all.retain(|_, (_, used_at)| used_at.elapsed() < idle);
+        before - all.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, (Arc<Pinned>, Instant)>> {
+        self.pinned.lock().expect("Can't acquire pins lock")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocksdb::DB;
+
+    use super::*;
+
+    #[test]
+    fn should_parse_cursors() {
+        let plain = encode_cursor(b"record_1");
+        assert_eq!(
+            ScanCursor {
+                snapshot: None,
+                key: b"record_1".to_vec(),
+            },
+            ScanCursor::parse(&plain).unwrap()
+        );
+
+        let pinned = pinned_cursor(7, plain);
+        let cursor = ScanCursor::parse(&pinned).unwrap();
+        assert_eq!(Some(7), cursor.snapshot);
+        assert_eq!(b"record_1".to_vec(), cursor.key);
+        assert_eq!(pinned, cursor.encode());
+
+        assert!(ScanCursor::parse("x.cmVjb3JkXzE").is_err());
+        assert!(ScanCursor::parse("7.not a cursor!").is_err());
+    }
+
+    #[test]
+    fn should_read_pinned_snapshot() {
+        let path = "./db/should_read_pinned_snapshot";
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        let rock = Arc::new(Rock::new(DB::open(&opts, path).unwrap()));
+        rock.read().put(b"a", b"1").unwrap();
+
+        let pins = Pins::default();
+        let pinned = pins.snapshot(&rock, None).unwrap();
+        let id = pins.keep(&pinned, None);
+        assert_eq!(id, pins.keep(&pinned, Some(id)));
+        rock.read().put(b"b", b"2").unwrap();
+        rock.read().delete(b"a").unwrap();
+
+        let keys: Vec<Box<[u8]>> = pins
+            .snapshot(&rock, Some(id))
+            .unwrap()
+            .iterator(IteratorMode::Start)
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(vec![b"a".to_vec().into_boxed_slice()], keys);
+
+        drop(pinned);
+        assert_eq!(0, pins.purge(Duration::from_secs(60)));
+        assert_eq!(1, pins.purge(Duration::from_secs(0)));
+        assert!(pins.snapshot(&rock, Some(id)).is_err());
+
+        let pinned = pins.snapshot(&rock, None).unwrap();
+        let id = pins.keep(&pinned, None);
+        drop(pinned);
+        assert_eq!(2, Arc::strong_count(&rock));
+        pins.release_all();
+        assert_eq!(1, Arc::strong_count(&rock));
+        let pinned = pins.snapshot(&rock, None).unwrap();
+        let next = pins.keep(&pinned, None);
+        drop(pinned);
+        assert_eq!(1, Arc::strong_count(&rock));
+        assert!(pins.snapshot(&rock, Some(id)).is_err());
+        assert!(pins.snapshot(&rock, Some(next)).is_err());
+        drop(rock);
+        DB::destroy(&opts, path).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+// Read-only mounts of checkpoints and backups.
+//
+// GET /_snapshots/{id}/{db}/{key} reads a record as it was when the snapshot of the db was taken, side by side with
+// live data. The id is the created_at of a checkpoint under checkpoint_path/{db} or backup-{backup_id} for a backup,
+// which is restored once into checkpoint_path/{db}/backup-{backup_id} first. A snapshot is opened read-only on first
+// use and stays mounted until it's unmounted with DELETE /_snapshots/{id}/{db}. Expiration isn't applied, records
+// are served as they were stored.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use rocksdb::{Options, DB};
+
+use crate::errors::ErrorCtx;
+
+const BACKUP_PREFIX: &str = "backup-";
+
+#[derive(Debug, PartialEq)]
+pub enum SnapshotId {
+    Checkpoint(String),
+    Backup(u32),
+}
+
+#[derive(Default)]
+pub struct Snapshots {
+    mounted: Mutex<HashMap<(String, String), Arc<DB>>>,
+}
+
+impl SnapshotId {
+    // ids end up in paths, anything but a number or backup-{number} is rejected
+    pub fn parse(id: &str) -> anyhow::Result<Self> {
+        let invalid = || {
+            anyhow!(ErrorCtx::Validation(format!(
+                "Invalid snapshot id {} - expected a checkpoint created_at or backup-{{backup_id}}",
+                id
+            )))
+        };
+        match id.strip_prefix(BACKUP_PREFIX) {
+            Some(backup_id) => backup_id
+                .parse()
+                .map(SnapshotId::Backup)
+                .map_err(|_| invalid()),
+            None if !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()) => {
+                Ok(SnapshotId::Checkpoint(id.into()))
+            }
+            None => Err(invalid()),
+        }
+    }
+
+    // under checkpoint_path/{db}
+    pub fn dir_name(&self) -> String {
+        match self {
+            SnapshotId::Checkpoint(created_at) => created_at.clone(),
+            SnapshotId::Backup(backup_id) => format!("{}{}", BACKUP_PREFIX, backup_id),
+        }
+    }
+}
+
+impl Snapshots {
+    // opens the snapshot in dir unless it's already mounted
+    pub fn mount(
+        &self,
+        id: &str,
+        db_name: &str,
+        dir: &str,
+        opts: &Options,
+    ) -> anyhow::Result<Arc<DB>> {
+        let mut mounted = self.mounted.lock().expect("Can't acquire snapshots lock");
+        let key = (id.to_string(), db_name.to_string());
+        if let Some(rock) = mounted.get(&key) {
+            return Ok(rock.clone());
+        }
+
+        info!(
+            "Mounting snapshot = {} of db = {} from {}",
+            id, db_name, dir
+        );
+        let rock = Arc::new(DB::open_for_read_only(opts, dir, false)?);
+        mounted.insert(key, rock.clone());
+        Ok(rock)
+    }
+
+    // reads in flight keep the snapshot open until they're done
+    pub fn unmount(&self, id: &str, db_name: &str) -> bool {
+        self.mounted
+            .lock()
+            .expect("Can't acquire snapshots lock")
+            .remove(&(id.to_string(), db_name.to_string()))
+            .is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_snapshot_ids() {
+        assert_eq!(
+            SnapshotId::Checkpoint("1602959000000".into()),
+            SnapshotId::parse("1602959000000").unwrap()
+        );
+        assert_eq!(
+            SnapshotId::Backup(3),
+            SnapshotId::parse("backup-3").unwrap()
+        );
+        assert_eq!("backup-3", SnapshotId::Backup(3).dir_name());
+
+        for id in &[
+            "",
+            "..",
+            "export-1602959000000",
+            "backup-",
+            "backup-x",
+            "1/2",
+        ] {
+            assert!(SnapshotId::parse(id).is_err(), "{} should be rejected", id);
+        }
+    }
+}
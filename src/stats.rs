@@ -0,0 +1,211 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use rocksdb::MergeOperands;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Eviction;
+use crate::conversion::current_ms;
+
+pub const STATS_CF: &str = "stats";
+pub const STATS_OPERATOR: &str = "stats-operator";
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub last_access: u128,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyStats {
+    key: String,
+    #[serde(flatten)]
+    stats: AccessStats,
+}
+
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Clone)]
+pub struct Sampler {
+    every: u64,
+    ops: Arc<AtomicU64>,
+}
+
+impl AccessStats {
+    fn merge(mut self, other: AccessStats) -> Self {
+        self.reads += other.reads;
+        self.writes += other.writes;
+        self.last_access = self.last_access.max(other.last_access);
+        self
+    }
+}
+
+impl KeyStats {
+    pub fn new(key: String, stats: AccessStats) -> Self {
+        KeyStats { key, stats }
+    }
+}
+
+impl Sampler {
+    pub fn new(every: u64) -> Self {
+        Sampler {
+            every: every.max(1),
+            ops: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // a sampled op is accounted as `every` ops so counts stay comparable to the real traffic
+    pub fn sample(&self, access: Access) -> Option<AccessStats> {
+        if self.ops.fetch_add(1, Ordering::Relaxed) % self.every != 0 {
+            return None;
+        }
+
+        let last_access = current_ms().ok()?;
+        Some(match access {
+            Access::Read => AccessStats {
+                reads: self.every,
+                writes: 0,
+                last_access,
+            },
+            Access::Write => AccessStats {
+                reads: 0,
+                writes: self.every,
+                last_access,
+            },
+        })
+    }
+}
+
+// hot keys are sorted by total access count, cold keys by least recent access
+pub fn rank(mut stats: Vec<KeyStats>, limit: usize, cold: bool) -> Vec<KeyStats> {
+    if cold {
+        stats.sort_by_key(|s| s.stats.last_access);
+    } else {
+        stats.sort_by_key(|s| Reverse(s.stats.reads + s.stats.writes));
+    }
+    stats.truncate(limit);
+    stats
+}
+
+// keys without stats were never sampled and are evicted first
+fn priority(eviction: Eviction, stats: Option<&AccessStats>) -> u128 {
+    match (eviction, stats) {
+        (Eviction::Lru, Some(s)) => s.last_access,
+        (Eviction::Lfu, Some(s)) => (s.reads + s.writes) as u128,
+        (_, None) => 0,
+    }
+}
+
+// picks the least used keys until the remaining size fits under max_size
+pub fn victims<K: Eq + Hash>(
+    mut live: Vec<(K, u64)>,
+    stats: &HashMap<K, AccessStats>,
+    max_size: u64,
+    eviction: Eviction,
+) -> Vec<K> {
+    let mut size: u64 = live.iter().map(|(_, s)| s).sum();
+    live.sort_by_key(|(k, _)| priority(eviction, stats.get(k)));
+
+    let mut victims = vec![];
+    for (key, key_size) in live {
+        if size <= max_size {
+            break;
+        }
+        size -= key_size;
+        victims.push(key);
+    }
+    victims
+}
+
+pub fn stats_merge(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &mut MergeOperands,
+) -> Option<Vec<u8>> {
+    let merged = existing
+        .into_iter()
+        .chain(operands)
+        .filter_map(|bytes| bincode::deserialize::<AccessStats>(bytes).ok())
+        .fold(AccessStats::default(), AccessStats::merge);
+
+    bincode::serialize(&merged).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(reads: u64, writes: u64, last_access: u128) -> AccessStats {
+        AccessStats {
+            reads,
+            writes,
+            last_access,
+        }
+    }
+
+    #[test]
+    fn should_sample_every_nth_access() {
+        let sampler = Sampler::new(3);
+        let sampled: Vec<AccessStats> = (0..6)
+            .filter_map(|_| sampler.sample(Access::Read))
+            .collect();
+
+        assert_eq!(2, sampled.len());
+        assert!(sampled.iter().all(|s| s.reads == 3 && s.writes == 0));
+    }
+
+    #[test]
+    fn should_merge_stats() {
+        let merged = stats(1, 2, 10).merge(stats(3, 0, 5));
+        assert_eq!(stats(4, 2, 10), merged);
+    }
+
+    #[test]
+    fn should_pick_eviction_victims() {
+        let live = || {
+            vec![
+                ("a".to_string(), 10),
+                ("b".to_string(), 10),
+                ("c".to_string(), 10),
+                ("d".to_string(), 10),
+            ]
+        };
+        let mut all = HashMap::new();
+        all.insert("a".to_string(), stats(1, 0, 30));
+        all.insert("b".to_string(), stats(9, 0, 10));
+        all.insert("c".to_string(), stats(5, 0, 20));
+
+        assert_eq!(vec!["d", "b"], victims(live(), &all, 20, Eviction::Lru));
+        assert_eq!(
+            vec!["d", "a", "c"],
+            victims(live(), &all, 10, Eviction::Lfu)
+        );
+        assert!(victims(live(), &all, 40, Eviction::Lru).is_empty());
+    }
+
+    #[test]
+    fn should_rank_hot_and_cold_keys() {
+        let all = || {
+            vec![
+                KeyStats::new("a".into(), stats(1, 0, 30)),
+                KeyStats::new("b".into(), stats(5, 5, 20)),
+                KeyStats::new("c".into(), stats(2, 0, 10)),
+            ]
+        };
+
+        let hot: Vec<String> = rank(all(), 2, false).into_iter().map(|s| s.key).collect();
+        assert_eq!(vec!["b", "c"], hot);
+
+        let cold: Vec<String> = rank(all(), 1, true).into_iter().map(|s| s.key).collect();
+        assert_eq!(vec!["c"], cold);
+    }
+}
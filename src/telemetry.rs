@@ -0,0 +1,111 @@
+// Distributed tracing over OpenTelemetry, enabled with otlp_endpoint in the service config.
+//
+// Every HTTP request gets a span with its route, db name, key and status, continuing the trace of the caller when
+// it sends a W3C traceparent header. DbManager operations are spans under it, whichever protocol called them, so
+// a request can be followed from the service calling Rocky down to storage in Jaeger or Tempo. Spans are exported
+// to the collector over OTLP gRPC. Without an endpoint spans aren't recorded at all.
+
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::HeaderMap;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::Uninstall;
+use tracing::field::Empty;
+use tracing::{info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+const SERVICE_NAME: &str = "rocky";
+
+struct Headers<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for Headers<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+// spans are flushed when the returned guard is dropped
+pub fn init(endpoint: &str) -> anyhow::Result<Uninstall> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let (tracer, uninstall) = opentelemetry_otlp::new_pipeline()
+        .with_endpoint(endpoint)
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                SERVICE_NAME,
+            )])),
+        )
+        .install()?;
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()?;
+    info!("Exporting traces to {}", endpoint);
+    Ok(uninstall)
+}
+
+// the route isn't matched yet, it's recorded with the response
+pub fn request_span(req: &ServiceRequest) -> Span {
+    let span = info_span!(
+        "request",
+        http.method = req.method().as_str(),
+        http.target = req.path(),
+        http.route = Empty,
+        http.status_code = Empty,
+        db = Empty,
+        key = Empty,
+    );
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&Headers(req.headers())));
+    span.set_parent(&parent);
+    span
+}
+
+pub fn record_response<B>(span: &Span, res: &ServiceResponse<B>) {
+    let req = res.request();
+    if let Some(route) = req.match_pattern() {
+        span.record("http.route", &route.as_str());
+    }
+    if let Some(db_name) = req.match_info().get("db_name") {
+        span.record("db", &db_name);
+    }
+    if let Some(key) = req.match_info().get("key") {
+        span.record("key", &key);
+    }
+    span.record("http.status_code", &res.status().as_u16());
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use opentelemetry::propagation::TextMapPropagator;
+    use opentelemetry::trace::{TraceContextExt, TraceId};
+
+    use super::*;
+
+    #[test]
+    fn should_continue_caller_trace() {
+        let req = TestRequest::default()
+            .header(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            )
+            .to_http_request();
+        let cx = TraceContextPropagator::new().extract(&Headers(req.headers()));
+        assert_eq!(
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736"),
+            cx.span().span_context().trace_id()
+        );
+
+        let req = TestRequest::default().to_http_request();
+        let cx = TraceContextPropagator::new().extract(&Headers(req.headers()));
+        assert!(!cx.span().span_context().is_valid());
+    }
+}
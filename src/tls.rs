@@ -0,0 +1,63 @@
+// TLS termination with rustls, so Rocky can be exposed without a reverse proxy in front of it.
+//
+// The certificate chain and private key are PEM files given by tls_cert and tls_key in the service config.
+// The key can be PKCS#8 or PKCS#1 (RSA), the first one found in the file is used. Clients aren't authenticated.
+
+use std::fs::File;
+use std::io::BufReader;
+
+use anyhow::anyhow;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{NoClientAuth, PrivateKey, ServerConfig};
+
+pub fn server_config(cert_path: &str, key_path: &str) -> anyhow::Result<ServerConfig> {
+    let chain = certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|_| anyhow!("Can't read certificates from tls_cert {}", cert_path))?;
+    if chain.is_empty() {
+        return Err(anyhow!("No certificate found in tls_cert {}", cert_path));
+    }
+    let key = private_key(key_path)?;
+
+    let mut cfg = ServerConfig::new(NoClientAuth::new());
+    cfg.set_single_cert(chain, key)
+        .map_err(|e| anyhow!("Invalid tls_cert or tls_key - {}", e))?;
+    Ok(cfg)
+}
+
+fn private_key(key_path: &str) -> anyhow::Result<PrivateKey> {
+    let pkcs8 = pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|_| anyhow!("Can't read private key from tls_key {}", key_path))?;
+    let rsa = rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|_| anyhow!("Can't read private key from tls_key {}", key_path))?;
+    pkcs8
+        .into_iter()
+        .chain(rsa)
+        .next()
+        .ok_or_else(|| anyhow!("No private key found in tls_key {}", key_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn should_reject_files_without_pem_content() {
+        let dir = std::env::temp_dir().join("rocky_tls_test");
+        fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        fs::write(&cert, "not a certificate").unwrap();
+        fs::write(&key, "not a key").unwrap();
+
+        let err = server_config(cert.to_str().unwrap(), key.to_str().unwrap())
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("No certificate found in tls_cert"));
+        assert!(server_config("missing.pem", key.to_str().unwrap()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
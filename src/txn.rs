@@ -0,0 +1,400 @@
+// JSON transactions - a list of puts, deletes and checks applied all-or-nothing.
+//
+// Values are base64 encoded, ttl is relative in ms like the ttl header. An operation with an expected version
+// fails the whole transaction when the record is at another version, 0 expects the record to be missing.
+//
+// Interactive transactions span several requests: reads record the version they saw and writes are buffered,
+// on commit the read versions become checks in front of the buffered writes so a record changed by someone
+// else in the meantime fails the commit instead of being overwritten (optimistic concurrency).
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::conversion::current_ms;
+use crate::errors::ErrorCtx;
+use crate::NO_TTL;
+
+pub const MAX_TXN_OPS: usize = 1000;
+// an interactive transaction is rolled back when idle for longer
+pub const TXN_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_OPEN_TXNS: usize = 10_000;
+
+#[derive(Deserialize, Debug)]
+pub struct TxnRequest {
+    pub ops: Vec<TxnOp>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum TxnOp {
+    #[serde(rename_all = "camelCase")]
+    Put {
+        key: String,
+        value: String,
+        ttl: Option<u64>,
+        expected_version: Option<u64>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Delete {
+        key: String,
+        expected_version: Option<u64>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Check { key: String, expected_version: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxnAction {
+    Put(Vec<u8>),
+    Delete,
+    Check,
+}
+
+// decoded operation
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxnWrite {
+    pub key: String,
+    pub action: TxnAction,
+    pub ttl: u128,
+    pub expected_version: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TxnResult {
+    // new version of every put, null for deletes and checks, in request order
+    pub versions: Vec<Option<u64>>,
+    pub seq: u64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TxnToken {
+    pub token: String,
+    pub idle_timeout_ms: u128,
+}
+
+struct Session {
+    db: String,
+    // first version seen by a read, 0 for a missing record
+    reads: HashMap<String, u64>,
+    writes: Vec<TxnWrite>,
+    last_used: Instant,
+}
+
+// open interactive transactions by token
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, Session>>,
+    counter: AtomicU64,
+    hasher: RandomState,
+}
+
+impl TxnRequest {
+    pub fn from_json(body: &[u8]) -> anyhow::Result<Self> {
+        serde_json::from_slice(body).map_err(|e| {
+            anyhow!(ErrorCtx::Validation(format!(
+                "Malformed transaction - {}",
+                e
+            )))
+        })
+    }
+
+    pub fn into_writes(self) -> anyhow::Result<Vec<TxnWrite>> {
+        if self.ops.is_empty() {
+            return Err(anyhow!(ErrorCtx::Validation(
+                "Transaction without operations".into()
+            )));
+        }
+        if self.ops.len() > MAX_TXN_OPS {
+            return Err(too_many_ops());
+        }
+
+        let now = current_ms()?;
+        self.ops.into_iter().map(|op| op.into_write(now)).collect()
+    }
+}
+
+impl TxnOp {
+    fn into_write(self, now: u128) -> anyhow::Result<TxnWrite> {
+        match self {
+            TxnOp::Put {
+                key,
+                value,
+                ttl,
+                expected_version,
+            } => {
+                let value = base64::decode(&value).map_err(|_| {
+                    anyhow!(ErrorCtx::Validation(format!(
+                        "Value of key {} is not valid base64",
+                        key
+                    )))
+                })?;
+                Ok(TxnWrite {
+                    key,
+                    action: TxnAction::Put(value),
+                    ttl: ttl.map_or(NO_TTL, |ttl| now + u128::from(ttl)),
+                    expected_version,
+                })
+            }
+            TxnOp::Delete {
+                key,
+                expected_version,
+            } => Ok(TxnWrite {
+                key,
+                action: TxnAction::Delete,
+                ttl: NO_TTL,
+                expected_version,
+            }),
+            TxnOp::Check {
+                key,
+                expected_version,
+            } => Ok(TxnWrite {
+                key,
+                action: TxnAction::Check,
+                ttl: NO_TTL,
+                expected_version: Some(expected_version),
+            }),
+        }
+    }
+}
+
+impl Sessions {
+    pub fn begin(&self, db_name: &str) -> anyhow::Result<String> {
+        let mut sessions = self.lock();
+        sessions.retain(|_, s| s.last_used.elapsed() < TXN_IDLE_TIMEOUT);
+        if sessions.len() >= MAX_OPEN_TXNS {
+            return Err(anyhow!(ErrorCtx::Unavailable(format!(
+                "Too many open transactions, limit is {}",
+                MAX_OPEN_TXNS
+            ))));
+        }
+
+        let token = self.token();
+        sessions.insert(
+            token.clone(),
+            Session {
+                db: db_name.into(),
+                reads: HashMap::new(),
+                writes: vec![],
+                last_used: Instant::now(),
+            },
+        );
+        Ok(token)
+    }
+
+    // value written earlier in the transaction - Some(None) for a delete, None when the key wasn't written
+    pub fn written(
+        &self,
+        db_name: &str,
+        token: &str,
+        key: &str,
+    ) -> anyhow::Result<Option<Option<Vec<u8>>>> {
+        let mut sessions = self.lock();
+        let session = session(&mut sessions, db_name, token)?;
+        Ok(session
+            .writes
+            .iter()
+            .rev()
+            .find(|w| w.key == key && w.action != TxnAction::Check)
+            .map(|w| match &w.action {
+                TxnAction::Put(value) => Some(value.clone()),
+                _ => None,
+            }))
+    }
+
+    // only the first read of a key counts, later ones must see the same version to commit anyway
+    pub fn record_read(
+        &self,
+        db_name: &str,
+        token: &str,
+        key: &str,
+        version: u64,
+    ) -> anyhow::Result<()> {
+        let mut sessions = self.lock();
+        let session = session(&mut sessions, db_name, token)?;
+        if !session.reads.contains_key(key) {
+            if session.writes.len() + session.reads.len() >= MAX_TXN_OPS {
+                return Err(too_many_ops());
+            }
+            session.reads.insert(key.into(), version);
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, db_name: &str, token: &str, write: TxnWrite) -> anyhow::Result<()> {
+        let mut sessions = self.lock();
+        let session = session(&mut sessions, db_name, token)?;
+        if session.writes.len() + session.reads.len() >= MAX_TXN_OPS {
+            return Err(too_many_ops());
+        }
+        session.writes.push(write);
+        Ok(())
+    }
+
+    // ends the transaction, returns the read checks followed by the buffered writes
+    pub fn commit(&self, db_name: &str, token: &str) -> anyhow::Result<Vec<TxnWrite>> {
+        let mut sessions = self.lock();
+        session(&mut sessions, db_name, token)?;
+        let session = sessions.remove(token).expect("Session checked above");
+
+        let mut reads: Vec<(String, u64)> = session.reads.into_iter().collect();
+        reads.sort();
+        Ok(reads
+            .into_iter()
+            .map(|(key, version)| TxnWrite {
+                key,
+                action: TxnAction::Check,
+                ttl: NO_TTL,
+                expected_version: Some(version),
+            })
+            .chain(session.writes)
+            .collect())
+    }
+
+    pub fn rollback(&self, db_name: &str, token: &str) -> anyhow::Result<()> {
+        let mut sessions = self.lock();
+        session(&mut sessions, db_name, token)?;
+        sessions.remove(token);
+        Ok(())
+    }
+
+    // open transactions of a closed db can't commit anymore
+    pub fn remove_db(&self, db_name: &str) {
+        self.lock().retain(|_, s| s.db != db_name);
+    }
+
+    fn token(&self) -> String {
+        let mut hasher = self.hasher.build_hasher();
+        self.counter
+            .fetch_add(1, Ordering::Relaxed)
+            .hash(&mut hasher);
+        current_ms().unwrap_or_default().hash(&mut hasher);
+        let high = hasher.finish();
+        high.hash(&mut hasher);
+        format!("{:016x}{:016x}", high, hasher.finish())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Session>> {
+        self.sessions
+            .lock()
+            .expect("Can't acquire txn sessions lock")
+    }
+}
+
+fn session<'a>(
+    sessions: &'a mut HashMap<String, Session>,
+    db_name: &str,
+    token: &str,
+) -> anyhow::Result<&'a mut Session> {
+    match sessions.get_mut(token) {
+        Some(s) if s.db == db_name && s.last_used.elapsed() < TXN_IDLE_TIMEOUT => {
+            s.last_used = Instant::now();
+            Ok(s)
+        }
+        _ => Err(anyhow!(ErrorCtx::NotFound(format!(
+            "Transaction {} doesn't exist or has timed out",
+            token
+        )))),
+    }
+}
+
+fn too_many_ops() -> anyhow::Error {
+    anyhow!(ErrorCtx::Validation(format!(
+        "Transaction exceeds {} operations",
+        MAX_TXN_OPS
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_decode_txn_request() {
+        let req: TxnRequest = serde_json::from_str(
+            r#"{"ops": [
+                {"op": "put", "key": "a", "value": "djE=", "ttl": 1000},
+                {"op": "delete", "key": "b", "expectedVersion": 3},
+                {"op": "check", "key": "c", "expectedVersion": 0}
+            ]}"#,
+        )
+        .unwrap();
+        let writes = req.into_writes().unwrap();
+
+        assert_eq!(TxnAction::Put(b"v1".to_vec()), writes[0].action);
+        assert!(writes[0].ttl > 1000);
+        assert_eq!(None, writes[0].expected_version);
+        assert_eq!(
+            TxnWrite {
+                key: "b".into(),
+                action: TxnAction::Delete,
+                ttl: NO_TTL,
+                expected_version: Some(3),
+            },
+            writes[1]
+        );
+        assert_eq!(TxnAction::Check, writes[2].action);
+        assert_eq!(Some(0), writes[2].expected_version);
+    }
+
+    #[test]
+    fn should_reject_invalid_txn_request() {
+        let empty = TxnRequest { ops: vec![] };
+        assert!(empty.into_writes().is_err());
+
+        let invalid = TxnRequest {
+            ops: vec![TxnOp::Put {
+                key: "a".into(),
+                value: "not base64!".into(),
+                ttl: None,
+                expected_version: None,
+            }],
+        };
+        assert!(invalid.into_writes().is_err());
+    }
+
+    #[test]
+    fn should_turn_reads_into_checks_on_commit() {
+        let sessions = Sessions::default();
+        let token = sessions.begin("db").unwrap();
+        assert_ne!(token, sessions.begin("db").unwrap());
+
+        sessions.record_read("db", &token, "b", 2).unwrap();
+        sessions.record_read("db", &token, "a", 0).unwrap();
+        sessions.record_read("db", &token, "a", 5).unwrap();
+        let put = TxnWrite {
+            key: "a".into(),
+            action: TxnAction::Put(b"v".to_vec()),
+            ttl: NO_TTL,
+            expected_version: None,
+        };
+        sessions.write("db", &token, put.clone()).unwrap();
+        assert_eq!(
+            Some(Some(b"v".to_vec())),
+            sessions.written("db", &token, "a").unwrap()
+        );
+        assert_eq!(None, sessions.written("db", &token, "b").unwrap());
+        assert!(sessions.written("other_db", &token, "a").is_err());
+
+        let writes = sessions.commit("db", &token).unwrap();
+        assert_eq!(3, writes.len());
+        assert_eq!(
+            ("a", Some(0)),
+            (writes[0].key.as_str(), writes[0].expected_version)
+        );
+        assert_eq!(
+            ("b", Some(2)),
+            (writes[1].key.as_str(), writes[1].expected_version)
+        );
+        assert_eq!(put, writes[2]);
+        assert!(sessions.commit("db", &token).is_err());
+    }
+}
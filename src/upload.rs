@@ -0,0 +1,195 @@
+// Multi-part uploads of large values.
+//
+// POST /{db}/{key}/_mpu starts an upload, parts are PUT to /{db}/{key}/_mpu/{upload_id}/parts/{n} in any order and
+// can be retried, a retried part replaces the previous one. Parts are staged in the uploads column family and count
+// towards the quota of the db. POST .../complete turns parts 1..=n into the chunks of the value - the record only
+// holds a manifest naming the upload, written in a single batch with the completed upload so readers never see a
+// partial value and no part is copied. Chunks are read back when the record is read, so the value is held in memory
+// then, and the sweeper drops them once no record or tombstone refers to them. Uploads not completed within
+// upload_expiry_ms are purged by the sweeper.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::conversion::current_ms;
+use crate::errors::ErrorCtx;
+use crate::key_encoding::display_key;
+
+pub const UPLOADS_CF: &str = "uploads";
+pub const MAX_PARTS: u32 = 10_000;
+pub const MAX_PART_BYTES: usize = 64 * 1024 * 1024;
+pub const MAX_UPLOAD_BYTES: u64 = 1024 * 1024 * 1024;
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// kept under the upload id, parts under {upload_id}/{n}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Upload {
+    pub key: Vec<u8>,
+    pub created_at: u128,
+    pub content_type: Option<String>,
+    // the parts are the chunks of the value of key, the upload can't be changed anymore
+    pub completed: bool,
+}
+
+// manifest of a value held by the parts 1..=parts of a completed upload
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Chunks {
+    pub upload_id: String,
+    pub parts: u32,
+    pub size: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PartInfo {
+    pub number: u32,
+    pub size: usize,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadInfo {
+    pub upload_id: String,
+    pub created_at: u128,
+    pub parts: Vec<PartInfo>,
+}
+
+impl Upload {
+    pub fn new(key: &[u8], content_type: Option<String>) -> anyhow::Result<Self> {
+        Ok(Upload {
+            key: key.to_vec(),
+            created_at: current_ms()?,
+            content_type,
+            completed: false,
+        })
+    }
+
+    // an upload id is valid only for the key it was started for, until it's completed
+    pub fn check_key(&self, upload_id: &str, key: &[u8]) -> anyhow::Result<()> {
+        if self.key == key && !self.completed {
+            Ok(())
+        } else {
+            Err(not_found(upload_id, key))
+        }
+    }
+}
+
+pub fn upload_id() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    COUNTER.fetch_add(1, Ordering::Relaxed).hash(&mut hasher);
+    current_ms().unwrap_or_default().hash(&mut hasher);
+    let high = hasher.finish();
+    high.hash(&mut hasher);
+    format!("{:016x}{:016x}", high, hasher.finish())
+}
+
+// ids are fixed length hex, none is a prefix of another or of a part key
+pub fn is_upload_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+pub fn meta_key(upload_id: &str) -> Vec<u8> {
+    upload_id.as_bytes().to_vec()
+}
+
+// zero padded so parts sort by number
+pub fn part_key(upload_id: &str, number: u32) -> Vec<u8> {
+    format!("{}/{:05}", upload_id, number).into_bytes()
+}
+
+pub fn part_number(key: &[u8]) -> Option<u32> {
+    let pos = key.iter().rposition(|b| *b == b'/')?;
+    std::str::from_utf8(&key[pos + 1..]).ok()?.parse().ok()
+}
+
+// the upload and all its parts, end exclusive
+pub fn upload_range(upload_id: &str) -> (Vec<u8>, Vec<u8>) {
+    (meta_key(upload_id), format!("{}0", upload_id).into_bytes())
+}
+
+pub fn check_part_number(number: u32) -> anyhow::Result<()> {
+    if number == 0 || number > MAX_PARTS {
+        return Err(anyhow!(ErrorCtx::Validation(format!(
+            "Part number {} is out of 1..={}",
+            number, MAX_PARTS
+        ))));
+    }
+    Ok(())
+}
+
+// parts have to be numbered 1..=n without gaps and add up to at most MAX_UPLOAD_BYTES, returns their size
+pub fn check_parts(parts: &[PartInfo]) -> anyhow::Result<u64> {
+    if parts.is_empty() {
+        return Err(anyhow!(ErrorCtx::Validation("Upload has no parts".into())));
+    }
+    if let Some((i, _)) = parts
+        .iter()
+        .enumerate()
+        .find(|(i, p)| p.number != *i as u32 + 1)
+    {
+        return Err(anyhow!(ErrorCtx::Validation(format!(
+            "Part {} is missing",
+            i + 1
+        ))));
+    }
+    let size = parts.iter().map(|p| p.size as u64).sum();
+    if size > MAX_UPLOAD_BYTES {
+        return Err(anyhow!(ErrorCtx::Validation(format!(
+            "Upload of {} bytes is over the limit of {} bytes",
+            size, MAX_UPLOAD_BYTES
+        ))));
+    }
+    Ok(size)
+}
+
+pub fn not_found(upload_id: &str, key: &[u8]) -> anyhow::Error {
+    anyhow!(ErrorCtx::NotFound(format!(
+        "Upload {} of key {} doesn't exist",
+        upload_id,
+        display_key(key)
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_key_parts_in_order() {
+        let id = upload_id();
+        assert!(is_upload_id(&id));
+        assert!(!is_upload_id(&format!("{}/00001", id)));
+        assert_ne!(id, upload_id());
+
+        let (start, end) = upload_range(&id);
+        let parts = [part_key(&id, 1), part_key(&id, 2), part_key(&id, 10_000)];
+        assert!(parts.windows(2).all(|w| w[0] < w[1]));
+        assert!(parts.iter().all(|p| p > &start && p < &end));
+        assert_eq!(Some(10_000), part_number(&parts[2]));
+    }
+
+    #[test]
+    fn should_require_contiguous_parts() {
+        let part = |number| PartInfo { number, size: 1 };
+        assert_eq!(2, check_parts(&[part(1), part(2)]).unwrap());
+        assert!(check_parts(&[]).is_err());
+        assert!(check_parts(&[part(1), part(3)]).is_err());
+        assert!(check_parts(&[part(2)]).is_err());
+        let huge = |number| PartInfo {
+            number,
+            size: MAX_PART_BYTES,
+        };
+        let parts: Vec<PartInfo> = (1..=17).map(huge).collect();
+        assert!(check_parts(&parts[..16]).is_ok());
+        assert!(check_parts(&parts).is_err());
+
+        assert!(check_part_number(0).is_err());
+        assert!(check_part_number(MAX_PARTS + 1).is_err());
+        assert!(check_part_number(MAX_PARTS).is_ok());
+    }
+}
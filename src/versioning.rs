@@ -0,0 +1,124 @@
+// API versions and route deprecation.
+//
+// The API is served under /v1/... The unversioned paths are kept as a compatibility layer answering the same as
+// their /v1 successors, their responses carry Deprecation: true and a Link to the successor so clients can move over
+// at their own pace. Routes of a version that are on their way out (e.g. before a status code or error model fix
+// lands in the next version) are listed in DEPRECATED, their responses carry Deprecation and, once a removal date is
+// set, Sunset. Database names that look like a version (v1, v2, ...) are reserved.
+
+use actix_web::http::{HeaderMap, HeaderName, HeaderValue};
+
+pub const API_PREFIX: &str = "/v1";
+
+pub const DEPRECATION_HEADER: &str = "deprecation";
+pub const SUNSET_HEADER: &str = "sunset";
+
+pub struct Deprecation {
+    // route pattern as registered, including the version prefix
+    pub pattern: &'static str,
+    // HTTP-date after which the route may be gone
+    pub sunset: Option<&'static str>,
+    // prefix of the version the route moves to
+    pub successor: Option<&'static str>,
+}
+
+// routes of the current version that are going away
+pub const DEPRECATED: &[Deprecation] = &[];
+
+pub fn is_version(name: &str) -> bool {
+    name.len() > 1 && name.starts_with('v') && name[1..].bytes().all(|b| b.is_ascii_digit())
+}
+
+// headers of a response to the path matched by the route pattern
+pub fn deprecation_headers(
+    path: &str,
+    pattern: Option<&str>,
+    deprecated: &[Deprecation],
+) -> Vec<(&'static str, String)> {
+    let mut headers = vec![];
+    let versioned = path == API_PREFIX || path.starts_with(&format!("{}/", API_PREFIX));
+    if !versioned {
+        headers.push((DEPRECATION_HEADER, "true".to_owned()));
+        headers.push((
+            "link",
+            format!("<{}{}>; rel=\"successor-version\"", API_PREFIX, path),
+        ));
+    }
+
+    let (path, pattern) = match pattern {
+        Some(pattern) if versioned => (&path[API_PREFIX.len()..], pattern.to_owned()),
+        Some(pattern) => (path, format!("{}{}", API_PREFIX, pattern)),
+        None => return headers,
+    };
+    if let Some(deprecation) = deprecated.iter().find(|d| d.pattern == pattern) {
+        if versioned {
+            headers.push((DEPRECATION_HEADER, "true".to_owned()));
+        }
+        if let Some(sunset) = deprecation.sunset {
+            headers.push((SUNSET_HEADER, sunset.to_owned()));
+        }
+        if let Some(successor) = deprecation.successor {
+            headers.push((
+                "link",
+                format!("<{}{}>; rel=\"successor-version\"", successor, path),
+            ));
+        }
+    }
+    headers
+}
+
+pub fn apply(headers: &mut HeaderMap, deprecation: Vec<(&'static str, String)>) {
+    for (name, value) in deprecation {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.append(HeaderName::from_static(name), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_recognize_versions() {
+        assert!(is_version("v1"));
+        assert!(is_version("v12"));
+        assert!(!is_version("v"));
+        assert!(!is_version("v1a"));
+        assert!(!is_version("db_v1"));
+    }
+
+    #[test]
+    fn should_deprecate_routes() {
+        assert!(deprecation_headers("/v1/db/key", Some("/v1/{db_name}/{key}"), &[]).is_empty());
+        assert_eq!(
+            vec![
+                (DEPRECATION_HEADER, "true".to_owned()),
+                ("link", "</v1/db/key>; rel=\"successor-version\"".to_owned()),
+            ],
+            deprecation_headers("/db/key", Some("/{db_name}/{key}"), &[])
+        );
+
+        let deprecated = [Deprecation {
+            pattern: "/v1/{db_name}/_query",
+            sunset: Some("Fri, 01 Jan 2027 00:00:00 GMT"),
+            successor: Some("/v2"),
+        }];
+        assert_eq!(
+            vec![
+                (DEPRECATION_HEADER, "true".to_owned()),
+                (SUNSET_HEADER, "Fri, 01 Jan 2027 00:00:00 GMT".to_owned()),
+                (
+                    "link",
+                    "</v2/db/_query>; rel=\"successor-version\"".to_owned()
+                ),
+            ],
+            deprecation_headers("/v1/db/_query", Some("/v1/{db_name}/_query"), &deprecated)
+        );
+        // the unversioned path is deprecated by both
+        assert_eq!(
+            4,
+            deprecation_headers("/db/_query", Some("/{db_name}/_query"), &deprecated).len()
+        );
+    }
+}
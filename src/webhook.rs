@@ -0,0 +1,186 @@
+// Expiration webhooks - expired keys are POSTed as JSON to the url configured for their db.
+//
+// Notifications are written to the outbound queue and delivered by a dedicated thread running its own actix system,
+// so neither reads nor the sweeper wait on a slow endpoint. A failed delivery stays queued and is retried with
+// exponential backoff, also after a restart, until it's dead-lettered. Delivery is at least once - a key expired on
+// read by concurrent requests can be reported twice.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use actix_web::client::Client;
+use actix_web::rt::time::timeout;
+use actix_web::rt::System;
+use futures::channel::mpsc::{channel, Sender};
+use futures::{stream, StreamExt};
+use serde::Serialize;
+
+use crate::key_encoding::KeyEncoding;
+use crate::metrics::WEBHOOK_DELIVERIES;
+use crate::outbound::{backoff, Delivery, Kind, Outbox};
+
+const MAX_IN_FLIGHT: usize = 16;
+// how often the queue is checked for deliveries due for a retry
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Expiration {
+    pub db: String,
+    // base64 encoded when it isn't UTF-8, see key_encoding
+    pub key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_encoding: Option<&'static str>,
+    pub expired_at: u128,
+}
+
+impl Expiration {
+    pub fn new(db: &str, key: &[u8], expired_at: u128) -> Self {
+        let encoding = KeyEncoding::of_document(std::iter::once(key));
+        Expiration {
+            db: db.into(),
+            key: encoding.encode(key).unwrap_or_default(),
+            key_encoding: encoding.header_value(),
+            expired_at,
+        }
+    }
+}
+
+pub struct Webhooks {
+    outbox: Arc<Outbox>,
+    wake: Mutex<Sender<()>>,
+}
+
+impl Webhooks {
+    // the delivery thread stops once the webhooks are dropped
+    pub fn start(outbox: Arc<Outbox>) -> Self {
+        let (wake, mut woken) = channel::<()>(1);
+        let queue = outbox.clone();
+        thread::Builder::new()
+            .name("webhook-delivery-thread".into())
+            .spawn(move || {
+                let mut runner = System::new("webhooks");
+                runner.block_on(async move {
+                    let client = Client::default();
+                    loop {
+                        match queue.pending(Kind::Webhook, true, MAX_IN_FLIGHT * 4) {
+                            Ok(due) => {
+                                stream::iter(due)
+                                    .for_each_concurrent(MAX_IN_FLIGHT, |d| {
+                                        deliver(&client, &queue, d)
+                                    })
+                                    .await
+                            }
+                            Err(e) => error!("Failed to read outbound webhooks, e = {}", e),
+                        }
+                        if let Ok(None) = timeout(POLL_INTERVAL, woken.next()).await {
+                            break;
+                        }
+                    }
+                });
+            })
+            .expect("Failed to register webhook delivery thread");
+
+        Webhooks {
+            outbox,
+            wake: Mutex::new(wake),
+        }
+    }
+
+    // notifications stay queued until a server delivers them
+    pub fn queued(outbox: Arc<Outbox>) -> Self {
+        let (wake, _) = channel::<()>(1);
+        Webhooks {
+            outbox,
+            wake: Mutex::new(wake),
+        }
+    }
+
+    pub fn notify(&self, url: &str, expiration: Expiration) {
+        let queued = serde_json::to_string(&expiration)
+            .map_err(anyhow::Error::from)
+            .and_then(|body| self.outbox.push(Kind::Webhook, &expiration.db, url, body));
+        match queued {
+            // a full wake channel means the thread is woken already
+            Ok(_) => {
+                let _ = self
+                    .wake
+                    .lock()
+                    .expect("Can't acquire webhook wake lock")
+                    .try_send(());
+            }
+            Err(e) => {
+                WEBHOOK_DELIVERIES.with_label_values(&["dropped"]).inc();
+                warn!(
+                    "Failed to queue webhook notification = {:?}, e = {}",
+                    expiration, e
+                );
+            }
+        }
+    }
+}
+
+async fn deliver(client: &Client, outbox: &Outbox, mut delivery: Delivery) {
+    let error = match client
+        .post(&delivery.target)
+        .timeout(DELIVERY_TIMEOUT)
+        .content_type("application/json")
+        .send_body(delivery.body.clone())
+        .await
+    {
+        Ok(res) if res.status().is_success() => {
+            WEBHOOK_DELIVERIES.with_label_values(&["delivered"]).inc();
+            if let Err(e) = outbox.done(&delivery) {
+                error!(
+                    "Failed to remove delivered webhook {}, e = {}",
+                    delivery.id, e
+                );
+            }
+            return;
+        }
+        Ok(res) => format!("answered with {}", res.status()),
+        Err(e) => e.to_string(),
+    };
+
+    WEBHOOK_DELIVERIES.with_label_values(&["failed"]).inc();
+    warn!(
+        "Webhook {} failed, attempt = {}, e = {}",
+        &delivery.target,
+        delivery.attempts + 1,
+        &error
+    );
+    let after = backoff(delivery.attempts + 1);
+    match outbox.retry(&mut delivery, error, after) {
+        Ok(true) => {
+            WEBHOOK_DELIVERIES.with_label_values(&["dead"]).inc();
+            error!(
+                "Webhook {} dead-lettered after {} attempts",
+                delivery.id, delivery.attempts
+            );
+        }
+        Ok(false) => {}
+        Err(e) => error!("Failed to reschedule webhook {}, e = {}", delivery.id, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_serialize_expiration() {
+        let expiration = Expiration::new("db", b"k", 1_600_000_000_000);
+        assert_eq!(
+            r#"{"db":"db","key":"k","expiredAt":1600000000000}"#,
+            serde_json::to_string(&expiration).unwrap()
+        );
+
+        let expiration = Expiration::new("db", &[0, 255], 1_600_000_000_000);
+        assert_eq!(
+            r#"{"db":"db","key":"AP8","keyEncoding":"base64","expiredAt":1600000000000}"#,
+            serde_json::to_string(&expiration).unwrap()
+        );
+    }
+}
@@ -0,0 +1,172 @@
+// Binary batch wire format used by the bulk endpoints.
+//
+// All integers are big endian:
+//
+// batch  := version:u8 count:u32 record*
+// record := key_len:u32 key:utf8 value_len:u32 value
+//
+// value_len = 0xFFFFFFFF marks an absent value (no value bytes follow) - a key to read in
+// a _mget request, a missing key in a _mget response and a delete in a _batch request.
+
+use std::convert::TryInto;
+use std::io::{self, Write};
+
+use anyhow::anyhow;
+
+use crate::errors::ErrorCtx;
+
+pub const WIRE_VERSION: u8 = 1;
+const ABSENT: u32 = u32::MAX;
+
+#[derive(Debug, PartialEq)]
+pub struct WireRecord {
+    pub key: String,
+    pub value: Option<Vec<u8>>,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl WireRecord {
+    pub fn new(key: String, value: Option<Vec<u8>>) -> Self {
+        WireRecord { key, value }
+    }
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.bytes.len() - self.pos < len {
+            return Err(malformed(format!("unexpected end at byte {}", self.pos)));
+        }
+        let slice = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        let bytes = self.take(4)?.try_into()?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+}
+
+pub fn encode(records: &[WireRecord]) -> Vec<u8> {
+    let size: usize = records
+        .iter()
+        .map(|r| 8 + r.key.len() + r.value.as_ref().map_or(0, Vec::len))
+        .sum();
+    let mut bytes = Vec::with_capacity(5 + size);
+    write_header(&mut bytes, records.len() as u32).expect("Writes to a Vec don't fail");
+    for record in records {
+        write_record(&mut bytes, record).expect("Writes to a Vec don't fail");
+    }
+    bytes
+}
+
+// a batch can be written record by record, with the header rewritten once the count is known
+pub fn write_header<W: Write>(out: &mut W, count: u32) -> io::Result<()> {
+    out.write_all(&[WIRE_VERSION])?;
+    out.write_all(&count.to_be_bytes())
+}
+
+pub fn write_record<W: Write>(out: &mut W, record: &WireRecord) -> io::Result<()> {
+    out.write_all(&(record.key.len() as u32).to_be_bytes())?;
+    out.write_all(record.key.as_bytes())?;
+    match &record.value {
+        Some(value) => {
+            out.write_all(&(value.len() as u32).to_be_bytes())?;
+            out.write_all(value)
+        }
+        None => out.write_all(&ABSENT.to_be_bytes()),
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> anyhow::Result<Vec<WireRecord>> {
+    let mut reader = Reader { bytes, pos: 0 };
+    let version = reader.u8()?;
+    if version != WIRE_VERSION {
+        return Err(malformed(format!("unsupported version {}", version)));
+    }
+
+    let count = reader.u32()? as usize;
+    // the count comes from the client, don't trust it for preallocation
+    let mut records = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let key_len = reader.u32()? as usize;
+        let key = String::from_utf8(reader.take(key_len)?.to_vec())
+            .map_err(|_| malformed("key is not valid utf8".into()))?;
+        let value = match reader.u32()? {
+            ABSENT => None,
+            len => Some(reader.take(len as usize)?.to_vec()),
+        };
+        records.push(WireRecord { key, value });
+    }
+
+    if reader.pos != bytes.len() {
+        return Err(malformed(format!(
+            "{} trailing bytes",
+            bytes.len() - reader.pos
+        )));
+    }
+    Ok(records)
+}
+
+fn malformed(reason: String) -> anyhow::Error {
+    anyhow!(ErrorCtx::Validation(format!(
+        "Malformed batch - {}",
+        reason
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<WireRecord> {
+        vec![
+            WireRecord::new("k1".into(), Some(b"v1".to_vec())),
+            WireRecord::new("k2".into(), None),
+            WireRecord::new("k3".into(), Some(vec![])),
+        ]
+    }
+
+    #[test]
+    fn should_encode_and_decode() {
+        let bytes = encode(&records());
+        assert_eq!(records(), decode(&bytes).unwrap());
+    }
+
+    #[test]
+    fn should_encode_to_documented_layout() {
+        let bytes = encode(&[WireRecord::new("k".into(), Some(b"v".to_vec()))]);
+        assert_eq!(
+            vec![1, 0, 0, 0, 1, 0, 0, 0, 1, b'k', 0, 0, 0, 1, b'v'],
+            bytes
+        );
+
+        let bytes = encode(&[WireRecord::new("k".into(), None)]);
+        assert_eq!(
+            vec![1, 0, 0, 0, 1, 0, 0, 0, 1, b'k', 0xFF, 0xFF, 0xFF, 0xFF],
+            bytes
+        );
+    }
+
+    #[test]
+    fn should_reject_malformed_batch() {
+        let bytes = encode(&records());
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode(&[bytes.as_slice(), &[0]].concat()).is_err());
+        assert!(decode(&[2, 0, 0, 0, 0]).is_err());
+        assert!(decode(&[]).is_err());
+    }
+
+    #[test]
+    fn should_decode_empty_batch() {
+        assert!(decode(&[1, 0, 0, 0, 0]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,249 @@
+// WebSocket subscriptions to changes of a db.
+//
+// A session subscribes to the whole db on the event bus when it's opened and forwards the events matching any
+// of the client's subscriptions, so overlapping subscriptions deliver an event once. Clients (un)subscribe
+// keys and prefixes with JSON text messages, a session without subscriptions receives nothing. The session
+// is closed when the db is closed or the client falls too far behind.
+
+use std::time::{Duration, Instant};
+
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web_actors::ws;
+use anyhow::anyhow;
+use futures::channel::mpsc::Receiver;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::ErrorCtx;
+use crate::events::{Event, EventKind, Filter};
+
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+// a client not answering pings for this long is disconnected
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(45);
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum WsRequest {
+    Subscribe {
+        key: Option<String>,
+        prefix: Option<String>,
+    },
+    Unsubscribe {
+        key: Option<String>,
+        prefix: Option<String>,
+    },
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum WsMessage<'a> {
+    Subscribed { subscriptions: usize },
+    Unsubscribed { subscriptions: usize },
+    Error { message: String },
+    Event(&'a Event),
+}
+
+pub struct WsSession {
+    db: String,
+    filters: Vec<Filter>,
+    changes: Option<Receiver<Event>>,
+    heartbeat: Instant,
+}
+
+impl WsRequest {
+    pub fn from_json(text: &str) -> anyhow::Result<Self> {
+        serde_json::from_str(text).map_err(|e| {
+            anyhow!(ErrorCtx::Validation(format!(
+                "Malformed subscription - {}",
+                e
+            )))
+        })
+    }
+}
+
+impl WsSession {
+    // changes of the whole db
+    pub fn new(db: &str, changes: Receiver<Event>) -> Self {
+        WsSession {
+            db: db.into(),
+            filters: vec![],
+            changes: Some(changes),
+            heartbeat: Instant::now(),
+        }
+    }
+
+    fn handle_request(&mut self, text: &str) -> anyhow::Result<WsMessage<'static>> {
+        match WsRequest::from_json(text)? {
+            WsRequest::Subscribe { key, prefix } => {
+                let filter = filter(&self.db, key, prefix)?;
+                if !self.filters.contains(&filter) {
+                    self.filters.push(filter);
+                }
+                Ok(WsMessage::Subscribed {
+                    subscriptions: self.filters.len(),
+                })
+            }
+            WsRequest::Unsubscribe { key, prefix } => {
+                let filter = filter(&self.db, key, prefix)?;
+                self.filters.retain(|f| f != &filter);
+                Ok(WsMessage::Unsubscribed {
+                    subscriptions: self.filters.len(),
+                })
+            }
+        }
+    }
+
+    fn wants(&self, event: &Event) -> bool {
+        // refreshes are requests to reload the record, not changes
+        event.kind != EventKind::Refresh && self.filters.iter().any(|f| f.matches(event))
+    }
+}
+
+// neither key nor prefix subscribes to the whole db
+fn filter(db: &str, key: Option<String>, prefix: Option<String>) -> anyhow::Result<Filter> {
+    match (key, prefix) {
+        (Some(_), Some(_)) => Err(anyhow!(ErrorCtx::Validation(
+            "Subscribe to either a key or a prefix".into()
+        ))),
+        (Some(key), None) => Ok(Filter::key(db, &key)),
+        (None, Some(prefix)) => Ok(Filter::prefix(db, &prefix)),
+        (None, None) => Ok(Filter::db(db)),
+    }
+}
+
+fn send(message: &WsMessage, ctx: &mut ws::WebsocketContext<WsSession>) {
+    match serde_json::to_string(message) {
+        Ok(text) => ctx.text(text),
+        Err(e) => error!("Failed to serialize message = {:?}, e = {}", message, e),
+    }
+}
+
+impl Actor for WsSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(changes) = self.changes.take() {
+            ctx.add_stream(changes);
+        }
+        ctx.run_interval(PING_INTERVAL, |session, ctx| {
+            if session.heartbeat.elapsed() > CLIENT_TIMEOUT {
+                info!("Closing unresponsive websocket of db = {}", &session.db);
+                ctx.stop();
+            } else {
+                ctx.ping(b"");
+            }
+        });
+    }
+}
+
+impl StreamHandler<Event> for WsSession {
+    fn handle(&mut self, event: Event, ctx: &mut Self::Context) {
+        if self.wants(&event) {
+            send(&WsMessage::Event(&event), ctx);
+        }
+    }
+
+    // the db was closed or the session fell behind and was disconnected from the event bus
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Away,
+            description: Some("Subscription ended".into()),
+        }));
+        ctx.stop();
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(payload)) => {
+                self.heartbeat = Instant::now();
+                ctx.pong(&payload);
+            }
+            Ok(ws::Message::Pong(_)) => self.heartbeat = Instant::now(),
+            Ok(ws::Message::Text(text)) => {
+                let reply = self
+                    .handle_request(&text)
+                    .unwrap_or_else(|e| WsMessage::Error {
+                        message: e.to_string(),
+                    });
+                send(&reply, ctx);
+            }
+            Ok(ws::Message::Binary(_)) => send(
+                &WsMessage::Error {
+                    message: "Subscriptions are JSON text messages".into(),
+                },
+                ctx,
+            ),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(ws::Message::Continuation(_)) | Ok(ws::Message::Nop) => {}
+            Err(e) => {
+                warn!("Websocket of db = {} failed, e = {}", &self.db, e);
+                ctx.stop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc::channel;
+
+    use super::*;
+
+    #[test]
+    fn should_parse_subscriptions() {
+        assert_eq!(
+            WsRequest::Subscribe {
+                key: None,
+                prefix: Some("user:".into())
+            },
+            WsRequest::from_json(r#"{"action": "subscribe", "prefix": "user:"}"#).unwrap()
+        );
+        assert!(WsRequest::from_json(r#"{"action": "watch"}"#).is_err());
+        assert!(filter("db", Some("k".into()), Some("p".into())).is_err());
+    }
+
+    #[test]
+    fn should_forward_subscribed_changes_once() {
+        let (_tx, rx) = channel(1);
+        let mut session = WsSession::new("db", rx);
+        let event = Event::new("db", "user:1", EventKind::Put);
+        assert!(!session.wants(&event));
+
+        session
+            .handle_request(r#"{"action": "subscribe", "prefix": "user:"}"#)
+            .unwrap();
+        session
+            .handle_request(r#"{"action": "subscribe", "key": "user:1"}"#)
+            .unwrap();
+        session
+            .handle_request(r#"{"action": "subscribe", "key": "user:1"}"#)
+            .unwrap();
+        assert_eq!(2, session.filters.len());
+        assert!(session.wants(&event));
+        assert!(!session.wants(&Event::new("db", "user:1", EventKind::Refresh)));
+        assert!(!session.wants(&Event::new("db", "order:1", EventKind::Put)));
+
+        session
+            .handle_request(r#"{"action": "unsubscribe", "prefix": "user:"}"#)
+            .unwrap();
+        assert!(!session.wants(&Event::new("db", "user:2", EventKind::Put)));
+        assert!(session.wants(&event));
+    }
+
+    #[test]
+    fn should_serialize_messages() {
+        let event = Event::new("db", "k", EventKind::Delete);
+        assert_eq!(
+            r#"{"type":"event","db":"db","key":"k","kind":"delete"}"#,
+            serde_json::to_string(&WsMessage::Event(&event)).unwrap()
+        );
+        assert_eq!(
+            r#"{"type":"subscribed","subscriptions":1}"#,
+            serde_json::to_string(&WsMessage::Subscribed { subscriptions: 1 }).unwrap()
+        );
+    }
+}